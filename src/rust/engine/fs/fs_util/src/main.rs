@@ -76,6 +76,12 @@ impl From<String> for ExitError {
   }
 }
 
+impl From<fs::FsError> for ExitError {
+  fn from(e: fs::FsError) -> Self {
+    ExitError(e.to_string(), ExitCode::UnknownError)
+  }
+}
+
 #[derive(Serialize)]
 struct SummaryWithDigest {
   digest: Digest,
@@ -383,11 +383,11 @@ impl<'a> Deref for TypedPath<'a> {
 impl<'a> From<&'a PathStat> for TypedPath<'a> {
   fn from(p: &'a PathStat) -> Self {
     match p {
-      PathStat::File { path, stat } => TypedPath::File {
+      PathStat::File { path, stat, .. } => TypedPath::File {
         path,
         is_executable: stat.is_executable,
       },
-      PathStat::Link { path, stat } => TypedPath::Link {
+      PathStat::Link { path, stat, .. } => TypedPath::Link {
         path,
         target: &stat.target,
       },
@@ -497,6 +497,153 @@ impl DigestTrie {
     Ok(Self(entries.into()))
   }
 
+  /// Return a copy of this trie with the given paths added (or replacing existing entries), and
+  /// the given removed paths dropped.
+  ///
+  /// Subtrees which contain none of the given paths are reused as-is, so only the directories
+  /// along the changed paths have their digests recomputed. The result is identical to a trie
+  /// created via `from_unique_paths` for the resulting set of paths.
+  pub fn update(
+    &self,
+    changed: Vec<TypedPath>,
+    file_digests: &HashMap<PathBuf, Digest>,
+    removed: Vec<&Path>,
+  ) -> Result<Self, String> {
+    self.update_helper(PathBuf::new(), changed, removed, file_digests)
+  }
+
+  fn update_helper(
+    &self,
+    prefix: PathBuf,
+    changed: Vec<TypedPath>,
+    removed: Vec<&Path>,
+    file_digests: &HashMap<PathBuf, Digest>,
+  ) -> Result<Self, String> {
+    let mut changed_by_name: HashMap<Name, Vec<TypedPath>> = HashMap::new();
+    for path in changed {
+      changed_by_name
+        .entry(first_path_component_to_name(&path)?)
+        .or_default()
+        .push(path);
+    }
+    let mut removed_by_name: HashMap<Name, Vec<&Path>> = HashMap::new();
+    for path in removed {
+      removed_by_name
+        .entry(first_path_component_to_name(path)?)
+        .or_default()
+        .push(path);
+    }
+
+    let existing_by_name = self
+      .0
+      .iter()
+      .map(|entry| (entry.name(), entry))
+      .collect::<HashMap<_, _>>();
+    let mut names = self
+      .0
+      .iter()
+      .map(Entry::name)
+      .chain(changed_by_name.keys().copied())
+      .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    let mut entries = Vec::new();
+    for name in names {
+      let existing = existing_by_name.get(&name).copied();
+      let changed = changed_by_name.remove(&name).unwrap_or_default();
+      let removed = removed_by_name.remove(&name).unwrap_or_default();
+      if changed.is_empty() && removed.is_empty() {
+        // Untouched: reuse the existing entry (and its digest).
+        entries.extend(existing.cloned());
+        continue;
+      }
+
+      let (mut leaves, child_changes): (Vec<_>, Vec<_>) = changed
+        .into_iter()
+        .partition(|path| path.components().count() == 1);
+      let (removed_leaves, child_removals): (Vec<_>, Vec<_>) = removed
+        .into_iter()
+        .partition(|path| path.components().count() == 1);
+      if leaves.len() > 1 {
+        return Err(format!(
+          "Snapshots must be updated with unique path stats; got duplicates for {:?}",
+          prefix.join(name.as_ref())
+        ));
+      }
+      let base = if removed_leaves.is_empty() {
+        existing
+      } else {
+        None
+      };
+
+      let is_dir_leaf = match leaves.pop() {
+        Some(TypedPath::File {
+          path,
+          is_executable,
+        }) => {
+          if !child_changes.is_empty() {
+            return Err(format!(
+              "Cannot update {:?} to be a file while also adding children below it.",
+              prefix.join(path)
+            ));
+          }
+          let digest = *file_digests
+            .get(prefix.join(path).as_path())
+            .ok_or_else(|| format!("No digest was provided for {:?}", prefix.join(path)))?;
+          entries.push(Entry::File(File {
+            name,
+            digest,
+            is_executable,
+          }));
+          continue;
+        }
+        Some(TypedPath::Link { path, target }) => {
+          if !child_changes.is_empty() {
+            return Err(format!(
+              "Cannot update {:?} to be a symlink while also adding children below it.",
+              prefix.join(path)
+            ));
+          }
+          entries.push(Entry::Symlink(Symlink {
+            name,
+            target: target.to_path_buf(),
+          }));
+          continue;
+        }
+        Some(TypedPath::Dir(_)) => true,
+        None => false,
+      };
+
+      let tree = match base {
+        Some(Entry::Directory(d)) => d.tree.clone(),
+        Some(entry) if !is_dir_leaf && child_changes.is_empty() => {
+          // Only removals below a file or symlink, which cannot have children.
+          entries.push(entry.clone());
+          continue;
+        }
+        None if !is_dir_leaf && child_changes.is_empty() => {
+          // Removed, or only removals below a path which does not exist.
+          continue;
+        }
+        // Either a new directory, or a file or symlink which is being replaced by a directory.
+        _ => EMPTY_DIGEST_TREE.clone(),
+      };
+      let tree = tree.update_helper(
+        prefix.join(name.as_ref()),
+        paths_of_child_dir(name, child_changes),
+        child_removals
+          .into_iter()
+          .map(|path| path.strip_prefix(name.as_ref()).unwrap())
+          .collect(),
+        file_digests,
+      )?;
+      entries.push(Entry::Directory(Directory::from_digest_tree(name, tree)));
+    }
+
+    Ok(Self(entries.into()))
+  }
+
   /// Create a DigestTrie from a root remexec::Directory and a map of its transitive children.
   fn from_remexec_directories(
     root: &remexec::Directory,
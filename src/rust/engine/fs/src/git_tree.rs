@@ -0,0 +1,272 @@
+// Copyright 2023 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::{Dir, DirectoryListing, File, FileContent, Link, Stat, Vfs};
+
+///
+/// A Vfs which reads the content of a committed git tree, rather than of a working directory, so
+/// that globs can be expanded as of a particular revision without checking it out.
+///
+/// Objects are read by invoking the `git` binary, which must be on the PATH: one process to list
+/// each directory, and one more to read the targets of any symlinks that it contains. Submodules
+/// are not traversed: like empty directories, they are not represented in a git tree.
+///
+#[derive(Clone)]
+pub struct GitTreeFS {
+  repository: PathBuf,
+  // The object id of the root tree.
+  tree: String,
+  executor: task_executor::Executor,
+}
+
+impl GitTreeFS {
+  ///
+  /// Create a GitTreeFS for the tree of the given revision (anything which `git rev-parse`
+  /// accepts, such as a commit id, branch or tag) in the repository containing `repository`.
+  ///
+  pub async fn new<P: AsRef<Path>>(
+    repository: P,
+    revision: &str,
+    executor: task_executor::Executor,
+  ) -> Result<GitTreeFS, String> {
+    let repository = repository.as_ref().to_owned();
+    let tree = Self::git(
+      &executor,
+      &repository,
+      vec![
+        "rev-parse".to_owned(),
+        "--verify".to_owned(),
+        "--quiet".to_owned(),
+        format!("{revision}^{{tree}}"),
+      ],
+      vec![],
+    )
+    .await
+    .map_err(|e| format!("Could not resolve {revision:?} to a git tree: {e}"))?;
+    let tree = String::from_utf8(tree)
+      .map_err(|e| format!("Unexpected output from `git rev-parse`: {e}"))?
+      .trim()
+      .to_owned();
+    Ok(GitTreeFS {
+      repository,
+      tree,
+      executor,
+    })
+  }
+
+  ///
+  /// Read the committed content of the given File.
+  ///
+  pub async fn read_file(&self, file: &File) -> Result<FileContent, String> {
+    let content = self.read_blob(&file.path).await?;
+    Ok(FileContent {
+      path: file.path.clone(),
+      content: Bytes::from(content),
+      is_executable: file.is_executable,
+    })
+  }
+
+  async fn read_blob(&self, path: &Path) -> Result<Vec<u8>, String> {
+    self
+      .run(
+        vec!["cat-file".to_owned(), "blob".to_owned(), self.object(path)?],
+        vec![],
+      )
+      .await
+  }
+
+  ///
+  /// Reads the content of many objects with a single `git cat-file --batch` process, in order.
+  ///
+  async fn read_blobs(&self, paths: &[PathBuf]) -> Result<Vec<Vec<u8>>, String> {
+    if paths.is_empty() {
+      return Ok(vec![]);
+    }
+    let mut input = Vec::new();
+    for path in paths {
+      input.extend_from_slice(self.object(path)?.as_bytes());
+      input.push(b'\n');
+    }
+    let output = self
+      .run(vec!["cat-file".to_owned(), "--batch".to_owned()], input)
+      .await?;
+
+    // Each object is `<object> SP <type> SP <size> LF <content> LF`, or `<name> SP missing LF`.
+    let mut blobs = Vec::with_capacity(paths.len());
+    let mut remaining = output.as_slice();
+    for path in paths {
+      let unexpected = || format!("Unexpected output from `git cat-file` for {path:?}");
+      let header_end = remaining
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(unexpected)?;
+      let header = std::str::from_utf8(&remaining[..header_end]).map_err(|_| unexpected())?;
+      if header.ends_with(" missing") {
+        return Err(format!("No object for {path:?} in tree {}", self.tree));
+      }
+      let size = header
+        .rsplit(' ')
+        .next()
+        .and_then(|size| size.parse::<usize>().ok())
+        .ok_or_else(unexpected)?;
+      let content_start = header_end + 1;
+      if remaining.len() < content_start + size + 1 {
+        return Err(unexpected());
+      }
+      blobs.push(remaining[content_start..content_start + size].to_vec());
+      remaining = &remaining[content_start + size + 1..];
+    }
+    Ok(blobs)
+  }
+
+  ///
+  /// The git object name of the given path (relative to the root) in this tree.
+  ///
+  fn object(&self, path: &Path) -> Result<String, String> {
+    if path.components().next().is_none() {
+      return Ok(self.tree.clone());
+    }
+    let path = path
+      .to_str()
+      .ok_or_else(|| format!("Path {} is not valid UTF-8.", path.display()))?;
+    Ok(format!("{}:{}", self.tree, path))
+  }
+
+  async fn run(&self, args: Vec<String>, input: Vec<u8>) -> Result<Vec<u8>, String> {
+    Self::git(&self.executor, &self.repository, args, input).await
+  }
+
+  ///
+  /// Runs git with the given arguments, writing the given input to its stdin.
+  ///
+  async fn git(
+    executor: &task_executor::Executor,
+    repository: &Path,
+    args: Vec<String>,
+    input: Vec<u8>,
+  ) -> Result<Vec<u8>, String> {
+    let repository = repository.to_owned();
+    executor
+      .spawn_blocking(
+        move || {
+          let mut child = Command::new("git")
+            .arg("-C")
+            .arg(&repository)
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run `git {}`: {e}", args.join(" ")))?;
+          // Write the input from another thread, so that git is never blocked writing output
+          // which we are not yet reading.
+          let mut stdin = child.stdin.take().expect("stdin was piped");
+          let writer = std::thread::spawn(move || stdin.write_all(&input));
+          let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to run `git {}`: {e}", args.join(" ")))?;
+          writer
+            .join()
+            .map_err(|_| format!("Failed to write input to `git {}`", args.join(" ")))?
+            .map_err(|e| format!("Failed to write input to `git {}`: {e}", args.join(" ")))?;
+          if !output.status.success() {
+            return Err(format!(
+              "`git {}` failed with {}: {}",
+              args.join(" "),
+              output.status,
+              String::from_utf8_lossy(&output.stderr).trim()
+            ));
+          }
+          Ok(output.stdout)
+        },
+        |e| Err(format!("git task failed: {e}")),
+      )
+      .await
+  }
+
+  async fn list_tree(&self, dir: &Dir) -> Result<DirectoryListing, String> {
+    let output = self
+      .run(
+        vec!["ls-tree".to_owned(), "-z".to_owned(), self.object(&dir.0)?],
+        vec![],
+      )
+      .await?;
+
+    let mut stats = Vec::new();
+    let mut link_names = Vec::new();
+    // Each entry is `<mode> SP <type> SP <object> TAB <name> NUL`.
+    for entry in output.split(|b| *b == 0).filter(|entry| !entry.is_empty()) {
+      let entry = std::str::from_utf8(entry)
+        .map_err(|e| format!("Unexpected output from `git ls-tree` for {dir:?}: {e}"))?;
+      let (mode, name) = entry
+        .split_once('\t')
+        .and_then(|(header, name)| Some((header.split(' ').next()?, name)))
+        .ok_or_else(|| format!("Unexpected output from `git ls-tree` for {dir:?}: {entry:?}"))?;
+      let path = PathBuf::from(name);
+      let stat = match mode {
+        "040000" => Stat::Dir(Dir(path)),
+        "100644" | "100755" => Stat::File(File {
+          path,
+          is_executable: mode == "100755",
+          xattrs: BTreeMap::new(),
+        }),
+        "120000" => {
+          // The target is filled in below, once the targets of all links have been read.
+          link_names.push(path);
+          continue;
+        }
+        // Submodules (mode 160000) are not traversed.
+        _ => continue,
+      };
+      stats.push(stat);
+    }
+
+    let link_paths = link_names
+      .iter()
+      .map(|name| dir.0.join(name))
+      .collect::<Vec<_>>();
+    let targets = self.read_blobs(&link_paths).await?;
+    for (path, target) in link_names.into_iter().zip(targets) {
+      let target = String::from_utf8(target)
+        .map_err(|e| format!("Symlink target for {path:?} is not valid UTF-8: {e}"))?;
+      stats.push(Stat::Link(Link {
+        path,
+        target: PathBuf::from(target),
+      }));
+    }
+    stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+    Ok(DirectoryListing(stats))
+  }
+}
+
+#[async_trait]
+impl Vfs<String> for GitTreeFS {
+  async fn read_link(&self, link: &Link) -> Result<PathBuf, String> {
+    // NB: The tree cannot change, so the target which was read when the Link was listed is still
+    // current.
+    link
+      .resolve_target(link.target.clone())
+      .map_err(|e| format!("Failed to read link {:?}: {e}", link.path))
+  }
+
+  async fn scandir(&self, dir: Dir) -> Result<Arc<DirectoryListing>, String> {
+    Ok(Arc::new(self.list_tree(&dir).await?))
+  }
+
+  fn is_ignored(&self, _stat: &Stat) -> bool {
+    false
+  }
+
+  fn mk_error(msg: &str) -> String {
+    msg.to_owned()
+  }
+}
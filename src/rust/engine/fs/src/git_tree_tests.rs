@@ -0,0 +1,123 @@
+// Copyright 2023 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use testutil::make_file;
+
+use crate::{
+  File, GitTreeFS, GlobExpansionConjunction, GlobMatching, Link, PathGlobs, StrictGlobMatching,
+  SymlinkBehavior, Vfs,
+};
+
+fn git(repository: &Path, args: &[&str]) {
+  // Ignore the user's and the system's git config, so that e.g. commit signing is not attempted.
+  let status = Command::new("git")
+    .env("GIT_CONFIG_GLOBAL", "/dev/null")
+    .env("GIT_CONFIG_NOSYSTEM", "1")
+    .arg("-C")
+    .arg(repository)
+    .args([
+      "-c",
+      "user.name=Pants",
+      "-c",
+      "user.email=pants@example.com",
+      "-c",
+      "commit.gpgsign=false",
+      "-c",
+      "init.defaultBranch=main",
+    ])
+    .args(args)
+    .status()
+    .unwrap();
+  assert!(status.success(), "git {args:?} failed with {status}");
+}
+
+#[tokio::test]
+async fn expand_committed_tree() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let repository = dir.path();
+  std::fs::create_dir_all(repository.join("src/nested")).unwrap();
+  make_file(&repository.join("src/a.rs"), b"fn a() {}", 0o600);
+  make_file(&repository.join("src/nested/b.rs"), b"fn b() {}", 0o700);
+  make_file(&repository.join("src/c.py"), b"", 0o600);
+  std::os::unix::fs::symlink("src", repository.join("link")).unwrap();
+  std::os::unix::fs::symlink("nested", repository.join("src/l")).unwrap();
+  std::os::unix::fs::symlink("/etc", repository.join("absolute")).unwrap();
+  git(repository, &["init", "--quiet"]);
+  git(repository, &["add", "."]);
+  git(repository, &["commit", "--quiet", "-m", "Initial commit."]);
+
+  // Changes to the working directory are not visible.
+  make_file(&repository.join("src/uncommitted.rs"), b"", 0o600);
+  std::fs::remove_file(repository.join("src/a.rs")).unwrap();
+
+  let git_fs = GitTreeFS::new(repository, "HEAD", task_executor::Executor::new())
+    .await
+    .unwrap();
+  let expand = |globs: Vec<&str>| {
+    let path_globs = PathGlobs::new(
+      globs.into_iter().map(|g| g.to_owned()).collect(),
+      StrictGlobMatching::Error("the test".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    let git_fs = git_fs.clone();
+    async move {
+      git_fs
+        .expand_globs(path_globs, SymlinkBehavior::Aware, None)
+        .await
+        .map(|path_stats| {
+          path_stats
+            .into_iter()
+            .map(|ps| ps.path().to_owned())
+            .collect::<Vec<_>>()
+        })
+    }
+  };
+
+  assert_eq!(
+    expand(vec!["**/*.rs"]).await.unwrap(),
+    vec![PathBuf::from("src/a.rs"), PathBuf::from("src/nested/b.rs")]
+  );
+  // Symlinks are expanded as committed.
+  assert_eq!(
+    expand(vec!["link/*.py"]).await.unwrap(),
+    vec![PathBuf::from("link/c.py")]
+  );
+  // Including relative to the directory containing a nested link.
+  assert_eq!(
+    expand(vec!["src/l/*.rs"]).await.unwrap(),
+    vec![PathBuf::from("src/l/b.rs")]
+  );
+  // Like PosixFS, link targets are made relative to the root, and absolute targets are rejected.
+  let link = |path: &str, target: &str| Link {
+    path: PathBuf::from(path),
+    target: PathBuf::from(target),
+  };
+  assert_eq!(
+    git_fs.read_link(&link("src/l", "nested")).await.unwrap(),
+    PathBuf::from("src/nested")
+  );
+  assert!(git_fs.read_link(&link("absolute", "/etc")).await.is_err());
+  assert!(expand(vec!["src/uncommitted.rs"]).await.is_err());
+
+  let content = git_fs
+    .read_file(&File {
+      path: PathBuf::from("src/a.rs"),
+      is_executable: false,
+      xattrs: BTreeMap::new(),
+    })
+    .await
+    .unwrap();
+  assert_eq!(content.content.as_ref(), b"fn a() {}");
+
+  assert!(
+    GitTreeFS::new(repository, "no-such-branch", task_executor::Executor::new())
+      .await
+      .is_err()
+  );
+}
@@ -1,6 +1,7 @@
 // Copyright 2023 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -12,14 +13,39 @@ use crate::Stat;
 lazy_static! {
   static ref EMPTY_IGNORE: Arc<GitignoreStyleExcludes> = Arc::new(GitignoreStyleExcludes {
     patterns: vec![],
+    gitignore_paths: vec![],
+    typed_patterns: vec![],
     gitignore: Gitignore::empty(),
+    file_gitignore: Gitignore::empty(),
+    dir_gitignore: Gitignore::empty(),
   });
 }
 
+///
+/// The kinds of Stat that an exclude pattern applies to: see
+/// `GitignoreStyleExcludes::create_typed`.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum StatKind {
+  /// Only paths which are not directories (i.e. files and symlinks).
+  File,
+  /// Only directories, like a gitignore pattern with a trailing slash.
+  Dir,
+  /// All paths, like a plain gitignore pattern.
+  Any,
+}
+
 #[derive(Debug)]
 pub struct GitignoreStyleExcludes {
   patterns: Vec<String>,
+  // Retained for `Self::merge`: the gitignore files which `gitignore` was built from, and the
+  // typed patterns (see `Self::create_typed`), which are the trailing entries of `patterns`.
+  gitignore_paths: Vec<PathBuf>,
+  typed_patterns: Vec<String>,
   gitignore: Gitignore,
+  // Patterns which only apply to files or only to directories: see `Self::create_typed`.
+  file_gitignore: Gitignore,
+  dir_gitignore: Gitignore,
 }
 
 impl GitignoreStyleExcludes {
@@ -34,15 +60,115 @@ impl GitignoreStyleExcludes {
   /// Create with patterns and possibly multiple files.
   ///
   /// Later paths in `gitignore_paths` take precedence. `patterns` takes precedence over all
-  /// `gitignore_paths`.
+  /// `gitignore_paths`. Surrounding whitespace (other than an escaped trailing space) is trimmed
+  /// from `patterns`, and duplicates are removed (preserving the last occurrence, which is the one
+  /// that takes effect).
   pub fn create_with_gitignore_files(
     patterns: Vec<String>,
     gitignore_paths: Vec<PathBuf>,
   ) -> Result<Arc<Self>, String> {
+    let patterns = Self::normalize_patterns(patterns)?;
     if patterns.is_empty() && gitignore_paths.is_empty() {
       return Ok(EMPTY_IGNORE.clone());
     }
 
+    let gitignore = Self::build_gitignore(&patterns, gitignore_paths.clone())?;
+
+    Ok(Arc::new(Self {
+      patterns,
+      gitignore_paths,
+      typed_patterns: vec![],
+      gitignore,
+      file_gitignore: Gitignore::empty(),
+      dir_gitignore: Gitignore::empty(),
+    }))
+  }
+
+  /// Create by appending the given (untyped) patterns to those of `base`, with the same
+  /// precedence as if they had been appended to a gitignore file: a `!` pattern in the overlay
+  /// re-includes paths which `base` ignores, and vice versa. A pattern which is repeated in the
+  /// overlay takes effect at its position in the overlay.
+  ///
+  /// This allows per-target excludes to be layered over global excludes without re-plumbing the
+  /// raw global patterns.
+  pub fn merge(base: &Arc<Self>, overlay: &[String]) -> Result<Arc<Self>, String> {
+    let overlay = Self::normalize_patterns(overlay.to_vec())?;
+    if overlay.is_empty() {
+      return Ok(base.clone());
+    }
+
+    let untyped_patterns = &base.patterns[..base.patterns.len() - base.typed_patterns.len()];
+    let patterns = untyped_patterns
+      .iter()
+      .filter(|pattern| !overlay.contains(pattern))
+      .chain(&overlay)
+      .cloned()
+      .collect::<Vec<_>>();
+    let gitignore = Self::build_gitignore(&patterns, base.gitignore_paths.clone())?;
+
+    Ok(Arc::new(Self {
+      patterns: patterns
+        .into_iter()
+        .chain(base.typed_patterns.iter().cloned())
+        .collect(),
+      gitignore_paths: base.gitignore_paths.clone(),
+      typed_patterns: base.typed_patterns.clone(),
+      gitignore,
+      file_gitignore: base.file_gitignore.clone(),
+      dir_gitignore: base.dir_gitignore.clone(),
+    }))
+  }
+
+  /// Create with patterns which each apply only to the given kind of Stat. This allows for
+  /// ignoring e.g. regular files named `build` while keeping directories named `build`, which
+  /// gitignore syntax cannot express.
+  ///
+  /// Patterns of each kind are matched independently: a `!` pattern only re-includes paths which
+  /// were ignored by a pattern of the same kind.
+  pub fn create_typed(patterns: Vec<(String, StatKind)>) -> Result<Arc<Self>, String> {
+    let mut any_patterns = Vec::new();
+    let mut file_patterns = Vec::new();
+    let mut dir_patterns = Vec::new();
+    for (pattern, kind) in patterns {
+      match kind {
+        StatKind::Any => any_patterns.push(pattern),
+        StatKind::File => file_patterns.push(pattern),
+        StatKind::Dir => dir_patterns.push(pattern),
+      }
+    }
+    let any_patterns = Self::normalize_patterns(any_patterns)?;
+    let file_patterns = Self::normalize_patterns(file_patterns)?;
+    let dir_patterns = Self::normalize_patterns(dir_patterns)?;
+    if any_patterns.is_empty() && file_patterns.is_empty() && dir_patterns.is_empty() {
+      return Ok(EMPTY_IGNORE.clone());
+    }
+
+    let typed_patterns = file_patterns
+      .iter()
+      .chain(&dir_patterns)
+      .cloned()
+      .collect::<Vec<_>>();
+    Ok(Arc::new(Self {
+      gitignore: Self::build_gitignore(&any_patterns, vec![])?,
+      file_gitignore: Self::build_gitignore(&file_patterns, vec![])?,
+      dir_gitignore: Self::build_gitignore(&dir_patterns, vec![])?,
+      gitignore_paths: vec![],
+      patterns: any_patterns
+        .into_iter()
+        .chain(typed_patterns.iter().cloned())
+        .collect(),
+      typed_patterns,
+    }))
+  }
+
+  fn build_gitignore(
+    patterns: &[String],
+    gitignore_paths: Vec<PathBuf>,
+  ) -> Result<Gitignore, String> {
+    if patterns.is_empty() && gitignore_paths.is_empty() {
+      return Ok(Gitignore::empty());
+    }
+
     let mut ignore_builder = GitignoreBuilder::new("");
 
     for path in gitignore_paths {
@@ -50,20 +176,54 @@ impl GitignoreStyleExcludes {
         return Err(format!("Error adding the path {}: {err:?}", path.display()));
       }
     }
-    for pattern in &patterns {
+    for pattern in patterns {
       ignore_builder
         .add_line(None, pattern)
         .map_err(|e| format!("Could not parse glob exclude pattern `{pattern:?}`: {e:?}"))?;
     }
 
-    let gitignore = ignore_builder
+    ignore_builder
       .build()
-      .map_err(|e| format!("Could not build ignore patterns: {e:?}"))?;
+      .map_err(|e| format!("Could not build ignore patterns: {e:?}"))
+  }
 
-    Ok(Arc::new(Self {
-      patterns,
-      gitignore,
-    }))
+  fn normalize_patterns(patterns: Vec<String>) -> Result<Vec<String>, String> {
+    let mut trimmed = Vec::with_capacity(patterns.len());
+    for pattern in &patterns {
+      let trimmed_pattern = Self::trim_pattern(pattern);
+      if trimmed_pattern.is_empty() {
+        return Err(format!(
+          "Glob exclude patterns must not be empty, but got `{pattern:?}`."
+        ));
+      }
+      trimmed.push(trimmed_pattern);
+    }
+    // The last match of a gitignore wins, so only the last occurrence of a duplicate matters.
+    let mut seen = HashSet::new();
+    let mut normalized = trimmed
+      .into_iter()
+      .rev()
+      .filter(|pattern| seen.insert(*pattern))
+      .map(|pattern| pattern.to_owned())
+      .collect::<Vec<_>>();
+    normalized.reverse();
+    Ok(normalized)
+  }
+
+  ///
+  /// Trims surrounding whitespace from a pattern, except for a trailing space which is escaped
+  /// with a backslash (which gitignore treats as part of the pattern).
+  ///
+  fn trim_pattern(pattern: &str) -> &str {
+    let pattern = pattern.trim_start();
+    let trimmed = pattern.trim_end();
+    let trailing_backslashes = trimmed.len() - trimmed.trim_end_matches('\\').len();
+    match pattern[trimmed.len()..].chars().next() {
+      Some(escaped) if trailing_backslashes % 2 == 1 => {
+        &pattern[..trimmed.len() + escaped.len_utf8()]
+      }
+      _ => trimmed,
+    }
   }
 
   /// Return the absolute file paths to the global gitignore, `<repo>/.gitignore`, and
@@ -103,15 +263,50 @@ impl GitignoreStyleExcludes {
     self.is_ignored_path(stat.path(), is_dir)
   }
 
+  ///
+  /// Like `Self::is_ignored`, but returns the pattern which caused the Stat to be ignored.
+  ///
+  pub(crate) fn ignoring_pattern(&self, stat: &Stat) -> Option<String> {
+    let is_dir = matches!(stat, &Stat::Dir(_));
+    let typed_gitignore = if is_dir {
+      &self.dir_gitignore
+    } else {
+      &self.file_gitignore
+    };
+    [&self.gitignore, typed_gitignore]
+      .into_iter()
+      .find_map(|gitignore| match gitignore.matched(stat.path(), is_dir) {
+        ::ignore::Match::Ignore(glob) => Some(glob.original().to_owned()),
+        ::ignore::Match::None | ::ignore::Match::Whitelist(_) => None,
+      })
+  }
+
   pub fn is_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
-    match self.gitignore.matched(path, is_dir) {
-      ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
-      ::ignore::Match::Ignore(_) => true,
-    }
+    let typed_gitignore = if is_dir {
+      &self.dir_gitignore
+    } else {
+      &self.file_gitignore
+    };
+    Self::is_ignore_match(self.gitignore.matched(path, is_dir))
+      || Self::is_ignore_match(typed_gitignore.matched(path, is_dir))
   }
 
   pub fn is_ignored_or_child_of_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
-    match self.gitignore.matched_path_or_any_parents(path, is_dir) {
+    if Self::is_ignore_match(self.gitignore.matched_path_or_any_parents(path, is_dir)) {
+      return true;
+    }
+    // Directory patterns apply to the path if it is a directory, and otherwise only to its parents.
+    let dir = if is_dir { Some(path) } else { path.parent() };
+    let is_ignored_dir = dir
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .map(|dir| Self::is_ignore_match(self.dir_gitignore.matched_path_or_any_parents(dir, true)))
+      .unwrap_or(false);
+    // And file patterns only ever apply to the path itself, since parents are directories.
+    is_ignored_dir || (!is_dir && Self::is_ignore_match(self.file_gitignore.matched(path, false)))
+  }
+
+  fn is_ignore_match<T>(m: ::ignore::Match<T>) -> bool {
+    match m {
       ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
       ::ignore::Match::Ignore(_) => true,
     }
@@ -121,10 +316,10 @@ impl GitignoreStyleExcludes {
 #[cfg(test)]
 mod tests {
   use std::fs;
-  use std::path::PathBuf;
+  use std::path::{Path, PathBuf};
   use std::sync::Arc;
 
-  use crate::{GitignoreStyleExcludes, PosixFS, Stat};
+  use crate::{GitignoreStyleExcludes, PosixFS, Stat, StatKind};
   use testutil::make_file;
 
   async fn read_mock_files(input: Vec<PathBuf>, posix_fs: &Arc<PosixFS>) -> Vec<Stat> {
@@ -214,6 +409,112 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_typed_patterns() {
+    let root = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(root.path().join("a")).unwrap();
+    make_file(&root.path().join("a/build"), b"content", 0o700);
+    fs::create_dir_all(root.path().join("b/build")).unwrap();
+    make_file(&root.path().join("b/build/out"), b"content", 0o700);
+
+    let create_posix_fs = |patterns| {
+      let ignorer = GitignoreStyleExcludes::create_typed(patterns).unwrap();
+      PosixFS::new(root.as_ref(), ignorer, task_executor::Executor::new()).unwrap()
+    };
+    let stat =
+      |posix_fs: &PosixFS, path: &str| posix_fs.stat_sync(Path::new(path)).unwrap().unwrap();
+
+    // A file-only ignore catches only the file.
+    let posix_fs = create_posix_fs(vec![("build".to_owned(), StatKind::File)]);
+    assert!(posix_fs.is_ignored(&stat(&posix_fs, "a/build")));
+    assert!(!posix_fs.is_ignored(&stat(&posix_fs, "b/build")));
+    assert!(!posix_fs.is_ignored(&stat(&posix_fs, "b/build/out")));
+
+    // And a directory-only ignore catches only the directory (and its contents).
+    let posix_fs = create_posix_fs(vec![("build".to_owned(), StatKind::Dir)]);
+    assert!(!posix_fs.is_ignored(&stat(&posix_fs, "a/build")));
+    assert!(posix_fs.is_ignored(&stat(&posix_fs, "b/build")));
+    let excludes =
+      GitignoreStyleExcludes::create_typed(vec![("build".to_owned(), StatKind::Dir)]).unwrap();
+    assert!(excludes.is_ignored_or_child_of_ignored_path(Path::new("b/build/out"), false));
+    assert!(!excludes.is_ignored_or_child_of_ignored_path(Path::new("a/build"), false));
+
+    // `Any` behaves like a plain pattern.
+    let posix_fs = create_posix_fs(vec![("build".to_owned(), StatKind::Any)]);
+    assert!(posix_fs.is_ignored(&stat(&posix_fs, "a/build")));
+    assert!(posix_fs.is_ignored(&stat(&posix_fs, "b/build")));
+  }
+
+  #[test]
+  fn test_patterns_are_normalized() {
+    let excludes = GitignoreStyleExcludes::create(vec![
+      "*.log".to_owned(),
+      "*.log".to_owned(),
+      " *.tmp ".to_owned(),
+    ])
+    .unwrap();
+    assert_eq!(
+      excludes.exclude_patterns(),
+      &["*.log".to_owned(), "*.tmp".to_owned()]
+    );
+
+    assert!(GitignoreStyleExcludes::create(vec!["*.log".to_owned(), "  ".to_owned()]).is_err());
+
+    // The last occurrence of a duplicate is kept, because it is the one which takes effect.
+    let excludes = GitignoreStyleExcludes::create(vec![
+      "*.log".to_owned(),
+      "!keep.log".to_owned(),
+      "*.log".to_owned(),
+    ])
+    .unwrap();
+    assert_eq!(
+      excludes.exclude_patterns(),
+      &["!keep.log".to_owned(), "*.log".to_owned()]
+    );
+    assert!(excludes.is_ignored_path(Path::new("keep.log"), false));
+
+    // An escaped trailing space is part of the pattern.
+    let excludes = GitignoreStyleExcludes::create(vec!["foo\\  ".to_owned()]).unwrap();
+    assert_eq!(excludes.exclude_patterns(), &["foo\\ ".to_owned()]);
+    assert!(excludes.is_ignored_path(Path::new("foo "), false));
+    assert!(!excludes.is_ignored_path(Path::new("foo"), false));
+  }
+
+  #[test]
+  fn test_merge() {
+    let base =
+      GitignoreStyleExcludes::create(vec!["*.log".to_owned(), "/dist".to_owned()]).unwrap();
+    let merged = GitignoreStyleExcludes::merge(&base, &["!keep.log".to_owned()]).unwrap();
+    assert!(base.is_ignored_path(Path::new("keep.log"), false));
+    assert!(!merged.is_ignored_path(Path::new("keep.log"), false));
+    assert!(merged.is_ignored_path(Path::new("other.log"), false));
+    assert!(merged.is_ignored_path(Path::new("dist"), true));
+    assert_eq!(
+      merged.exclude_patterns(),
+      &[
+        "*.log".to_owned(),
+        "/dist".to_owned(),
+        "!keep.log".to_owned()
+      ]
+    );
+
+    // A later overlay can re-ignore what an earlier one re-included.
+    let remerged = GitignoreStyleExcludes::merge(&merged, &["*.log".to_owned()]).unwrap();
+    assert!(remerged.is_ignored_path(Path::new("keep.log"), false));
+
+    // Typed patterns are preserved, and an empty overlay is a no-op.
+    let typed =
+      GitignoreStyleExcludes::create_typed(vec![("build".to_owned(), StatKind::Dir)]).unwrap();
+    let merged = GitignoreStyleExcludes::merge(&typed, &["*.log".to_owned()]).unwrap();
+    assert!(merged.is_ignored_path(Path::new("build"), true));
+    assert!(!merged.is_ignored_path(Path::new("build"), false));
+    assert!(merged.is_ignored_path(Path::new("a.log"), false));
+    assert!(Arc::ptr_eq(
+      &GitignoreStyleExcludes::merge(&base, &[]).unwrap(),
+      &base
+    ));
+  }
+
   #[test]
   fn test_gitignore_file_paths() {
     let root = tempfile::TempDir::new().unwrap();
@@ -1,26 +1,32 @@
 // Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
-use std::fmt::Display;
+use std::fmt::{self, Display};
 use std::iter::Iterator;
 use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures::future::{self, TryFutureExt};
 use glob::{MatchOptions, Pattern};
+use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use log::warn;
 use parking_lot::Mutex;
 
 use crate::{
-  Dir, GitignoreStyleExcludes, GlobExpansionConjunction, Link, LinkDepth, PathStat, Stat,
-  StrictGlobMatching, SymlinkBehavior, Vfs, MAX_LINK_DEPTH,
+  Dir, ExcludeMode, ExtensionAllowlist, FsError, GitignoreStyleExcludes, GlobExpansionConjunction,
+  Link, LinkDepth, PathStat, Stat, StatKind, StrictGlobMatching, SymlinkBehavior, Vfs,
+  MAX_LINK_DEPTH,
 };
 
 static DOUBLE_STAR: &str = "**";
+static RECURSIVE_SHORTHAND: &str = "::";
 
 lazy_static! {
   pub static ref SINGLE_STAR_GLOB: Pattern = Pattern::new("*").unwrap();
@@ -50,13 +56,60 @@ pub enum PathGlob {
   },
 }
 
+///
+/// One of the filespecs which a PreparedPathGlobs was parsed from, as it was given.
+///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-struct GlobParsedSource(String);
+pub struct GlobParsedSource(pub String);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub(crate) struct PathGlobIncludeEntry {
   input: GlobParsedSource,
   globs: Vec<PathGlob>,
+  // The kind of path which this input is expected to match: see
+  // `PreparedPathGlobs::with_expected_kind`.
+  expected_kind: StatKind,
+  // Excludes which are matched relative to each directory that this input lists: see
+  // `PreparedPathGlobs::with_relative_excludes`.
+  relative_exclude: Option<Arc<GitignoreStyleExcludes>>,
+}
+
+impl PartialEq for PathGlobIncludeEntry {
+  fn eq(&self, other: &Self) -> bool {
+    self.input == other.input
+      && self.globs == other.globs
+      && self.expected_kind == other.expected_kind
+      && self.relative_exclude.as_ref().map(|e| e.exclude_patterns())
+        == other
+          .relative_exclude
+          .as_ref()
+          .map(|e| e.exclude_patterns())
+  }
+}
+
+impl PathGlobIncludeEntry {
+  ///
+  /// The literal prefix of the input glob. All of the PathGlobs that it was parsed into share the
+  /// same literal prefix, since they differ only after a `**`.
+  ///
+  pub(crate) fn literal_prefix(&self) -> PathBuf {
+    self
+      .globs
+      .first()
+      .map(PathGlob::literal_prefix)
+      .unwrap_or_default()
+  }
+
+  ///
+  /// The literal directory prefix of the input glob: see `PathGlob::literal_directory_prefix`.
+  ///
+  pub(crate) fn literal_directory_prefix(&self) -> PathBuf {
+    self
+      .globs
+      .first()
+      .map(PathGlob::literal_directory_prefix)
+      .unwrap_or_default()
+  }
 }
 
 impl PathGlob {
@@ -101,6 +154,104 @@ impl PathGlob {
     )
   }
 
+  ///
+  /// Returns the globs in `a` which are not present in `b`, in their order in `a`. Globs are
+  /// compared as parsed (so e.g. `./a/*.rs` and `a/*.rs` are equal), rather than by what they
+  /// would match.
+  ///
+  pub fn difference(a: &[PathGlob], b: &[PathGlob]) -> Vec<PathGlob> {
+    let b: HashSet<&PathGlob> = b.iter().collect();
+    a.iter().filter(|glob| !b.contains(glob)).cloned().collect()
+  }
+
+  ///
+  /// The Pattern which this glob matches against the entries of its directory.
+  ///
+  pub fn wildcard_pattern(&self) -> &Pattern {
+    match self {
+      PathGlob::Wildcard { wildcard, .. } | PathGlob::DirWildcard { wildcard, .. } => wildcard,
+    }
+  }
+
+  ///
+  /// The Patterns which are matched below the directories matched by a `DirWildcard`, or an empty
+  /// slice for a `Wildcard`.
+  ///
+  pub fn remainder(&self) -> &[Pattern] {
+    match self {
+      PathGlob::Wildcard { .. } => &[],
+      PathGlob::DirWildcard { remainder, .. } => remainder,
+    }
+  }
+
+  ///
+  /// The (uncanonicalized) path of the directory that this glob is matched in.
+  ///
+  pub fn symbolic_path(&self) -> &Path {
+    match self {
+      PathGlob::Wildcard { symbolic_path, .. } | PathGlob::DirWildcard { symbolic_path, .. } => {
+        symbolic_path
+      }
+    }
+  }
+
+  ///
+  /// True if this glob begins with a recursive `**` component, and so matches at any depth below
+  /// its `symbolic_path`. A `**` is parsed into both a recursive `DirWildcard` and the alternative
+  /// with the `**` matching zero directories: only the former reports a leading doublestar.
+  ///
+  pub fn leading_doublestar(&self) -> bool {
+    match self {
+      PathGlob::Wildcard { .. } => false,
+      PathGlob::DirWildcard {
+        wildcard,
+        remainder,
+        ..
+      } => {
+        *wildcard == *SINGLE_STAR_GLOB
+          && remainder
+            .first()
+            .map(|pattern| DOUBLE_STAR == pattern.as_str())
+            .unwrap_or(false)
+      }
+    }
+  }
+
+  ///
+  /// The longest leading run of literal (i.e., non-wildcard) components of this glob, joined to
+  /// its `symbolic_path`: all paths which this glob matches are at or below it. For example,
+  /// `src/main/**/*.rs` has the literal prefix `src/main`, while a glob which begins with a
+  /// wildcard has only its `symbolic_path` (for a parsed filespec, the root).
+  ///
+  pub fn literal_prefix(&self) -> PathBuf {
+    self.literal_components(true)
+  }
+
+  ///
+  /// As `literal_prefix`, but excluding the final component of the glob, which might match a file
+  /// rather than a directory: the result always names a (possibly nonexistent) directory.
+  ///
+  pub(crate) fn literal_directory_prefix(&self) -> PathBuf {
+    self.literal_components(false)
+  }
+
+  fn literal_components(&self, include_final: bool) -> PathBuf {
+    let mut components = std::iter::once(self.wildcard_pattern())
+      .chain(self.remainder())
+      .collect::<Vec<_>>();
+    if !include_final {
+      components.pop();
+    }
+    let mut prefix = self.symbolic_path().to_owned();
+    prefix.extend(
+      components
+        .into_iter()
+        .take_while(|pattern| Pattern::escape(pattern.as_str()) == pattern.as_str())
+        .map(Pattern::as_str),
+    );
+    prefix
+  }
+
   pub(crate) fn spread_filespecs(
     filespecs: Vec<String>,
   ) -> Result<Vec<PathGlobIncludeEntry>, String> {
@@ -112,11 +263,27 @@ impl PathGlob {
       spec_globs_map.push(PathGlobIncludeEntry {
         input: GlobParsedSource(filespec),
         globs,
+        expected_kind: StatKind::Any,
+        relative_exclude: None,
       });
     }
     Ok(spec_globs_map)
   }
 
+  ///
+  /// Expands the `dir::` shorthand for "everything recursively under dir" into the equivalent
+  /// `dir/**` glob (and a bare `::` into `**`). Other globs are returned unchanged.
+  ///
+  pub(crate) fn expand_recursive_shorthand(filespec: &str) -> Cow<'_, str> {
+    match filespec.strip_suffix(RECURSIVE_SHORTHAND) {
+      Some(dir) => match dir.trim_end_matches('/') {
+        "" => Cow::Borrowed(DOUBLE_STAR),
+        dir => Cow::Owned(format!("{dir}/{DOUBLE_STAR}")),
+      },
+      None => Cow::Borrowed(filespec),
+    }
+  }
+
   ///
   /// Normalize the given glob pattern string by splitting it into path components, and dropping
   /// references to the current directory, and consecutive '**'s.
@@ -149,11 +316,15 @@ impl PathGlob {
   /// Given a filespec String relative to a canonical Dir and path, parse it to a normalized
   /// series of PathGlob objects.
   ///
+  /// A filespec which normalizes to the root itself (such as `.` or the empty string) is an
+  /// error: selecting the root directory as a PathStat is not supported.
+  ///
   fn parse(
     canonical_dir: Dir,
     symbolic_path: PathBuf,
     filespec: &str,
   ) -> Result<Vec<PathGlob>, String> {
+    let filespec = &*Self::expand_recursive_shorthand(filespec);
     // NB: Because the filespec is a String input, calls to `to_str_lossy` are not lossy; the
     // use of `Path` is strictly for os-independent Path parsing.
     let parts = Self::normalize_pattern(filespec)?
@@ -163,6 +334,12 @@ impl PathGlob {
           .map_err(|e| format!("Could not parse {filespec:?} as a glob: {e:?}"))
       })
       .collect::<Result<Vec<_>, _>>()?;
+    if parts.is_empty() {
+      return Err(format!(
+        "The glob {filespec:?} selects the root directory, which is not supported: use `*` to \
+        match its children, or `**` to match everything below it."
+      ));
+    }
 
     PathGlob::parse_globs(canonical_dir, symbolic_path, &parts, 0)
   }
@@ -264,38 +441,124 @@ impl PathGlob {
   }
 }
 
+///
+/// Renders the glob as a filespec relative to the root, e.g. `a/**/b/*.rs`. Parsing the
+/// rendered filespec produces an equivalent glob.
+///
+impl fmt::Display for PathGlob {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let (symbolic_path, wildcard, remainder) = match self {
+      PathGlob::Wildcard {
+        symbolic_path,
+        wildcard,
+        ..
+      } => (symbolic_path, wildcard, &[][..]),
+      PathGlob::DirWildcard {
+        symbolic_path,
+        wildcard,
+        remainder,
+        ..
+      } => (symbolic_path, wildcard, &remainder[..]),
+    };
+    if !symbolic_path.as_os_str().is_empty() {
+      write!(f, "{}/", symbolic_path.display())?;
+    }
+    write!(f, "{}", wildcard.as_str())?;
+    for pattern in remainder {
+      write!(f, "/{}", pattern.as_str())?;
+    }
+    Ok(())
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct PreparedPathGlobs {
   pub(crate) include: Vec<PathGlobIncludeEntry>,
   pub(crate) exclude: Arc<GitignoreStyleExcludes>,
+  // Excludes which are expanded like includes, with their matches removed from the result.
+  glob_exclude: Vec<PathGlob>,
   strict_match_behavior: StrictGlobMatching,
   conjunction: GlobExpansionConjunction,
+  // If set, the maximum number of directory levels below the root which expansion descends into.
+  pub(crate) max_depth: Option<usize>,
+  // If set, matched files without an allowed extension are removed from the result.
+  pub(crate) allowed_extensions: Option<ExtensionAllowlist>,
+  // If set, expansion fails once more than this many distinct paths have matched.
+  pub(crate) max_matches: Option<usize>,
+  // If true, every directory which is traversed during expansion is also emitted.
+  pub(crate) traversed_directories: bool,
+  // If true, a symlink whose destination does not exist fails expansion rather than being dropped.
+  pub(crate) broken_symlink_errors: bool,
+  // If true, excludes which never excluded a path are reported like unmatched includes.
+  pub(crate) strict_excludes: bool,
+  // If set, expansion fails as soon as possible once this becomes true.
+  cancelled: Option<Arc<AtomicBool>>,
+  // If set, the time taken by each directory listing is recorded here.
+  scandir_profile: Option<ScandirProfile>,
 }
 
+///
+/// The wall-clock time taken to list each directory during glob expansion, keyed by the canonical
+/// path of the directory (relative to the root). See `PreparedPathGlobs::with_scandir_profile`.
+///
+pub type ScandirProfile = Arc<Mutex<Vec<(PathBuf, Duration)>>>;
+
 impl PreparedPathGlobs {
   pub fn create(
     globs: Vec<String>,
     strict_match_behavior: StrictGlobMatching,
     conjunction: GlobExpansionConjunction,
-  ) -> Result<PreparedPathGlobs, String> {
+  ) -> Result<PreparedPathGlobs, FsError> {
+    Self::create_with_exclude_mode(
+      globs,
+      strict_match_behavior,
+      conjunction,
+      ExcludeMode::Gitignore,
+    )
+  }
+
+  pub(crate) fn create_with_exclude_mode(
+    globs: Vec<String>,
+    strict_match_behavior: StrictGlobMatching,
+    conjunction: GlobExpansionConjunction,
+    exclude_mode: ExcludeMode,
+  ) -> Result<PreparedPathGlobs, FsError> {
     let mut include_globs = Vec::new();
     let mut exclude_globs = Vec::new();
     for glob in globs {
       if glob.starts_with('!') {
-        let normalized_exclude: String = glob.chars().skip(1).collect();
+        let normalized_exclude = PathGlob::expand_recursive_shorthand(&glob[1..]).into_owned();
         exclude_globs.push(normalized_exclude);
       } else {
         include_globs.push(glob);
       }
     }
-    let include = PathGlob::spread_filespecs(include_globs)?;
-    let exclude = GitignoreStyleExcludes::create(exclude_globs)?;
+    let include = PathGlob::spread_filespecs(include_globs).map_err(FsError::GlobParse)?;
+    let (exclude, glob_exclude) = match exclude_mode {
+      ExcludeMode::Gitignore => (
+        GitignoreStyleExcludes::create(exclude_globs).map_err(FsError::IgnoreParse)?,
+        vec![],
+      ),
+      ExcludeMode::Glob => (
+        GitignoreStyleExcludes::create(vec![]).map_err(FsError::IgnoreParse)?,
+        PathGlob::create(exclude_globs).map_err(FsError::GlobParse)?,
+      ),
+    };
 
     Ok(PreparedPathGlobs {
       include,
       exclude,
+      glob_exclude,
       strict_match_behavior,
       conjunction,
+      max_depth: None,
+      allowed_extensions: None,
+      max_matches: None,
+      traversed_directories: false,
+      broken_symlink_errors: false,
+      strict_excludes: false,
+      cancelled: None,
+      scandir_profile: None,
     })
   }
 
@@ -305,6 +568,8 @@ impl PreparedPathGlobs {
       .map(|glob| PathGlobIncludeEntry {
         input: MISSING_GLOB_SOURCE.clone(),
         globs: vec![glob],
+        expected_kind: StatKind::Any,
+        relative_exclude: None,
       })
       .collect();
 
@@ -312,10 +577,91 @@ impl PreparedPathGlobs {
       include,
       // An empty exclude becomes EMPTY_IGNORE.
       exclude: GitignoreStyleExcludes::create(vec![])?,
+      glob_exclude: vec![],
       strict_match_behavior: StrictGlobMatching::Ignore,
       conjunction: GlobExpansionConjunction::AllMatch,
+      max_depth: None,
+      allowed_extensions: None,
+      max_matches: None,
+      traversed_directories: false,
+      broken_symlink_errors: false,
+      strict_excludes: false,
+      cancelled: None,
+      scandir_profile: None,
     })
   }
+
+  ///
+  /// Allow expansion to be cancelled by setting the given flag, in which case it fails with an
+  /// error as soon as possible. Directory listings which are already in flight may still complete,
+  /// but no further directories will be listed.
+  ///
+  pub fn with_cancellation(mut self, cancelled: Arc<AtomicBool>) -> PreparedPathGlobs {
+    self.cancelled = Some(cancelled);
+    self
+  }
+
+  ///
+  /// Record the time taken by each directory listing during expansion into the given profile, in
+  /// order to find the directories which dominate the cost of an expansion. A directory which is
+  /// listed more than once has one entry per listing. Nothing is timed unless this is set.
+  ///
+  pub fn with_scandir_profile(mut self, profile: ScandirProfile) -> PreparedPathGlobs {
+    self.scandir_profile = Some(profile);
+    self
+  }
+
+  ///
+  /// Declare that the given include glob is expected to match only directories (or only files).
+  /// Under strict matching, a glob which matches paths but none of the expected kind is reported
+  /// according to the strict match behavior. Includes default to `StatKind::Any`.
+  ///
+  pub fn with_expected_kind(
+    mut self,
+    glob: &str,
+    expected_kind: StatKind,
+  ) -> Result<PreparedPathGlobs, String> {
+    let mut found = false;
+    for pgie in &mut self.include {
+      if pgie.input.0 == glob {
+        pgie.expected_kind = expected_kind;
+        found = true;
+      }
+    }
+    if !found {
+      return Err(format!("{glob:?} is not one of the include globs."));
+    }
+    Ok(self)
+  }
+
+  ///
+  /// Exclude paths from the matches of the given include glob using gitignore-style patterns which
+  /// are relative to each directory that the glob lists while it is expanded, rather than to the
+  /// root. For example, `*/**` with the relative exclude `skip/` matches everything below each
+  /// top-level directory except the contents of any directory named `skip` within them.
+  ///
+  /// Each pattern is matched against the name of each entry of a listed directory, so a pattern
+  /// should not contain a slash other than a trailing one (which restricts it to directories).
+  /// Excluded directories are not descended into. Other include globs are not affected.
+  ///
+  pub fn with_relative_excludes(
+    mut self,
+    glob: &str,
+    patterns: Vec<String>,
+  ) -> Result<PreparedPathGlobs, String> {
+    let relative_exclude = GitignoreStyleExcludes::create(patterns)?;
+    let mut found = false;
+    for pgie in &mut self.include {
+      if pgie.input.0 == glob {
+        pgie.relative_exclude = Some(relative_exclude.clone());
+        found = true;
+      }
+    }
+    if !found {
+      return Err(format!("{glob:?} is not one of the include globs."));
+    }
+    Ok(self)
+  }
 }
 
 /// Allows checking in-memory if paths match the patterns.
@@ -386,6 +732,13 @@ pub trait GlobMatching<E: Display + Send + Sync + 'static>: Vfs<E> {
   ///
   /// Recursively expands PathGlobs into PathStats while applying excludes.
   ///
+  /// The PathStats are sorted by their (symbolic) paths, without duplicates, regardless of the
+  /// order in which they were discovered.
+  ///
+  /// PathGlobs without any includes (including those which consist only of excludes) always
+  /// expand to nothing, successfully, regardless of the strict match behavior: there is no input
+  /// which could have failed to match.
+  ///
   async fn expand_globs(
     &self,
     path_globs: PreparedPathGlobs,
@@ -400,10 +753,322 @@ pub trait GlobMatching<E: Display + Send + Sync + 'static>: Vfs<E> {
     )
     .await
   }
+
+  ///
+  /// Expands PathGlobs like `expand_globs`, but blocks the calling thread until the expansion has
+  /// completed on the given Executor: a convenience for simple single-shot (e.g. CLI) tools.
+  ///
+  /// NB: This blocks the calling thread, and so must not be called from within an async context
+  /// (where it will panic).
+  ///
+  fn expand_globs_blocking(
+    &self,
+    executor: &task_executor::Executor,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+    unmatched_globs_additional_context: Option<String>,
+  ) -> Result<Vec<PathStat>, E> {
+    executor.block_on(self.expand_globs(
+      path_globs,
+      symlink_behavior,
+      unmatched_globs_additional_context,
+    ))
+  }
+
+  ///
+  /// Expands PathGlobs like `expand_globs`, but additionally returns the canonical paths (relative
+  /// to the root) of the directories which were listed during expansion, sorted and without
+  /// duplicates. These are the directories which a filesystem watcher must watch in order to know
+  /// when the expansion might change.
+  ///
+  async fn expand_with_dependencies(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+    unmatched_globs_additional_context: Option<String>,
+  ) -> Result<(Vec<PathStat>, Vec<PathBuf>), E> {
+    // Any profile which the caller requested is extended once expansion has completed.
+    let requested_profile = path_globs.scandir_profile.clone();
+    let profile = ScandirProfile::default();
+    let path_stats = GlobMatchingImplementation::expand_globs(
+      self,
+      path_globs.with_scandir_profile(profile.clone()),
+      symlink_behavior,
+      unmatched_globs_additional_context,
+    )
+    .await;
+
+    let profile = std::mem::take(&mut *profile.lock());
+    let mut dependencies = profile
+      .iter()
+      .map(|(dir, _)| dir.clone())
+      .collect::<Vec<_>>();
+    dependencies.sort();
+    dependencies.dedup();
+    if let Some(requested_profile) = requested_profile {
+      requested_profile.lock().extend(profile);
+    }
+    Ok((path_stats?, dependencies))
+  }
+
+  ///
+  /// Recursively expands PathGlobs into PathStats like `expand_globs`, but additionally returns
+  /// the input filespecs which matched each PathStat. The strict match behavior and conjunction of
+  /// the PathGlobs are ignored.
+  ///
+  async fn expand_attributed(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<Vec<(PathStat, Vec<GlobParsedSource>)>, E> {
+    GlobMatchingImplementation::expand_attributed(self, path_globs, symlink_behavior).await
+  }
+
+  ///
+  /// Expands PathGlobs like `expand_attributed`, but only returns the paths which were matched by
+  /// more than one of the input filespecs, alongside those filespecs. This is a diagnostic for
+  /// overlapping globs: the matched paths are otherwise deduplicated silently.
+  ///
+  async fn expand_overlaps(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<Vec<(PathBuf, Vec<GlobParsedSource>)>, E> {
+    let attributed =
+      GlobMatchingImplementation::expand_attributed(self, path_globs, symlink_behavior).await?;
+    Ok(
+      attributed
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .map(|(path_stat, sources)| (path_stat.path().to_owned(), sources))
+        .collect(),
+    )
+  }
+
+  ///
+  /// Returns true if the given PathGlobs match at least one path.
+  ///
+  /// Unlike `expand_globs`, this stops expanding (and scanning directories) as soon as any path
+  /// has matched. The strict match behavior and conjunction of the PathGlobs are ignored.
+  ///
+  async fn any_match(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<bool, E> {
+    GlobMatchingImplementation::any_match(self, path_globs, symlink_behavior).await
+  }
+
+  ///
+  /// Recursively expands PathGlobs into PathStats like `expand_globs`, but rather than failing
+  /// on the first error listing a directory, records the symbolic path of each directory which
+  /// could not be listed alongside its error, and continues expanding. The strict match behavior
+  /// and conjunction of the PathGlobs are ignored.
+  ///
+  async fn expand_lenient(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<(Vec<PathStat>, Vec<(PathBuf, E)>), E> {
+    GlobMatchingImplementation::expand_lenient(self, path_globs, symlink_behavior).await
+  }
+
+  ///
+  /// Expands PathGlobs like `expand_globs`, but requires that exactly one PathStat matches: for
+  /// example, for a value which must name a single file. Zero or multiple matches are an error,
+  /// which names the ambiguous matches. The strict match behavior of the PathGlobs is ignored.
+  ///
+  async fn expand_single_required(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<PathStat, E> {
+    GlobMatchingImplementation::expand_single_required(self, path_globs, symlink_behavior).await
+  }
+
+  ///
+  /// Recursively expands PathGlobs into PathStats like `expand_globs`, and groups them by their
+  /// parent directory (which is empty for paths directly below the root). Directories are ordered
+  /// by their first matched path, and each directory's PathStats retain the order in which
+  /// `expand_globs` returned them.
+  ///
+  async fn expand_grouped_by_dir(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<IndexMap<PathBuf, Vec<PathStat>>, E> {
+    GlobMatchingImplementation::expand_grouped_by_dir(self, path_globs, symlink_behavior).await
+  }
 }
 
 impl<E: Display + Send + Sync + 'static, T: Vfs<E>> GlobMatching<E> for T {}
 
+///
+/// State which is shared between all of the concurrent branches of one glob expansion.
+///
+struct Expansion<E> {
+  path_stats: Mutex<Vec<PathStat>>,
+  // If true, expansion stops as soon as any PathStat has matched.
+  stop_at_first_match: bool,
+  max_depth: Option<usize>,
+  // Set if a directory was not descended into because it was beyond the max_depth.
+  max_depth_reached: Mutex<bool>,
+  // If set, errors listing directories are recorded here rather than failing the expansion.
+  errors: Option<Mutex<Vec<(PathBuf, E)>>>,
+  cancelled: Option<Arc<AtomicBool>>,
+  scandir_profile: Option<ScandirProfile>,
+  // If set, the maximum number of distinct matches, and the include globs to blame if it is
+  // exceeded.
+  max_matches: Option<(usize, Vec<String>)>,
+  // If true, directories which are traversed by a DirWildcard are also emitted.
+  traversed_directories: bool,
+  // If true, a symlink whose destination does not exist is an error rather than being dropped.
+  broken_symlink_errors: bool,
+  // If set, the exclude patterns which have excluded at least one path.
+  exclude_hits: Option<Mutex<HashSet<String>>>,
+}
+
+impl<E> Expansion<E> {
+  fn new(stop_at_first_match: bool, max_depth: Option<usize>) -> Expansion<E> {
+    Expansion {
+      path_stats: Mutex::new(Vec::new()),
+      stop_at_first_match,
+      max_depth,
+      max_depth_reached: Mutex::new(false),
+      errors: None,
+      cancelled: None,
+      scandir_profile: None,
+      max_matches: None,
+      traversed_directories: false,
+      broken_symlink_errors: false,
+      exclude_hits: None,
+    }
+  }
+
+  fn collecting_errors(mut self) -> Expansion<E> {
+    self.errors = Some(Mutex::new(Vec::new()));
+    self
+  }
+
+  fn cancellable(mut self, cancelled: Option<Arc<AtomicBool>>) -> Expansion<E> {
+    self.cancelled = cancelled;
+    self
+  }
+
+  fn profiled(mut self, scandir_profile: Option<ScandirProfile>) -> Expansion<E> {
+    self.scandir_profile = scandir_profile;
+    self
+  }
+
+  fn capped(mut self, max_matches: Option<usize>, sources: Vec<String>) -> Expansion<E> {
+    self.max_matches = max_matches.map(|max_matches| (max_matches, sources));
+    self
+  }
+
+  fn emitting_traversed_directories(mut self, traversed_directories: bool) -> Expansion<E> {
+    self.traversed_directories = traversed_directories;
+    self
+  }
+
+  fn failing_on_broken_symlinks(mut self, broken_symlink_errors: bool) -> Expansion<E> {
+    self.broken_symlink_errors = broken_symlink_errors;
+    self
+  }
+
+  fn tracking_exclude_hits(mut self, strict_excludes: bool) -> Expansion<E> {
+    self.exclude_hits = strict_excludes.then(|| Mutex::new(HashSet::new()));
+    self
+  }
+
+  ///
+  /// Returns true if the given Stat is excluded, and records the pattern which excluded it if
+  /// exclude hits are being tracked.
+  ///
+  fn is_excluded(&self, exclude: &GitignoreStyleExcludes, stat: &Stat) -> bool {
+    match &self.exclude_hits {
+      Some(exclude_hits) => match exclude.ignoring_pattern(stat) {
+        Some(pattern) => {
+          exclude_hits.lock().insert(pattern);
+          true
+        }
+        None => false,
+      },
+      None => exclude.is_ignored(stat),
+    }
+  }
+
+  ///
+  /// Returns an error message if the given matches exceed the max_matches. Because the same path
+  /// may be matched more than once, duplicates are removed before deciding that the limit has
+  /// actually been exceeded.
+  ///
+  fn exceeded_max_matches(&self, path_stats: &mut Vec<PathStat>) -> Option<String> {
+    let (max_matches, sources) = self.max_matches.as_ref()?;
+    if path_stats.len() <= *max_matches {
+      return None;
+    }
+    #[allow(clippy::unnecessary_sort_by)]
+    path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    path_stats.dedup_by(|a, b| a.path() == b.path());
+    if path_stats.len() <= *max_matches {
+      return None;
+    }
+    Some(format!(
+      "Globs {sources:?} matched more than the maximum of {max_matches} paths."
+    ))
+  }
+
+  fn is_cancelled(&self) -> bool {
+    self
+      .cancelled
+      .as_ref()
+      .map(|cancelled| cancelled.load(Ordering::SeqCst))
+      .unwrap_or(false)
+  }
+
+  ///
+  /// Records an error which occurred while listing the given directory if errors are being
+  /// collected, or otherwise returns it.
+  ///
+  fn record_error(&self, symbolic_path: PathBuf, error: E) -> Result<(), E> {
+    match &self.errors {
+      Some(errors) => {
+        errors.lock().push((symbolic_path, error));
+        Ok(())
+      }
+      None => Err(error),
+    }
+  }
+
+  fn should_stop(&self) -> bool {
+    self.stop_at_first_match && !self.path_stats.lock().is_empty()
+  }
+
+  ///
+  /// Returns true if the given directory is too deep to descend into, and records that the
+  /// max_depth was reached.
+  ///
+  fn beyond_max_depth(&self, dir: &Path) -> bool {
+    match self.max_depth {
+      Some(max_depth) if dir.components().count() > max_depth => {
+        *self.max_depth_reached.lock() = true;
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+///
+/// True if there is no ExtensionAllowlist, or if it permits the given PathStat.
+///
+fn permitted_by(allowed_extensions: &Option<ExtensionAllowlist>, path_stat: &PathStat) -> bool {
+  match allowed_extensions {
+    Some(allowed_extensions) => allowed_extensions.permits_path_stat(path_stat),
+    None => true,
+  }
+}
+
 // NB: This trait exists because `expand_single()` (and its return type) should be private, but
 // traits don't allow specifying private methods (and we don't want to use a top-level `fn` because
 // it's much more awkward than just specifying `&self`).
@@ -412,15 +1077,27 @@ impl<E: Display + Send + Sync + 'static, T: Vfs<E>> GlobMatching<E> for T {}
 trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
   async fn directory_listing(
     &self,
+    result: &Expansion<E>,
     canonical_dir: Dir,
     symbolic_path: PathBuf,
     wildcard: Pattern,
     exclude: &Arc<GitignoreStyleExcludes>,
+    relative_exclude: Option<&Arc<GitignoreStyleExcludes>>,
     symlink_behavior: SymlinkBehavior,
     link_depth: LinkDepth,
   ) -> Result<Vec<(PathStat, LinkDepth)>, E> {
     // List the directory to create relative Stats.
-    let dir_listing = self.scandir(canonical_dir.clone()).await?;
+    let dir_listing = match &result.scandir_profile {
+      Some(scandir_profile) => {
+        let start = Instant::now();
+        let dir_listing = self.scandir(canonical_dir.clone()).await;
+        scandir_profile
+          .lock()
+          .push((canonical_dir.0.clone(), start.elapsed()));
+        dir_listing?
+      }
+      None => self.scandir(canonical_dir.clone()).await?,
+    };
 
     // Match any relevant Stats, and join them into PathStats.
     let path_stats = future::try_join_all(
@@ -435,6 +1112,12 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
             .map(|file_name| wildcard.matches_path(Path::new(file_name)))
             .unwrap_or(false)
         })
+        .filter(|stat| {
+          // Filter out paths which are excluded relative to this directory.
+          relative_exclude
+            .map(|relative_exclude| !relative_exclude.is_ignored(stat))
+            .unwrap_or(true)
+        })
         .filter_map(|stat| {
           // Append matched filenames.
           stat
@@ -445,12 +1128,14 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
         })
         .map(|(stat_symbolic_path, stat)| {
           let context = self.clone();
-          let exclude = exclude.clone();
+          let broken_symlink_errors = result.broken_symlink_errors;
           let stat = stat.within(&canonical_dir.0);
+          // Filter paths that are ignored by local excludes. Context ("global") ignore patterns
+          // are applied during `scandir`.
+          let excluded = result.is_excluded(exclude, &stat);
           async move {
-            // Canonicalize matched PathStats, and filter paths that are ignored by local excludes.
-            // Context ("global") ignore patterns are applied during `scandir`.
-            if exclude.is_ignored(&stat) {
+            // Canonicalize matched PathStats.
+            if excluded {
               Ok(None)
             } else {
               match stat {
@@ -469,7 +1154,15 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
                     )));
                   }
 
-                  let dest = context.canonicalize_link(stat_symbolic_path, l).await?;
+                  let target = l.target.clone();
+                  let dest = context
+                    .canonicalize_link(stat_symbolic_path.clone(), l)
+                    .await?;
+                  if dest.is_none() && broken_symlink_errors {
+                    return Err(Self::mk_error(&format!(
+                      "Symlink {stat_symbolic_path:?} points to {target:?}, which does not exist."
+                    )));
+                  }
 
                   Ok(dest.map(|ps| (ps, link_depth + 1)))
                 }
@@ -495,33 +1188,108 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
     let PreparedPathGlobs {
       include,
       exclude,
+      glob_exclude,
       strict_match_behavior,
       conjunction,
-      ..
+      max_depth,
+      allowed_extensions,
+      max_matches,
+      traversed_directories,
+      broken_symlink_errors,
+      strict_excludes,
+      cancelled,
+      scandir_profile,
     } = path_globs;
 
+    // NB: Vacuously successful, even under strict matching: see `GlobMatching::expand_globs`.
     if include.is_empty() {
       return Ok(vec![]);
     }
 
-    let result = Arc::new(Mutex::new(Vec::new()));
+    let result = Arc::new(
+      Expansion::new(false, max_depth)
+        .cancellable(cancelled)
+        .profiled(scandir_profile.clone())
+        .capped(
+          max_matches,
+          include.iter().map(|pgie| pgie.input.0.clone()).collect(),
+        )
+        .emitting_traversed_directories(traversed_directories)
+        .failing_on_broken_symlinks(broken_symlink_errors)
+        .tracking_exclude_hits(
+          strict_excludes && strict_match_behavior.should_check_glob_matches(),
+        ),
+    );
 
     let mut sources = Vec::new();
+    let mut root_kinds = Vec::new();
+    let mut root_globs = Vec::new();
     let mut roots = Vec::new();
     for pgie in include {
       let source = Arc::new(pgie.input);
       for path_glob in pgie.globs {
         sources.push(source.clone());
+        root_kinds.push(pgie.expected_kind);
+        root_globs.push(path_glob.clone());
         roots.push(self.expand_single(
           result.clone(),
           exclude.clone(),
+          pgie.relative_exclude.clone(),
           path_glob,
           symlink_behavior,
         ));
       }
     }
 
-    let matched = future::try_join_all(roots).await?;
+    let root_matches = future::try_join_all(roots).await?;
+
+    // Glob excludes are subtracted before checking for unmatched globs, so that an include whose
+    // matches were all excluded is reported like it would be for gitignore-style excludes.
+    let glob_excluded = self
+      .expand_glob_exclude(
+        glob_exclude,
+        &exclude,
+        max_depth,
+        scandir_profile,
+        symlink_behavior,
+      )
+      .await?;
+    let matched = root_matches
+      .iter()
+      .map(|path_stats| {
+        path_stats
+          .iter()
+          .any(|ps| !glob_excluded.contains(ps.path()))
+      })
+      .collect::<Vec<_>>();
+
+    // The paths which were matched by each include which expects a particular kind of path.
+    let mut expected_kinds: IndexMap<Arc<GlobParsedSource>, (StatKind, Vec<PathStat>)> =
+      IndexMap::new();
+    for ((source, expected_kind), path_stats) in
+      sources.iter().zip(root_kinds).zip(root_matches.iter())
+    {
+      if expected_kind == StatKind::Any {
+        continue;
+      }
+      let (_, matched_paths) = expected_kinds
+        .entry(source.clone())
+        .or_insert_with(|| (expected_kind, vec![]));
+      matched_paths.extend(
+        path_stats
+          .iter()
+          .filter(|ps| !glob_excluded.contains(ps.path()))
+          .cloned(),
+      );
+    }
+
+    if strict_match_behavior.should_check_glob_matches() && *result.max_depth_reached.lock() {
+      warn!(
+        "Glob expansion did not descend more than {} directories deep, so some paths may not \
+         have been matched.",
+        max_depth.unwrap_or_default()
+      );
+    }
 
     if strict_match_behavior.should_check_glob_matches() {
       // Get all the inputs which didn't transitively expand to any files.
@@ -540,8 +1308,9 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
         .collect::<HashSet<_>>();
 
       let non_matching_inputs = sources
-        .into_iter()
-        .filter(|s| !matching_inputs.contains(s))
+        .iter()
+        .filter(|s| !matching_inputs.contains(*s))
+        .cloned()
         .collect::<HashSet<_>>();
 
       let match_failed = match conjunction {
@@ -581,12 +1350,33 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
             format!(", excludes: {exclude_patterns:?}")
           }
         };
+        // Distinguish globs which did not match because a directory that they name does not
+        // exist from globs which matched nothing in existing directories.
+        let mut missing_directories = future::join_all(
+          sources
+            .iter()
+            .zip(root_globs.into_iter())
+            .filter(|(source, _)| non_matching_inputs.contains(*source))
+            .map(|(_, path_glob)| self.missing_directory(path_glob)),
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        missing_directories.sort();
+        missing_directories.dedup();
+        let missing_directories_portion = match missing_directories.as_slice() {
+          [] => "".to_string(),
+          [missing_directory] => format!(", missing directory: {missing_directory:?}"),
+          _ => format!(", missing directories: {missing_directories:?}"),
+        };
         let msg = format!(
-          "{}{}{}{}{}",
+          "{}{}{}{}{}{}",
           prefix,
           origin,
           unmatched_globs,
           excludes_portion,
+          missing_directories_portion,
           unmatched_globs_additional_context.unwrap_or_else(|| "".to_owned())
         );
         if strict_match_behavior.should_throw_on_error() {
@@ -597,24 +1387,438 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
       }
     }
 
+    if let Some(exclude_hits) = &result.exclude_hits {
+      let exclude_hits = exclude_hits.lock();
+      // NB: A `!` pattern re-includes paths rather than excluding them, so it can never be hit.
+      let unmatched_excludes = exclude
+        .exclude_patterns()
+        .iter()
+        .filter(|pattern| !pattern.starts_with('!') && !exclude_hits.contains(*pattern))
+        .cloned()
+        .collect::<Vec<_>>();
+      if !unmatched_excludes.is_empty() {
+        let origin = match &strict_match_behavior {
+          StrictGlobMatching::Warn(description) | StrictGlobMatching::Error(description) => {
+            format!(" from {description}: ")
+          }
+          _ => ": ".to_string(),
+        };
+        let msg = match unmatched_excludes.as_slice() {
+          [unmatched_exclude] => format!("Unmatched exclude{origin}{unmatched_exclude:?}"),
+          _ => format!("Unmatched excludes{origin}{unmatched_excludes:?}"),
+        };
+        if strict_match_behavior.should_throw_on_error() {
+          return Err(Self::mk_error(&msg));
+        } else {
+          warn!("{}", msg);
+        }
+      }
+    }
+
     let mut path_stats = Arc::try_unwrap(result)
       .unwrap_or_else(|_| panic!("expand violated its contract."))
+      .path_stats
       .into_inner()
       .into_iter()
       .collect::<Vec<_>>();
     #[allow(clippy::unnecessary_sort_by)]
     path_stats.sort_by(|a, b| a.path().cmp(b.path()));
     path_stats.dedup_by(|a, b| a.path() == b.path());
+
+    path_stats.retain(|ps| !glob_excluded.contains(ps.path()));
+
+    if strict_match_behavior.should_check_glob_matches() && !expected_kinds.is_empty() {
+      let mut mismatches = Vec::new();
+      for (source, (expected_kind, mut matched_paths)) in expected_kinds {
+        #[allow(clippy::unnecessary_sort_by)]
+        matched_paths.sort_by(|a, b| a.path().cmp(b.path()));
+        matched_paths.dedup_by(|a, b| a.path() == b.path());
+        let (dirs, non_dirs): (Vec<_>, Vec<_>) = matched_paths
+          .iter()
+          .partition(|ps| matches!(ps, PathStat::Dir { .. }));
+        let (expected, actual, mismatched) = match expected_kind {
+          StatKind::Dir => ("directories", non_dirs, dirs.is_empty()),
+          StatKind::File => ("files", dirs, non_dirs.is_empty()),
+          StatKind::Any => continue,
+        };
+        if mismatched && !actual.is_empty() {
+          mismatches.push(format!(
+            "{:?} was expected to match {} but matched: {:?}",
+            source.0,
+            expected,
+            actual.iter().map(|ps| ps.path()).collect::<Vec<_>>()
+          ));
+        }
+      }
+      if !mismatches.is_empty() {
+        let origin = match &strict_match_behavior {
+          StrictGlobMatching::Warn(description) | StrictGlobMatching::Error(description) => {
+            format!(" from {description}")
+          }
+          _ => "".to_string(),
+        };
+        let msg = format!(
+          "Globs{} matched the wrong kind of path: {}",
+          origin,
+          mismatches.join("; ")
+        );
+        if strict_match_behavior.should_throw_on_error() {
+          return Err(Self::mk_error(&msg));
+        } else {
+          warn!("{}", msg);
+        }
+      }
+    }
+
+    if let Some(allowed_extensions) = allowed_extensions {
+      let (permitted, not_permitted): (Vec<_>, Vec<_>) = path_stats
+        .into_iter()
+        .partition(|ps| allowed_extensions.permits_path_stat(ps));
+      if strict_match_behavior.should_check_glob_matches() && !not_permitted.is_empty() {
+        let origin = match &strict_match_behavior {
+          StrictGlobMatching::Warn(description) | StrictGlobMatching::Error(description) => {
+            format!(" from {description}")
+          }
+          _ => "".to_string(),
+        };
+        let msg = format!(
+          "Globs{} matched files without an allowed extension ({:?}): {:?}",
+          origin,
+          allowed_extensions.extensions(),
+          not_permitted
+            .iter()
+            .map(|ps| ps.path().to_owned())
+            .collect::<Vec<_>>(),
+        );
+        if strict_match_behavior.should_throw_on_error() {
+          return Err(Self::mk_error(&msg));
+        } else {
+          warn!("{}", msg);
+        }
+      }
+      path_stats = permitted;
+    }
+
     Ok(path_stats)
   }
 
+  ///
+  /// Expands exclude globs (see `ExcludeMode::Glob`) to the set of paths which they match.
+  ///
+  async fn expand_glob_exclude(
+    &self,
+    glob_exclude: Vec<PathGlob>,
+    exclude: &Arc<GitignoreStyleExcludes>,
+    max_depth: Option<usize>,
+    scandir_profile: Option<ScandirProfile>,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<HashSet<PathBuf>, E> {
+    if glob_exclude.is_empty() {
+      return Ok(HashSet::new());
+    }
+    let result = Arc::new(Expansion::new(false, max_depth).profiled(scandir_profile));
+    future::try_join_all(
+      glob_exclude
+        .into_iter()
+        .map(|path_glob| {
+          self.expand_single(
+            result.clone(),
+            exclude.clone(),
+            None,
+            path_glob,
+            symlink_behavior,
+          )
+        })
+        .collect::<Vec<_>>(),
+    )
+    .await?;
+    let excluded = result
+      .path_stats
+      .lock()
+      .iter()
+      .map(|ps| ps.path().to_owned())
+      .collect();
+    Ok(excluded)
+  }
+
+  async fn expand_attributed(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<Vec<(PathStat, Vec<GlobParsedSource>)>, E> {
+    let PreparedPathGlobs {
+      include,
+      exclude,
+      glob_exclude,
+      max_depth,
+      allowed_extensions,
+      cancelled,
+      scandir_profile,
+      ..
+    } = path_globs;
+
+    // Expand each root separately, so that its matches can be attributed to its source.
+    let roots = include
+      .into_iter()
+      .flat_map(|pgie| {
+        let source = pgie.input;
+        let relative_exclude = pgie.relative_exclude;
+        pgie
+          .globs
+          .into_iter()
+          .map(move |path_glob| (source.clone(), relative_exclude.clone(), path_glob))
+      })
+      .map(|(source, relative_exclude, path_glob)| {
+        let result = Arc::new(
+          Expansion::new(false, max_depth)
+            .cancellable(cancelled.clone())
+            .profiled(scandir_profile.clone()),
+        );
+        let expansion = self.expand_single(
+          result.clone(),
+          exclude.clone(),
+          relative_exclude,
+          path_glob,
+          symlink_behavior,
+        );
+        async move {
+          expansion.await?;
+          let path_stats = std::mem::take(&mut *result.path_stats.lock());
+          Ok::<_, E>((source, path_stats))
+        }
+      })
+      .collect::<Vec<_>>();
+
+    let glob_excluded = self
+      .expand_glob_exclude(
+        glob_exclude,
+        &exclude,
+        max_depth,
+        scandir_profile,
+        symlink_behavior,
+      )
+      .await?;
+    let mut attributed: BTreeMap<PathBuf, (PathStat, Vec<GlobParsedSource>)> = BTreeMap::new();
+    for (source, path_stats) in future::try_join_all(roots).await? {
+      for path_stat in path_stats {
+        if glob_excluded.contains(path_stat.path())
+          || !permitted_by(&allowed_extensions, &path_stat)
+        {
+          continue;
+        }
+        let (_, sources) = attributed
+          .entry(path_stat.path().to_owned())
+          .or_insert_with(|| (path_stat, vec![]));
+        if !sources.contains(&source) {
+          sources.push(source.clone());
+        }
+      }
+    }
+    Ok(attributed.into_values().collect())
+  }
+
+  async fn expand_lenient(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<(Vec<PathStat>, Vec<(PathBuf, E)>), E> {
+    let PreparedPathGlobs {
+      include,
+      exclude,
+      glob_exclude,
+      max_depth,
+      allowed_extensions,
+      cancelled,
+      scandir_profile,
+      ..
+    } = path_globs;
+
+    let result = Arc::new(
+      Expansion::new(false, max_depth)
+        .collecting_errors()
+        .cancellable(cancelled)
+        .profiled(scandir_profile.clone()),
+    );
+    future::try_join_all(
+      include
+        .into_iter()
+        .flat_map(|pgie| {
+          let relative_exclude = pgie.relative_exclude;
+          pgie
+            .globs
+            .into_iter()
+            .map(move |path_glob| (relative_exclude.clone(), path_glob))
+        })
+        .map(|(relative_exclude, path_glob)| {
+          self.expand_single(
+            result.clone(),
+            exclude.clone(),
+            relative_exclude,
+            path_glob,
+            symlink_behavior,
+          )
+        })
+        .collect::<Vec<_>>(),
+    )
+    .await?;
+
+    let Expansion {
+      path_stats, errors, ..
+    } = Arc::try_unwrap(result).unwrap_or_else(|_| panic!("expand violated its contract."));
+    let mut path_stats = path_stats.into_inner();
+    #[allow(clippy::unnecessary_sort_by)]
+    path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    path_stats.dedup_by(|a, b| a.path() == b.path());
+    let mut errors = errors.map(Mutex::into_inner).unwrap_or_default();
+    errors.sort_by(|(a, _), (b, _)| a.cmp(b));
+    // A directory may be listed by more than one branch of the expansion.
+    errors.dedup_by(|(a, _), (b, _)| a == b);
+
+    let glob_excluded = self
+      .expand_glob_exclude(
+        glob_exclude,
+        &exclude,
+        max_depth,
+        scandir_profile,
+        symlink_behavior,
+      )
+      .await?;
+    path_stats
+      .retain(|ps| !glob_excluded.contains(ps.path()) && permitted_by(&allowed_extensions, ps));
+    Ok((path_stats, errors))
+  }
+
+  async fn expand_single_required(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<PathStat, E> {
+    let filespecs = path_globs
+      .include
+      .iter()
+      .map(|pgie| pgie.input.0.clone())
+      .collect::<Vec<_>>();
+    let path_globs = PreparedPathGlobs {
+      strict_match_behavior: StrictGlobMatching::Ignore,
+      ..path_globs
+    };
+    let mut path_stats =
+      GlobMatchingImplementation::expand_globs(self, path_globs, symlink_behavior, None).await?;
+    match path_stats.len() {
+      1 => Ok(path_stats.pop().unwrap()),
+      0 => Err(Self::mk_error(&format!(
+        "Expected exactly one path to match {filespecs:?}, but none did."
+      ))),
+      n => Err(Self::mk_error(&format!(
+        "Expected exactly one path to match {filespecs:?}, but {n} did: {:?}",
+        path_stats.iter().map(|ps| ps.path()).collect::<Vec<_>>()
+      ))),
+    }
+  }
+
+  async fn expand_grouped_by_dir(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<IndexMap<PathBuf, Vec<PathStat>>, E> {
+    let path_stats =
+      GlobMatchingImplementation::expand_globs(self, path_globs, symlink_behavior, None).await?;
+    let mut grouped: IndexMap<PathBuf, Vec<PathStat>> = IndexMap::new();
+    for path_stat in path_stats {
+      let parent = path_stat
+        .path()
+        .parent()
+        .map(|parent| parent.to_owned())
+        .unwrap_or_default();
+      grouped.entry(parent).or_default().push(path_stat);
+    }
+    Ok(grouped)
+  }
+
+  async fn any_match(
+    &self,
+    path_globs: PreparedPathGlobs,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<bool, E> {
+    let PreparedPathGlobs {
+      include,
+      exclude,
+      glob_exclude,
+      max_depth,
+      allowed_extensions,
+      cancelled,
+      scandir_profile,
+      ..
+    } = path_globs;
+
+    // Glob excludes and allowed extensions are only applied once expansion has completed, so an
+    // early match cannot be trusted if there are any.
+    let result = Arc::new(
+      Expansion::new(
+        glob_exclude.is_empty() && allowed_extensions.is_none(),
+        max_depth,
+      )
+      .cancellable(cancelled)
+      .profiled(scandir_profile.clone()),
+    );
+    future::try_join_all(
+      include
+        .into_iter()
+        .flat_map(|pgie| {
+          let relative_exclude = pgie.relative_exclude;
+          pgie
+            .globs
+            .into_iter()
+            .map(move |path_glob| (relative_exclude.clone(), path_glob))
+        })
+        .map(|(relative_exclude, path_glob)| {
+          self.expand_single(
+            result.clone(),
+            exclude.clone(),
+            relative_exclude,
+            path_glob,
+            symlink_behavior,
+          )
+        })
+        .collect::<Vec<_>>(),
+    )
+    .await?;
+
+    let glob_excluded = self
+      .expand_glob_exclude(
+        glob_exclude,
+        &exclude,
+        max_depth,
+        scandir_profile,
+        symlink_behavior,
+      )
+      .await?;
+    let matched = result
+      .path_stats
+      .lock()
+      .iter()
+      .any(|ps| !glob_excluded.contains(ps.path()) && permitted_by(&allowed_extensions, ps));
+    Ok(matched)
+  }
+
+  ///
+  /// Expands the given PathGlob into the `result`, and returns the PathStats which it matched
+  /// (not including any traversed directories).
+  ///
   async fn expand_single(
     &self,
-    result: Arc<Mutex<Vec<PathStat>>>,
+    result: Arc<Expansion<E>>,
     exclude: Arc<GitignoreStyleExcludes>,
+    relative_exclude: Option<Arc<GitignoreStyleExcludes>>,
     path_glob: PathGlob,
     symlink_behavior: SymlinkBehavior,
-  ) -> Result<bool, E> {
+  ) -> Result<Vec<PathStat>, E> {
+    if result.is_cancelled() {
+      return Err(Self::mk_error("Glob expansion was cancelled."));
+    }
+    if result.should_stop() {
+      return Ok(vec![]);
+    }
     match path_glob {
       PathGlob::Wildcard {
         canonical_dir,
@@ -626,6 +1830,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
           .expand_wildcard(
             result,
             exclude,
+            relative_exclude,
             canonical_dir,
             symbolic_path,
             wildcard,
@@ -645,6 +1850,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
           .expand_dir_wildcard(
             result,
             exclude,
+            relative_exclude,
             canonical_dir,
             symbolic_path,
             wildcard,
@@ -659,76 +1865,156 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
 
   async fn expand_wildcard(
     &self,
-    result: Arc<Mutex<Vec<PathStat>>>,
+    result: Arc<Expansion<E>>,
     exclude: Arc<GitignoreStyleExcludes>,
+    relative_exclude: Option<Arc<GitignoreStyleExcludes>>,
     canonical_dir: Dir,
     symbolic_path: PathBuf,
     wildcard: Pattern,
     symlink_behavior: SymlinkBehavior,
     link_depth: LinkDepth,
-  ) -> Result<bool, E> {
+  ) -> Result<Vec<PathStat>, E> {
     // Filter directory listing to append PathStats, with no continuation.
-    let path_stats = self
+    let path_stats = match self
       .directory_listing(
+        &result,
         canonical_dir,
-        symbolic_path,
+        symbolic_path.clone(),
         wildcard,
         &exclude,
+        relative_exclude.as_ref(),
         symlink_behavior,
         link_depth,
       )
-      .await?;
+      .await
+    {
+      Ok(path_stats) => path_stats,
+      Err(e) => return result.record_error(symbolic_path, e).map(|()| vec![]),
+    };
 
-    let mut result = result.lock();
-    let matched = !path_stats.is_empty();
-    result.extend(path_stats.into_iter().map(|(ps, _)| ps));
+    let mut result_path_stats = result.path_stats.lock();
+    let matched = path_stats.into_iter().map(|(ps, _)| ps).collect::<Vec<_>>();
+    result_path_stats.extend(matched.iter().cloned());
+    if let Some(msg) = result.exceeded_max_matches(&mut result_path_stats) {
+      return Err(Self::mk_error(&msg));
+    }
     Ok(matched)
   }
 
   async fn expand_dir_wildcard(
     &self,
-    result: Arc<Mutex<Vec<PathStat>>>,
+    result: Arc<Expansion<E>>,
     exclude: Arc<GitignoreStyleExcludes>,
+    relative_exclude: Option<Arc<GitignoreStyleExcludes>>,
     canonical_dir: Dir,
     symbolic_path: PathBuf,
     wildcard: Pattern,
     remainder: Vec<Pattern>,
     symlink_behavior: SymlinkBehavior,
     link_depth: LinkDepth,
-  ) -> Result<bool, E> {
+  ) -> Result<Vec<PathStat>, E> {
     // Filter directory listing and recurse for matched Dirs.
     let context = self.clone();
-    let path_stats = self
+    let path_stats = match self
       .directory_listing(
+        &result,
         canonical_dir,
-        symbolic_path,
+        symbolic_path.clone(),
         wildcard,
         &exclude,
+        relative_exclude.as_ref(),
         symlink_behavior,
         link_depth,
       )
-      .await?;
+      .await
+    {
+      Ok(path_stats) => path_stats,
+      Err(e) => return result.record_error(symbolic_path, e).map(|()| vec![]),
+    };
 
+    let mut traversed = Vec::new();
     let path_globs = path_stats
       .into_iter()
       .filter_map(|(ps, link_depth)| match ps {
-        PathStat::Dir { path, stat } => Some(
-          PathGlob::parse_globs(stat, path, &remainder, link_depth)
-            .map_err(|e| Self::mk_error(e.as_str())),
-        ),
+        PathStat::Dir { path, .. } if result.beyond_max_depth(&path) => None,
+        PathStat::Dir { path, stat, .. } => {
+          if result.traversed_directories {
+            traversed.push(PathStat::dir(path.clone(), stat.clone()));
+          }
+          Some(
+            PathGlob::parse_globs(stat, path, &remainder, link_depth)
+              .map_err(|e| Self::mk_error(e.as_str())),
+          )
+        }
         PathStat::Link { .. } => None,
         PathStat::File { .. } => None,
       })
       .collect::<Result<Vec<_>, E>>()?;
 
+    if result.traversed_directories {
+      let mut result_path_stats = result.path_stats.lock();
+      result_path_stats.extend(traversed);
+      if let Some(msg) = result.exceeded_max_matches(&mut result_path_stats) {
+        return Err(Self::mk_error(&msg));
+      }
+    }
+
     let child_globs = path_globs
       .into_iter()
       .flat_map(Vec::into_iter)
-      .map(|pg| context.expand_single(result.clone(), exclude.clone(), pg, symlink_behavior))
+      .map(|pg| {
+        context.expand_single(
+          result.clone(),
+          exclude.clone(),
+          relative_exclude.clone(),
+          pg,
+          symlink_behavior,
+        )
+      })
       .collect::<Vec<_>>();
 
     let child_matches = future::try_join_all(child_globs).await?;
-    Ok(child_matches.into_iter().any(|m| m))
+    Ok(child_matches.into_iter().flatten().collect())
+  }
+
+  ///
+  /// If the leading literal (i.e., non-wildcard) directory components of the given PathGlob name a
+  /// directory which does not exist, returns the symbolic path of the first missing directory.
+  ///
+  async fn missing_directory(&self, path_glob: PathGlob) -> Option<PathBuf> {
+    let PathGlob::DirWildcard {
+      canonical_dir,
+      symbolic_path,
+      wildcard,
+      remainder,
+      ..
+    } = path_glob
+    else {
+      // A Wildcard matches within its canonical_dir, which exists.
+      return None;
+    };
+
+    // The final component of the remainder may match either a file or a directory.
+    let directory_components = std::iter::once(&wildcard)
+      .chain(remainder.iter().take(remainder.len().saturating_sub(1)))
+      .take_while(|pattern| Pattern::escape(pattern.as_str()) == pattern.as_str());
+
+    let mut canonical_dir = canonical_dir;
+    let mut symbolic_path = symbolic_path;
+    for component in directory_components {
+      let listing = self.scandir(canonical_dir.clone()).await.ok()?;
+      let name = Path::new(component.as_str());
+      match listing.0.iter().find(|stat| stat.path() == name) {
+        Some(Stat::Dir(_)) => {
+          canonical_dir = Dir(canonical_dir.0.join(name));
+          symbolic_path = symbolic_path.join(name);
+        }
+        Some(Stat::File(_)) | None => return Some(symbolic_path.join(name)),
+        // We do not attempt to resolve links.
+        Some(Stat::Link(_)) => return None,
+      }
+    }
+    None
   }
 
   async fn canonicalize_link(
@@ -758,9 +2044,21 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
 
     // Since we've escaped any globs in the parsed path, expect either 0 or 1 destination.
     Ok(path_stats.pop().map(|ps| match ps {
-      PathStat::Dir { stat, .. } => PathStat::dir(symbolic_path, stat),
-      PathStat::File { stat, .. } => PathStat::file(symbolic_path, stat),
-      PathStat::Link { stat, .. } => PathStat::link(symbolic_path, stat),
+      PathStat::Dir { stat, .. } => PathStat::Dir {
+        path: symbolic_path,
+        stat,
+        via_symlink: true,
+      },
+      PathStat::File { stat, .. } => PathStat::File {
+        path: symbolic_path,
+        stat,
+        via_symlink: true,
+      },
+      PathStat::Link { stat, .. } => PathStat::Link {
+        path: symbolic_path,
+        stat,
+        via_symlink: true,
+      },
     }))
   }
 }
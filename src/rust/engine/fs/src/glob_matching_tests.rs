@@ -1,8 +1,15 @@
 // Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+
 use crate::glob_matching::PathGlob;
-use crate::{GitignoreStyleExcludes, GlobExpansionConjunction, PathGlobs, StrictGlobMatching};
+use crate::{
+  FsError, GitignoreStyleExcludes, GlobExpansionConjunction, PathGlobs, StrictGlobMatching,
+};
 
 #[test]
 fn path_globs_create_distinguishes_between_includes_and_excludes() {
@@ -32,3 +39,445 @@ fn path_globs_create_distinguishes_between_includes_and_excludes() {
       .exclude_patterns()
   );
 }
+
+fn parse_include_with_home_expansion(
+  glob: &str,
+  root: Option<PathBuf>,
+) -> Result<Vec<PathGlob>, String> {
+  let path_globs = PathGlobs::new(
+    vec![glob.to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  );
+  let path_globs = match root {
+    Some(root) => path_globs.with_home_expansion(root),
+    None => path_globs,
+  };
+  Ok(
+    path_globs
+      .parse()?
+      .include
+      .into_iter()
+      .flat_map(|pgie| pgie.globs)
+      .collect(),
+  )
+}
+
+#[test]
+fn path_globs_home_is_literal_by_default() {
+  assert_eq!(
+    parse_include_with_home_expansion("~/src/*.rs", None).unwrap(),
+    PathGlob::create(vec!["~/src/*.rs".to_owned()]).unwrap(),
+  );
+}
+
+#[test]
+fn path_globs_home_expansion() {
+  let home = dirs_next::home_dir().unwrap();
+  let root = home.parent().unwrap().to_owned();
+  let home_name = home.file_name().unwrap().to_str().unwrap();
+
+  assert_eq!(
+    parse_include_with_home_expansion("~/src/*.rs", Some(root.clone())).unwrap(),
+    PathGlob::create(vec![format!("{home_name}/src/*.rs")]).unwrap(),
+  );
+  // Only a leading `~` component is expanded.
+  assert_eq!(
+    parse_include_with_home_expansion("src/~/*.rs", Some(root.clone())).unwrap(),
+    PathGlob::create(vec!["src/~/*.rs".to_owned()]).unwrap(),
+  );
+  assert_eq!(
+    parse_include_with_home_expansion("~user/*.rs", Some(root)).unwrap(),
+    PathGlob::create(vec!["~user/*.rs".to_owned()]).unwrap(),
+  );
+}
+
+#[test]
+fn path_globs_home_expansion_outside_root() {
+  let root = tempfile::TempDir::new().unwrap();
+  let err = parse_include_with_home_expansion("~/src/*.rs", Some(root.path().to_owned()))
+    .expect_err("Expected home expansion outside of the root to fail.");
+  assert!(err.contains("is not under the root"), "{err}");
+}
+
+#[test]
+fn path_globs_backslash_separators() {
+  let parse_include = |path_globs: PathGlobs| -> Vec<PathGlob> {
+    path_globs
+      .parse()
+      .unwrap()
+      .include
+      .into_iter()
+      .flat_map(|pgie| pgie.globs)
+      .collect()
+  };
+  let path_globs = || {
+    PathGlobs::new(
+      vec!["a\\b\\c.rs".to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+  };
+
+  // By default, the backslashes are part of a single path component.
+  assert_eq!(
+    parse_include(path_globs()),
+    PathGlob::create(vec!["a\\b\\c.rs".to_owned()]).unwrap(),
+  );
+  assert_eq!(
+    parse_include(path_globs().with_backslash_separators()),
+    PathGlob::create(vec!["a/b/c.rs".to_owned()]).unwrap(),
+  );
+}
+
+#[test]
+fn path_globs_builder() {
+  let globs = vec!["src/**/*.rs".to_owned(), "!src/ignored.rs".to_owned()];
+
+  // With the defaults, equivalent to the positional constructor.
+  assert_eq!(
+    PathGlobs::builder(globs.clone()).build().unwrap(),
+    PathGlobs::new(
+      globs.clone(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+  );
+
+  // And with options.
+  assert_eq!(
+    PathGlobs::builder(globs.clone())
+      .strict_match_behavior(StrictGlobMatching::Error("the test".to_owned()))
+      .conjunction(GlobExpansionConjunction::AnyMatch)
+      .max_depth(3)
+      .build()
+      .unwrap(),
+    PathGlobs::new(
+      globs,
+      StrictGlobMatching::Error("the test".to_owned()),
+      GlobExpansionConjunction::AnyMatch,
+    )
+    .with_max_depth(3)
+  );
+
+  // Invalid globs are rejected when they are built.
+  let err = PathGlobs::builder(vec!["src/a[.rs".to_owned()])
+    .build()
+    .unwrap_err();
+  assert!(matches!(err, FsError::GlobParse(_)), "{err:?}");
+}
+
+#[test]
+fn path_globs_parse_errors() {
+  let parse = |glob: &str| {
+    PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .expect_err("Expected parsing to fail.")
+  };
+
+  let err = parse("src/a[.rs");
+  assert!(matches!(err, FsError::GlobParse(_)), "{err:?}");
+  assert!(
+    err
+      .to_string()
+      .starts_with("Could not parse \"src/a[.rs\" as a glob"),
+    "{err}"
+  );
+  let err = parse("!   ");
+  assert!(matches!(err, FsError::IgnoreParse(_)), "{err:?}");
+  assert!(matches!(parse("/abs/*.rs"), FsError::GlobParse(_)));
+}
+
+#[test]
+fn path_globs_root_is_not_selectable() {
+  for filespec in [".", "", "./"] {
+    let err = PathGlob::create(vec![filespec.to_owned()])
+      .expect_err("Expected selecting the root to fail.");
+    assert!(err.contains("selects the root directory"), "{err}");
+  }
+  // But its contents may be selected.
+  assert!(PathGlob::create(vec!["./*".to_owned()]).is_ok());
+}
+
+#[test]
+fn path_glob_difference() {
+  let globs = |filespecs: &[&str]| {
+    PathGlob::create(filespecs.iter().map(|f| (*f).to_owned()).collect()).unwrap()
+  };
+
+  // Overlapping: globs are compared as parsed.
+  assert_eq!(
+    PathGlob::difference(
+      &globs(&["a/*.rs", "b/*.rs", "c.txt"]),
+      &globs(&["./b/*.rs", "d.txt"])
+    ),
+    globs(&["a/*.rs", "c.txt"])
+  );
+  // Disjoint.
+  assert_eq!(
+    PathGlob::difference(&globs(&["a/*.rs"]), &globs(&["b/*.rs"])),
+    globs(&["a/*.rs"])
+  );
+  // Identical.
+  assert_eq!(
+    PathGlob::difference(&globs(&["**/*.rs"]), &globs(&["**/*.rs"])),
+    vec![]
+  );
+}
+
+#[test]
+fn path_glob_display() {
+  let rendered = |filespec: &str| {
+    PathGlob::create(vec![filespec.to_owned()])
+      .unwrap()
+      .iter()
+      .map(|glob| glob.to_string())
+      .collect::<Vec<_>>()
+  };
+
+  // Wildcards.
+  assert_eq!(rendered("*.rs"), vec!["*.rs"]);
+  assert_eq!(rendered("./a.rs"), vec!["a.rs"]);
+  // DirWildcards.
+  assert_eq!(rendered("a/b/*.rs"), vec!["a/b/*.rs"]);
+  assert_eq!(rendered("a/**/b/*.rs"), vec!["a/**/b/*.rs"]);
+  assert_eq!(rendered("a/**/**/*.rs"), vec!["a/**/*.rs"]);
+  // A leading or trailing `**` expands to multiple globs.
+  assert_eq!(rendered("**"), vec!["*/**", "*"]);
+  assert_eq!(rendered("**/*.rs"), vec!["*/**/*.rs", "*.rs"]);
+}
+
+#[test]
+fn path_glob_display_roundtrip() {
+  for filespec in [
+    "*.rs",
+    "a/b/*.rs",
+    "a/**/b/*.rs",
+    "a/**",
+    "**/*.rs",
+    "a/../b/*.rs",
+  ] {
+    let globs = PathGlob::create(vec![filespec.to_owned()]).unwrap();
+    let reparsed = PathGlob::create(globs.iter().map(|glob| glob.to_string()).collect()).unwrap();
+    assert_eq!(
+      globs.into_iter().collect::<HashSet<_>>(),
+      reparsed.into_iter().collect::<HashSet<_>>(),
+      "Round trip failed for {filespec}",
+    );
+  }
+}
+
+#[test]
+fn path_glob_components() {
+  let patterns = |patterns: &[Pattern]| {
+    patterns
+      .iter()
+      .map(|pattern| pattern.as_str().to_owned())
+      .collect::<Vec<_>>()
+  };
+
+  let globs = PathGlob::create(vec!["**/*.rs".to_owned()]).unwrap();
+  assert_eq!(globs.len(), 2);
+  // The recursive glob.
+  assert!(globs[0].leading_doublestar());
+  assert_eq!(globs[0].wildcard_pattern().as_str(), "*");
+  assert_eq!(patterns(globs[0].remainder()), vec!["**", "*.rs"]);
+  assert_eq!(globs[0].symbolic_path(), Path::new(""));
+  // The alternative, in which the `**` matches zero directories.
+  assert!(!globs[1].leading_doublestar());
+  assert_eq!(globs[1].wildcard_pattern().as_str(), "*.rs");
+  assert!(globs[1].remainder().is_empty());
+
+  // A `**` which does not lead the glob.
+  let globs = PathGlob::create(vec!["a/**/*.rs".to_owned()]).unwrap();
+  assert_eq!(globs.len(), 1);
+  assert!(!globs[0].leading_doublestar());
+  assert_eq!(globs[0].wildcard_pattern().as_str(), "a");
+  assert_eq!(patterns(globs[0].remainder()), vec!["**", "*.rs"]);
+}
+
+#[test]
+fn path_glob_fixed_depth() {
+  // Each `*` directory component is parsed into a single level of DirWildcard, without any of the
+  // alternatives that a `**` produces.
+  let globs = PathGlob::create(vec!["*/*/*.rs".to_owned()]).unwrap();
+  assert_eq!(globs.len(), 1);
+  match &globs[0] {
+    PathGlob::DirWildcard {
+      wildcard,
+      remainder,
+      ..
+    } => {
+      assert_eq!(wildcard.as_str(), "*");
+      assert_eq!(
+        remainder.iter().map(Pattern::as_str).collect::<Vec<_>>(),
+        vec!["*", "*.rs"]
+      );
+    }
+    glob => panic!("Expected a DirWildcard, got {glob:?}"),
+  }
+  assert!(!globs[0].leading_doublestar());
+}
+
+#[test]
+fn path_glob_normalized_components() {
+  // `.` components and redundant separators are dropped while parsing, but `..` is preserved.
+  for filespec in [
+    "a/./b/*.rs",
+    "./a/b/*.rs",
+    "a//b/*.rs",
+    "a/b/./*.rs",
+    "a/b//./*.rs",
+  ] {
+    let globs = PathGlob::create(vec![filespec.to_owned()]).unwrap();
+    assert_eq!(globs.len(), 1, "{filespec}");
+    assert_eq!(globs[0].to_string(), "a/b/*.rs", "{filespec}");
+    assert_eq!(
+      globs[0].literal_prefix(),
+      PathBuf::from("a/b"),
+      "{filespec}"
+    );
+  }
+  let globs = PathGlob::create(vec!["a/./../a/b/*.rs".to_owned()]).unwrap();
+  assert_eq!(globs[0].to_string(), "a/../a/b/*.rs");
+}
+
+#[test]
+fn path_globs_literal_prefixes() {
+  let literal_prefixes = |globs: &[&str]| {
+    PathGlobs::new(
+      globs.iter().map(|glob| (*glob).to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .literal_prefixes()
+    .unwrap()
+  };
+
+  // Literal globs.
+  assert_eq!(
+    literal_prefixes(&["src/lib.rs", "./a/b.rs"]),
+    vec![PathBuf::from("src/lib.rs"), PathBuf::from("a/b.rs")]
+  );
+  // Wildcards in the middle or at the end of a glob.
+  assert_eq!(
+    literal_prefixes(&["src/main/**/*.rs", "src/*/mod.rs", "tests/*", "docs::"]),
+    vec![
+      PathBuf::from("src/main"),
+      PathBuf::from("src"),
+      PathBuf::from("tests"),
+      PathBuf::from("docs"),
+    ]
+  );
+  // Leading wildcards, and excludes (which are skipped).
+  assert_eq!(
+    literal_prefixes(&["**/*.rs", "*.py", "!src/excluded.rs"]),
+    vec![PathBuf::new(), PathBuf::new()]
+  );
+}
+
+#[test]
+fn path_globs_cache_key() {
+  let cache_key = |globs: &[&str]| {
+    PathGlobs::new(
+      globs.iter().map(|glob| (*glob).to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .cache_key()
+  };
+  let key = cache_key(&["src/**/*.rs", "!**/target", "!*.tmp"]);
+
+  // Equivalent inputs produce equal keys, regardless of how they were constructed.
+  assert_eq!(key, cache_key(&["src/**/*.rs", "!**/target", "!*.tmp"]));
+  assert_eq!(
+    key,
+    cache_key(&["src/**/*.rs", "!*.tmp", "!**/target", "!*.tmp"])
+  );
+  assert_eq!(
+    key,
+    PathGlobs::builder(vec![
+      "src/**/*.rs".to_owned(),
+      "!*.tmp".to_owned(),
+      "!**/target".to_owned(),
+    ])
+    .build()
+    .unwrap()
+    .cache_key()
+  );
+  assert_eq!(
+    key,
+    PathGlobs::builder(vec![
+      "src\\**\\*.rs".to_owned(),
+      "!**\\target".to_owned(),
+      "!*.tmp".to_owned(),
+    ])
+    .backslash_separators()
+    .build()
+    .unwrap()
+    .cache_key()
+  );
+  assert_eq!(cache_key(&["src::"]), cache_key(&["src/**"]));
+
+  // But changing the includes, the excludes, or the match behavior changes the key.
+  assert_ne!(key, cache_key(&["src/**/*.py", "!**/target", "!*.tmp"]));
+  assert_ne!(key, cache_key(&["src/**/*.rs", "!**/target", "!*.bak"]));
+  assert_ne!(key, cache_key(&["src/**/*.rs", "!**/target"]));
+  assert_ne!(
+    key,
+    PathGlobs::new(
+      vec![
+        "src/**/*.rs".to_owned(),
+        "!**/target".to_owned(),
+        "!*.tmp".to_owned(),
+      ],
+      StrictGlobMatching::Error("test".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .cache_key()
+  );
+  assert_ne!(
+    key,
+    PathGlobs::builder(vec![
+      "src/**/*.rs".to_owned(),
+      "!**/target".to_owned(),
+      "!*.tmp".to_owned(),
+    ])
+    .max_depth(3)
+    .build()
+    .unwrap()
+    .cache_key()
+  );
+}
+
+#[test]
+fn path_globs_common_root() {
+  let common_root = |globs: &[&str]| {
+    PathGlobs::new(
+      globs.iter().map(|glob| (*glob).to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .common_root()
+    .unwrap()
+  };
+
+  assert_eq!(
+    common_root(&["src/main/**/*.rs", "src/test/*.rs", "!tests/**"]),
+    PathBuf::from("src")
+  );
+  assert_eq!(
+    common_root(&["src/main/**/*.rs", "src/main/lib.rs"]),
+    PathBuf::from("src/main")
+  );
+  // The final component of a literal glob might be a file.
+  assert_eq!(common_root(&["src/lib.rs"]), PathBuf::from("src"));
+  // Globs which diverge at the root.
+  assert_eq!(common_root(&["src/*.rs", "tests/*.rs"]), PathBuf::new());
+  assert_eq!(common_root(&["src/*.rs", "**/*.py"]), PathBuf::new());
+  assert_eq!(common_root(&[]), PathBuf::new());
+}
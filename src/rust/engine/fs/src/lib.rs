@@ -28,39 +28,62 @@
 pub mod directory;
 #[cfg(test)]
 mod directory_tests;
+mod git_tree;
+#[cfg(test)]
+mod git_tree_tests;
 pub mod gitignore;
 mod glob_matching;
 #[cfg(test)]
 mod glob_matching_tests;
+mod openat;
 #[cfg(test)]
 mod posixfs_tests;
+mod xattr;
 
 pub use crate::directory::{
   DigestTrie, DirectoryDigest, Entry, SymlinkBehavior, TypedPath, EMPTY_DIGEST_TREE,
   EMPTY_DIRECTORY_DIGEST,
 };
-pub use crate::gitignore::GitignoreStyleExcludes;
+pub use crate::git_tree::GitTreeFS;
+pub use crate::gitignore::{GitignoreStyleExcludes, StatKind};
 pub use crate::glob_matching::{
-  FilespecMatcher, GlobMatching, PathGlob, PreparedPathGlobs, DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB,
+  FilespecMatcher, GlobMatching, GlobParsedSource, PathGlob, PreparedPathGlobs, ScandirProfile,
+  DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB,
 };
 
 use std::cmp::min;
-use std::io;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{self, Read};
 use std::ops::Deref;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, fs};
 
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use deepsize::DeepSizeOf;
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use futures::Future;
 use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 const TARGET_NOFILE_LIMIT: u64 = 10000;
 
 const XDG_CACHE_HOME: &str = "XDG_CACHE_HOME";
 
+/// The number of Stats which `PosixFS::scandir_stream` may read ahead of its consumer.
+const SCANDIR_STREAM_BUFFER_SIZE: usize = 256;
+
+/// The maximum number of files which a `PosixFS` holds open at once if the file handle limit
+/// cannot be determined. See `PosixFS::with_max_open_files`.
+const FALLBACK_MAX_OPEN_FILES: usize = 128;
+
+// The size of the chunks in which `PosixFS::read_file_blocking` reads a file.
+const READ_FILE_BLOCKING_CHUNK_SIZE: usize = 64 * 1024;
+
 /// NB: Linux limits path lookups to 40 symlink traversals: https://lwn.net/Articles/650786/
 ///
 /// We use a slightly different limit because this is not exactly the same operation: we're
@@ -172,6 +195,7 @@ impl Stat {
     Stat::File(File {
       path,
       is_executable,
+      xattrs: BTreeMap::new(),
     })
   }
 
@@ -185,9 +209,11 @@ impl Stat {
       Stat::File(File {
         path,
         is_executable,
+        xattrs,
       }) => Stat::File(File {
         path: directory.join(path),
         is_executable: *is_executable,
+        xattrs: xattrs.clone(),
       }),
       Stat::Link(Link { path, target }) => Stat::Link(Link {
         path: directory.join(path),
@@ -203,6 +229,23 @@ pub struct Link {
   pub target: PathBuf,
 }
 
+impl Link {
+  ///
+  /// Validates a raw target read for this Link, and makes it relative to the root: `Vfs::read_link`
+  /// must return root-relative paths.
+  ///
+  pub(crate) fn resolve_target(&self, target: PathBuf) -> Result<PathBuf, String> {
+    if target.is_absolute() {
+      return Err(format!("Absolute symlink: {target:?}"));
+    }
+    self
+      .path
+      .parent()
+      .map(|parent| parent.join(&target))
+      .ok_or_else(|| format!("Symlink without a parent?: {target:?}"))
+  }
+}
+
 #[derive(Clone, Debug, DeepSizeOf, Eq, Hash, PartialEq)]
 pub struct Dir(pub PathBuf);
 
@@ -210,8 +253,16 @@ pub struct Dir(pub PathBuf);
 pub struct File {
   pub path: PathBuf,
   pub is_executable: bool,
+  // The values of any extended attributes which were requested via `PosixFS::with_xattrs`, and
+  // which were present on the file.
+  pub xattrs: BTreeMap<String, Vec<u8>>,
 }
 
+///
+/// NB: `via_symlink` participates in `Eq` and `Hash`, so a PathStat which was reached via a
+/// symlink is distinct from an otherwise identical PathStat which was not. In practice the two can
+/// only collide if a symlink resolves to a destination with the same symbolic path as itself.
+///
 #[derive(Clone, Debug, DeepSizeOf, Eq, Hash, PartialEq)]
 pub enum PathStat {
   Dir {
@@ -219,32 +270,50 @@ pub enum PathStat {
     path: PathBuf,
     // The canonical Stat that underlies the Path.
     stat: Dir,
+    // True if a symlink was resolved to reach the Stat.
+    via_symlink: bool,
   },
   File {
     // The symbolic name of some filesystem Path, which is context specific.
     path: PathBuf,
     // The canonical Stat that underlies the Path.
     stat: File,
+    // True if a symlink was resolved to reach the Stat.
+    via_symlink: bool,
   },
   Link {
     // The symbolic name of some filesystem Path, which is context specific.
     path: PathBuf,
     // The canonical Stat that underlies the Path.
     stat: Link,
+    // True if a symlink was resolved to reach the Stat.
+    via_symlink: bool,
   },
 }
 
 impl PathStat {
   pub fn dir(path: PathBuf, stat: Dir) -> PathStat {
-    PathStat::Dir { path, stat }
+    PathStat::Dir {
+      path,
+      stat,
+      via_symlink: false,
+    }
   }
 
   pub fn file(path: PathBuf, stat: File) -> PathStat {
-    PathStat::File { path, stat }
+    PathStat::File {
+      path,
+      stat,
+      via_symlink: false,
+    }
   }
 
   pub fn link(path: PathBuf, stat: Link) -> PathStat {
-    PathStat::Link { path, stat }
+    PathStat::Link {
+      path,
+      stat,
+      via_symlink: false,
+    }
   }
 
   pub fn path(&self) -> &Path {
@@ -254,6 +323,18 @@ impl PathStat {
       PathStat::Link { path, .. } => path.as_path(),
     }
   }
+
+  ///
+  /// True if this PathStat was produced by resolving a symlink (in which case its `path` is the
+  /// path of the symlink, rather than of the underlying Stat).
+  ///
+  pub fn via_symlink(&self) -> bool {
+    match self {
+      PathStat::Dir { via_symlink, .. }
+      | PathStat::File { via_symlink, .. }
+      | PathStat::Link { via_symlink, .. } => *via_symlink,
+    }
+  }
 }
 
 #[derive(Debug, DeepSizeOf, Eq, PartialEq)]
@@ -312,11 +393,110 @@ impl GlobExpansionConjunction {
   }
 }
 
+///
+/// How the exclude (`!`-prefixed) specs of a PathGlobs are interpreted.
+///
+#[derive(Debug, DeepSizeOf, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum ExcludeMode {
+  /// Excludes are gitignore-style patterns: e.g., a pattern without a slash matches at any depth,
+  /// and excluded directories are not descended into.
+  Gitignore,
+  /// Excludes are expanded exactly as an identical include would be, and the matched paths are
+  /// removed from the result.
+  Glob,
+}
+
+///
+/// The file extensions (without a leading `.`) which PathGlobs are allowed to match: see
+/// `PathGlobs::with_allowed_extensions`.
+///
+#[derive(Debug, DeepSizeOf, Clone, Eq, PartialEq, Hash)]
+pub(crate) struct ExtensionAllowlist {
+  extensions: BTreeSet<String>,
+  allow_no_extension: bool,
+}
+
+impl ExtensionAllowlist {
+  pub(crate) fn permits(&self, path: &Path) -> bool {
+    match path.extension() {
+      Some(extension) => self
+        .extensions
+        .contains(extension.to_string_lossy().as_ref()),
+      None => self.allow_no_extension,
+    }
+  }
+
+  ///
+  /// Directories and symlinks are always permitted: only files are filtered by extension.
+  ///
+  pub(crate) fn permits_path_stat(&self, path_stat: &PathStat) -> bool {
+    match path_stat {
+      PathStat::File { .. } => self.permits(path_stat.path()),
+      PathStat::Dir { .. } | PathStat::Link { .. } => true,
+    }
+  }
+
+  pub(crate) fn extensions(&self) -> &BTreeSet<String> {
+    &self.extensions
+  }
+}
+
+///
+/// An error from constructing a PosixFS or parsing PathGlobs. Each variant holds a complete
+/// message, which is what is rendered by `Display`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FsError {
+  /// The root directory does not exist.
+  RootNotFound(String),
+  /// The root exists, but is not a directory.
+  RootNotDirectory(String),
+  /// The root could not be accessed for some other reason.
+  RootInaccessible(String),
+  /// An exclude pattern could not be parsed.
+  IgnoreParse(String),
+  /// An include (or glob-style exclude) pattern could not be parsed.
+  GlobParse(String),
+  /// A leading `~` could not be expanded.
+  HomeExpansion(String),
+}
+
+impl fmt::Display for FsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::RootNotFound(s)
+      | Self::RootNotDirectory(s)
+      | Self::RootInaccessible(s)
+      | Self::IgnoreParse(s)
+      | Self::GlobParse(s)
+      | Self::HomeExpansion(s) => write!(f, "{s}"),
+    }
+  }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<FsError> for String {
+  fn from(err: FsError) -> Self {
+    err.to_string()
+  }
+}
+
 #[derive(Debug, DeepSizeOf, Clone, Eq, PartialEq, Hash)]
 pub struct PathGlobs {
   globs: Vec<String>,
   strict_match_behavior: StrictGlobMatching,
   conjunction: GlobExpansionConjunction,
+  // If set, the root that a leading `~` is made relative to after expanding it to the home dir.
+  home_expansion_root: Option<PathBuf>,
+  max_depth: Option<usize>,
+  exclude_mode: ExcludeMode,
+  backslash_separators: bool,
+  allowed_extensions: Option<ExtensionAllowlist>,
+  max_matches: Option<usize>,
+  traversed_directories: bool,
+  broken_symlink_errors: bool,
+  strict_excludes: bool,
 }
 
 impl PathGlobs {
@@ -329,16 +509,520 @@ impl PathGlobs {
       globs,
       strict_match_behavior,
       conjunction,
+      home_expansion_root: None,
+      max_depth: None,
+      exclude_mode: ExcludeMode::Gitignore,
+      backslash_separators: false,
+      allowed_extensions: None,
+      max_matches: None,
+      traversed_directories: false,
+      broken_symlink_errors: false,
+      strict_excludes: false,
+    }
+  }
+
+  ///
+  /// Returns a builder for PathGlobs with the given filespecs, which allows for setting options by
+  /// name rather than position.
+  ///
+  pub fn builder(globs: Vec<String>) -> PathGlobsBuilder {
+    PathGlobsBuilder {
+      path_globs: PathGlobs::new(
+        globs,
+        StrictGlobMatching::Ignore,
+        GlobExpansionConjunction::AllMatch,
+      ),
     }
   }
 
-  pub fn parse(self) -> Result<glob_matching::PreparedPathGlobs, String> {
-    glob_matching::PreparedPathGlobs::create(
-      self.globs,
+  ///
+  /// Globs for the immediate children (both files and directories) of the given directory, which
+  /// is relative to the root: the equivalent of a single `dir/*` filespec, with any glob
+  /// metacharacters in `dir` escaped. Does not recurse.
+  ///
+  pub fn children_of(dir: PathBuf) -> PathGlobs {
+    let filespec = dir
+      .components()
+      .map(|component| glob::Pattern::escape(&component.as_os_str().to_string_lossy()))
+      .chain(std::iter::once("*".to_owned()))
+      .collect::<Vec<_>>()
+      .join("/");
+    PathGlobs::new(
+      vec![filespec],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+  }
+
+  ///
+  /// Set how exclude specs are interpreted. Defaults to `ExcludeMode::Gitignore`.
+  ///
+  pub fn with_exclude_mode(mut self, exclude_mode: ExcludeMode) -> PathGlobs {
+    self.exclude_mode = exclude_mode;
+    self
+  }
+
+  ///
+  /// Limit expansion to descending at most `max_depth` directory levels below the root: deeper
+  /// paths will not be matched (by `**` or otherwise). If the limit is reached while strict glob
+  /// matching is enabled, a warning is logged. By default, the depth is unlimited.
+  ///
+  pub fn with_max_depth(mut self, max_depth: usize) -> PathGlobs {
+    self.max_depth = Some(max_depth);
+    self
+  }
+
+  ///
+  /// Fail expansion (via `GlobMatching::expand_globs`) with an error naming the include globs as
+  /// soon as they have matched more than `max_matches` distinct paths, rather than continuing to
+  /// collect the matches of a runaway glob. By default, the number of matches is unlimited.
+  ///
+  pub fn with_max_matches(mut self, max_matches: usize) -> PathGlobs {
+    self.max_matches = Some(max_matches);
+    self
+  }
+
+  ///
+  /// Additionally emit (from `GlobMatching::expand_globs`) a `PathStat::Dir` for every directory
+  /// which is traversed while expanding the globs, including directories which contain no matches
+  /// at all. For example, `src/**/*.rs` then also emits `src` and every directory below it.
+  ///
+  /// Traversed directories do not count as matches for the purposes of strict matching, or of an
+  /// expected kind of match (see `PreparedPathGlobs::with_expected_kind`).
+  ///
+  pub fn with_traversed_directories(mut self) -> PathGlobs {
+    self.traversed_directories = true;
+    self
+  }
+
+  ///
+  /// Fail expansion (via `GlobMatching::expand_globs` with `SymlinkBehavior::Oblivious`) with an
+  /// error naming the link and its target if a matched symlink points to a path which does not
+  /// exist (or which is ignored). By default, such symlinks are silently dropped from the result.
+  ///
+  pub fn with_broken_symlink_errors(mut self) -> PathGlobs {
+    self.broken_symlink_errors = true;
+    self
+  }
+
+  ///
+  /// Also apply the strict match behavior to (gitignore-style) excludes: an exclude which did not
+  /// exclude any path from a directory listing during `GlobMatching::expand_globs` is reported
+  /// like an unmatched include, since it is likely to be a typo. `!` patterns within excludes,
+  /// which re-include paths, are not reported.
+  ///
+  pub fn with_strict_excludes(mut self) -> PathGlobs {
+    self.strict_excludes = true;
+    self
+  }
+
+  ///
+  /// Opt in to expanding a leading `~` component of each glob to the current user's home
+  /// directory, which must be located under the given (absolute) root: the glob is rewritten to be
+  /// relative to the root. By default, `~` is treated as a literal path component.
+  ///
+  pub fn with_home_expansion(mut self, root: PathBuf) -> PathGlobs {
+    self.home_expansion_root = Some(root);
+    self
+  }
+
+  ///
+  /// Opt in to treating backslashes as path separators, for globs which were produced by Windows
+  /// tooling: `src\main.rs` is then equivalent to `src/main.rs`. By default, a backslash is an
+  /// ordinary character in a path component.
+  ///
+  pub fn with_backslash_separators(mut self) -> PathGlobs {
+    self.backslash_separators = true;
+    self
+  }
+
+  ///
+  /// Only match files with one of the given extensions (with or without a leading `.`, and
+  /// compared case-sensitively). Files without an extension are matched only if
+  /// `allow_no_extension` is set. Directories and symlinks are not affected.
+  ///
+  /// This is applied after `GlobMatching::expand_globs` (and by `PathGlobs::matches`): if the
+  /// globs matched any other files, they are dropped from the result and reported according to
+  /// the strict match behavior. The other `GlobMatching` methods, which ignore the strict match
+  /// behavior, drop such files silently.
+  ///
+  pub fn with_allowed_extensions(
+    mut self,
+    extensions: HashSet<String>,
+    allow_no_extension: bool,
+  ) -> PathGlobs {
+    self.allowed_extensions = Some(ExtensionAllowlist {
+      extensions: extensions
+        .into_iter()
+        .map(|extension| extension.trim_start_matches('.').to_owned())
+        .collect(),
+      allow_no_extension,
+    });
+    self
+  }
+
+  pub fn parse(self) -> Result<glob_matching::PreparedPathGlobs, FsError> {
+    let globs = self.expanded_globs()?;
+    let mut prepared = glob_matching::PreparedPathGlobs::create_with_exclude_mode(
+      globs,
       self.strict_match_behavior,
       self.conjunction,
+      self.exclude_mode,
+    )?;
+    prepared.max_depth = self.max_depth;
+    prepared.allowed_extensions = self.allowed_extensions;
+    prepared.max_matches = self.max_matches;
+    prepared.traversed_directories = self.traversed_directories;
+    prepared.broken_symlink_errors = self.broken_symlink_errors;
+    prepared.strict_excludes = self.strict_excludes;
+    Ok(prepared)
+  }
+
+  ///
+  /// Returns the literal prefix (see `PathGlob::literal_prefix`) of each include glob, in order:
+  /// for example, to decide which directories to watch or preload for the paths that these globs
+  /// might match. Excludes are ignored.
+  ///
+  pub fn literal_prefixes(&self) -> Result<Vec<PathBuf>, FsError> {
+    Ok(
+      self
+        .include_entries()?
+        .iter()
+        .map(|entry| entry.literal_prefix())
+        .collect(),
+    )
+  }
+
+  ///
+  /// Returns the deepest directory which contains every path that the include globs might match:
+  /// the longest common prefix of their literal prefixes (see `literal_prefixes`), where the final
+  /// component of an entirely literal glob is excluded, since it might name a file. This allows
+  /// callers to e.g. create a `PosixFS` for a narrower subtree. Returns the root if the globs
+  /// diverge at the root, or if there are no include globs.
+  ///
+  pub fn common_root(&self) -> Result<PathBuf, FsError> {
+    let entries = self.include_entries()?;
+    let mut prefixes = entries.iter().map(|entry| entry.literal_directory_prefix());
+    let Some(mut common_root) = prefixes.next() else {
+      return Ok(PathBuf::new());
+    };
+    for prefix in prefixes {
+      common_root = common_root
+        .components()
+        .zip(prefix.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect();
+    }
+    Ok(common_root)
+  }
+
+  ///
+  /// Returns the include globs (in order) which are redundant when expanded against the given
+  /// PosixFS: those whose matches are all also matched by the other includes, such as `src/a.rs`
+  /// alongside `src/**`. Excludes are applied to every include. This is intended for diagnostics
+  /// (e.g. to flag BUILD file hygiene issues), and expands each include individually.
+  ///
+  /// NB: Each include is judged against all of the others, so duplicate includes are all reported
+  /// redundant, as is an include which matches nothing.
+  ///
+  pub async fn redundant_includes(&self, fs: &Arc<PosixFS>) -> Result<Vec<String>, String> {
+    let (includes, excludes): (Vec<_>, Vec<_>) = self
+      .expanded_globs()?
+      .into_iter()
+      .partition(|glob| !glob.starts_with('!'));
+
+    let mut matches = Vec::with_capacity(includes.len());
+    for include in &includes {
+      let mut path_globs = self.clone();
+      path_globs.globs = std::iter::once(include.clone())
+        .chain(excludes.iter().cloned())
+        .collect();
+      path_globs.strict_match_behavior = StrictGlobMatching::Ignore;
+      path_globs.conjunction = GlobExpansionConjunction::AllMatch;
+      path_globs.max_matches = None;
+      path_globs.traversed_directories = false;
+      let path_stats = fs
+        .expand_globs(path_globs.parse()?, fs.symlink_behavior, None)
+        .await
+        .map_err(|e| format!("Failed to expand {include:?}: {e}"))?;
+      matches.push(
+        path_stats
+          .into_iter()
+          .map(|path_stat| path_stat.path().to_owned())
+          .collect::<HashSet<_>>(),
+      );
+    }
+
+    Ok(
+      includes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+          matches[*i].iter().all(|path| {
+            matches
+              .iter()
+              .enumerate()
+              .any(|(j, other)| j != *i && other.contains(path))
+          })
+        })
+        .map(|(_, include)| include.clone())
+        .collect(),
+    )
+  }
+
+  ///
+  /// Returns a deterministic key (a hex-encoded digest) for the configuration of these PathGlobs,
+  /// which may be used to memoize their expansion against an unchanged filesystem. PathGlobs with
+  /// equivalent inputs have equal keys regardless of how they were constructed: in particular, the
+  /// order and duplication of excludes is irrelevant, and the `dir::` shorthand is equivalent to
+  /// `dir/**`. The order of includes is significant, since it may affect the order of matches.
+  ///
+  pub fn cache_key(&self) -> String {
+    let (includes, excludes): (Vec<_>, Vec<_>) = self
+      .globs
+      .iter()
+      .map(|glob| {
+        if self.backslash_separators {
+          glob.replace('\\', "/")
+        } else {
+          glob.clone()
+        }
+      })
+      .partition(|glob| !glob.starts_with('!'));
+    let includes = includes
+      .iter()
+      .map(|glob| PathGlob::expand_recursive_shorthand(glob).into_owned())
+      .collect::<Vec<_>>();
+    let excludes = excludes
+      .iter()
+      .map(|glob| PathGlob::expand_recursive_shorthand(&glob[1..]).into_owned())
+      .collect::<BTreeSet<_>>();
+    // NB: Every component of the key has a deterministic `Debug` representation.
+    let key = format!(
+      "{:?}",
+      (
+        includes,
+        excludes,
+        &self.strict_match_behavior,
+        &self.conjunction,
+        &self.home_expansion_root,
+        self.max_depth,
+        self.exclude_mode,
+        &self.allowed_extensions,
+        self.max_matches,
+        self.traversed_directories,
+        self.broken_symlink_errors,
+        self.strict_excludes,
+      )
+    );
+    hashing::Digest::of_bytes(key.as_bytes()).hash.to_hex()
+  }
+
+  fn include_entries(&self) -> Result<Vec<glob_matching::PathGlobIncludeEntry>, FsError> {
+    let includes = self
+      .expanded_globs()?
+      .into_iter()
+      .filter(|glob| !glob.starts_with('!'))
+      .collect();
+    PathGlob::spread_filespecs(includes).map_err(FsError::GlobParse)
+  }
+
+  ///
+  /// Filter an already-known set of (relative) file paths down to those which these globs match,
+  /// without touching the filesystem. This is useful when the candidate paths come from
+  /// elsewhere, such as a VCS listing or a previously captured snapshot.
+  ///
+  /// Matching is intended to agree with expansion of the same globs against a tree containing
+  /// exactly the candidate files: `**` matches zero or more directories, gitignore-style excludes
+  /// also exclude paths below an excluded directory, and any `max_depth` and allowed extensions
+  /// are respected. Candidates are returned in their input order.
+  ///
+  pub fn matches(&self, candidates: &[PathBuf]) -> Result<Vec<PathBuf>, FsError> {
+    let (includes, excludes): (Vec<_>, Vec<_>) = self
+      .expanded_globs()?
+      .into_iter()
+      .partition(|glob| !glob.starts_with('!'));
+    let includes: Vec<String> = includes
+      .iter()
+      .map(|glob| PathGlob::expand_recursive_shorthand(glob).into_owned())
+      .collect();
+    let excludes: Vec<String> = excludes
+      .iter()
+      .map(|glob| PathGlob::expand_recursive_shorthand(&glob[1..]).into_owned())
+      .collect();
+
+    let include_matcher = FilespecMatcher::new(includes, vec![]).map_err(FsError::GlobParse)?;
+    let is_excluded: Box<dyn Fn(&Path) -> bool> = match self.exclude_mode {
+      ExcludeMode::Gitignore => {
+        let excludes = GitignoreStyleExcludes::create(excludes).map_err(FsError::IgnoreParse)?;
+        Box::new(move |path| excludes.is_ignored_or_child_of_ignored_path(path, false))
+      }
+      ExcludeMode::Glob => {
+        let exclude_matcher = FilespecMatcher::new(excludes, vec![]).map_err(FsError::GlobParse)?;
+        Box::new(move |path| exclude_matcher.matches(path))
+      }
+    };
+    let within_max_depth = |path: &Path| match self.max_depth {
+      Some(max_depth) => path.components().count() <= max_depth + 1,
+      None => true,
+    };
+    let has_allowed_extension = |path: &Path| match &self.allowed_extensions {
+      Some(allowed_extensions) => allowed_extensions.permits(path),
+      None => true,
+    };
+
+    Ok(
+      candidates
+        .iter()
+        .filter(|path| {
+          within_max_depth(path)
+            && has_allowed_extension(path)
+            && include_matcher.matches(path)
+            && !is_excluded(path)
+        })
+        .cloned()
+        .collect(),
     )
   }
+
+  fn expanded_globs(&self) -> Result<Vec<String>, FsError> {
+    let globs = self.globs.iter().map(|glob| {
+      if self.backslash_separators {
+        glob.replace('\\', "/")
+      } else {
+        glob.clone()
+      }
+    });
+    let Some(root) = &self.home_expansion_root else {
+      return Ok(globs.collect());
+    };
+    let home = dirs_next::home_dir().ok_or_else(|| {
+      FsError::HomeExpansion("Could not determine the home directory to expand `~`.".to_owned())
+    })?;
+    globs
+      .map(|glob| Self::expand_home(glob, &home, root).map_err(FsError::HomeExpansion))
+      .collect()
+  }
+
+  fn expand_home(glob: String, home: &Path, root: &Path) -> Result<String, String> {
+    let (prefix, pattern) = match glob.strip_prefix('!') {
+      Some(pattern) => ("!", pattern),
+      None => ("", glob.as_str()),
+    };
+    let remainder = if pattern == "~" {
+      ""
+    } else if let Some(remainder) = pattern.strip_prefix("~/") {
+      remainder
+    } else {
+      return Ok(glob);
+    };
+
+    let relative_home = home.strip_prefix(root).map_err(|_| {
+      format!(
+        "Cannot expand `~` in {glob:?}: the home directory {} is not under the root {}.",
+        home.display(),
+        root.display()
+      )
+    })?;
+    let expanded = relative_home.join(remainder);
+    let expanded = expanded
+      .to_str()
+      .ok_or_else(|| format!("Cannot expand `~` in {glob:?}: {expanded:?} is not valid UTF-8."))?;
+    Ok(format!("{prefix}{expanded}"))
+  }
+}
+
+///
+/// A builder for PathGlobs, which validates the globs when they are built. Obtain one via
+/// `PathGlobs::builder`.
+///
+/// Unless otherwise set, unmatched globs are ignored and all globs must match, as is common for
+/// internal uses of PathGlobs.
+///
+#[derive(Clone, Debug)]
+pub struct PathGlobsBuilder {
+  path_globs: PathGlobs,
+}
+
+impl PathGlobsBuilder {
+  pub fn strict_match_behavior(mut self, strict_match_behavior: StrictGlobMatching) -> Self {
+    self.path_globs.strict_match_behavior = strict_match_behavior;
+    self
+  }
+
+  pub fn conjunction(mut self, conjunction: GlobExpansionConjunction) -> Self {
+    self.path_globs.conjunction = conjunction;
+    self
+  }
+
+  /// See `PathGlobs::with_exclude_mode`.
+  pub fn exclude_mode(mut self, exclude_mode: ExcludeMode) -> Self {
+    self.path_globs = self.path_globs.with_exclude_mode(exclude_mode);
+    self
+  }
+
+  /// See `PathGlobs::with_max_depth`.
+  pub fn max_depth(mut self, max_depth: usize) -> Self {
+    self.path_globs = self.path_globs.with_max_depth(max_depth);
+    self
+  }
+
+  /// See `PathGlobs::with_max_matches`.
+  pub fn max_matches(mut self, max_matches: usize) -> Self {
+    self.path_globs = self.path_globs.with_max_matches(max_matches);
+    self
+  }
+
+  /// See `PathGlobs::with_traversed_directories`.
+  pub fn traversed_directories(mut self) -> Self {
+    self.path_globs = self.path_globs.with_traversed_directories();
+    self
+  }
+
+  /// See `PathGlobs::with_broken_symlink_errors`.
+  pub fn broken_symlink_errors(mut self) -> Self {
+    self.path_globs = self.path_globs.with_broken_symlink_errors();
+    self
+  }
+
+  /// See `PathGlobs::with_strict_excludes`.
+  pub fn strict_excludes(mut self) -> Self {
+    self.path_globs = self.path_globs.with_strict_excludes();
+    self
+  }
+
+  /// See `PathGlobs::with_home_expansion`.
+  pub fn home_expansion(mut self, root: PathBuf) -> Self {
+    self.path_globs = self.path_globs.with_home_expansion(root);
+    self
+  }
+
+  /// See `PathGlobs::with_backslash_separators`.
+  pub fn backslash_separators(mut self) -> Self {
+    self.path_globs = self.path_globs.with_backslash_separators();
+    self
+  }
+
+  /// See `PathGlobs::with_allowed_extensions`.
+  pub fn allowed_extensions(
+    mut self,
+    extensions: HashSet<String>,
+    allow_no_extension: bool,
+  ) -> Self {
+    self.path_globs = self
+      .path_globs
+      .with_allowed_extensions(extensions, allow_no_extension);
+    self
+  }
+
+  ///
+  /// Returns the PathGlobs, or an error if they could not be parsed.
+  ///
+  pub fn build(self) -> Result<PathGlobs, FsError> {
+    self.path_globs.clone().parse()?;
+    Ok(self.path_globs)
+  }
 }
 
 impl fmt::Display for PathGlobs {
@@ -347,6 +1031,60 @@ impl fmt::Display for PathGlobs {
   }
 }
 
+///
+/// The order in which `PosixFS::scandir_with_order` returns the entries of a directory.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanOrder {
+  /// Sort by the raw bytes of each path. This order is deterministic, and is the order that must
+  /// be used when computing digests.
+  Bytewise,
+  /// Sort by the lowercased path, falling back to `Bytewise` for paths which differ only by case.
+  /// Intended for display.
+  CaseInsensitive,
+}
+
+///
+/// How `PosixFS::scandir` handles a directory which cannot be listed because permission to read
+/// it was denied. See `PosixFS::with_permission_denied_behavior`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PermissionDeniedBehavior {
+  /// Fail the listing (and so e.g. the glob expansion) with the error.
+  Error,
+  /// Treat the directory as empty.
+  SkipEmpty,
+  /// Treat the directory as empty, but log a warning naming it.
+  SkipWithWarning,
+}
+
+///
+/// Which execute permission bits cause `PosixFS` to consider a file executable (i.e., to set
+/// `File::is_executable`). See `PosixFS::with_executable_detection`.
+///
+/// NB: Because `is_executable` is recorded in snapshots, changing the detection for a PosixFS may
+/// change the digests of the snapshots captured from it.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExecutableDetection {
+  /// A file is executable if its owner execute bit (`0o100`) is set.
+  Owner,
+  /// A file is executable if any of its execute bits (`0o111`) are set.
+  Any,
+  /// A file is executable if any of the bits in the given mask are set.
+  Mask(u32),
+}
+
+impl ExecutableDetection {
+  fn mask(self) -> u32 {
+    match self {
+      ExecutableDetection::Owner => 0o100,
+      ExecutableDetection::Any => 0o111,
+      ExecutableDetection::Mask(mask) => mask,
+    }
+  }
+}
+
 ///
 /// All Stats consumed or returned by this type are relative to the root.
 ///
@@ -361,6 +1099,21 @@ pub struct PosixFS {
   ignore: Arc<GitignoreStyleExcludes>,
   executor: task_executor::Executor,
   symlink_behavior: SymlinkBehavior,
+  operation_timeout: Option<Duration>,
+  file_open_flags: i32,
+  xattr_names: Vec<String>,
+  permission_denied_behavior: PermissionDeniedBehavior,
+  // If set, paths are resolved relative to this open file descriptor for the root, rather than
+  // relative to the path of the root.
+  root_fd: Option<Arc<openat::RootFd>>,
+  // If set, the device id of the root: directories on other devices are not listed.
+  root_device: Option<u64>,
+  executable_detection: ExecutableDetection,
+  // Limits the number of files and directories which are held open at once by this PosixFS (and
+  // its clones).
+  open_files: Arc<Semaphore>,
+  // If set, a directory (relative to the root) for scratch files, which is always ignored.
+  scratch_dir: Option<RelativePath>,
 }
 
 impl PosixFS {
@@ -368,7 +1121,7 @@ impl PosixFS {
     root: P,
     ignorer: Arc<GitignoreStyleExcludes>,
     executor: task_executor::Executor,
-  ) -> Result<PosixFS, String> {
+  ) -> Result<PosixFS, FsError> {
     Self::new_with_symlink_behavior(root, ignorer, executor, SymlinkBehavior::Aware)
   }
 
@@ -377,7 +1130,7 @@ impl PosixFS {
     ignorer: Arc<GitignoreStyleExcludes>,
     executor: task_executor::Executor,
     symlink_behavior: SymlinkBehavior,
-  ) -> Result<PosixFS, String> {
+  ) -> Result<PosixFS, FsError> {
     let root: &Path = root.as_ref();
     let canonical_root = root
       .canonicalize()
@@ -393,114 +1146,976 @@ impl PosixFS {
           }
         })
       })
-      .map_err(|e| format!("Could not canonicalize root {root:?}: {e:?}"))?;
+      .map_err(|e| {
+        let msg = format!("Could not canonicalize root {root:?}: {e:?}");
+        match e.kind() {
+          io::ErrorKind::NotFound => FsError::RootNotFound(msg),
+          io::ErrorKind::InvalidInput => FsError::RootNotDirectory(msg),
+          _ => FsError::RootInaccessible(msg),
+        }
+      })?;
 
     Ok(PosixFS {
       root: canonical_root,
       ignore: ignorer,
       executor: executor,
       symlink_behavior: symlink_behavior,
+      operation_timeout: None,
+      file_open_flags: 0,
+      xattr_names: vec![],
+      permission_denied_behavior: PermissionDeniedBehavior::Error,
+      root_fd: None,
+      root_device: None,
+      executable_detection: ExecutableDetection::Owner,
+      open_files: Arc::new(Semaphore::new(Self::default_max_open_files())),
+      scratch_dir: None,
     })
   }
 
-  pub async fn scandir(&self, dir_relative_to_root: Dir) -> Result<DirectoryListing, io::Error> {
-    let vfs = self.clone();
+  ///
+  /// Fail each `scandir` or `read_link` operation with `io::ErrorKind::TimedOut` if it has not
+  /// completed within the given timeout, rather than waiting indefinitely (as can happen on slow
+  /// network mounts).
+  ///
+  /// NB: A blocking syscall cannot be cancelled, so a timed out operation may continue to occupy a
+  /// blocking thread until the syscall eventually returns.
+  ///
+  pub fn with_operation_timeout(mut self, timeout: Duration) -> PosixFS {
+    self.operation_timeout = Some(timeout);
     self
-      .executor
-      .spawn_blocking(
-        move || vfs.scandir_sync(&dir_relative_to_root),
-        |e| {
-          Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Synchronous scandir failed: {e}"),
-          ))
-        },
+  }
+
+  ///
+  /// Open files for reading (in `digest_file` and `read_file_chunks`) with the given additional
+  /// flags, which are passed to `open(2)`. For example, `O_NOFOLLOW` causes reads to fail if the
+  /// final component of a file's path is a symlink, so that a file cannot be swapped for a symlink
+  /// between being stat'd and being read. By default, no additional flags are used.
+  ///
+  pub fn with_file_open_flags(mut self, flags: i32) -> PosixFS {
+    self.file_open_flags = flags;
+    self
+  }
+
+  ///
+  /// Read the given extended attributes (e.g. `user.origin` or `com.apple.quarantine`) of each
+  /// file when it is stat'd, and record those which are present in `File::xattrs`. Because this
+  /// costs additional syscalls per file, no extended attributes are read by default.
+  ///
+  /// Extended attributes are only supported on Linux and macOS: on other platforms (or on
+  /// filesystems which do not support them) none will be found.
+  ///
+  pub fn with_xattrs(mut self, names: Vec<String>) -> PosixFS {
+    self.xattr_names = names;
+    self
+  }
+
+  ///
+  /// Control whether a directory which cannot be listed because permission to read it was denied
+  /// fails `scandir` (the default), or is treated as empty so that e.g. glob expansion can proceed
+  /// over a partially-restricted tree. Other errors listing a directory always fail.
+  ///
+  pub fn with_permission_denied_behavior(mut self, behavior: PermissionDeniedBehavior) -> PosixFS {
+    self.permission_denied_behavior = behavior;
+    self
+  }
+
+  ///
+  /// Open a file descriptor for the root, and resolve the paths of all subsequent `scandir`,
+  /// `stat_sync`, `read_link(s)`, `digest_file` and `read_file_chunks` operations relative to it
+  /// (with the `*at` family of syscalls) rather than relative to the path of the root. This
+  /// guarantees that they observe the directory which was the root when this was called, even if
+  /// the root path is later replaced (for example, with a symlink to elsewhere).
+  ///
+  /// NB: Extended attributes (see `with_xattrs`) are not read for an fd-rooted PosixFS, and
+  /// methods which return absolute paths (like `file_path`) are still relative to the root path.
+  ///
+  pub fn with_root_fd(mut self) -> Result<PosixFS, io::Error> {
+    let root_fd = openat::RootFd::open(&self.root.0).map_err(|e| {
+      io::Error::new(
+        e.kind(),
+        format!("Failed to open root {:?}: {e}", self.root.0),
       )
+    })?;
+    self.root_fd = Some(Arc::new(root_fd));
+    Ok(self)
+  }
+
+  ///
+  /// Confine listings to the filesystem that the root is on: a directory on a different device
+  /// (i.e., a mount point for another filesystem, like `/proc` or a network mount) is listed as
+  /// empty by `scandir`, so that recursive glob expansion does not descend into it. The directory
+  /// itself is still reported in the listing of its parent. By default, listings may cross devices.
+  ///
+  pub fn with_single_device(mut self) -> Result<PosixFS, io::Error> {
+    let metadata = fs::metadata(&self.root.0).map_err(|e| {
+      io::Error::new(
+        e.kind(),
+        format!("Failed to stat root {:?}: {e}", self.root.0),
+      )
+    })?;
+    self.root_device = Some(metadata.dev());
+    Ok(self)
+  }
+
+  ///
+  /// Control which execute permission bits cause a file to be considered executable. By default
+  /// (and for stable snapshot digests), only the owner execute bit is considered.
+  ///
+  pub fn with_executable_detection(mut self, detection: ExecutableDetection) -> PosixFS {
+    self.executable_detection = detection;
+    self
+  }
+
+  ///
+  /// Limit the number of files and directories which `scandir`, `digest_file` and
+  /// `read_file_chunks` hold open at once (across this PosixFS and its clones): further operations
+  /// wait for a running operation to complete, so that large batches of concurrent operations do
+  /// not fail with "Too many open files". By default, the limit is half of the soft limit on file
+  /// handles for the process.
+  ///
+  pub fn with_max_open_files(mut self, max_open_files: usize) -> PosixFS {
+    self.open_files = Arc::new(Semaphore::new(
+      max_open_files.clamp(1, Semaphore::MAX_PERMITS),
+    ));
+    self
+  }
+
+  ///
+  /// Reserve the given directory (relative to the root, and created if it does not exist) for
+  /// scratch files: it and everything below it are added to the ignore patterns, so that files
+  /// written under `scratch_path` are never returned by `scandir` or glob expansion.
+  ///
+  pub fn with_scratch_dir(mut self, scratch: PathBuf) -> Result<PosixFS, io::Error> {
+    let scratch_dir =
+      RelativePath::new(&scratch).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if scratch_dir.as_os_str().is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("The scratch directory may not be the root: {scratch:?}"),
+      ));
+    }
+    let Some(escaped) = scratch_dir.to_str().map(Self::escape_ignore_pattern) else {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("The scratch directory must be valid UTF-8: {scratch:?}"),
+      ));
+    };
+    fs::create_dir_all(self.root.0.join(&*scratch_dir)).map_err(|e| {
+      io::Error::new(
+        e.kind(),
+        format!("Failed to create scratch directory {scratch:?}: {e}"),
+      )
+    })?;
+    // NB: The contents are ignored explicitly (rather than only by ignoring the directory) so
+    // that listing the scratch directory itself is also empty.
+    self.ignore = GitignoreStyleExcludes::merge(
+      &self.ignore,
+      &[format!("/{escaped}"), format!("/{escaped}/**")],
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    self.scratch_dir = Some(scratch_dir);
+    Ok(self)
+  }
+
+  ///
+  /// Whether this PosixFS reports symlinks as `Link`s (Aware), or as what they point to (Oblivious).
+  ///
+  pub fn symlink_behavior(&self) -> SymlinkBehavior {
+    self.symlink_behavior
+  }
+
+  ///
+  /// The absolute path of the scratch directory, if one was set with `with_scratch_dir`.
+  ///
+  pub fn scratch_path(&self) -> Option<PathBuf> {
+    self
+      .scratch_dir
+      .as_ref()
+      .map(|scratch_dir| self.root.0.join(&**scratch_dir))
+  }
+
+  ///
+  /// Escapes the characters of a path which are special in gitignore patterns, so that it matches
+  /// only itself.
+  ///
+  fn escape_ignore_pattern(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+      if matches!(c, '\\' | '*' | '?' | '[' | ']' | '{' | '}' | '!' | '#') {
+        escaped.push('\\');
+      }
+      escaped.push(c);
+    }
+    escaped
+  }
+
+  fn default_max_open_files() -> usize {
+    match rlimit::Resource::NOFILE.get() {
+      Ok((cur, _)) => usize::try_from(cur / 2)
+        .unwrap_or(usize::MAX)
+        .clamp(1, Semaphore::MAX_PERMITS),
+      Err(_) => FALLBACK_MAX_OPEN_FILES,
+    }
+  }
+
+  ///
+  /// Waits for a permit to hold a file or directory open: see `with_max_open_files`.
+  ///
+  async fn open_file_permit(open_files: Arc<Semaphore>) -> Result<OwnedSemaphorePermit, io::Error> {
+    open_files.acquire_owned().await.map_err(|e| {
+      io::Error::new(
+        io::ErrorKind::Other,
+        format!("Failed to acquire a permit to open a file: {e}"),
+      )
+    })
+  }
+
+  fn open_file(path_abs: &Path, flags: i32) -> Result<fs::File, io::Error> {
+    fs::OpenOptions::new()
+      .read(true)
+      .custom_flags(flags)
+      .open(path_abs)
+  }
+
+  ///
+  /// Returns a function which opens the given File, relative to the root fd if there is one.
+  ///
+  fn file_opener(&self, file: &File) -> impl Fn() -> Result<fs::File, io::Error> + Send + 'static {
+    let path_abs = self.file_path(file);
+    let path = file.path.clone();
+    let flags = self.file_open_flags;
+    let root_fd = self.root_fd.clone();
+    move || match &root_fd {
+      Some(root_fd) => root_fd.open_file(&path, flags),
+      None => Self::open_file(&path_abs, flags),
+    }
+  }
+
+  async fn with_timeout<T>(
+    &self,
+    description: impl FnOnce() -> String,
+    operation: impl Future<Output = Result<T, io::Error>>,
+  ) -> Result<T, io::Error> {
+    let Some(timeout) = self.operation_timeout else {
+      return operation.await;
+    };
+    tokio::time::timeout(timeout, operation)
+      .await
+      .unwrap_or_else(|_| {
+        Err(io::Error::new(
+          io::ErrorKind::TimedOut,
+          format!("{} timed out after {timeout:?}", description()),
+        ))
+      })
+  }
+
+  pub async fn scandir(&self, dir_relative_to_root: Dir) -> Result<DirectoryListing, io::Error> {
+    self
+      .scandir_with_order(dir_relative_to_root, ScanOrder::Bytewise)
       .await
   }
 
-  fn scandir_sync(&self, dir_relative_to_root: &Dir) -> Result<DirectoryListing, io::Error> {
+  ///
+  /// Lists the given directory like `scandir`, but blocks the calling thread until the listing
+  /// has completed: a convenience for simple single-shot (e.g. CLI) tools.
+  ///
+  /// NB: This blocks the calling thread, and so must not be called from within an async context
+  /// (where it will panic).
+  ///
+  pub fn scandir_blocking(&self, dir_relative_to_root: Dir) -> Result<DirectoryListing, io::Error> {
+    self.executor.block_on(self.scandir(dir_relative_to_root))
+  }
+
+  ///
+  /// As `scandir`, but sorts the resulting listing using the given `ScanOrder`.
+  ///
+  /// NB: Only `ScanOrder::Bytewise` is stable enough to be used when computing digests: other
+  /// orders are intended for display.
+  ///
+  pub async fn scandir_with_order(
+    &self,
+    dir_relative_to_root: Dir,
+    order: ScanOrder,
+  ) -> Result<DirectoryListing, io::Error> {
+    let vfs = self.clone();
     let dir_abs = self.root.0.join(&dir_relative_to_root.0);
-    let mut stats: Vec<Stat> = dir_abs
-      .read_dir()?
-      .map(|readdir| {
-        let dir_entry = readdir?;
-        let (file_type, compute_metadata): (_, Box<dyn FnOnce() -> Result<_, _>>) =
-          match self.symlink_behavior {
-            SymlinkBehavior::Aware => {
-              // Use the dir_entry metadata, which is symlink aware.
-              (dir_entry.file_type()?, Box::new(|| dir_entry.metadata()))
-            }
-            SymlinkBehavior::Oblivious => {
-              // Use an independent stat call to get metadata, which is symlink oblivious.
-              let metadata = std::fs::metadata(dir_abs.join(dir_entry.file_name()))?;
-              (metadata.file_type(), Box::new(|| Ok(metadata)))
-            }
-          };
-        PosixFS::stat_internal(
-          &dir_abs.join(dir_entry.file_name()),
-          file_type,
-          compute_metadata,
-        )
-      })
-      .filter_map(|s| match s {
-        Ok(Some(s))
-          if !self.ignore.is_ignored_path(
-            &dir_relative_to_root.0.join(s.path()),
-            matches!(s, Stat::Dir(_)),
-          ) =>
-        {
-          // It would be nice to be able to ignore paths before stat'ing them, but in order to apply
-          // git-style ignore patterns, we need to know whether a path represents a directory.
-          Some(Ok(s))
+    // NB: The permit is moved into the blocking task, so that it is held until the directory has
+    // been closed, even if the operation times out.
+    let permit = Self::open_file_permit(self.open_files.clone()).await?;
+    self
+      .with_timeout(
+        || format!("Scanning directory {dir_abs:?}"),
+        self.executor.spawn_blocking(
+          move || {
+            let _permit = permit;
+            vfs.scandir_sync(&dir_relative_to_root, order)
+          },
+          |e| {
+            Err(io::Error::new(
+              io::ErrorKind::Other,
+              format!("Synchronous scandir failed: {e}"),
+            ))
+          },
+        ),
+      )
+      .await
+  }
+
+  ///
+  /// Handles an error opening the given directory for listing, according to the
+  /// `permission_denied_behavior`: returns Ok if the directory should be treated as empty.
+  ///
+  fn unreadable_dir(&self, dir: &Dir, err: io::Error) -> Result<(), io::Error> {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+      return Err(err);
+    }
+    match self.permission_denied_behavior {
+      PermissionDeniedBehavior::Error => Err(err),
+      PermissionDeniedBehavior::SkipEmpty => Ok(()),
+      PermissionDeniedBehavior::SkipWithWarning => {
+        log::warn!(
+          "Skipping directory {:?}, which could not be read: {err}",
+          dir.0
+        );
+        Ok(())
+      }
+    }
+  }
+
+  ///
+  /// Returns an iterator over the Stats of the (non-ignored) entries of the given directory, in
+  /// the order that they are read from the filesystem.
+  ///
+  fn scandir_entries<'a>(
+    &'a self,
+    dir_relative_to_root: &'a Dir,
+  ) -> Result<Box<dyn Iterator<Item = Result<Stat, io::Error>> + 'a>, io::Error> {
+    let dir_abs = self.root.0.join(&dir_relative_to_root.0);
+    if let Some(root_device) = self.root_device {
+      let device = match &self.root_fd {
+        Some(root_fd) => root_fd.stat(&dir_relative_to_root.0, true)?.st_dev as u64,
+        None => fs::metadata(&dir_abs)?.dev(),
+      };
+      if device != root_device {
+        return Ok(Box::new(std::iter::empty()));
+      }
+    }
+    let stats: Box<dyn Iterator<Item = Result<Option<Stat>, io::Error>> + 'a> = match &self.root_fd
+    {
+      Some(root_fd) => match root_fd.read_dir(&dir_relative_to_root.0) {
+        Ok(names) => Box::new(
+          names
+            .into_iter()
+            .map(move |name| self.stat_at(root_fd, &dir_relative_to_root.0.join(name))),
+        ),
+        Err(e) => {
+          self.unreadable_dir(dir_relative_to_root, e)?;
+          return Ok(Box::new(std::iter::empty()));
         }
-        Ok(_) => None,
-        Err(e) => Some(Err(e)),
-      })
-      .collect::<Result<Vec<_>, io::Error>>()
-      .map_err(|e| {
-        io::Error::new(
-          e.kind(),
-          format!("Failed to scan directory {dir_abs:?}: {e}"),
+      },
+      None => match dir_abs.read_dir() {
+        Ok(read_dir) => {
+          let dir_abs = dir_abs.clone();
+          Box::new(read_dir.map(move |readdir| {
+            let dir_entry = readdir?;
+            let (file_type, compute_metadata): (_, Box<dyn FnOnce() -> Result<_, _>>) =
+              match self.symlink_behavior {
+                SymlinkBehavior::Aware => {
+                  // Use the dir_entry metadata, which is symlink aware.
+                  (dir_entry.file_type()?, Box::new(|| dir_entry.metadata()))
+                }
+                SymlinkBehavior::Oblivious => {
+                  // Use an independent stat call to get metadata, which is symlink oblivious.
+                  let metadata = std::fs::metadata(dir_abs.join(dir_entry.file_name()))?;
+                  (metadata.file_type(), Box::new(|| Ok(metadata)))
+                }
+              };
+            PosixFS::stat_internal(
+              &dir_abs.join(dir_entry.file_name()),
+              file_type,
+              compute_metadata,
+              &self.xattr_names,
+              self.executable_detection,
+            )
+          }))
+        }
+        Err(e) => {
+          self.unreadable_dir(dir_relative_to_root, e)?;
+          return Ok(Box::new(std::iter::empty()));
+        }
+      },
+    };
+    Ok(Box::new(stats.filter_map(move |s| match s {
+      Ok(Some(s))
+        if !self.ignore.is_ignored_path(
+          &dir_relative_to_root.0.join(s.path()),
+          matches!(s, Stat::Dir(_)),
+        ) =>
+      {
+        // It would be nice to be able to ignore paths before stat'ing them, but in order to apply
+        // git-style ignore patterns, we need to know whether a path represents a directory.
+        Some(Ok(s))
+      }
+      Ok(_) => None,
+      Err(e) => Some(Err(io::Error::new(
+        e.kind(),
+        format!("Failed to scan directory {dir_abs:?}: {e}"),
+      ))),
+    })))
+  }
+
+  fn scandir_sync(
+    &self,
+    dir_relative_to_root: &Dir,
+    order: ScanOrder,
+  ) -> Result<DirectoryListing, io::Error> {
+    let mut stats = self
+      .scandir_entries(dir_relative_to_root)?
+      .collect::<Result<Vec<_>, io::Error>>()?;
+    match order {
+      ScanOrder::Bytewise => stats.sort_by(|s1, s2| s1.path().cmp(s2.path())),
+      ScanOrder::CaseInsensitive => stats.sort_by_cached_key(|s| {
+        (
+          s.path().to_string_lossy().to_lowercase(),
+          s.path().to_owned(),
         )
-      })?;
-    stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+      }),
+    }
     Ok(DirectoryListing(stats))
   }
 
+  ///
+  /// As `scandir`, but yields the Stats of the directory's entries as they are read, rather than
+  /// once all of them have been read, so that consumers can begin processing the entries of a huge
+  /// directory sooner.
+  ///
+  /// NB: Unlike `scandir`, the entries are not sorted (since sorting requires reading all of
+  /// them): they are yielded in the order that the filesystem returns them, which is arbitrary.
+  /// The `operation_timeout` does not apply.
+  ///
+  pub fn scandir_stream(
+    &self,
+    dir_relative_to_root: &Dir,
+  ) -> BoxStream<'static, Result<Stat, io::Error>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(SCANDIR_STREAM_BUFFER_SIZE);
+    let vfs = self.clone();
+    let dir = dir_relative_to_root.clone();
+    let _join = self.executor.native_spawn_blocking(move || {
+      let entries = match vfs.scandir_entries(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+          let _ = sender.blocking_send(Err(e));
+          return;
+        }
+      };
+      for entry in entries {
+        let is_err = entry.is_err();
+        // Sending fails if the stream has been dropped, in which case we stop reading.
+        if sender.blocking_send(entry).is_err() || is_err {
+          break;
+        }
+      }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+      receiver.recv().await.map(|entry| (entry, receiver))
+    })
+    .boxed()
+  }
+
   pub fn is_ignored(&self, stat: &Stat) -> bool {
     self.ignore.is_ignored(stat)
   }
 
+  ///
+  /// Computes the total size in bytes of the regular files below the given directory (relative to
+  /// the root), recursing via `scandir` so that ignored paths are not counted.
+  ///
+  /// Symlinks are not followed when this PosixFS is symlink-aware, and so contribute nothing. When
+  /// it is symlink-oblivious, `scandir` resolves each symlink to its destination, so each symlink to
+  /// a file counts the size of that file (even if the file is also counted elsewhere), and each
+  /// symlink to a directory counts the directory's contents.
+  ///
+  pub async fn du(&self, dir: &Dir) -> Result<u64, io::Error> {
+    let mut total = 0;
+    let mut dirs = vec![dir.clone()];
+    while let Some(dir) = dirs.pop() {
+      let mut files = Vec::new();
+      for stat in self.scandir(dir.clone()).await?.0 {
+        match stat {
+          Stat::Dir(d) => dirs.push(Dir(dir.0.join(d.0))),
+          Stat::File(f) => files.push(dir.0.join(f.path)),
+          Stat::Link(_) => (),
+        }
+      }
+      if files.is_empty() {
+        continue;
+      }
+
+      let root = self.root.0.clone();
+      let root_fd = self.root_fd.clone();
+      total += self
+        .executor
+        .spawn_blocking(
+          move || {
+            files.iter().try_fold(0, |total, path| {
+              let size = match &root_fd {
+                Some(root_fd) => root_fd.stat(path, true)?.st_size as u64,
+                None => fs::metadata(root.join(path))?.len(),
+              };
+              Ok::<_, io::Error>(total + size)
+            })
+          },
+          |e| {
+            Err(io::Error::new(
+              io::ErrorKind::Other,
+              format!("du task failed: {e}"),
+            ))
+          },
+        )
+        .await?;
+    }
+    Ok(total)
+  }
+
   pub fn file_path(&self, file: &File) -> PathBuf {
     self.root.0.join(&file.path)
   }
 
+  ///
+  /// Canonicalizes the given path (which, if relative, is interpreted relative to the root), and
+  /// returns it relative to the root. Fails if the path does not exist, or if it is not contained
+  /// within the root.
+  ///
+  pub fn relativize(&self, path: &Path) -> Result<PathBuf, String> {
+    let canonical = self
+      .root
+      .0
+      .join(path)
+      .canonicalize()
+      .map_err(|e| format!("Failed to canonicalize {path:?}: {e}"))?;
+    canonical
+      .strip_prefix(&self.root.0)
+      .map(|relative| relative.to_owned())
+      .map_err(|_| {
+        format!(
+          "Path {path:?} resolves to {canonical:?}, which is not within the root {:?}.",
+          self.root.0
+        )
+      })
+  }
+
+  ///
+  /// Converts the given absolute paths (e.g. the files open in an editor) into filespecs relative
+  /// to the root, which each match exactly the path they were created from: any glob
+  /// metacharacters in the paths are escaped. Fails if any path does not exist, or is not within
+  /// the root: see `relativize`.
+  ///
+  pub fn to_filespecs(&self, abs_paths: &[PathBuf]) -> Result<Vec<String>, String> {
+    abs_paths
+      .iter()
+      .map(|path| {
+        let relative = self.relativize(path)?;
+        Ok(
+          relative
+            .components()
+            .map(|component| glob::Pattern::escape(&component.as_os_str().to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join("/"),
+        )
+      })
+      .collect()
+  }
+
+  ///
+  /// Computes the Digest of the given File by streaming its content through the hasher, without
+  /// storing it anywhere.
+  ///
+  pub async fn digest_file(&self, file: &File) -> Result<hashing::Digest, io::Error> {
+    let path_abs = self.file_path(file);
+    let open = self.file_opener(file);
+    let permit = Self::open_file_permit(self.open_files.clone()).await?;
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let _permit = permit;
+          open()
+            .and_then(|mut reader| hashing::sync_copy_and_hash(&mut reader, &mut io::sink()))
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to digest {path_abs:?}: {e}")))
+        },
+        |e| {
+          Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Synchronous digest failed: {e}"),
+          ))
+        },
+      )
+      .await
+  }
+
+  ///
+  /// Reads the entire content of the given File into memory, blocking the calling thread until it
+  /// has been read (in chunks, as by `read_file_chunks`): a convenience for simple single-shot
+  /// (e.g. CLI) tools.
+  ///
+  /// NB: This blocks the calling thread, and so must not be called from within an async context
+  /// (where it will panic).
+  ///
+  pub fn read_file_blocking(&self, file: &File) -> Result<Bytes, io::Error> {
+    let chunks = self.read_file_chunks(file, READ_FILE_BLOCKING_CHUNK_SIZE);
+    self
+      .executor
+      .block_on(chunks.try_fold(BytesMut::new(), |mut content, chunk| {
+        content.extend_from_slice(&chunk);
+        future::ready(Ok(content))
+      }))
+      .map(BytesMut::freeze)
+  }
+
+  ///
+  /// Streams the content of the given File as chunks of (at most) `chunk_size` bytes, each of
+  /// which is read on the blocking pool. The next chunk is only read once the previous chunk has
+  /// been consumed, so the reader never runs ahead of the consumer.
+  ///
+  pub fn read_file_chunks(
+    &self,
+    file: &File,
+    chunk_size: usize,
+  ) -> BoxStream<'static, Result<Bytes, io::Error>> {
+    let path_abs = self.file_path(file);
+    let open = Arc::new(self.file_opener(file));
+    let executor = self.executor.clone();
+    let open_files = self.open_files.clone();
+    let chunk_size = chunk_size.max(1);
+    // NB: The permit to hold the file open is held (alongside the file) until the stream ends.
+    stream::try_unfold(
+      None,
+      move |state: Option<(fs::File, OwnedSemaphorePermit)>| {
+        let path_abs = path_abs.clone();
+        let open = open.clone();
+        let executor = executor.clone();
+        let open_files = open_files.clone();
+        async move {
+          let (reader, permit) = match state {
+            Some((reader, permit)) => (Some(reader), permit),
+            None => (None, Self::open_file_permit(open_files).await?),
+          };
+          executor
+            .spawn_blocking(
+              move || {
+                let read_chunk = || -> io::Result<(Vec<u8>, fs::File)> {
+                  let mut reader = match reader {
+                    Some(reader) => reader,
+                    None => open()?,
+                  };
+                  let mut chunk = Vec::with_capacity(chunk_size);
+                  (&mut reader)
+                    .take(chunk_size as u64)
+                    .read_to_end(&mut chunk)?;
+                  Ok((chunk, reader))
+                };
+                match read_chunk() {
+                  Ok((chunk, _)) if chunk.is_empty() => Ok(None),
+                  Ok((chunk, reader)) => Ok(Some((Bytes::from(chunk), Some((reader, permit))))),
+                  Err(e) => Err(io::Error::new(
+                    e.kind(),
+                    format!("Failed to read {path_abs:?}: {e}"),
+                  )),
+                }
+              },
+              |e| {
+                Err(io::Error::new(
+                  io::ErrorKind::Other,
+                  format!("Synchronous read failed: {e}"),
+                ))
+              },
+            )
+            .await
+        }
+      },
+    )
+    .boxed()
+  }
+
   pub async fn read_link(&self, link: &Link) -> Result<PathBuf, io::Error> {
-    let link_parent = link.path.parent().map(Path::to_owned);
     let link_abs = self.root.0.join(link.path.as_path());
-    tokio::fs::read_link(&link_abs)
+    let target = match &self.root_fd {
+      Some(root_fd) => {
+        let root_fd = root_fd.clone();
+        let path = link.path.clone();
+        self
+          .with_timeout(
+            || format!("Reading link {link_abs:?}"),
+            self.executor.spawn_blocking(
+              move || root_fd.read_link(&path),
+              |e| {
+                Err(io::Error::new(
+                  io::ErrorKind::Other,
+                  format!("Reading link failed: {e}"),
+                ))
+              },
+            ),
+          )
+          .await
+      }
+      None => {
+        self
+          .with_timeout(
+            || format!("Reading link {link_abs:?}"),
+            tokio::fs::read_link(&link_abs),
+          )
+          .await
+      }
+    };
+    Self::resolve_link_target(link, &link_abs, target)
+  }
+
+  ///
+  /// Reads many links at once, using a single task on the blocking pool rather than one task per
+  /// link. Each link's result is returned in the position of the link: the outer result fails only
+  /// if the batch as a whole could not be run.
+  ///
+  pub async fn read_links(
+    &self,
+    links: Vec<Link>,
+  ) -> Result<Vec<Result<PathBuf, io::Error>>, io::Error> {
+    let root = self.root.0.clone();
+    let root_fd = self.root_fd.clone();
+    let link_count = links.len();
+    self
+      .with_timeout(
+        || format!("Reading {link_count} links"),
+        self.executor.spawn_blocking(
+          move || {
+            Ok(
+              links
+                .iter()
+                .map(|link| {
+                  let link_abs = root.join(link.path.as_path());
+                  let target = match &root_fd {
+                    Some(root_fd) => root_fd.read_link(&link.path),
+                    None => fs::read_link(&link_abs),
+                  };
+                  Self::resolve_link_target(link, &link_abs, target)
+                })
+                .collect::<Vec<_>>(),
+            )
+          },
+          |e| {
+            Err(io::Error::new(
+              io::ErrorKind::Other,
+              format!("Reading links failed: {e}"),
+            ))
+          },
+        ),
+      )
       .await
-      .and_then(|path_buf| {
-        if path_buf.is_absolute() {
-          Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Absolute symlink: {path_buf:?}"),
-          ))
-        } else {
-          link_parent
-            .map(|parent| parent.join(&path_buf))
-            .ok_or_else(|| {
-              io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Symlink without a parent?: {path_buf:?}"),
-              )
-            })
+  }
+
+  ///
+  /// Stats each of the given paths (relative to the root), canonicalizing symlinks to the files or
+  /// directories that they (transitively) point to as `GlobMatching::canonicalize_link` does. Each
+  /// result is in the position of its path, and is None if the path does not exist, is ignored, or
+  /// is a broken link.
+  ///
+  /// Rather than canonicalizing each path independently, each round of stats runs as a single
+  /// task on the blocking pool, and each level of links is read with a single `read_links` batch.
+  ///
+  /// NB: Intermediate components of a link target which are themselves symlinks are followed by
+  /// the operating system, and so are retained in the canonical path of the target.
+  ///
+  pub async fn canonicalize_paths(
+    &self,
+    paths: Vec<PathBuf>,
+  ) -> Result<Vec<Option<PathStat>>, io::Error> {
+    let mut stats: Vec<Option<Stat>> = vec![None; paths.len()];
+    let mut via_symlink = vec![false; paths.len()];
+    // The index and current (canonical) path of each path which has not yet been resolved.
+    let mut pending = paths.iter().cloned().enumerate().collect::<Vec<_>>();
+    let mut link_depth = 0;
+    while !pending.is_empty() {
+      let (indices, pending_paths): (Vec<_>, Vec<_>) = pending.into_iter().unzip();
+      let pending_stats = self.stat_paths(pending_paths).await?;
+      let mut links = Vec::new();
+      for (i, stat) in indices.into_iter().zip(pending_stats) {
+        match stat {
+          Some(Stat::Link(link)) => links.push((i, link)),
+          stat => stats[i] = stat,
+        }
+      }
+      if links.is_empty() {
+        break;
+      }
+      if link_depth >= MAX_LINK_DEPTH {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!(
+            "Maximum link depth exceeded for {:?}",
+            links.iter().map(|(i, _)| &paths[*i]).collect::<Vec<_>>()
+          ),
+        ));
+      }
+      link_depth += 1;
+
+      let (indices, links): (Vec<_>, Vec<_>) = links.into_iter().unzip();
+      pending = Vec::new();
+      for (i, target) in indices.into_iter().zip(self.read_links(links).await?) {
+        via_symlink[i] = true;
+        // A target which escapes the root (or is the root itself) is broken.
+        if let Some(target) = Self::normalize_link_target(&target?) {
+          pending.push((i, target));
+        }
+      }
+    }
+
+    Ok(
+      paths
+        .into_iter()
+        .zip(stats.into_iter().zip(via_symlink))
+        .map(|(path, (stat, via_symlink))| {
+          Some(match stat? {
+            Stat::Dir(stat) => PathStat::Dir {
+              path,
+              stat,
+              via_symlink,
+            },
+            Stat::File(stat) => PathStat::File {
+              path,
+              stat,
+              via_symlink,
+            },
+            Stat::Link(stat) => PathStat::Link {
+              path,
+              stat,
+              via_symlink,
+            },
+          })
+        })
+        .collect(),
+    )
+  }
+
+  ///
+  /// Stats the given paths (relative to the root) in a single task on the blocking pool, returning
+  /// None for each path which does not exist or is ignored.
+  ///
+  async fn stat_paths(&self, paths: Vec<PathBuf>) -> Result<Vec<Option<Stat>>, io::Error> {
+    let vfs = self.clone();
+    let path_count = paths.len();
+    self
+      .with_timeout(
+        || format!("Statting {path_count} paths"),
+        self.executor.spawn_blocking(
+          move || {
+            paths
+              .iter()
+              .map(|path| {
+                let stat = vfs
+                  .stat_sync(path)?
+                  .map(|stat| stat.within(path.parent().unwrap_or_else(|| Path::new(""))))
+                  .filter(|stat| !vfs.ignore.is_ignored(stat));
+                Ok(stat)
+              })
+              .collect()
+          },
+          |e| {
+            Err(io::Error::new(
+              io::ErrorKind::Other,
+              format!("Statting paths failed: {e}"),
+            ))
+          },
+        ),
+      )
+      .await
+  }
+
+  ///
+  /// Lexically normalizes a link target which is relative to the root, returning None if it is
+  /// the root itself or escapes the root.
+  ///
+  fn normalize_link_target(target: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in target.components() {
+      match component {
+        Component::Normal(name) => normalized.push(name),
+        Component::ParentDir => {
+          if !normalized.pop() {
+            return None;
+          }
         }
+        Component::CurDir => (),
+        Component::Prefix(_) | Component::RootDir => return None,
+      }
+    }
+    if normalized.as_os_str().is_empty() {
+      None
+    } else {
+      Some(normalized)
+    }
+  }
+
+  ///
+  /// Validates the raw target of a Link, and makes it relative to the root.
+  ///
+  fn resolve_link_target(
+    link: &Link,
+    link_abs: &Path,
+    target: Result<PathBuf, io::Error>,
+  ) -> Result<PathBuf, io::Error> {
+    target
+      .and_then(|path_buf| {
+        link
+          .resolve_target(path_buf)
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
       })
       .map_err(|e| io::Error::new(e.kind(), format!("Failed to read link {link_abs:?}: {e}")))
   }
 
+  ///
+  /// Probes whether the filesystem containing the root is case-sensitive, by creating a file with
+  /// a mixed case name in the root and checking whether its lowercased name resolves. The probe
+  /// file is removed afterward, even if the probe fails.
+  ///
+  pub async fn detect_case_sensitivity(&self) -> Result<bool, io::Error> {
+    let root = self.root.0.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+          let probe_name = format!(".CaseProbe-{}-{nanos}", std::process::id());
+          let probe_path = root.join(&probe_name);
+          fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe_path)
+            .map_err(|e| {
+              io::Error::new(
+                e.kind(),
+                format!("Failed to create case sensitivity probe {probe_path:?}: {e}"),
+              )
+            })?;
+          let result = match fs::symlink_metadata(root.join(probe_name.to_lowercase())) {
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+          };
+          fs::remove_file(&probe_path)?;
+          result
+        },
+        |e| {
+          Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Case sensitivity probe failed: {e}"),
+          ))
+        },
+      )
+      .await
+  }
+
   ///
   /// Makes a Stat for path_to_stat relative to its containing directory.
   ///
@@ -513,6 +2128,8 @@ impl PosixFS {
     path_to_stat: &Path,
     file_type: std::fs::FileType,
     compute_metadata: F,
+    xattr_names: &[String],
+    executable_detection: ExecutableDetection,
   ) -> Result<Option<Stat>, io::Error>
   where
     F: FnOnce() -> Result<std::fs::Metadata, io::Error>,
@@ -538,10 +2155,18 @@ impl PosixFS {
         target: std::fs::read_link(path_to_stat)?,
       })))
     } else if file_type.is_file() {
-      let is_executable = compute_metadata()?.permissions().mode() & 0o100 == 0o100;
+      let is_executable =
+        compute_metadata()?.permissions().mode() & executable_detection.mask() != 0;
+      let mut xattrs = BTreeMap::new();
+      for name in xattr_names {
+        if let Some(value) = xattr::get(path_to_stat, name)? {
+          xattrs.insert(name.clone(), value);
+        }
+      }
       Ok(Some(Stat::File(File {
         path,
         is_executable: is_executable,
+        xattrs,
       })))
     } else if file_type.is_dir() {
       Ok(Some(Stat::Dir(Dir(path))))
@@ -550,6 +2175,40 @@ impl PosixFS {
     }
   }
 
+  ///
+  /// As `stat_internal`, but for a path relative to the given root fd.
+  ///
+  fn stat_at(
+    &self,
+    root_fd: &openat::RootFd,
+    relative_path: &Path,
+  ) -> Result<Option<Stat>, io::Error> {
+    let Some(file_name) = relative_path.file_name() else {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Path {relative_path:?} to stat must have a file name."),
+      ));
+    };
+    let path = PathBuf::from(file_name);
+    let stat = root_fd.stat(
+      relative_path,
+      matches!(self.symlink_behavior, SymlinkBehavior::Oblivious),
+    )?;
+    match stat.st_mode & libc::S_IFMT {
+      libc::S_IFLNK => Ok(Some(Stat::Link(Link {
+        path,
+        target: root_fd.read_link(relative_path)?,
+      }))),
+      libc::S_IFREG => Ok(Some(Stat::File(File {
+        path,
+        is_executable: stat.st_mode & self.executable_detection.mask() as libc::mode_t != 0,
+        xattrs: BTreeMap::new(),
+      }))),
+      libc::S_IFDIR => Ok(Some(Stat::Dir(Dir(path)))),
+      _ => Ok(None),
+    }
+  }
+
   ///
   /// Returns a Stat relative to its containing directory.
   ///
@@ -567,12 +2226,28 @@ impl PosixFS {
       ));
     }
     let abs_path = self.root.0.join(relative_path);
+    if let Some(root_fd) = &self.root_fd {
+      return self
+        .stat_at(root_fd, relative_path)
+        .or_else(|err| match err.kind() {
+          io::ErrorKind::NotFound => Ok(None),
+          _ => Err(err),
+        });
+    }
     let metadata = match self.symlink_behavior {
       SymlinkBehavior::Aware => fs::symlink_metadata(&abs_path),
       SymlinkBehavior::Oblivious => fs::metadata(&abs_path),
     };
     metadata
-      .and_then(|metadata| PosixFS::stat_internal(&abs_path, metadata.file_type(), || Ok(metadata)))
+      .and_then(|metadata| {
+        PosixFS::stat_internal(
+          &abs_path,
+          metadata.file_type(),
+          || Ok(metadata),
+          &self.xattr_names,
+          self.executable_detection,
+        )
+      })
       .or_else(|err| match err.kind() {
         io::ErrorKind::NotFound => Ok(None),
         _ => Err(err),
@@ -599,6 +2274,117 @@ impl Vfs<io::Error> for Arc<PosixFS> {
   }
 }
 
+///
+/// A union of multiple PosixFS roots, which are presented as a single filesystem.
+///
+/// Entries in earlier roots shadow same-named entries in later roots: a directory listing contains
+/// the union of the entries of that directory in each root, and each entry is read from the first
+/// root which contains it.
+///
+pub struct OverlayFS {
+  roots: Vec<Arc<PosixFS>>,
+}
+
+impl OverlayFS {
+  pub fn new(roots: Vec<Arc<PosixFS>>) -> Result<OverlayFS, String> {
+    if roots.is_empty() {
+      return Err("An OverlayFS must have at least one root.".to_owned());
+    }
+    Ok(OverlayFS { roots })
+  }
+
+  pub async fn scandir(&self, dir_relative_to_root: Dir) -> Result<DirectoryListing, io::Error> {
+    let mut entries: Vec<Stat> = vec![];
+    let mut seen_names = HashSet::new();
+    let mut not_found = None;
+    let mut found = false;
+    for root in &self.roots {
+      let listing = match root.scandir(dir_relative_to_root.clone()).await {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+          // The directory need not exist in every root.
+          not_found = Some(e);
+          continue;
+        }
+        Err(e) => return Err(e),
+      };
+      found = true;
+      for stat in listing.0 {
+        if seen_names.insert(stat.path().to_owned()) {
+          entries.push(stat);
+        }
+      }
+    }
+
+    if let (false, Some(e)) = (found, not_found) {
+      return Err(e);
+    }
+    entries.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+    Ok(DirectoryListing(entries))
+  }
+
+  pub async fn read_link(&self, link: &Link) -> Result<PathBuf, io::Error> {
+    self
+      .dispatch(&link.path, |root| async move { root.read_link(link).await })
+      .await
+  }
+
+  ///
+  /// Returns the absolute path of the given File in the first root which contains it.
+  ///
+  pub async fn file_path(&self, file: &File) -> Result<PathBuf, io::Error> {
+    self
+      .dispatch(&file.path, |root| async move {
+        let path = root.file_path(file);
+        tokio::fs::symlink_metadata(&path).await.map(|_| path)
+      })
+      .await
+  }
+
+  ///
+  /// Runs the given operation against each root in order, until one of them fails with an error
+  /// other than `io::ErrorKind::NotFound` (indicating that the root contains the path).
+  ///
+  async fn dispatch<'a, T, F, Fut>(&'a self, path: &Path, operation: F) -> Result<T, io::Error>
+  where
+    F: Fn(&'a Arc<PosixFS>) -> Fut,
+    Fut: Future<Output = Result<T, io::Error>>,
+  {
+    let mut last_err = None;
+    for root in &self.roots {
+      match operation(root).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => last_err = Some(e),
+        res => return res,
+      }
+    }
+    Err(last_err.unwrap_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{path:?} does not exist in any root."),
+      )
+    }))
+  }
+}
+
+#[async_trait]
+impl Vfs<io::Error> for Arc<OverlayFS> {
+  async fn read_link(&self, link: &Link) -> Result<PathBuf, io::Error> {
+    OverlayFS::read_link(self, link).await
+  }
+
+  async fn scandir(&self, dir: Dir) -> Result<Arc<DirectoryListing>, io::Error> {
+    Ok(Arc::new(OverlayFS::scandir(self, dir).await?))
+  }
+
+  fn is_ignored(&self, stat: &Stat) -> bool {
+    self.roots.iter().any(|root| root.is_ignored(stat))
+  }
+
+  fn mk_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+  }
+}
+
 #[async_trait]
 impl Vfs<String> for DigestTrie {
   async fn read_link(&self, link: &Link) -> Result<PathBuf, String> {
@@ -656,6 +2442,7 @@ impl Vfs<String> for DigestTrie {
           directory::Entry::File(f) => Stat::File(File {
             path: f.name().as_ref().into(),
             is_executable: f.is_executable(),
+            xattrs: BTreeMap::new(),
           }),
           directory::Entry::Symlink(s) => Stat::Link(Link {
             path: s.name().as_ref().into(),
@@ -712,7 +2499,28 @@ impl fmt::Debug for FileContent {
   }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl FileContent {
+  ///
+  /// Guesses whether the content is binary rather than text, in the same way as `git` and `grep`:
+  /// by checking for a NUL byte within the first `BINARY_SNIFF_LENGTH` bytes.
+  ///
+  pub fn is_probably_binary(&self) -> bool {
+    let len = min(self.content.len(), BINARY_SNIFF_LENGTH);
+    self.content[..len].contains(&0)
+  }
+
+  ///
+  /// Returns the content as a string, if it is valid UTF-8.
+  ///
+  pub fn as_utf8(&self) -> Option<&str> {
+    std::str::from_utf8(&self.content).ok()
+  }
+}
+
+/// The number of leading bytes of a file which are checked by `FileContent::is_probably_binary`.
+const BINARY_SNIFF_LENGTH: usize = 8000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FileEntry {
   pub path: PathBuf,
   pub digest: hashing::Digest,
@@ -0,0 +1,152 @@
+// Copyright 2023 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Minimal wrappers around the `*at` family of syscalls, which resolve paths relative to an open
+//! directory file descriptor rather than to a path. See `PosixFS::with_root_fd`.
+//!
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+///
+/// An open file descriptor for a directory, relative to which (relative) paths are resolved.
+///
+#[derive(Debug)]
+pub(crate) struct RootFd(OwnedFd);
+
+impl RootFd {
+  pub(crate) fn open(root: &Path) -> Result<RootFd, io::Error> {
+    let root = c_path(root)?;
+    let fd = unsafe {
+      libc::open(
+        root.as_ptr(),
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(RootFd(unsafe { OwnedFd::from_raw_fd(fd) }))
+  }
+
+  ///
+  /// Opens the given path relative to the root, with the given flags.
+  ///
+  fn open_at(&self, path: &Path, flags: i32) -> Result<OwnedFd, io::Error> {
+    let path = c_relative_path(path)?;
+    let fd = unsafe { libc::openat(self.0.as_raw_fd(), path.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+  }
+
+  pub(crate) fn open_file(&self, path: &Path, flags: i32) -> Result<fs::File, io::Error> {
+    Ok(fs::File::from(self.open_at(path, libc::O_RDONLY | flags)?))
+  }
+
+  ///
+  /// Lists the names of the entries of the given directory, other than `.` and `..`.
+  ///
+  pub(crate) fn read_dir(&self, dir: &Path) -> Result<Vec<OsString>, io::Error> {
+    let fd = self.open_at(dir, libc::O_RDONLY | libc::O_DIRECTORY)?;
+    // NB: On success, the DIR takes ownership of the file descriptor, and closes it in `closedir`.
+    let dirp = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+    if dirp.is_null() {
+      return Err(io::Error::last_os_error());
+    }
+    std::mem::forget(fd);
+
+    let mut names = Vec::new();
+    let result = loop {
+      // `readdir` returns null both at the end of the directory and on error: only the latter
+      // sets errno.
+      clear_errno();
+      let entry = unsafe { libc::readdir(dirp) };
+      if entry.is_null() {
+        let err = io::Error::last_os_error();
+        break match err.raw_os_error() {
+          Some(0) | None => Ok(()),
+          _ => Err(err),
+        };
+      }
+      let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+      if name != b"." && name != b".." {
+        names.push(OsString::from_vec(name.to_vec()));
+      }
+    };
+    unsafe { libc::closedir(dirp) };
+    result.map(|()| names)
+  }
+
+  ///
+  /// Stats the given path relative to the root, following a final symlink only if requested.
+  ///
+  pub(crate) fn stat(&self, path: &Path, follow_symlinks: bool) -> Result<libc::stat, io::Error> {
+    let path = c_relative_path(path)?;
+    let flags = if follow_symlinks {
+      0
+    } else {
+      libc::AT_SYMLINK_NOFOLLOW
+    };
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    let res = unsafe { libc::fstatat(self.0.as_raw_fd(), path.as_ptr(), stat.as_mut_ptr(), flags) };
+    if res != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+  }
+
+  pub(crate) fn read_link(&self, path: &Path) -> Result<PathBuf, io::Error> {
+    let path = c_relative_path(path)?;
+    let mut buf = vec![0_u8; libc::PATH_MAX as usize];
+    let len = unsafe {
+      libc::readlinkat(
+        self.0.as_raw_fd(),
+        path.as_ptr(),
+        buf.as_mut_ptr() as *mut libc::c_char,
+        buf.len(),
+      )
+    };
+    if len < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    buf.truncate(len as usize);
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+  }
+}
+
+fn c_path(path: &Path) -> Result<CString, io::Error> {
+  CString::new(path.as_os_str().as_bytes())
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+///
+/// Converts a path relative to the root into a CString, using `.` for the root itself.
+///
+fn c_relative_path(path: &Path) -> Result<CString, io::Error> {
+  if path.as_os_str().is_empty() {
+    c_path(Path::new(OsStr::new(".")))
+  } else {
+    c_path(path)
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn clear_errno() {
+  unsafe { *libc::__errno_location() = 0 };
+}
+
+#[cfg(target_os = "macos")]
+fn clear_errno() {
+  unsafe { *libc::__error() = 0 };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clear_errno() {}
@@ -1,15 +1,24 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::{BTreeMap, HashSet};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt;
 use hashing::EMPTY_DIGEST;
+use parking_lot::Mutex;
 use testutil::make_file;
 
 use crate::{
-  DigestTrie, Dir, DirectoryListing, File, GitignoreStyleExcludes, GlobExpansionConjunction,
-  GlobMatching, Link, PathGlobs, PathStat, PosixFS, Stat, StrictGlobMatching, SymlinkBehavior,
-  TypedPath,
+  DigestTrie, Dir, DirectoryListing, ExcludeMode, ExecutableDetection, File, FsError,
+  GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching, GlobParsedSource, Link,
+  OverlayFS, PathGlobs, PathStat, PermissionDeniedBehavior, PosixFS, ScanOrder, ScandirProfile,
+  Stat, StatKind, StrictGlobMatching, SymlinkBehavior, TypedPath, Vfs,
 };
 
 #[tokio::test]
@@ -26,6 +35,30 @@ async fn is_executable_true() {
   assert_only_file_is_executable(dir.path(), true).await;
 }
 
+#[tokio::test]
+async fn is_executable_detection() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("group_exec"), &[], 0o610);
+  let is_executable = |detection| {
+    let fs = new_posixfs(dir.path()).with_executable_detection(detection);
+    async move {
+      let stats = fs.scandir(Dir(PathBuf::from("."))).await.unwrap();
+      let stat_sync = fs.stat_sync(Path::new("group_exec")).unwrap().unwrap();
+      match (&stats.0[..], stat_sync) {
+        ([Stat::File(scanned)], Stat::File(statted)) => {
+          assert_eq!(scanned.is_executable, statted.is_executable);
+          scanned.is_executable
+        }
+        other => panic!("Expected a single file, got {other:?}"),
+      }
+    }
+  };
+  assert!(!is_executable(ExecutableDetection::Owner).await);
+  assert!(is_executable(ExecutableDetection::Any).await);
+  assert!(is_executable(ExecutableDetection::Mask(0o010)).await);
+  assert!(!is_executable(ExecutableDetection::Mask(0o001)).await);
+}
+
 #[tokio::test]
 async fn file_path() {
   let dir = tempfile::TempDir::new().unwrap();
@@ -35,10 +68,267 @@ async fn file_path() {
   let actual_path = fs.file_path(&File {
     path: path,
     is_executable: false,
+    xattrs: BTreeMap::new(),
   });
   assert_eq!(actual_path, expected_path);
 }
 
+#[tokio::test]
+async fn relativize() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let root = dir.path().join("root");
+  std::fs::create_dir_all(root.join("a/b")).unwrap();
+  make_file(&root.join("a/b/c.txt"), &[], 0o600);
+  make_file(&dir.path().join("escape"), &[], 0o600);
+  let fs = new_posixfs(&root);
+
+  // Relative, and `..`-containing paths within the root.
+  assert_eq!(
+    fs.relativize(Path::new("a/b/c.txt")).unwrap(),
+    PathBuf::from("a/b/c.txt")
+  );
+  assert_eq!(
+    fs.relativize(Path::new("a/b/../b/c.txt")).unwrap(),
+    PathBuf::from("a/b/c.txt")
+  );
+  assert_eq!(fs.relativize(Path::new(".")).unwrap(), PathBuf::from(""));
+
+  // An absolute path within the root.
+  assert_eq!(
+    fs.relativize(&root.join("a/b")).unwrap(),
+    PathBuf::from("a/b")
+  );
+
+  // Paths which escape the root.
+  let err = fs.relativize(Path::new("../escape")).unwrap_err();
+  assert!(err.contains("is not within the root"), "{err}");
+  let err = fs.relativize(&dir.path().join("escape")).unwrap_err();
+  assert!(err.contains("is not within the root"), "{err}");
+
+  // Paths which do not exist.
+  let err = fs.relativize(Path::new("a/missing")).unwrap_err();
+  assert!(err.contains("Failed to canonicalize"), "{err}");
+}
+
+#[tokio::test]
+async fn to_filespecs() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let root = dir.path().join("root");
+  std::fs::create_dir_all(root.join("a/[b]")).unwrap();
+  make_file(&root.join("a/[b]/c*.txt"), &[], 0o600);
+  make_file(&root.join("d.txt"), &[], 0o600);
+  make_file(&dir.path().join("escape"), &[], 0o600);
+  let fs = new_posixfs(&root);
+
+  let filespecs = fs
+    .to_filespecs(&[root.join("a/[b]/c*.txt"), root.join("d.txt")])
+    .unwrap();
+  assert_eq!(filespecs, vec!["a/[[]b[]]/c[*].txt", "d.txt"]);
+
+  // Each filespec matches exactly the path it was created from.
+  let globs = PathGlobs::new(
+    filespecs,
+    StrictGlobMatching::Error("".to_owned()),
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let paths = Arc::new(fs)
+    .expand_globs(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    paths,
+    vec![PathBuf::from("a/[b]/c*.txt"), PathBuf::from("d.txt")]
+  );
+
+  // A path outside of the root fails the whole conversion.
+  let err = new_posixfs(&root)
+    .to_filespecs(&[root.join("d.txt"), dir.path().join("escape")])
+    .unwrap_err();
+  assert!(err.contains("is not within the root"), "{err}");
+}
+
+#[tokio::test]
+async fn detect_case_sensitivity() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let posix_fs = new_posixfs(dir.path());
+
+  // Whether the filesystem is case-sensitive depends on the environment, but the probe should
+  // succeed, and should not leave anything behind.
+  posix_fs.detect_case_sensitivity().await.unwrap();
+  assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn scratch_dir() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("tmp")).unwrap();
+  make_file(&dir.path().join("tmp/kept.txt"), &[], 0o600);
+  let posix_fs = Arc::new(
+    new_posixfs(dir.path())
+      .with_scratch_dir(PathBuf::from("tmp/scratch"))
+      .unwrap(),
+  );
+  let scratch_path = posix_fs.scratch_path().unwrap();
+  assert_eq!(
+    scratch_path,
+    dir.path().canonicalize().unwrap().join("tmp/scratch")
+  );
+  make_file(&scratch_path.join("out.txt"), &[], 0o600);
+  std::fs::create_dir(scratch_path.join("nested")).unwrap();
+  make_file(&scratch_path.join("nested/out.txt"), &[], 0o600);
+
+  let listing = |path: &str| {
+    let posix_fs = posix_fs.clone();
+    let path = PathBuf::from(path);
+    async move {
+      posix_fs
+        .scandir(Dir(path))
+        .await
+        .unwrap()
+        .0
+        .into_iter()
+        .map(|stat| stat.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+  assert_eq!(listing("").await, vec![PathBuf::from("tmp")]);
+  assert_eq!(listing("tmp").await, vec![PathBuf::from("kept.txt")]);
+  assert_eq!(listing("tmp/scratch").await, Vec::<PathBuf>::new());
+
+  let globs = PathGlobs::new(
+    vec!["**".into(), "tmp/scratch/out.txt".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+  assert_eq!(
+    posix_fs
+      .expand_globs(globs, SymlinkBehavior::Aware, None)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|ps| ps.path().to_owned())
+      .collect::<Vec<_>>(),
+    vec![PathBuf::from("tmp"), PathBuf::from("tmp/kept.txt")]
+  );
+
+  // The scratch directory must be below the root.
+  assert!(new_posixfs(dir.path())
+    .with_scratch_dir(PathBuf::from("../scratch"))
+    .is_err());
+  assert!(new_posixfs(dir.path())
+    .with_scratch_dir(PathBuf::new())
+    .is_err());
+}
+
+#[tokio::test]
+async fn max_open_files() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let file_count = 50;
+  let files = (0..file_count)
+    .map(|i| {
+      let name = format!("{i}.txt");
+      make_file(&dir.path().join(&name), name.as_bytes(), 0o600);
+      File {
+        path: PathBuf::from(name),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      }
+    })
+    .collect::<Vec<_>>();
+  let max_open_files = 2;
+  let fs = new_posixfs(dir.path()).with_max_open_files(max_open_files);
+
+  // A batch of operations which is much larger than the limit all complete.
+  let digests = futures::future::try_join_all(files.iter().map(|file| fs.digest_file(file)))
+    .await
+    .unwrap();
+  assert_eq!(digests.len(), file_count);
+  let contents = futures::future::try_join_all(
+    files
+      .iter()
+      .map(|file| fs.read_file_chunks(file, 1).try_collect::<Vec<_>>()),
+  )
+  .await
+  .unwrap();
+  for (file, content) in files.iter().zip(contents) {
+    assert_eq!(content.concat(), file.path.to_str().unwrap().as_bytes());
+  }
+  let listings =
+    futures::future::try_join_all((0..file_count).map(|_| fs.scandir(Dir(PathBuf::new()))))
+      .await
+      .unwrap();
+  assert!(listings.iter().all(|l| l.0.len() == file_count));
+
+  // And all permits are released once they have.
+  assert_eq!(fs.open_files.available_permits(), max_open_files);
+}
+
+#[tokio::test]
+async fn read_file_chunks() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let content = b"abcdefghij";
+  make_file(&dir.path().join("letters"), content, 0o600);
+  let fs = new_posixfs(dir.path());
+  let file = File {
+    path: PathBuf::from("letters"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+
+  let chunks = fs
+    .read_file_chunks(&file, 3)
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+  assert_eq!(
+    chunks.iter().map(|c| c.len()).collect::<Vec<_>>(),
+    vec![3, 3, 3, 1]
+  );
+  assert_eq!(chunks.concat(), content.to_vec());
+
+  let missing = File {
+    path: PathBuf::from("missing"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  let err = fs
+    .read_file_chunks(&missing, 3)
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap_err();
+  assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn file_open_flags() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("target"), b"content", 0o600);
+  std::os::unix::fs::symlink("target", dir.path().join("link")).unwrap();
+  let link = File {
+    path: PathBuf::from("link"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  let read = |fs: &PosixFS| fs.read_file_chunks(&link, 1024).try_collect::<Vec<_>>();
+
+  // By default, a symlink is followed.
+  let fs = new_posixfs(dir.path());
+  assert_eq!(read(&fs).await.unwrap().concat(), b"content".to_vec());
+  assert!(fs.digest_file(&link).await.is_ok());
+
+  // But not with O_NOFOLLOW.
+  let fs = new_posixfs(dir.path()).with_file_open_flags(libc::O_NOFOLLOW);
+  assert!(read(&fs).await.is_err());
+  assert!(fs.digest_file(&link).await.is_err());
+}
+
 #[tokio::test]
 async fn stat_executable_file() {
   let dir = tempfile::TempDir::new().unwrap();
@@ -50,6 +340,7 @@ async fn stat_executable_file() {
     super::Stat::File(File {
       path: path,
       is_executable: true,
+      xattrs: BTreeMap::new(),
     })
   )
 }
@@ -65,10 +356,59 @@ async fn stat_nonexecutable_file() {
     super::Stat::File(File {
       path: path,
       is_executable: false,
+      xattrs: BTreeMap::new(),
     })
   )
 }
 
+// NB: Extended attributes are only supported on Linux and macOS, and setting one requires the
+// platform-specific syscall.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn stat_file_xattrs() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let path = PathBuf::from("marmosets");
+  make_file(&dir.path().join(&path), &[], 0o600);
+  let c_path = std::ffi::CString::new(dir.path().join(&path).to_str().unwrap()).unwrap();
+  let c_name = std::ffi::CString::new("user.origin").unwrap();
+  let value = b"zoo";
+  let res = unsafe {
+    libc::setxattr(
+      c_path.as_ptr(),
+      c_name.as_ptr(),
+      value.as_ptr() as *const libc::c_void,
+      value.len(),
+      0,
+    )
+  };
+  if res != 0 {
+    // The filesystem of the tempdir does not support (user) extended attributes.
+    return;
+  }
+
+  // By default, no extended attributes are read.
+  assert_eq!(
+    new_posixfs(dir.path()).stat_sync(&path).unwrap().unwrap(),
+    super::Stat::file(path.clone(), false)
+  );
+
+  // Requested attributes are captured if they are present.
+  let posix_fs =
+    new_posixfs(dir.path()).with_xattrs(vec!["user.origin".to_owned(), "user.missing".to_owned()]);
+  let expected = super::Stat::File(File {
+    path: path.clone(),
+    is_executable: false,
+    xattrs: vec![("user.origin".to_owned(), value.to_vec())]
+      .into_iter()
+      .collect(),
+  });
+  assert_eq!(posix_fs.stat_sync(&path).unwrap().unwrap(), expected);
+  assert_eq!(
+    posix_fs.scandir(Dir(PathBuf::from("."))).await.unwrap().0,
+    vec![expected]
+  );
+}
+
 #[tokio::test]
 async fn stat_dir() {
   let dir = tempfile::TempDir::new().unwrap();
@@ -99,6 +439,114 @@ async fn stat_symlink() {
   )
 }
 
+#[tokio::test]
+async fn canonicalize_paths() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let root = dir.path().join("root");
+  std::fs::create_dir_all(root.join("a/dir")).unwrap();
+  make_file(&root.join("a/file.txt"), b"content", 0o600);
+  std::os::unix::fs::symlink("a/file.txt", root.join("link_file")).unwrap();
+  std::os::unix::fs::symlink("a", root.join("link_dir")).unwrap();
+  std::os::unix::fs::symlink("link_file", root.join("link_link")).unwrap();
+  std::os::unix::fs::symlink("../a/./file.txt", root.join("a/up")).unwrap();
+  std::os::unix::fs::symlink("missing", root.join("broken")).unwrap();
+  std::os::unix::fs::symlink("../outside", root.join("escape")).unwrap();
+  let posix_fs = Arc::new(new_posixfs(&root));
+
+  let paths = [
+    "a/file.txt",
+    "a/dir",
+    "link_file",
+    "link_dir",
+    "link_link",
+    "a/up",
+    "link_dir/file.txt",
+    "broken",
+    "escape",
+    "missing",
+  ]
+  .into_iter()
+  .map(PathBuf::from)
+  .collect::<Vec<_>>();
+
+  // Canonicalize each path independently, for comparison.
+  let mut expected = Vec::new();
+  for path in &paths {
+    let stat = posix_fs
+      .stat_sync(path)
+      .unwrap()
+      .map(|stat| stat.within(path.parent().unwrap()));
+    expected.push(match stat {
+      Some(Stat::Link(link)) => posix_fs
+        .canonicalize_link(path.clone(), link)
+        .await
+        .unwrap(),
+      Some(Stat::Dir(stat)) => Some(PathStat::dir(path.clone(), stat)),
+      Some(Stat::File(stat)) => Some(PathStat::file(path.clone(), stat)),
+      None => None,
+    });
+  }
+
+  let actual = posix_fs.canonicalize_paths(paths).await.unwrap();
+  assert_eq!(actual, expected);
+  assert_eq!(
+    actual[4],
+    Some(PathStat::File {
+      path: PathBuf::from("link_link"),
+      stat: File {
+        path: PathBuf::from("a/file.txt"),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      },
+      via_symlink: true,
+    })
+  );
+  assert_eq!(&actual[7..], &[None, None, None]);
+}
+
+#[tokio::test]
+async fn read_links() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let posix_fs = new_posixfs(dir.path());
+  std::fs::create_dir(dir.path().join("sub")).unwrap();
+  make_file(&dir.path().join("marmosets"), &[], 0o600);
+  std::os::unix::fs::symlink("marmosets", dir.path().join("sibling")).unwrap();
+  std::os::unix::fs::symlink("../marmosets", dir.path().join("sub/parent")).unwrap();
+  std::os::unix::fs::symlink("missing", dir.path().join("sub/dangling")).unwrap();
+  std::os::unix::fs::symlink(dir.path().join("marmosets"), dir.path().join("absolute")).unwrap();
+
+  let link = |path: &str| Link {
+    path: PathBuf::from(path),
+    target: PathBuf::new(),
+  };
+  let results = posix_fs
+    .read_links(vec![
+      link("sibling"),
+      link("sub/parent"),
+      link("sub/dangling"),
+      link("absolute"),
+      link("not_a_link"),
+    ])
+    .await
+    .unwrap();
+
+  assert_eq!(results.len(), 5);
+  assert_eq!(results[0].as_ref().unwrap(), &PathBuf::from("marmosets"));
+  assert_eq!(
+    results[1].as_ref().unwrap(),
+    &PathBuf::from("sub/../marmosets")
+  );
+  // A dangling link can still be read.
+  assert_eq!(results[2].as_ref().unwrap(), &PathBuf::from("sub/missing"));
+  let err = results[3].as_ref().unwrap_err();
+  assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  assert!(err.to_string().contains("Absolute symlink"), "{err}");
+  assert_eq!(
+    results[4].as_ref().unwrap_err().kind(),
+    io::ErrorKind::NotFound
+  );
+}
+
 #[tokio::test]
 async fn stat_symlink_oblivious() {
   let dir = tempfile::TempDir::new().unwrap();
@@ -114,6 +562,7 @@ async fn stat_symlink_oblivious() {
     super::Stat::File(File {
       path: link_path,
       is_executable: false,
+      xattrs: BTreeMap::new(),
     })
   )
 }
@@ -126,6 +575,28 @@ async fn stat_other() {
   );
 }
 
+#[tokio::test]
+async fn posixfs_root_errors() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("file"), &[], 0o600);
+  let new_posixfs = |root: PathBuf| {
+    PosixFS::new(
+      root,
+      GitignoreStyleExcludes::empty(),
+      task_executor::Executor::new(),
+    )
+  };
+
+  let err = new_posixfs(dir.path().join("missing")).err().unwrap();
+  assert!(matches!(err, FsError::RootNotFound(_)), "{err:?}");
+  assert!(
+    err.to_string().starts_with("Could not canonicalize root"),
+    "{err}"
+  );
+  let err = new_posixfs(dir.path().join("file")).err().unwrap();
+  assert!(matches!(err, FsError::RootNotDirectory(_)), "{err:?}");
+}
+
 #[tokio::test]
 async fn stat_missing() {
   let dir = tempfile::TempDir::new().unwrap();
@@ -186,10 +657,12 @@ async fn scandir() {
       Stat::File(File {
         path: a_marmoset.clone(),
         is_executable: false,
+        xattrs: BTreeMap::new(),
       }),
       Stat::File(File {
         path: feed.clone(),
         is_executable: true,
+        xattrs: BTreeMap::new(),
       }),
       Stat::Dir(Dir(hammock.clone())),
       Stat::Link(Link {
@@ -199,6 +672,7 @@ async fn scandir() {
       Stat::File(File {
         path: sneaky_marmoset.clone(),
         is_executable: false,
+        xattrs: BTreeMap::new(),
       }),
     ])
   );
@@ -213,134 +687,450 @@ async fn scandir() {
       Stat::File(File {
         path: a_marmoset,
         is_executable: false,
+        xattrs: BTreeMap::new(),
       }),
       Stat::File(File {
         path: feed,
         is_executable: true,
+        xattrs: BTreeMap::new(),
       }),
       Stat::Dir(Dir(hammock)),
       Stat::File(File {
         path: remarkably_similar_marmoset,
         is_executable: false,
+        xattrs: BTreeMap::new(),
       }),
       Stat::File(File {
         path: sneaky_marmoset,
         is_executable: false,
+        xattrs: BTreeMap::new(),
       }),
     ])
   );
 }
 
 #[tokio::test]
-async fn scandir_missing() {
+async fn scandir_with_order() {
   let dir = tempfile::TempDir::new().unwrap();
+  for name in ["A.txt", "b.txt", "C.txt"] {
+    make_file(&dir.path().join(name), &[], 0o600);
+  }
   let posix_fs = new_posixfs(dir.path());
-  posix_fs
-    .scandir(Dir(PathBuf::from("no_marmosets_here")))
-    .await
-    .expect_err("Want error");
+  let paths = |listing: DirectoryListing| {
+    listing
+      .0
+      .iter()
+      .map(|s| s.path().to_owned())
+      .collect::<Vec<_>>()
+  };
+
+  // Bytewise ordering places all uppercase names before lowercase names.
+  assert_eq!(
+    paths(
+      posix_fs
+        .scandir_with_order(Dir(PathBuf::new()), ScanOrder::Bytewise)
+        .await
+        .unwrap()
+    ),
+    vec![
+      PathBuf::from("A.txt"),
+      PathBuf::from("C.txt"),
+      PathBuf::from("b.txt")
+    ]
+  );
+  assert_eq!(
+    paths(
+      posix_fs
+        .scandir_with_order(Dir(PathBuf::new()), ScanOrder::CaseInsensitive)
+        .await
+        .unwrap()
+    ),
+    vec![
+      PathBuf::from("A.txt"),
+      PathBuf::from("b.txt"),
+      PathBuf::from("C.txt")
+    ]
+  );
+  // The default ordering is bytewise.
+  assert_eq!(
+    paths(posix_fs.scandir(Dir(PathBuf::new())).await.unwrap()),
+    vec![
+      PathBuf::from("A.txt"),
+      PathBuf::from("C.txt"),
+      PathBuf::from("b.txt")
+    ]
+  );
 }
 
 #[tokio::test]
-async fn stats_for_paths() {
+async fn du() {
   let dir = tempfile::TempDir::new().unwrap();
-  let root_path = dir.path();
+  std::fs::create_dir_all(dir.path().join("a/b/empty")).unwrap();
+  make_file(&dir.path().join("top"), &[0; 3], 0o600);
+  make_file(&dir.path().join("a/middle"), &[0; 50], 0o600);
+  make_file(&dir.path().join("a/b/bottom"), &[0; 700], 0o700);
+  make_file(&dir.path().join("a/b/skipped.ignored"), &[0; 10_000], 0o600);
+  std::os::unix::fs::symlink("middle", dir.path().join("a/link")).unwrap();
 
-  // File tree:
-  // dir
-  // dir/recursive_symlink -> ../symlink -> executable_file
-  // dir_symlink -> dir
-  // executable_file
-  // regular_file
-  // symlink -> executable_file
-  // symlink_to_nothing -> doesnotexist
+  let ignorer = GitignoreStyleExcludes::create(vec!["*.ignored".to_owned()]).unwrap();
+  let posix_fs = PosixFS::new(dir.path(), ignorer.clone(), task_executor::Executor::new()).unwrap();
+  assert_eq!(posix_fs.du(&Dir(PathBuf::new())).await.unwrap(), 753);
+  assert_eq!(posix_fs.du(&Dir(PathBuf::from("a"))).await.unwrap(), 750);
+  assert_eq!(
+    posix_fs.du(&Dir(PathBuf::from("a/b/empty"))).await.unwrap(),
+    0
+  );
+  assert!(posix_fs.du(&Dir(PathBuf::from("missing"))).await.is_err());
 
-  make_file(&root_path.join("executable_file"), &[], 0o700);
-  make_file(&root_path.join("regular_file"), &[], 0o600);
-  std::fs::create_dir(root_path.join("dir")).unwrap();
-  std::os::unix::fs::symlink("executable_file", root_path.join("symlink")).unwrap();
-  std::os::unix::fs::symlink(
-    "../symlink",
-    root_path.join("dir").join("recursive_symlink"),
+  // When symlinks are resolved, they count the size of their destination.
+  let posix_fs = PosixFS::new_with_symlink_behavior(
+    dir.path(),
+    ignorer,
+    task_executor::Executor::new(),
+    SymlinkBehavior::Oblivious,
   )
   .unwrap();
-  std::os::unix::fs::symlink("dir", root_path.join("dir_symlink")).unwrap();
-  std::os::unix::fs::symlink("doesnotexist", root_path.join("symlink_to_nothing")).unwrap();
-
-  let posix_fs = Arc::new(new_posixfs(root_path));
-  let path_stats = vec![
-    PathBuf::from("executable_file"),
-    PathBuf::from("regular_file"),
-    PathBuf::from("dir"),
-    PathBuf::from("symlink"),
-    PathBuf::from("dir").join("recursive_symlink"),
-    PathBuf::from("dir_symlink"),
-    PathBuf::from("symlink_to_nothing"),
-    PathBuf::from("doesnotexist"),
-  ]
-  .into_iter()
-  .map(|p| posix_fs.stat_sync(&p).unwrap())
-  .collect::<Vec<_>>();
-  let v: Vec<Option<Stat>> = vec![
-    Some(Stat::File(File {
-      path: PathBuf::from("executable_file"),
-      is_executable: true,
-    })),
-    Some(Stat::File(File {
-      path: PathBuf::from("regular_file"),
-      is_executable: false,
-    })),
-    Some(Stat::Dir(Dir(PathBuf::from("dir")))),
-    Some(Stat::Link(Link {
-      path: PathBuf::from("symlink"),
-      target: PathBuf::from("executable_file"),
-    })),
-    Some(Stat::Link(Link {
-      path: PathBuf::from("recursive_symlink"),
-      target: PathBuf::from("../symlink"),
-    })),
-    Some(Stat::Link(Link {
-      path: PathBuf::from("dir_symlink"),
-      target: PathBuf::from("dir"),
-    })),
-    Some(Stat::Link(Link {
-      path: PathBuf::from("symlink_to_nothing"),
-      target: PathBuf::from("doesnotexist"),
-    })),
-    None,
-  ];
-  assert_eq!(v, path_stats);
+  assert_eq!(posix_fs.du(&Dir(PathBuf::new())).await.unwrap(), 803);
 }
 
 #[tokio::test]
-async fn memfs_expand_basic() {
-  // Create two files, with the effect that there is a nested directory for the longer path.
-  let p1 = PathBuf::from("some/file");
-  let p2 = PathBuf::from("some/other");
-  let p3 = p2.join("file");
+async fn scandir_permission_denied() {
+  use std::os::unix::fs::PermissionsExt;
 
-  let fs = DigestTrie::from_unique_paths(
-    vec![
-      TypedPath::File {
-        path: &p1,
-        is_executable: false,
-      },
-      TypedPath::File {
-        path: &p3,
-        is_executable: false,
-      },
-    ],
-    &vec![(p1.clone(), EMPTY_DIGEST), (p3.clone(), EMPTY_DIGEST)]
-      .into_iter()
-      .collect(),
-  )
-  .unwrap();
-  let globs = PathGlobs::new(
-    vec!["some/*".into()],
-    StrictGlobMatching::Ignore,
-    GlobExpansionConjunction::AnyMatch,
-  )
-  .parse()
+  let dir = tempfile::TempDir::new().unwrap();
+  let locked = dir.path().join("locked");
+  std::fs::create_dir(&locked).unwrap();
+  make_file(&locked.join("secret"), &[], 0o600);
+  make_file(&dir.path().join("visible"), &[], 0o600);
+  std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+  if std::fs::read_dir(&locked).is_ok() {
+    // Permissions are not enforced (e.g. when running as root), so there is nothing to test.
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+    return;
+  }
+
+  let scandir = |behavior| {
+    let posix_fs = new_posixfs(dir.path()).with_permission_denied_behavior(behavior);
+    async move { posix_fs.scandir(Dir(PathBuf::from("locked"))).await }
+  };
+  assert_eq!(
+    scandir(PermissionDeniedBehavior::Error)
+      .await
+      .unwrap_err()
+      .kind(),
+    io::ErrorKind::PermissionDenied
+  );
+  assert_eq!(
+    scandir(PermissionDeniedBehavior::SkipEmpty).await.unwrap(),
+    DirectoryListing(vec![])
+  );
+  assert_eq!(
+    scandir(PermissionDeniedBehavior::SkipWithWarning)
+      .await
+      .unwrap(),
+    DirectoryListing(vec![])
+  );
+
+  // Expansion proceeds over the rest of the tree.
+  let globs = PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let posix_fs = Arc::new(
+    new_posixfs(dir.path()).with_permission_denied_behavior(PermissionDeniedBehavior::SkipEmpty),
+  );
+  let paths = posix_fs
+    .expand_globs(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    paths,
+    vec![PathBuf::from("locked"), PathBuf::from("visible")]
+  );
+
+  std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+}
+
+#[tokio::test]
+async fn scandir_single_device() {
+  use std::os::unix::fs::MetadataExt;
+
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("local")).unwrap();
+  make_file(&dir.path().join("local").join("file"), &[], 0o600);
+  // A symlink-oblivious PosixFS lists the destination of the symlink, which is on another device.
+  let other_device = Path::new("/dev");
+  std::os::unix::fs::symlink(other_device, dir.path().join("mnt")).unwrap();
+  if std::fs::metadata(other_device).unwrap().dev() == std::fs::metadata(dir.path()).unwrap().dev()
+  {
+    // The temporary directory is on the same device, so there is nothing to test.
+    return;
+  }
+
+  let posix_fs = new_posixfs_symlink_oblivious(dir.path());
+  assert!(!posix_fs
+    .scandir(Dir(PathBuf::from("mnt")))
+    .await
+    .unwrap()
+    .0
+    .is_empty());
+
+  let posix_fs = Arc::new(
+    new_posixfs_symlink_oblivious(dir.path())
+      .with_single_device()
+      .unwrap(),
+  );
+  assert_eq!(
+    posix_fs.scandir(Dir(PathBuf::from("mnt"))).await.unwrap(),
+    DirectoryListing(vec![])
+  );
+  assert_eq!(
+    posix_fs
+      .scandir(Dir(PathBuf::from("local")))
+      .await
+      .unwrap()
+      .0
+      .len(),
+    1
+  );
+
+  let globs = PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let paths = posix_fs
+    .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    paths,
+    vec![
+      PathBuf::from("local"),
+      PathBuf::from("local/file"),
+      PathBuf::from("mnt"),
+    ]
+  );
+}
+
+// NB: Not a `tokio::test`, because the blocking variants must not be called from within an async
+// context.
+#[test]
+fn blocking_variants() {
+  let executor = task_executor::Executor::new_owned(1, 2, || ()).unwrap();
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("a")).unwrap();
+  make_file(&dir.path().join("a/b.rs"), &[], 0o600);
+  // Larger than a single chunk.
+  let content = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+  make_file(&dir.path().join("c.rs"), &content, 0o600);
+  let posix_fs = Arc::new(
+    PosixFS::new(
+      dir.path(),
+      GitignoreStyleExcludes::empty(),
+      executor.clone(),
+    )
+    .unwrap(),
+  );
+
+  let root = Dir(PathBuf::new());
+  assert_eq!(
+    posix_fs.scandir_blocking(root.clone()).unwrap(),
+    executor.block_on(posix_fs.scandir(root)).unwrap()
+  );
+
+  let file = File {
+    path: PathBuf::from("c.rs"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  let chunks = executor
+    .block_on(
+      posix_fs
+        .read_file_chunks(&file, 1024)
+        .try_collect::<Vec<_>>(),
+    )
+    .unwrap();
+  assert_eq!(posix_fs.read_file_blocking(&file).unwrap(), chunks.concat());
+  assert_eq!(posix_fs.read_file_blocking(&file).unwrap(), content);
+
+  let path_globs = PathGlobs::new(
+    vec!["**/*.rs".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let expanded = posix_fs
+    .expand_globs_blocking(&executor, path_globs.clone(), SymlinkBehavior::Aware, None)
+    .unwrap();
+  assert_eq!(expanded.len(), 2);
+  assert_eq!(
+    expanded,
+    executor
+      .block_on(posix_fs.expand_globs(path_globs, SymlinkBehavior::Aware, None))
+      .unwrap()
+  );
+}
+
+#[tokio::test]
+async fn scandir_stream() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("subdir")).unwrap();
+  // More entries than the stream buffers.
+  for i in 0..300 {
+    make_file(&dir.path().join(format!("file{i}")), &[], 0o600);
+  }
+  std::os::unix::fs::symlink("file0", dir.path().join("link")).unwrap();
+  let posix_fs = PosixFS::new(
+    dir.path(),
+    GitignoreStyleExcludes::create(vec!["/file1".to_owned()]).unwrap(),
+    task_executor::Executor::new(),
+  )
+  .unwrap();
+
+  let mut streamed = posix_fs
+    .scandir_stream(&Dir(PathBuf::new()))
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+  // The stream is unordered.
+  streamed.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+  let listing = posix_fs.scandir(Dir(PathBuf::new())).await.unwrap();
+  assert_eq!(streamed, listing.0);
+  assert_eq!(streamed.len(), 301);
+
+  let err = posix_fs
+    .scandir_stream(&Dir(PathBuf::from("missing")))
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap_err();
+  assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn scandir_missing() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let posix_fs = new_posixfs(dir.path());
+  posix_fs
+    .scandir(Dir(PathBuf::from("no_marmosets_here")))
+    .await
+    .expect_err("Want error");
+}
+
+#[tokio::test]
+async fn stats_for_paths() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let root_path = dir.path();
+
+  // File tree:
+  // dir
+  // dir/recursive_symlink -> ../symlink -> executable_file
+  // dir_symlink -> dir
+  // executable_file
+  // regular_file
+  // symlink -> executable_file
+  // symlink_to_nothing -> doesnotexist
+
+  make_file(&root_path.join("executable_file"), &[], 0o700);
+  make_file(&root_path.join("regular_file"), &[], 0o600);
+  std::fs::create_dir(root_path.join("dir")).unwrap();
+  std::os::unix::fs::symlink("executable_file", root_path.join("symlink")).unwrap();
+  std::os::unix::fs::symlink(
+    "../symlink",
+    root_path.join("dir").join("recursive_symlink"),
+  )
+  .unwrap();
+  std::os::unix::fs::symlink("dir", root_path.join("dir_symlink")).unwrap();
+  std::os::unix::fs::symlink("doesnotexist", root_path.join("symlink_to_nothing")).unwrap();
+
+  let posix_fs = Arc::new(new_posixfs(root_path));
+  let path_stats = vec![
+    PathBuf::from("executable_file"),
+    PathBuf::from("regular_file"),
+    PathBuf::from("dir"),
+    PathBuf::from("symlink"),
+    PathBuf::from("dir").join("recursive_symlink"),
+    PathBuf::from("dir_symlink"),
+    PathBuf::from("symlink_to_nothing"),
+    PathBuf::from("doesnotexist"),
+  ]
+  .into_iter()
+  .map(|p| posix_fs.stat_sync(&p).unwrap())
+  .collect::<Vec<_>>();
+  let v: Vec<Option<Stat>> = vec![
+    Some(Stat::File(File {
+      path: PathBuf::from("executable_file"),
+      is_executable: true,
+      xattrs: BTreeMap::new(),
+    })),
+    Some(Stat::File(File {
+      path: PathBuf::from("regular_file"),
+      is_executable: false,
+      xattrs: BTreeMap::new(),
+    })),
+    Some(Stat::Dir(Dir(PathBuf::from("dir")))),
+    Some(Stat::Link(Link {
+      path: PathBuf::from("symlink"),
+      target: PathBuf::from("executable_file"),
+    })),
+    Some(Stat::Link(Link {
+      path: PathBuf::from("recursive_symlink"),
+      target: PathBuf::from("../symlink"),
+    })),
+    Some(Stat::Link(Link {
+      path: PathBuf::from("dir_symlink"),
+      target: PathBuf::from("dir"),
+    })),
+    Some(Stat::Link(Link {
+      path: PathBuf::from("symlink_to_nothing"),
+      target: PathBuf::from("doesnotexist"),
+    })),
+    None,
+  ];
+  assert_eq!(v, path_stats);
+}
+
+#[tokio::test]
+async fn memfs_expand_basic() {
+  // Create two files, with the effect that there is a nested directory for the longer path.
+  let p1 = PathBuf::from("some/file");
+  let p2 = PathBuf::from("some/other");
+  let p3 = p2.join("file");
+
+  let fs = DigestTrie::from_unique_paths(
+    vec![
+      TypedPath::File {
+        path: &p1,
+        is_executable: false,
+      },
+      TypedPath::File {
+        path: &p3,
+        is_executable: false,
+      },
+    ],
+    &vec![(p1.clone(), EMPTY_DIGEST), (p3.clone(), EMPTY_DIGEST)]
+      .into_iter()
+      .collect(),
+  )
+  .unwrap();
+  let globs = PathGlobs::new(
+    vec!["some/*".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
   .unwrap();
 
   assert_eq!(
@@ -353,6 +1143,7 @@ async fn memfs_expand_basic() {
         File {
           path: p1,
           is_executable: false,
+          xattrs: BTreeMap::new(),
         },
       ),
       PathStat::dir(p2.clone(), Dir(p2)),
@@ -360,15 +1151,1728 @@ async fn memfs_expand_basic() {
   );
 }
 
-async fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
-  let fs = new_posixfs(path);
-  let stats = fs.scandir(Dir(PathBuf::from("."))).await.unwrap();
-  assert_eq!(stats.0.len(), 1);
-  match stats.0.get(0).unwrap() {
-    &super::Stat::File(File {
-      is_executable: got, ..
-    }) => assert_eq!(want_is_executable, got),
-    other => panic!("Expected file, got {other:?}"),
+#[tokio::test]
+async fn expand_no_includes() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("a.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  for strict_match_behavior in [
+    StrictGlobMatching::Ignore,
+    StrictGlobMatching::Warn("the test".to_owned()),
+    StrictGlobMatching::Error("the test".to_owned()),
+  ] {
+    for globs in [vec![], vec!["!a.rs".to_owned()]] {
+      let path_globs = PathGlobs::new(
+        globs,
+        strict_match_behavior.clone(),
+        GlobExpansionConjunction::AllMatch,
+      )
+      .parse()
+      .unwrap();
+      assert_eq!(
+        posix_fs
+          .expand_globs(path_globs, SymlinkBehavior::Aware, None)
+          .await
+          .unwrap(),
+        vec![],
+        "{strict_match_behavior:?}"
+      );
+    }
+  }
+}
+
+#[tokio::test]
+async fn any_match() {
+  let dir = tempfile::TempDir::new().unwrap();
+  for i in 0..10 {
+    for j in 0..10 {
+      let subdir = dir
+        .path()
+        .join(format!("dir{i}"))
+        .join(format!("subdir{j}"));
+      std::fs::create_dir_all(&subdir).unwrap();
+      make_file(&subdir.join("lib.rs"), &[], 0o600);
+      make_file(&subdir.join("BUILD"), &[], 0o600);
+    }
+  }
+  make_file(&dir.path().join("top.rs"), &[], 0o600);
+  let vfs = RecordingFS {
+    posix_fs: Arc::new(new_posixfs(dir.path())),
+    scanned: Arc::default(),
+  };
+
+  let globs = |glob: &str| {
+    PathGlobs::new(
+      vec![glob.to_owned()],
+      // Strict matching is ignored by `any_match`.
+      StrictGlobMatching::Error("".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap()
+  };
+
+  // The match in the root stops the expansion before the subdirectories are listed.
+  assert!(vfs
+    .any_match(globs("**/*.rs"), SymlinkBehavior::Aware)
+    .await
+    .unwrap());
+  let scanned = std::mem::take(&mut *vfs.scanned.lock());
+  assert!(
+    scanned.iter().all(|dir| dir.components().count() < 2),
+    "Unexpectedly scanned: {scanned:?}"
+  );
+
+  // Whereas without a match, every directory is listed.
+  assert!(!vfs
+    .any_match(globs("**/*.java"), SymlinkBehavior::Aware)
+    .await
+    .unwrap());
+  let scanned = vfs.scanned.lock().clone();
+  assert!(
+    scanned.contains(&PathBuf::from("dir9/subdir9")),
+    "{scanned:?}"
+  );
+}
+
+#[tokio::test]
+async fn expand_single_required() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("main.rs"), &[], 0o600);
+  make_file(&dir.path().join("lib.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  let expand = |glob: &str| {
+    let globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      // Strict matching is ignored by `expand_single_required`.
+      StrictGlobMatching::Error("".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    posix_fs.expand_single_required(globs, SymlinkBehavior::Aware)
+  };
+
+  assert_eq!(
+    expand("main.*").await.unwrap(),
+    PathStat::file(
+      PathBuf::from("main.rs"),
+      File {
+        path: PathBuf::from("main.rs"),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      },
+    )
+  );
+
+  let err = expand("*.java").await.unwrap_err().to_string();
+  assert!(err.contains("but none did"), "{err}");
+
+  let err = expand("*.rs").await.unwrap_err().to_string();
+  assert!(err.contains("but 2 did"), "{err}");
+  assert!(
+    err.contains("\"lib.rs\"") && err.contains("\"main.rs\""),
+    "{err}"
+  );
+}
+
+#[tokio::test]
+async fn expand_children_of() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a[1]/sub/deeper")).unwrap();
+  make_file(&dir.path().join("top.txt"), &[], 0o600);
+  make_file(&dir.path().join("a[1]/file.txt"), &[], 0o600);
+  make_file(&dir.path().join("a[1]/sub/nested.txt"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  let children = |dir: &str| {
+    let globs = PathGlobs::children_of(PathBuf::from(dir)).parse().unwrap();
+    let posix_fs = &posix_fs;
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|ps| ps.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  assert_eq!(
+    children("a[1]").await,
+    vec![PathBuf::from("a[1]/file.txt"), PathBuf::from("a[1]/sub")]
+  );
+  assert_eq!(
+    children("").await,
+    vec![PathBuf::from("a[1]"), PathBuf::from("top.txt")]
+  );
+}
+
+#[tokio::test]
+async fn expand_max_depth() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let mut path = PathBuf::new();
+  let mut expected = vec![];
+  for depth in 0..=5 {
+    if depth > 0 {
+      path.push(format!("d{depth}"));
+      std::fs::create_dir(dir.path().join(&path)).unwrap();
+    }
+    let file = path.join("a.rs");
+    make_file(&dir.path().join(&file), &[], 0o600);
+    if depth <= 2 {
+      expected.push(file);
+    }
+  }
+  let posix_fs = new_posixfs(dir.path());
+
+  let globs = PathGlobs::new(
+    vec!["**/*.rs".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .with_max_depth(2)
+  .parse()
+  .unwrap();
+  let matched = posix_fs
+    .expand_globs(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .filter_map(|ps| match ps {
+      PathStat::File { path, .. } => Some(path),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(matched, expected);
+}
+
+#[tokio::test]
+async fn expand_recursive_shorthand() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("src/nested/deeper")).unwrap();
+  std::fs::create_dir_all(dir.path().join("src/excluded")).unwrap();
+  make_file(&dir.path().join("src/a.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/nested/b.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/nested/deeper/c.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/excluded/d.rs"), &[], 0o600);
+  make_file(&dir.path().join("top.rs"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |globs: Vec<&str>| {
+    let posix_fs = posix_fs.clone();
+    let globs = PathGlobs::new(
+      globs.into_iter().map(|g| g.to_owned()).collect(),
+      StrictGlobMatching::Error("tests".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .map_err(|e| e.to_string())
+    }
+  };
+
+  assert_eq!(
+    expand(vec!["src::"]).await.unwrap(),
+    expand(vec!["src/**"]).await.unwrap()
+  );
+  assert_eq!(
+    expand(vec!["::"]).await.unwrap(),
+    expand(vec!["**"]).await.unwrap()
+  );
+  // The shorthand composes with excludes, which may also use it.
+  assert_eq!(
+    expand(vec!["src::", "!src/excluded::"]).await.unwrap(),
+    expand(vec!["src/**", "!src/excluded/**"]).await.unwrap()
+  );
+  // Strict matching reports the glob as written.
+  assert_eq!(
+    expand(vec!["missing::"]).await.unwrap_err(),
+    "Unmatched glob from tests: \"missing::\", missing directory: \"missing\""
+  );
+
+  let candidates = vec![
+    PathBuf::from("src/a.rs"),
+    PathBuf::from("src/excluded/d.rs"),
+    PathBuf::from("top.rs"),
+  ];
+  assert_eq!(
+    PathGlobs::new(
+      vec!["src::".to_owned(), "!src/excluded::".to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .matches(&candidates)
+    .unwrap(),
+    vec![PathBuf::from("src/a.rs")]
+  );
+}
+
+#[tokio::test]
+async fn expand_normalized_symbolic_paths() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+  make_file(&dir.path().join("a/b/c.rs"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |glob: &str| {
+    let posix_fs = posix_fs.clone();
+    let globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|ps| ps.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  for glob in [
+    "a/./b/*.rs",
+    "./a/b/*.rs",
+    "a//b/*.rs",
+    "a/b/./*.rs",
+    "a/./**/*.rs",
+  ] {
+    let paths = expand(glob).await;
+    assert_eq!(paths, vec![PathBuf::from("a/b/c.rs")], "{glob}");
+    assert_eq!(paths[0].to_str(), Some("a/b/c.rs"), "{glob}");
+  }
+  // Parent traversal is intentionally preserved in the symbolic path.
+  assert_eq!(
+    expand("a/b/./../b/*.rs").await,
+    vec![PathBuf::from("a/b/../b/c.rs")]
+  );
+}
+
+#[tokio::test]
+async fn expand_sorted_by_path() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+  std::fs::create_dir_all(dir.path().join("b")).unwrap();
+  for path in [
+    "z.rs",
+    "a/y.rs",
+    "a/b/x.rs",
+    "a/b/c/w.rs",
+    "a/b.rs",
+    "b/a.rs",
+    "B.rs",
+  ] {
+    make_file(&dir.path().join(path), &[], 0o600);
+  }
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  // The globs (and the depth of their matches) discover paths in an order which is not sorted.
+  let path_globs = PathGlobs::new(
+    vec![
+      "z.rs".to_owned(),
+      "b/*.rs".to_owned(),
+      "a/**/*.rs".to_owned(),
+      "*.rs".to_owned(),
+    ],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let paths = posix_fs
+    .expand_globs(path_globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  assert_eq!(paths.len(), 7);
+  assert!(
+    paths.windows(2).all(|pair| pair[0] < pair[1]),
+    "Not strictly sorted: {paths:?}"
+  );
+}
+
+#[tokio::test]
+async fn expand_relative_excludes() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/skip")).unwrap();
+  std::fs::create_dir_all(dir.path().join("a/sub/skip")).unwrap();
+  std::fs::create_dir_all(dir.path().join("b/skip")).unwrap();
+  make_file(&dir.path().join("a/keep.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/skip/x.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/sub/skip/y.txt"), &[], 0o600);
+  make_file(&dir.path().join("b/keep.txt"), &[], 0o600);
+  make_file(&dir.path().join("b/skip/z.txt"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let path_globs = PathGlobs::new(
+    vec!["*/**".to_owned(), "b/skip/*".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_relative_excludes("*/**", vec!["skip/".to_owned()])
+  .unwrap();
+  let paths = posix_fs
+    .expand_globs(path_globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  // The `skip` directories within each matched directory are excluded, but only from the matches
+  // of the glob that the relative exclude was declared for.
+  assert_eq!(
+    paths,
+    vec![
+      PathBuf::from("a/keep.txt"),
+      PathBuf::from("a/sub"),
+      PathBuf::from("b/keep.txt"),
+      PathBuf::from("b/skip/z.txt"),
+    ]
+  );
+
+  assert!(PathGlobs::new(
+    vec!["*/**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_relative_excludes("b/**", vec!["skip/".to_owned()])
+  .is_err());
+}
+
+#[tokio::test]
+async fn expand_fixed_depth() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+  std::fs::create_dir_all(dir.path().join("d/e")).unwrap();
+  make_file(&dir.path().join("zero.rs"), &[], 0o600);
+  make_file(&dir.path().join("a/one.rs"), &[], 0o600);
+  make_file(&dir.path().join("a/b/two.rs"), &[], 0o600);
+  make_file(&dir.path().join("a/b/two.py"), &[], 0o600);
+  make_file(&dir.path().join("a/b/c/three.rs"), &[], 0o600);
+  make_file(&dir.path().join("d/e/two.rs"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |glob: &str| {
+    let posix_fs = posix_fs.clone();
+    let globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|ps| ps.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  // Only files exactly two directories deep are matched.
+  assert_eq!(
+    expand("*/*/*.rs").await,
+    vec![PathBuf::from("a/b/two.rs"), PathBuf::from("d/e/two.rs")]
+  );
+  assert_eq!(expand("*/*.rs").await, vec![PathBuf::from("a/one.rs")]);
+  assert_eq!(
+    expand("*/*/*/*.rs").await,
+    vec![PathBuf::from("a/b/c/three.rs")]
+  );
+  // Whereas `**` matches at any depth.
+  assert_eq!(
+    expand("**/*.rs").await,
+    vec![
+      PathBuf::from("a/b/c/three.rs"),
+      PathBuf::from("a/b/two.rs"),
+      PathBuf::from("a/one.rs"),
+      PathBuf::from("d/e/two.rs"),
+      PathBuf::from("zero.rs"),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn expand_traversed_directories() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("src/empty")).unwrap();
+  std::fs::create_dir_all(dir.path().join("src/nested")).unwrap();
+  make_file(&dir.path().join("src/a.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/nested/b.py"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |glob: &str, traversed_directories: bool| {
+    let posix_fs = posix_fs.clone();
+    let mut globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Error("tests".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    );
+    if traversed_directories {
+      globs = globs.with_traversed_directories();
+    }
+    let globs = globs.parse().unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .map(|path_stats| {
+          path_stats
+            .into_iter()
+            .map(|ps| match ps {
+              PathStat::Dir { path, .. } => format!("{}/", path.display()),
+              ps => ps.path().display().to_string(),
+            })
+            .collect::<Vec<_>>()
+        })
+        .map_err(|e| e.to_string())
+    }
+  };
+
+  assert_eq!(
+    expand("src/**/*.rs", false).await.unwrap(),
+    vec!["src/a.rs"]
+  );
+  assert_eq!(
+    expand("src/**/*.rs", true).await.unwrap(),
+    vec!["src/", "src/a.rs", "src/empty/", "src/nested/"]
+  );
+  // Traversed directories are not matches for the purposes of strict matching.
+  assert_eq!(
+    expand("src/**/*.go", true).await.unwrap_err(),
+    "Unmatched glob from tests: \"src/**/*.go\""
+  );
+}
+
+#[tokio::test]
+async fn expand_expected_kind() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("pkg")).unwrap();
+  make_file(&dir.path().join("pkg/lib.rs"), &[], 0o600);
+  make_file(&dir.path().join("README"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+  let expand = |strict_match_behavior, glob: &str, expected_kind| {
+    let path_globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      strict_match_behavior,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap()
+    .with_expected_kind(glob, expected_kind)
+    .unwrap();
+    posix_fs.expand_globs(path_globs, SymlinkBehavior::Aware, None)
+  };
+  let strict = || StrictGlobMatching::Error("the test".to_owned());
+
+  // Globs which match the expected kind of path succeed.
+  for (glob, expected_kind) in [
+    ("pkg", StatKind::Dir),
+    ("pkg/*", StatKind::File),
+    ("*", StatKind::Any),
+  ] {
+    expand(strict(), glob, expected_kind).await.unwrap();
+  }
+
+  // A directory-expected glob which matches only a file is an error which names the mismatch.
+  let err = expand(strict(), "README", StatKind::Dir).await.unwrap_err();
+  assert!(err.to_string().contains("from the test"), "{err}");
+  assert!(
+    err
+      .to_string()
+      .contains("\"README\" was expected to match directories"),
+    "{err}"
+  );
+
+  // And vice versa.
+  let err = expand(strict(), "pkg", StatKind::File).await.unwrap_err();
+  assert!(
+    err
+      .to_string()
+      .contains("\"pkg\" was expected to match files"),
+    "{err}"
+  );
+
+  // The paths which a glob actually expanded to are checked, even when they differ from its
+  // spelling, as for the recursive shorthand.
+  expand(strict(), "pkg::", StatKind::File).await.unwrap();
+  let err = expand(strict(), "pkg::", StatKind::Dir).await.unwrap_err();
+  assert!(
+    err
+      .to_string()
+      .contains("\"pkg::\" was expected to match directories but matched: [\"pkg/lib.rs\"]"),
+    "{err}"
+  );
+
+  // The expectation is only checked under strict matching.
+  assert_eq!(
+    expand(StrictGlobMatching::Ignore, "README", StatKind::Dir)
+      .await
+      .unwrap()
+      .len(),
+    1
+  );
+
+  // Only include globs may be annotated.
+  assert!(PathGlobs::new(
+    vec!["pkg".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_expected_kind("README", StatKind::Dir)
+  .is_err());
+}
+
+#[tokio::test]
+async fn path_globs_redundant_includes() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("src/b")).unwrap();
+  make_file(&dir.path().join("src/a.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/b/c.rs"), &[], 0o600);
+  make_file(&dir.path().join("README"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+  let path_globs = |globs: &[&str]| {
+    PathGlobs::new(
+      globs.iter().map(|g| g.to_string()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+  };
+
+  assert_eq!(
+    path_globs(&["src/**", "src/a.rs", "README"])
+      .redundant_includes(&posix_fs)
+      .await
+      .unwrap(),
+    vec!["src/a.rs".to_owned()]
+  );
+  // An include which is only partially covered by the others is not redundant.
+  assert_eq!(
+    path_globs(&["src/*.rs", "src/**/c.rs"])
+      .redundant_includes(&posix_fs)
+      .await
+      .unwrap(),
+    Vec::<String>::new()
+  );
+  // Excludes apply to each include: once `src/b` is excluded, `src/**/c.rs` matches nothing.
+  assert_eq!(
+    path_globs(&["src/*.rs", "src/**/c.rs", "!src/b"])
+      .redundant_includes(&posix_fs)
+      .await
+      .unwrap(),
+    vec!["src/**/c.rs".to_owned()]
+  );
+}
+
+#[tokio::test]
+async fn expand_max_matches() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let file_count = 5;
+  for i in 0..file_count {
+    make_file(&dir.path().join(format!("{i}.rs")), &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+  let expand = |globs: Vec<&str>, max_matches| {
+    let path_globs = PathGlobs::new(
+      globs.into_iter().map(|g| g.to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .with_max_matches(max_matches)
+    .parse()
+    .unwrap();
+    posix_fs.expand_globs(path_globs, SymlinkBehavior::Aware, None)
+  };
+
+  // Matching exactly the maximum succeeds, even if paths are matched by more than one glob.
+  assert_eq!(
+    expand(vec!["*"], file_count).await.unwrap().len(),
+    file_count
+  );
+  assert_eq!(
+    expand(vec!["*", "*.rs"], file_count).await.unwrap().len(),
+    file_count
+  );
+
+  // But matching more fails, naming the globs.
+  let err = expand(vec!["**/*.rs"], file_count - 1).await.unwrap_err();
+  assert!(
+    err.to_string().contains(&format!(
+      "Globs [\"**/*.rs\"] matched more than the maximum of {} paths",
+      file_count - 1
+    )),
+    "{err}"
+  );
+}
+
+#[tokio::test]
+async fn expand_allowed_extensions() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("src")).unwrap();
+  for file in [
+    "src/lib.rs",
+    "src/main.rs",
+    "src/native.so",
+    "src/notes.txt",
+    "src/BUILD",
+  ] {
+    make_file(&dir.path().join(file), &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+  let allowed: HashSet<String> = vec!["rs".to_owned()].into_iter().collect();
+  let path_globs = |strict_match_behavior, allow_no_extension| {
+    PathGlobs::new(
+      vec!["src/*".to_owned()],
+      strict_match_behavior,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .with_allowed_extensions(allowed.clone(), allow_no_extension)
+  };
+  let expand = |path_globs: PathGlobs| {
+    posix_fs.expand_globs(path_globs.parse().unwrap(), SymlinkBehavior::Aware, None)
+  };
+  let paths = |path_stats: Vec<PathStat>| {
+    path_stats
+      .into_iter()
+      .map(|ps| ps.path().to_owned())
+      .collect::<Vec<_>>()
+  };
+
+  // Only the `.rs` files pass.
+  assert_eq!(
+    paths(
+      expand(path_globs(StrictGlobMatching::Ignore, false))
+        .await
+        .unwrap()
+    ),
+    vec![PathBuf::from("src/lib.rs"), PathBuf::from("src/main.rs")]
+  );
+
+  // Files without an extension may optionally be allowed.
+  assert_eq!(
+    paths(
+      expand(path_globs(StrictGlobMatching::Ignore, true))
+        .await
+        .unwrap()
+    ),
+    vec![
+      PathBuf::from("src/BUILD"),
+      PathBuf::from("src/lib.rs"),
+      PathBuf::from("src/main.rs")
+    ]
+  );
+
+  // Under strict matching, the other files are reported.
+  let err = expand(path_globs(
+    StrictGlobMatching::Error("the test".to_owned()),
+    false,
+  ))
+  .await
+  .unwrap_err();
+  assert!(err.to_string().contains("from the test"), "{err}");
+  assert!(err.to_string().contains("src/native.so"), "{err}");
+  assert!(err.to_string().contains("src/notes.txt"), "{err}");
+  assert!(!err.to_string().contains("src/lib.rs"), "{err}");
+
+  // Offline matching agrees.
+  let candidates = ["src/lib.rs", "src/native.so", "src/BUILD"]
+    .iter()
+    .map(PathBuf::from)
+    .collect::<Vec<_>>();
+  assert_eq!(
+    path_globs(StrictGlobMatching::Ignore, false)
+      .matches(&candidates)
+      .unwrap(),
+    vec![PathBuf::from("src/lib.rs")]
+  );
+
+  // As do the other methods of expansion.
+  let (lenient, errors) = posix_fs
+    .expand_lenient(
+      path_globs(StrictGlobMatching::Ignore, false)
+        .parse()
+        .unwrap(),
+      SymlinkBehavior::Aware,
+    )
+    .await
+    .unwrap();
+  assert!(errors.is_empty());
+  assert_eq!(
+    paths(lenient),
+    vec![PathBuf::from("src/lib.rs"), PathBuf::from("src/main.rs")]
+  );
+  let attributed = posix_fs
+    .expand_attributed(
+      path_globs(StrictGlobMatching::Ignore, false)
+        .parse()
+        .unwrap(),
+      SymlinkBehavior::Aware,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    paths(attributed.into_iter().map(|(ps, _)| ps).collect()),
+    vec![PathBuf::from("src/lib.rs"), PathBuf::from("src/main.rs")]
+  );
+  let any_match = |glob: &str| {
+    posix_fs.any_match(
+      PathGlobs::new(
+        vec![glob.to_owned()],
+        StrictGlobMatching::Ignore,
+        GlobExpansionConjunction::AllMatch,
+      )
+      .with_allowed_extensions(allowed.clone(), false)
+      .parse()
+      .unwrap(),
+      SymlinkBehavior::Aware,
+    )
+  };
+  assert!(any_match("src/*").await.unwrap());
+  assert!(!any_match("src/*.so").await.unwrap());
+  assert!(!any_match("src/BUILD").await.unwrap());
+}
+
+#[tokio::test]
+async fn expand_grouped_by_dir() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("b/c")).unwrap();
+  std::fs::create_dir(dir.path().join("a")).unwrap();
+  for file in [
+    "top.rs",
+    "b/c/deep.rs",
+    "b/two.rs",
+    "b/one.rs",
+    "a/only.rs",
+    "a/skip.txt",
+  ] {
+    make_file(&dir.path().join(file), &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+
+  let globs = PathGlobs::new(
+    vec!["**/*.rs".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let grouped = posix_fs
+    .expand_grouped_by_dir(globs, SymlinkBehavior::Aware)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|(dir, path_stats)| {
+      (
+        dir,
+        path_stats
+          .into_iter()
+          .map(|ps| ps.path().to_owned())
+          .collect::<Vec<_>>(),
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    grouped,
+    vec![
+      (PathBuf::from("a"), vec![PathBuf::from("a/only.rs")]),
+      (PathBuf::from("b/c"), vec![PathBuf::from("b/c/deep.rs")]),
+      (
+        PathBuf::from("b"),
+        vec![PathBuf::from("b/one.rs"), PathBuf::from("b/two.rs")]
+      ),
+      (PathBuf::from(""), vec![PathBuf::from("top.rs")]),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn expand_scandir_profile() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+  std::fs::create_dir(dir.path().join("c")).unwrap();
+  for file in ["a/one.rs", "a/b/two.rs", "c/three.rs"] {
+    make_file(&dir.path().join(file), &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+
+  let profile = ScandirProfile::default();
+  let globs = PathGlobs::new(
+    vec!["*/*.rs".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_scandir_profile(profile.clone());
+  posix_fs
+    .expand_globs(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap();
+
+  // One entry per listed directory: the root, and then each of its children.
+  let mut scanned = profile
+    .lock()
+    .iter()
+    .map(|(dir, _)| dir.clone())
+    .collect::<Vec<_>>();
+  scanned.sort();
+  assert_eq!(
+    scanned,
+    vec![PathBuf::new(), PathBuf::from("a"), PathBuf::from("c")]
+  );
+}
+
+#[tokio::test]
+async fn expand_with_dependencies() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b/empty")).unwrap();
+  std::fs::create_dir(dir.path().join("c")).unwrap();
+  for file in ["a/one.rs", "a/b/two.rs", "c/three.rs"] {
+    make_file(&dir.path().join(file), &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+
+  let profile = ScandirProfile::default();
+  let globs = PathGlobs::new(
+    vec!["a/**/*.rs".to_owned()],
+    StrictGlobMatching::Error("tests".to_owned()),
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_scandir_profile(profile.clone());
+  let (path_stats, dependencies) = posix_fs
+    .expand_with_dependencies(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap();
+
+  assert_eq!(
+    path_stats
+      .iter()
+      .map(|ps| ps.path().to_owned())
+      .collect::<Vec<_>>(),
+    vec![PathBuf::from("a/b/two.rs"), PathBuf::from("a/one.rs")]
+  );
+  // Every directory which was listed (including those which contained no matches), but not
+  // directories which the glob could not match below.
+  assert_eq!(
+    dependencies,
+    vec![
+      PathBuf::new(),
+      PathBuf::from("a"),
+      PathBuf::from("a/b"),
+      PathBuf::from("a/b/empty")
+    ]
+  );
+  // A requested profile still receives an entry per listing.
+  assert!(profile.lock().len() >= dependencies.len());
+}
+
+#[tokio::test]
+async fn expand_attributed() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("a")).unwrap();
+  make_file(&dir.path().join("a/foo.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/bar.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/foo.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  let globs = PathGlobs::new(
+    vec!["a/*.txt".to_owned(), "a/foo.*".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let attributed = posix_fs
+    .expand_attributed(globs, SymlinkBehavior::Aware)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|(ps, sources)| (ps.path().to_owned(), sources))
+    .collect::<Vec<_>>();
+  let source = |filespec: &str| GlobParsedSource(filespec.to_owned());
+
+  assert_eq!(
+    attributed,
+    vec![
+      (PathBuf::from("a/bar.txt"), vec![source("a/*.txt")]),
+      (PathBuf::from("a/foo.rs"), vec![source("a/foo.*")]),
+      (
+        PathBuf::from("a/foo.txt"),
+        vec![source("a/*.txt"), source("a/foo.*")]
+      ),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn expand_overlaps() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("a")).unwrap();
+  make_file(&dir.path().join("a/foo.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/bar.txt"), &[], 0o600);
+  make_file(&dir.path().join("a/foo.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  // NB: `**/bar.txt` is expanded via more than one PathGlob, but is a single source.
+  let globs = PathGlobs::new(
+    vec![
+      "a/*.txt".to_owned(),
+      "a/foo.*".to_owned(),
+      "**/bar.txt".to_owned(),
+    ],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let overlaps = posix_fs
+    .expand_overlaps(globs, SymlinkBehavior::Aware)
+    .await
+    .unwrap();
+  let source = |filespec: &str| GlobParsedSource(filespec.to_owned());
+
+  assert_eq!(
+    overlaps,
+    vec![
+      (
+        PathBuf::from("a/bar.txt"),
+        vec![source("a/*.txt"), source("**/bar.txt")]
+      ),
+      (
+        PathBuf::from("a/foo.txt"),
+        vec![source("a/*.txt"), source("a/foo.*")]
+      ),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn expand_exclude_modes() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("sub")).unwrap();
+  make_file(&dir.path().join("foo.rs"), &[], 0o600);
+  make_file(&dir.path().join("sub/foo.rs"), &[], 0o600);
+  make_file(&dir.path().join("sub/bar.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  let expand = |exclude_mode| {
+    let globs = PathGlobs::new(
+      vec!["**/*.rs".to_owned(), "!foo.rs".to_owned()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .with_exclude_mode(exclude_mode)
+    .parse()
+    .unwrap();
+    let posix_fs = &posix_fs;
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|ps| ps.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  // A gitignore pattern without a slash matches at any depth.
+  assert_eq!(
+    expand(ExcludeMode::Gitignore).await,
+    vec![PathBuf::from("sub/bar.rs")]
+  );
+  // But a glob is relative to the root, exactly as it would be as an include.
+  assert_eq!(
+    expand(ExcludeMode::Glob).await,
+    vec![PathBuf::from("sub/bar.rs"), PathBuf::from("sub/foo.rs")]
+  );
+}
+
+#[tokio::test]
+async fn expand_exclude_modes_strict() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("foo.rs"), &[], 0o600);
+  make_file(&dir.path().join("bar.rs"), &[], 0o600);
+  std::fs::create_dir(dir.path().join("src")).unwrap();
+  make_file(&dir.path().join("src/a.rs"), &[], 0o600);
+  make_file(&dir.path().join("src/b.rs"), &[], 0o600);
+  let posix_fs = new_posixfs(dir.path());
+
+  let expand = |globs: Vec<&str>, exclude_mode| {
+    let globs = PathGlobs::new(
+      globs.into_iter().map(|g| g.to_owned()).collect(),
+      StrictGlobMatching::Error("test".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .with_exclude_mode(exclude_mode)
+    .parse()
+    .unwrap();
+    let posix_fs = &posix_fs;
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .map(|path_stats| {
+          path_stats
+            .into_iter()
+            .map(|ps| ps.path().to_owned())
+            .collect::<Vec<_>>()
+        })
+    }
+  };
+
+  // An include whose only match is excluded is unmatched in either mode.
+  for exclude_mode in [ExcludeMode::Gitignore, ExcludeMode::Glob] {
+    let err = expand(vec!["foo.rs", "!foo.rs"], exclude_mode)
+      .await
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.starts_with("Unmatched glob from test: \"foo.rs\""),
+      "{exclude_mode:?}: {err}"
+    );
+  }
+  // But an include with other matches is not.
+  assert_eq!(
+    expand(vec!["*.rs", "!foo.rs"], ExcludeMode::Glob)
+      .await
+      .unwrap(),
+    vec![PathBuf::from("bar.rs")]
+  );
+  // Including when the include is not a literal glob, such as the recursive shorthand.
+  assert_eq!(
+    expand(vec!["src::", "!src/a.rs"], ExcludeMode::Glob)
+      .await
+      .unwrap(),
+    vec![PathBuf::from("src/b.rs")]
+  );
+}
+
+#[tokio::test]
+async fn offline_matches_agree_with_expand() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let files = vec![
+    PathBuf::from("a.rs"),
+    PathBuf::from("b.txt"),
+    PathBuf::from("src/lib.rs"),
+    PathBuf::from("src/nested/mod.rs"),
+    PathBuf::from("target/out.rs"),
+    PathBuf::from(".hidden.rs"),
+  ];
+  for file in &files {
+    let path = dir.path().join(file);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    make_file(&path, &[], 0o600);
+  }
+  let posix_fs = new_posixfs(dir.path());
+
+  let cases: Vec<Vec<&str>> = vec![
+    vec!["**/*.rs"],
+    vec!["*"],
+    vec!["src/**"],
+    vec!["src/**/*.rs", "!nested"],
+    vec!["**/*.rs", "!target/"],
+    vec!["**", "!*.txt", "!.hidden.rs"],
+  ];
+  for globs in cases {
+    let path_globs = PathGlobs::new(
+      globs.iter().map(|g| (*g).to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    );
+    let mut expanded = posix_fs
+      .expand_globs(
+        path_globs.clone().parse().unwrap(),
+        SymlinkBehavior::Aware,
+        None,
+      )
+      .await
+      .unwrap()
+      .into_iter()
+      .filter_map(|ps| match ps {
+        PathStat::File { path, .. } => Some(path),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    expanded.sort();
+    let mut matched = path_globs.matches(&files).unwrap();
+    matched.sort();
+    assert_eq!(expanded, matched, "for globs {globs:?}");
+  }
+}
+
+#[tokio::test]
+async fn operation_timeout() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let posix_fs = new_posixfs(dir.path()).with_operation_timeout(Duration::from_millis(10));
+
+  // An artificially slow operation times out.
+  let err = posix_fs
+    .with_timeout(|| "Sleeping".to_owned(), async {
+      tokio::time::sleep(Duration::from_secs(10)).await;
+      Ok(())
+    })
+    .await
+    .expect_err("Expected the operation to time out.");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+  assert!(err.to_string().starts_with("Sleeping timed out"), "{err}");
+
+  // But operations which complete in time succeed.
+  let posix_fs = new_posixfs(dir.path()).with_operation_timeout(Duration::from_secs(30));
+  assert_eq!(
+    posix_fs.scandir(Dir(PathBuf::new())).await.unwrap(),
+    DirectoryListing(vec![])
+  );
+}
+
+#[tokio::test]
+async fn root_fd() {
+  let parent = tempfile::TempDir::new().unwrap();
+  let root = parent.path().join("root");
+  std::fs::create_dir_all(root.join("sub")).unwrap();
+  make_file(&root.join("marmosets"), b"marmosets", 0o600);
+  make_file(&root.join("sub/executable"), &[], 0o700);
+  std::os::unix::fs::symlink("marmosets", root.join("sibling")).unwrap();
+
+  let path_fs = new_posixfs(&root);
+  let fd_fs = new_posixfs(&root).with_root_fd().unwrap();
+
+  // The fd-rooted FS observes the same tree as the path-based FS.
+  for dir in ["", "sub"] {
+    assert_eq!(
+      fd_fs.scandir(Dir(PathBuf::from(dir))).await.unwrap(),
+      path_fs.scandir(Dir(PathBuf::from(dir))).await.unwrap(),
+    );
+  }
+  for path in ["marmosets", "sub", "sub/executable", "sibling", "missing"] {
+    assert_eq!(
+      fd_fs.stat_sync(Path::new(path)).unwrap(),
+      path_fs.stat_sync(Path::new(path)).unwrap(),
+      "for {path}"
+    );
+  }
+  let file = File {
+    path: PathBuf::from("marmosets"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  assert_eq!(
+    fd_fs.digest_file(&file).await.unwrap(),
+    path_fs.digest_file(&file).await.unwrap(),
+  );
+  let link = Link {
+    path: PathBuf::from("sibling"),
+    target: PathBuf::new(),
+  };
+  assert_eq!(
+    fd_fs.read_link(&link).await.unwrap(),
+    PathBuf::from("marmosets")
+  );
+
+  // Replacing the root path does not affect the fd-rooted FS.
+  std::fs::rename(&root, parent.path().join("moved")).unwrap();
+  std::fs::create_dir(&root).unwrap();
+  assert_eq!(
+    path_fs.scandir(Dir(PathBuf::new())).await.unwrap(),
+    DirectoryListing(vec![])
+  );
+  assert_eq!(
+    fd_fs
+      .scandir(Dir(PathBuf::new()))
+      .await
+      .unwrap()
+      .0
+      .iter()
+      .map(|s| s.path().to_owned())
+      .collect::<Vec<_>>(),
+    vec![
+      PathBuf::from("marmosets"),
+      PathBuf::from("sibling"),
+      PathBuf::from("sub")
+    ]
+  );
+  let chunks = fd_fs
+    .read_file_chunks(&file, 1024)
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+  assert_eq!(chunks, vec![Bytes::from_static(b"marmosets")]);
+}
+
+#[tokio::test]
+async fn expand_strict_missing_directory() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("empty")).unwrap();
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand_err = |glob: &str| {
+    let posix_fs = posix_fs.clone();
+    let globs = PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Error("tests".to_owned()),
+      GlobExpansionConjunction::AnyMatch,
+    )
+    .parse()
+    .unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Aware, None)
+        .await
+        .unwrap_err()
+        .to_string()
+    }
+  };
+
+  assert_eq!(
+    expand_err("nonexistent/**/*.rs").await,
+    "Unmatched glob from tests: \"nonexistent/**/*.rs\", missing directory: \"nonexistent\""
+  );
+  assert_eq!(
+    expand_err("empty/nonexistent/*.rs").await,
+    "Unmatched glob from tests: \"empty/nonexistent/*.rs\", missing directory: \
+     \"empty/nonexistent\""
+  );
+  assert_eq!(
+    expand_err("empty/**/*.rs").await,
+    "Unmatched glob from tests: \"empty/**/*.rs\""
+  );
+}
+
+#[tokio::test]
+async fn expand_via_symlink() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("direct"), &[], 0o600);
+  std::os::unix::fs::symlink("direct", dir.path().join("linked")).unwrap();
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let globs = PathGlobs::new(
+    vec!["*".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+  let file = File {
+    path: PathBuf::from("direct"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  assert_eq!(
+    posix_fs
+      .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+      .await
+      .unwrap(),
+    vec![
+      PathStat::file(PathBuf::from("direct"), file.clone()),
+      PathStat::File {
+        path: PathBuf::from("linked"),
+        stat: file,
+        via_symlink: true,
+      },
+    ]
+  );
+}
+
+#[tokio::test]
+async fn expand_broken_symlink_errors() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("direct"), &[], 0o600);
+  std::os::unix::fs::symlink("doesnotexist", dir.path().join("dangling")).unwrap();
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |broken_symlink_errors: bool| {
+    let posix_fs = posix_fs.clone();
+    let mut globs = PathGlobs::new(
+      vec!["*".into()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AnyMatch,
+    );
+    if broken_symlink_errors {
+      globs = globs.with_broken_symlink_errors();
+    }
+    let globs = globs.parse().unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+        .await
+    }
+  };
+
+  // By default, the dangling symlink is dropped.
+  assert_eq!(
+    expand(false)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|ps| ps.path().to_owned())
+      .collect::<Vec<_>>(),
+    vec![PathBuf::from("direct")]
+  );
+  assert_eq!(
+    expand(true).await.unwrap_err().to_string(),
+    "Symlink \"dangling\" points to \"doesnotexist\", which does not exist."
+  );
+}
+
+#[tokio::test]
+async fn expand_strict_excludes() {
+  let dir = tempfile::TempDir::new().unwrap();
+  make_file(&dir.path().join("a.rs"), &[], 0o600);
+  make_file(&dir.path().join("b.rs"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+
+  let expand = |strict_excludes: bool| {
+    let posix_fs = posix_fs.clone();
+    let mut globs = PathGlobs::new(
+      vec!["*.rs".into(), "!*.nonexistent".into(), "!b.rs".into()],
+      StrictGlobMatching::Error("test".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    );
+    if strict_excludes {
+      globs = globs.with_strict_excludes();
+    }
+    let globs = globs.parse().unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+        .await
+    }
+  };
+
+  // By default, an exclude which matches nothing is not an error.
+  assert_eq!(
+    expand(false)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|ps| ps.path().to_owned())
+      .collect::<Vec<_>>(),
+    vec![PathBuf::from("a.rs")]
+  );
+  // But with strict excludes, only the exclude which never matched is reported.
+  assert_eq!(
+    expand(true).await.unwrap_err().to_string(),
+    "Unmatched exclude from test: \"*.nonexistent\""
+  );
+}
+
+#[tokio::test]
+async fn expand_lenient() {
+  let dir = tempfile::TempDir::new().unwrap();
+  for subdir in ["readable", "unreadable"] {
+    std::fs::create_dir(dir.path().join(subdir)).unwrap();
+    make_file(&dir.path().join(subdir).join("file.txt"), &[], 0o600);
+  }
+  let vfs = UnreadableDirFS {
+    posix_fs: Arc::new(new_posixfs(dir.path())),
+    unreadable: PathBuf::from("unreadable"),
+  };
+  let globs = || {
+    PathGlobs::new(
+      vec!["**/*.txt".into()],
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AnyMatch,
+    )
+    .parse()
+    .unwrap()
+  };
+
+  // Fails fast by default.
+  assert!(vfs
+    .expand_globs(globs(), SymlinkBehavior::Aware, None)
+    .await
+    .is_err());
+
+  let (path_stats, errors) = vfs
+    .expand_lenient(globs(), SymlinkBehavior::Aware)
+    .await
+    .unwrap();
+  assert_eq!(
+    path_stats,
+    vec![PathStat::file(
+      PathBuf::from("readable/file.txt"),
+      File {
+        path: PathBuf::from("readable/file.txt"),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      },
+    )]
+  );
+  assert_eq!(
+    errors
+      .iter()
+      .map(|(path, e)| (path.as_path(), e.kind()))
+      .collect::<Vec<_>>(),
+    vec![(Path::new("unreadable"), io::ErrorKind::PermissionDenied)]
+  );
+}
+
+#[tokio::test]
+async fn expand_skips_ignored_dirs() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(dir.path().join("vendor")).unwrap();
+  make_file(&dir.path().join("vendor/lib.js"), &[], 0o600);
+  make_file(&dir.path().join("main.js"), &[], 0o600);
+  let vfs = RecordingFS {
+    posix_fs: Arc::new(new_posixfs(dir.path())),
+    scanned: Arc::default(),
+  };
+  let expand = |globs: Vec<&str>| {
+    let globs = PathGlobs::new(
+      globs.into_iter().map(|g| g.to_owned()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap();
+    let vfs = vfs.clone();
+    async move {
+      vfs
+        .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|ps| ps.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  // An ignored directory is dropped from the listing of its parent, so it is never listed...
+  assert_eq!(
+    expand(vec!["**/*.js", "!vendor/"]).await,
+    vec![PathBuf::from("main.js")]
+  );
+  let scanned = vfs.scanned.lock().clone();
+  assert!(!scanned.is_empty());
+  assert!(
+    !scanned.contains(&PathBuf::from("vendor")),
+    "Unexpectedly scanned: {scanned:?}"
+  );
+  // ...including when an include names it literally.
+  assert_eq!(
+    expand(vec!["vendor/*.js", "!vendor/"]).await,
+    Vec::<PathBuf>::new()
+  );
+
+  // But the contents of an ignored directory which is reached via a symlink are only subject to
+  // the excludes for their own paths.
+  std::os::unix::fs::symlink("vendor", dir.path().join("linked")).unwrap();
+  assert_eq!(
+    expand(vec!["**/*.js", "!vendor/"]).await,
+    vec![PathBuf::from("linked/lib.js"), PathBuf::from("main.js")]
+  );
+}
+
+#[tokio::test]
+async fn expand_cancellation() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+  make_file(&dir.path().join("a/b/c/deep.txt"), &[], 0o600);
+  let cancelled = Arc::new(AtomicBool::new(false));
+  let vfs = RecordingFS {
+    posix_fs: Arc::new(new_posixfs(dir.path())),
+    scanned: Arc::default(),
+  };
+  let globs = PathGlobs::new(
+    vec!["**".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+  .with_cancellation(cancelled.clone());
+
+  // Cancel once the first directory has been listed.
+  let expansion = vfs.expand_globs(globs, SymlinkBehavior::Aware, None);
+  let cancel = async {
+    while vfs.scanned.lock().is_empty() {
+      tokio::task::yield_now().await;
+    }
+    cancelled.store(true, Ordering::SeqCst);
+  };
+  let (result, ()) = futures::join!(expansion, cancel);
+
+  let err = result.expect_err("Expected expansion to be cancelled.");
+  assert!(err.to_string().contains("cancelled"), "{err}");
+  assert!(!vfs.scanned.lock().contains(&PathBuf::from("a/b/c")));
+}
+
+#[tokio::test]
+async fn overlayfs() {
+  let generated = tempfile::TempDir::new().unwrap();
+  let sources = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir(generated.path().join("gen")).unwrap();
+  std::fs::create_dir(sources.path().join("gen")).unwrap();
+  std::fs::create_dir(sources.path().join("src")).unwrap();
+  make_file(&generated.path().join("gen/a.rs"), &[], 0o600);
+  make_file(&generated.path().join("shared.rs"), &[], 0o700);
+  make_file(&sources.path().join("gen/b.rs"), &[], 0o600);
+  make_file(&sources.path().join("src/c.rs"), &[], 0o600);
+  make_file(&sources.path().join("shared.rs"), &[], 0o600);
+
+  let overlay_fs = Arc::new(
+    OverlayFS::new(vec![
+      Arc::new(new_posixfs(generated.path())),
+      Arc::new(new_posixfs(sources.path())),
+    ])
+    .unwrap(),
+  );
+
+  // The shadowed file is taken from the first root.
+  assert_eq!(
+    overlay_fs.scandir(Dir(PathBuf::new())).await.unwrap(),
+    DirectoryListing(vec![
+      Stat::Dir(Dir(PathBuf::from("gen"))),
+      Stat::File(File {
+        path: PathBuf::from("shared.rs"),
+        is_executable: true,
+        xattrs: BTreeMap::new(),
+      }),
+      Stat::Dir(Dir(PathBuf::from("src"))),
+    ])
+  );
+  let shared = File {
+    path: PathBuf::from("shared.rs"),
+    is_executable: true,
+    xattrs: BTreeMap::new(),
+  };
+  assert_eq!(
+    overlay_fs.file_path(&shared).await.unwrap(),
+    generated.path().canonicalize().unwrap().join("shared.rs")
+  );
+
+  // Globs expand across the union of the roots.
+  let globs = PathGlobs::new(
+    vec!["**/*.rs".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+  let mut paths = overlay_fs
+    .expand_globs(globs, SymlinkBehavior::Aware, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|ps| ps.path().to_owned())
+    .collect::<Vec<_>>();
+  paths.sort();
+  assert_eq!(
+    paths,
+    vec![
+      PathBuf::from("gen/a.rs"),
+      PathBuf::from("gen/b.rs"),
+      PathBuf::from("shared.rs"),
+      PathBuf::from("src/c.rs"),
+    ]
+  );
+
+  // A directory which is missing from every root is an error.
+  let err = overlay_fs
+    .scandir(Dir(PathBuf::from("missing")))
+    .await
+    .unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+async fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
+  let fs = new_posixfs(path);
+  let stats = fs.scandir(Dir(PathBuf::from("."))).await.unwrap();
+  assert_eq!(stats.0.len(), 1);
+  match stats.0.get(0).unwrap() {
+    &super::Stat::File(File {
+      is_executable: got, ..
+    }) => assert_eq!(want_is_executable, got),
+    other => panic!("Expected file, got {other:?}"),
+  }
+}
+
+///
+/// A PosixFS which fails to list one directory, as if it were not readable. Permissions are not
+/// used for this, because they are not enforced for root.
+///
+#[derive(Clone)]
+struct UnreadableDirFS {
+  posix_fs: Arc<PosixFS>,
+  unreadable: PathBuf,
+}
+
+#[async_trait]
+impl Vfs<io::Error> for UnreadableDirFS {
+  async fn read_link(&self, link: &Link) -> Result<PathBuf, io::Error> {
+    self.posix_fs.read_link(link).await
+  }
+
+  async fn scandir(&self, dir: Dir) -> Result<Arc<DirectoryListing>, io::Error> {
+    if dir.0 == self.unreadable {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Cannot read {:?}", dir.0),
+      ));
+    }
+    Vfs::scandir(&self.posix_fs, dir).await
+  }
+
+  fn is_ignored(&self, stat: &Stat) -> bool {
+    self.posix_fs.is_ignored(stat)
+  }
+
+  fn mk_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+  }
+}
+
+///
+/// A PosixFS which records the directories that are listed.
+///
+#[derive(Clone)]
+struct RecordingFS {
+  posix_fs: Arc<PosixFS>,
+  scanned: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+#[async_trait]
+impl Vfs<io::Error> for RecordingFS {
+  async fn read_link(&self, link: &Link) -> Result<PathBuf, io::Error> {
+    self.posix_fs.read_link(link).await
+  }
+
+  async fn scandir(&self, dir: Dir) -> Result<Arc<DirectoryListing>, io::Error> {
+    self.scanned.lock().push(dir.0.clone());
+    Vfs::scandir(&self.posix_fs, dir).await
+  }
+
+  fn is_ignored(&self, stat: &Stat) -> bool {
+    self.posix_fs.is_ignored(stat)
+  }
+
+  fn mk_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
   }
 }
 
@@ -1,6 +1,10 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
-use crate::RelativePath;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+
+use crate::{FileContent, RelativePath};
 
 #[test]
 fn relative_path_ok() {
@@ -23,3 +27,31 @@ fn relative_path_err() {
 fn relative_path_normalize() {
   assert_eq!(Some("a"), RelativePath::new("a/").unwrap().to_str());
 }
+
+fn file_content(content: &[u8]) -> FileContent {
+  FileContent {
+    path: PathBuf::from("file"),
+    content: Bytes::copy_from_slice(content),
+    is_executable: false,
+  }
+}
+
+#[test]
+fn file_content_is_probably_binary() {
+  assert!(!file_content(b"").is_probably_binary());
+  assert!(!file_content(b"fn main() {}\n").is_probably_binary());
+  assert!(file_content(b"\x7fELF\x02\x01\x00\x00").is_probably_binary());
+  // Only a prefix of the content is checked.
+  let mut late_nul = vec![b'a'; 10_000];
+  late_nul.push(0);
+  assert!(!file_content(&late_nul).is_probably_binary());
+}
+
+#[test]
+fn file_content_as_utf8() {
+  assert_eq!(
+    Some("marmoset 🐒"),
+    file_content("marmoset 🐒".as_bytes()).as_utf8()
+  );
+  assert_eq!(None, file_content(b"marmoset \xff").as_utf8());
+}
@@ -0,0 +1,85 @@
+// Copyright 2023 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A minimal wrapper around the `getxattr` syscall, which is only available on Linux and macOS.
+//! On other platforms, no extended attributes are ever found.
+//!
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+///
+/// Reads the value of the named extended attribute of the given path, following symlinks. Returns
+/// None if the path does not have the attribute, or if the platform or filesystem does not
+/// support extended attributes.
+///
+pub(crate) fn get(path: &Path, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+  let path = CString::new(path.as_os_str().as_bytes())
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let name =
+    CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+  loop {
+    // First determine the size of the value, and then read it. The value may change size between
+    // the two calls, in which case the read fails with ERANGE and we try again.
+    let size = match getxattr(&path, &name, &mut [])? {
+      Some(size) => size,
+      None => return Ok(None),
+    };
+    let mut value = vec![0; size];
+    match getxattr(&path, &name, &mut value) {
+      Ok(Some(size)) => {
+        value.truncate(size);
+        return Ok(Some(value));
+      }
+      Ok(None) => return Ok(None),
+      Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn getxattr(path: &CString, name: &CString, value: &mut [u8]) -> Result<Option<usize>, io::Error> {
+  #[cfg(target_os = "linux")]
+  let (missing, size) = (libc::ENODATA, unsafe {
+    libc::getxattr(
+      path.as_ptr(),
+      name.as_ptr(),
+      value.as_mut_ptr() as *mut libc::c_void,
+      value.len(),
+    )
+  });
+  #[cfg(target_os = "macos")]
+  let (missing, size) = (libc::ENOATTR, unsafe {
+    libc::getxattr(
+      path.as_ptr(),
+      name.as_ptr(),
+      value.as_mut_ptr() as *mut libc::c_void,
+      value.len(),
+      0,
+      0,
+    )
+  });
+
+  if size >= 0 {
+    return Ok(Some(size as usize));
+  }
+  let err = io::Error::last_os_error();
+  match err.raw_os_error() {
+    Some(errno) if errno == missing || errno == libc::ENOTSUP => Ok(None),
+    _ => Err(err),
+  }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn getxattr(
+  _path: &CString,
+  _name: &CString,
+  _value: &mut [u8],
+) -> Result<Option<usize>, io::Error> {
+  Ok(None)
+}
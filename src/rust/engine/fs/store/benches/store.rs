@@ -27,7 +27,7 @@
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
@@ -308,6 +308,7 @@ fn tempdir_containing(max_files: usize, file_target_size: usize) -> (TempDir, Ve
           File {
             path: path_buf,
             is_executable: false,
+            xattrs: BTreeMap::new(),
           },
         ))
       }
@@ -29,7 +29,10 @@
 mod immutable_inputs;
 pub use crate::immutable_inputs::{ImmutableInputs, WorkdirSymlink};
 mod snapshot;
-pub use crate::snapshot::{OneOffStoreFileByDigest, Snapshot, StoreFileByDigest};
+pub use crate::snapshot::{
+  DedupStats, DigestCache, InMemoryDigestCache, OneOffStoreFileByDigest, Snapshot,
+  StoreFileByDigest,
+};
 mod snapshot_ops;
 #[cfg(test)]
 mod snapshot_ops_tests;
@@ -42,7 +45,7 @@ use std::fmt::{self, Debug, Display};
 use std::fs::OpenOptions;
 use std::fs::Permissions as FSPermissions;
 use std::future::Future;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
@@ -53,12 +56,13 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use fs::{
   default_cache_path, directory, DigestEntry, DigestTrie, Dir, DirectoryDigest, File, FileContent,
-  FileEntry, Link, PathStat, Permissions, RelativePath, SymlinkBehavior, SymlinkEntry,
+  FileEntry, Link, PathStat, Permissions, RelativePath, SymlinkBehavior, SymlinkEntry, TypedPath,
   EMPTY_DIRECTORY_DIGEST,
 };
 use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
 use grpc_util::prost::MessageExt;
-use hashing::{Digest, Fingerprint};
+use hashing::{Digest, Fingerprint, HashAlgorithm};
 use local::ByteStore;
 use parking_lot::Mutex;
 use prost::Message;
@@ -66,7 +70,7 @@ use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use protos::require_digest;
 use remexec::{ServerCapabilities, Tree};
 use serde_derive::Serialize;
-use sharded_lmdb::DEFAULT_LEASE_TIME;
+use sharded_lmdb::{ShardedLmdbStats, DEFAULT_LEASE_TIME};
 #[cfg(target_os = "macos")]
 use tokio::fs::copy;
 #[cfg(not(target_os = "macos"))]
@@ -79,6 +83,10 @@ const KILOBYTES: usize = 1024;
 const MEGABYTES: usize = 1024 * KILOBYTES;
 const GIGABYTES: usize = 1024 * MEGABYTES;
 
+// The maximum total size of the blobs which are written to the target store in a single batch by
+// `Store::copy_all_to`.
+const COPY_BATCH_SIZE_BYTES: usize = 16 * MEGABYTES;
+
 mod local;
 #[cfg(test)]
 pub mod local_tests;
@@ -92,6 +100,25 @@ pub struct LocalOptions {
   pub directories_max_size_bytes: usize,
   pub lease_time: Duration,
   pub shard_count: u8,
+  ///
+  /// The algorithm used to compute the Digests of files stored via `Store::store_file_bytes` and
+  /// `Store::store_file` (and so of the files in Snapshots captured into the Store).
+  ///
+  /// NB: Directory digests are always computed with the default algorithm, as are digests
+  /// provided by callers (e.g. via `Store::store_file_bytes_batch`) or by a remote store.
+  ///
+  pub hash_algorithm: HashAlgorithm,
+  ///
+  /// Whether to record the time that each blob was last loaded, which is required by
+  /// `Store::prune_to_size`. Recording is best-effort, and is buffered in memory.
+  ///
+  pub track_access: bool,
+  ///
+  /// If set, Directories smaller than this many bytes are stored in a separate unsharded database,
+  /// which packs small entries more densely. Directories are found in either database regardless
+  /// of this setting, so it may be changed for an existing store.
+  ///
+  pub small_directory_size_limit: Option<usize>,
 }
 
 ///
@@ -105,6 +132,46 @@ impl Default for LocalOptions {
       directories_max_size_bytes: 2 * 4 * GIGABYTES,
       lease_time: DEFAULT_LEASE_TIME,
       shard_count: 16,
+      hash_algorithm: HashAlgorithm::default(),
+      track_access: false,
+      small_directory_size_limit: None,
+    }
+  }
+}
+
+///
+/// Options which control how the files of a directory are laid out on disk by
+/// `Store::materialize_directory_with_options`.
+///
+#[derive(Clone, Default)]
+pub struct MaterializeOptions {
+  file_mode: Option<Arc<dyn Fn(&directory::File) -> u32 + Send + Sync>>,
+}
+
+impl MaterializeOptions {
+  ///
+  /// Use the given function to decide the mode of each materialized file, rather than deriving it
+  /// from the file's executable bit and the requested `Permissions` (e.g. 0o755 or 0o644 for
+  /// `Permissions::Writable`). Because a hardlink shares the mode of the file in the store, files
+  /// are always copied rather than hardlinked when a mode function is set.
+  ///
+  pub fn with_file_mode(
+    mut self,
+    file_mode: impl Fn(&directory::File) -> u32 + Send + Sync + 'static,
+  ) -> Self {
+    self.file_mode = Some(Arc::new(file_mode));
+    self
+  }
+
+  fn mode_for(&self, file: &directory::File, perms: Permissions) -> u32 {
+    match &self.file_mode {
+      Some(file_mode) => file_mode(file),
+      None => match perms {
+        Permissions::ReadOnly if file.is_executable() => 0o555,
+        Permissions::ReadOnly => 0o444,
+        Permissions::Writable if file.is_executable() => 0o755,
+        Permissions::Writable => 0o644,
+      },
     }
   }
 }
@@ -156,6 +223,61 @@ pub struct UploadSummary {
   pub upload_wall_time: Duration,
 }
 
+///
+/// Usage statistics for the LMDB databases of a local store. See `Store::stats`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StoreStats {
+  pub files: ShardedLmdbStats,
+  pub directories: ShardedLmdbStats,
+}
+
+///
+/// The outcome of pruning a local store. See `Store::prune_to_size`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GcStats {
+  /// The number of blobs which were removed.
+  pub removed_count: usize,
+  /// The total size of the blobs which were removed.
+  pub removed_bytes: u64,
+  /// The total size of the blobs which remain, which may be larger than the requested size if
+  /// they are leased.
+  pub remaining_bytes: u64,
+}
+
+///
+/// The outcome of copying a local store into another. See `Store::copy_all_to`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CopyStats {
+  /// The number of blobs which were copied.
+  pub copied_count: usize,
+  /// The total size of the blobs which were copied.
+  pub copied_bytes: u64,
+  /// The number of blobs which were skipped because the target store already contained them.
+  pub skipped_count: usize,
+}
+
+impl Display for StoreStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let describe = |stats: &ShardedLmdbStats| {
+      format!(
+        "{} entries, {:.1} MiB used of {:.1} MiB",
+        stats.entries,
+        stats.used_bytes as f64 / MEGABYTES as f64,
+        stats.map_size_bytes as f64 / MEGABYTES as f64,
+      )
+    };
+    write!(
+      f,
+      "files: {}; directories: {}",
+      describe(&self.files),
+      describe(&self.directories)
+    )
+  }
+}
+
 ///
 /// Wraps a `remote::ByteStore` with state to help avoid uploading common blobs multiple times.
 ///
@@ -341,6 +463,43 @@ impl Store {
     })
   }
 
+  ///
+  /// Make a store which only uses its local storage, with the given maximum size for each of its
+  /// file and directory databases. If a database fills up, it will be grown automatically (up to
+  /// a limit).
+  ///
+  pub fn local_only_with_map_size<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    map_size: usize,
+  ) -> Result<Store, String> {
+    let options = LocalOptions {
+      files_max_size_bytes: map_size,
+      directories_max_size_bytes: map_size,
+      ..LocalOptions::default()
+    };
+    Ok(Store {
+      local: local::ByteStore::new_with_options(executor, path, options)?,
+      remote: None,
+      immutable_inputs_base: None,
+    })
+  }
+
+  ///
+  /// Open an existing local store for inspection, without write access. Loads succeed as usual,
+  /// but all methods which would write to the store return an error.
+  ///
+  pub fn open_readonly<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+  ) -> Result<Store, String> {
+    Ok(Store {
+      local: local::ByteStore::new_read_only(executor, path, LocalOptions::default())?,
+      remote: None,
+      immutable_inputs_base: None,
+    })
+  }
+
   pub fn local_only_with_options<P: AsRef<Path>>(
     executor: task_executor::Executor,
     path: P,
@@ -403,6 +562,14 @@ impl Store {
     })
   }
 
+  ///
+  /// The algorithm used to compute the Digests of files stored in this Store: see
+  /// `LocalOptions::hash_algorithm`.
+  ///
+  pub fn hash_algorithm(&self) -> HashAlgorithm {
+    self.local.hash_algorithm()
+  }
+
   // This default suffix is also hard-coded into the Python options code in global_options.py
   pub fn default_path() -> PathBuf {
     default_cache_path().join("lmdb_store")
@@ -431,7 +598,7 @@ impl Store {
     bytes: Bytes,
     initial_lease: bool,
   ) -> Result<Digest, String> {
-    let digest = Digest::of_bytes(&bytes);
+    let digest = Digest::of_bytes_with_algorithm(&bytes, self.hash_algorithm());
     self
       .local
       .store_bytes(EntryType::File, digest.hash, bytes, initial_lease)
@@ -439,6 +606,135 @@ impl Store {
     Ok(digest)
   }
 
+  ///
+  /// Reads a tar archive from the given reader, stores the content of each of its files, and
+  /// returns a Snapshot of the resulting tree (the Directories of which are also stored). A file is
+  /// executable if any of the execute bits of its mode are set in the archive. If the archive
+  /// contains multiple entries for a path, the last one wins, as it would when extracting.
+  ///
+  /// Symlinks in the archive are captured as symlinks if `symlink_behavior` is Aware. If it is
+  /// Oblivious, they are resolved within the archive to the files or directories that they point
+  /// to, and symlinks which do not resolve to an entry of the archive are dropped.
+  ///
+  /// NB: The archive is read synchronously, and buffered in memory before it is stored.
+  ///
+  pub async fn ingest_tar(
+    &self,
+    reader: impl Read,
+    symlink_behavior: SymlinkBehavior,
+  ) -> Result<Snapshot, String> {
+    enum TarEntry {
+      File { content: Bytes, is_executable: bool },
+      Directory,
+      Symlink(PathBuf),
+    }
+
+    let mut entries = BTreeMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive
+      .entries()
+      .map_err(|e| format!("Failed to read tar: {e}"))?
+    {
+      let mut entry = entry.map_err(|e| format!("Failed to read tar entry: {e}"))?;
+      let path = RelativePath::new(
+        entry
+          .path()
+          .map_err(|e| format!("Failed to read path of tar entry: {e}"))?,
+      )?
+      .to_path_buf();
+      let entry_type = entry.header().entry_type();
+      let tar_entry = match entry_type {
+        tar::EntryType::Regular | tar::EntryType::Continuous => {
+          let mode = entry
+            .header()
+            .mode()
+            .map_err(|e| format!("Failed to read mode of {path:?} in tar: {e}"))?;
+          let mut content = Vec::new();
+          entry
+            .read_to_end(&mut content)
+            .map_err(|e| format!("Failed to read {path:?} from tar: {e}"))?;
+          TarEntry::File {
+            content: Bytes::from(content),
+            is_executable: mode & 0o111 != 0,
+          }
+        }
+        tar::EntryType::Directory => TarEntry::Directory,
+        tar::EntryType::Symlink => TarEntry::Symlink(
+          entry
+            .link_name()
+            .map_err(|e| format!("Failed to read target of {path:?} in tar: {e}"))?
+            .ok_or_else(|| format!("Symlink {path:?} in tar has no target."))?
+            .into_owned(),
+        ),
+        t if t.is_pax_global_extensions() || t.is_pax_local_extensions() => continue,
+        t => return Err(format!("Unsupported tar entry type {t:?} for {path:?}.")),
+      };
+      if path.as_os_str().is_empty() {
+        // The root directory, as `./`.
+        continue;
+      }
+      entries.insert(path, tar_entry);
+    }
+
+    let file_digests = future::try_join_all(entries.iter().filter_map(|(path, entry)| {
+      let TarEntry::File { content, .. } = entry else {
+        return None;
+      };
+      Some(async move {
+        let digest = self.store_file_bytes(content.clone(), true).await?;
+        Ok::<_, String>((path.clone(), digest))
+      })
+    }))
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    let typed_paths = entries
+      .iter()
+      .map(|(path, entry)| match entry {
+        TarEntry::File { is_executable, .. } => TypedPath::File {
+          path,
+          is_executable: *is_executable,
+        },
+        TarEntry::Directory => TypedPath::Dir(path),
+        TarEntry::Symlink(target) => TypedPath::Link { path, target },
+      })
+      .collect();
+    let mut tree = DigestTrie::from_unique_paths(typed_paths, &file_digests)?;
+
+    if let SymlinkBehavior::Oblivious = symlink_behavior {
+      // Re-create the tree from the files and directories that the symlinks resolve to.
+      let mut resolved_entries = Vec::new();
+      let mut resolved_digests = HashMap::new();
+      tree.walk(SymlinkBehavior::Oblivious, &mut |path, entry| match entry {
+        directory::Entry::File(f) => {
+          resolved_digests.insert(path.to_owned(), f.digest());
+          resolved_entries.push((path.to_owned(), Some(f.is_executable())));
+        }
+        directory::Entry::Directory(_) if !path.as_os_str().is_empty() => {
+          resolved_entries.push((path.to_owned(), None));
+        }
+        _ => (),
+      });
+      let typed_paths = resolved_entries
+        .iter()
+        .map(|(path, is_executable)| match is_executable {
+          Some(is_executable) => TypedPath::File {
+            path,
+            is_executable: *is_executable,
+          },
+          None => TypedPath::Dir(path),
+        })
+        .collect();
+      tree = DigestTrie::from_unique_paths(typed_paths, &resolved_digests)?;
+    }
+
+    let digest = self.record_digest_trie(tree.clone(), true).await?;
+    Ok(Snapshot {
+      digest: digest.as_digest(),
+      tree,
+    })
+  }
+
   ///
   /// A convenience method for storing batches of small files.
   ///
@@ -495,13 +791,14 @@ impl Store {
 
     Snapshot::from_path_stats(
       Digester { digest },
-      vec![fs::PathStat::File {
-        path: name.clone().into(),
-        stat: fs::File {
+      vec![fs::PathStat::file(
+        name.clone().into(),
+        fs::File {
           path: name.into(),
           is_executable,
+          xattrs: BTreeMap::new(),
         },
-      }],
+      )],
     )
     .await
   }
@@ -526,6 +823,20 @@ impl Store {
       .await
   }
 
+  ///
+  /// Loads the bytes of each of the given files from the local store, using a single read
+  /// transaction rather than one per file. Returns the files which were found with their bytes,
+  /// and the digests of the files which were missing, each in the order they were requested.
+  ///
+  /// Unlike `load_file_bytes_with`, this does not back-fill missing files from remote.
+  ///
+  pub async fn load_file_bytes_many(
+    &self,
+    digests: Vec<Digest>,
+  ) -> Result<(Vec<(Digest, Bytes)>, Vec<Digest>), StoreError> {
+    Ok(self.local.load_file_bytes_batch(digests).await?)
+  }
+
   ///
   /// Ensure that the recursive contents of the given DigestTrie are persisted in the local Store.
   ///
@@ -614,6 +925,7 @@ impl Store {
               File {
                 path: path.clone(),
                 is_executable: file_node.is_executable,
+                xattrs: BTreeMap::new(),
               },
             ),
             Some((path, file_node.digest.as_ref().unwrap().try_into().unwrap())),
@@ -961,6 +1273,55 @@ impl Store {
     Ok(missing.is_empty())
   }
 
+  ///
+  /// Returns the Digests of the files and directories which are referenced (transitively) by the
+  /// given directory, but which are missing from the local store: for example, after a garbage
+  /// collection or crash which left the store inconsistent. If the given directory is itself
+  /// missing, its own digest is returned.
+  ///
+  /// This is read-only: nothing is fetched from a remote store, and nothing is repaired.
+  ///
+  pub async fn verify_directory(&self, digest: Digest) -> Result<Vec<Digest>, StoreError> {
+    let mut missing = Vec::new();
+    let mut file_digests = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![digest];
+    while let Some(digest) = to_visit.pop() {
+      if !visited.insert(digest) {
+        continue;
+      }
+      let directory = self
+        .local
+        .load_bytes_with(EntryType::Directory, digest, |bytes| {
+          remexec::Directory::decode(bytes)
+            .map_err(|e| format!("Failed to decode Directory {digest:?}: {e}"))
+        })
+        .await?;
+      let directory = match directory {
+        Some(directory) => directory?,
+        None => {
+          missing.push(digest);
+          continue;
+        }
+      };
+      for file_node in &directory.files {
+        file_digests.insert(require_digest(file_node.digest.as_ref())?);
+      }
+      for dir_node in &directory.directories {
+        to_visit.push(require_digest(dir_node.digest.as_ref())?);
+      }
+    }
+
+    missing.extend(
+      self
+        .local
+        .get_missing_digests(EntryType::File, file_digests)
+        .await?,
+    );
+    missing.sort();
+    Ok(missing)
+  }
+
   /// Ensure that the files are locally loadable. This will download them from the remote store as
   /// a side effect, if one is configured.
   pub async fn ensure_downloaded(
@@ -1091,6 +1452,14 @@ impl Store {
     }
   }
 
+  ///
+  /// Removes the least recently loaded blobs from the local store until it is no bigger than
+  /// max_bytes. Requires that the store was created with `LocalOptions::track_access`.
+  ///
+  pub async fn prune_to_size(&self, max_bytes: u64) -> Result<GcStats, String> {
+    self.local.prune_to_size(max_bytes).await
+  }
+
   ///
   /// To check if it might be faster to upload the digests recursively
   /// vs checking if the files are present first.
@@ -1210,6 +1579,30 @@ impl Store {
     force_mutable: bool,
     mutable_paths: &BTreeSet<RelativePath>,
     perms: Permissions,
+  ) -> Result<(), StoreError> {
+    self
+      .materialize_directory_with_options(
+        destination,
+        digest,
+        force_mutable,
+        mutable_paths,
+        perms,
+        &MaterializeOptions::default(),
+      )
+      .await
+  }
+
+  ///
+  /// As `materialize_directory`, but with the given `MaterializeOptions`.
+  ///
+  pub async fn materialize_directory_with_options(
+    &self,
+    destination: PathBuf,
+    digest: DirectoryDigest,
+    force_mutable: bool,
+    mutable_paths: &BTreeSet<RelativePath>,
+    perms: Permissions,
+    options: &MaterializeOptions,
   ) -> Result<(), StoreError> {
     // Load the DigestTrie for the digest, and convert it into a mapping between a fully qualified
     // parent path and its children.
@@ -1268,6 +1661,7 @@ impl Store {
         &parent_to_child,
         &mutable_path_ancestors,
         perms,
+        options,
       )
       .await
   }
@@ -1281,6 +1675,7 @@ impl Store {
     parent_to_child: &'a HashMap<PathBuf, Vec<directory::Entry>>,
     mutable_paths: &'a BTreeSet<PathBuf>,
     perms: Permissions,
+    options: &'a MaterializeOptions,
   ) -> BoxFuture<'a, Result<(), StoreError>> {
     let store = self.clone();
     async move {
@@ -1299,7 +1694,10 @@ impl Store {
           let path = destination.join(child.name().as_ref());
           let store = store.clone();
           child_futures.push(async move {
-            let can_be_immutable = !force_mutable && can_hardlink && !mutable_paths.contains(&path);
+            let can_be_immutable = !force_mutable
+              && can_hardlink
+              && options.file_mode.is_none()
+              && !mutable_paths.contains(&path);
 
             match child {
               directory::Entry::File(f) => {
@@ -1307,8 +1705,7 @@ impl Store {
                   .materialize_file_maybe_hardlink(
                     path,
                     f.digest(),
-                    perms,
-                    f.is_executable(),
+                    options.mode_for(f, perms),
                     can_be_immutable,
                   )
                   .await
@@ -1328,6 +1725,7 @@ impl Store {
                     parent_to_child,
                     mutable_paths,
                     perms,
+                    options,
                   )
                   .await
               }
@@ -1357,8 +1755,7 @@ impl Store {
     &self,
     destination: PathBuf,
     digest: Digest,
-    perms: Permissions,
-    is_executable: bool,
+    mode: u32,
     can_be_immutable: bool,
   ) -> Result<(), StoreError> {
     let hardlink_tgt = if can_be_immutable {
@@ -1367,32 +1764,38 @@ impl Store {
       None
     };
     match hardlink_tgt {
-      Some(path) => {
-        self
-          .materialize_hardlink(destination, path.to_str().unwrap().to_string())
-          .await
-      }
+      Some(path) => match link_file(&path, &destination).await {
+        Ok(()) => Ok(()),
+        // NB: The destination root was on the same device as the store, but a mount point below
+        // it might not be: fall back to copying (which also applies the requested permissions).
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+          self
+            .materialize_file_contents(destination, digest, mode)
+            .await
+        }
+        Err(e) => Err(
+          format!(
+            "Failed to create hardlink to {} at {}: {e}",
+            path.display(),
+            destination.display()
+          )
+          .into(),
+        ),
+      },
       None => {
         self
-          .materialize_file(destination, digest, perms, is_executable)
+          .materialize_file_contents(destination, digest, mode)
           .await
       }
     }
   }
 
-  async fn materialize_file(
+  async fn materialize_file_contents(
     &self,
     destination: PathBuf,
     digest: Digest,
-    perms: Permissions,
-    is_executable: bool,
+    mode: u32,
   ) -> Result<(), StoreError> {
-    let mode = match perms {
-      Permissions::ReadOnly if is_executable => 0o555,
-      Permissions::ReadOnly => 0o444,
-      Permissions::Writable if is_executable => 0o755,
-      Permissions::Writable => 0o644,
-    };
     match self.local.load_from_fs(digest).await? {
       Some(path) => {
         tokio::fs::copy(&path, &destination).await.map_err(|e| {
@@ -1432,6 +1835,57 @@ impl Store {
     }
   }
 
+  ///
+  /// Materializes the single file with the given Digest at the given absolute path (rather than
+  /// materializing a whole directory), with mode 0o755 if it is executable and 0o644 otherwise.
+  ///
+  /// The content is written to a temporary file in the destination directory, which is then
+  /// renamed into place: the destination either retains its previous content (if any) or contains
+  /// the complete file, and the temporary file is removed if materialization fails.
+  ///
+  pub async fn materialize_file(
+    &self,
+    dest_abs: &Path,
+    digest: Digest,
+    is_executable: bool,
+  ) -> Result<(), StoreError> {
+    let parent = dest_abs.parent().ok_or_else(|| {
+      format!(
+        "Cannot materialize a file at {}: it has no parent directory.",
+        dest_abs.display()
+      )
+    })?;
+    // NB: The temporary file is deleted when this is dropped, unless it is kept below.
+    let tmp_path = tempfile::Builder::new()
+      .prefix(".materialize")
+      .tempfile_in(parent)
+      .map_err(|e| {
+        format!(
+          "Failed to create a temporary file in {}: {e}",
+          parent.display()
+        )
+      })?
+      .into_temp_path();
+    let mode = if is_executable { 0o755 } else { 0o644 };
+    self
+      .materialize_file_contents(tmp_path.to_path_buf(), digest, mode)
+      .await?;
+    // The temporary file already existed, so the mode was not applied when it was opened.
+    tokio::fs::set_permissions(&tmp_path, FSPermissions::from_mode(mode))
+      .await
+      .map_err(|e| format!("Error setting permissions on {}: {e}", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, dest_abs).await.map_err(|e| {
+      format!(
+        "Error renaming {} to {}: {e}",
+        tmp_path.display(),
+        dest_abs.display()
+      )
+    })?;
+    // The temporary file has been renamed into place, so must no longer be deleted.
+    let _ = tmp_path.keep();
+    Ok(())
+  }
+
   pub async fn materialize_symlink(
     &self,
     destination: PathBuf,
@@ -1467,26 +1921,14 @@ impl Store {
     destination: PathBuf,
     target: String,
   ) -> Result<(), StoreError> {
-    // On macOS, copy uses a copy-on-write syscall (fclonefileat) which creates a disconnected
-    // clone. It is more defensive than a hardlink, but has the same requirement that the source
-    // and destination filesystem are the same.
-    //
-    // It also has the benefit of playing nicely with Docker for macOS file virtualization: see
-    // #18162.
-    #[cfg(target_os = "macos")]
-    copy(&target, &destination).await.map_err(|e| {
-      format!(
-        "Failed to copy from {target} to {}: {e}",
-        destination.display()
-      )
-    })?;
-    #[cfg(not(target_os = "macos"))]
-    hard_link(&target, &destination).await.map_err(|e| {
-      format!(
-        "Failed to create hardlink to {target} at {}: {e}",
-        destination.display()
-      )
-    })?;
+    link_file(Path::new(&target), &destination)
+      .await
+      .map_err(|e| {
+        format!(
+          "Failed to create hardlink to {target} at {}: {e}",
+          destination.display()
+        )
+      })?;
     Ok(())
   }
 
@@ -1524,6 +1966,177 @@ impl Store {
     .await
   }
 
+  ///
+  /// Returns every file below the given directory, along with its Digest and executable bit,
+  /// sorted by path. Only the Directory structure is loaded: file contents need not be present
+  /// in the Store, and nothing is written to disk. Symlinks are not followed.
+  ///
+  pub async fn expand_directory(
+    &self,
+    digest: DirectoryDigest,
+  ) -> Result<Vec<FileEntry>, StoreError> {
+    let mut files = Vec::new();
+    self.load_digest_trie(digest).await?.walk(
+      SymlinkBehavior::Aware,
+      &mut |path, entry| match entry {
+        directory::Entry::File(f) => files.push(FileEntry {
+          path: path.to_owned(),
+          digest: f.digest(),
+          is_executable: f.is_executable(),
+        }),
+        directory::Entry::Symlink(_) => (),
+        directory::Entry::Directory(_) => (),
+      },
+    );
+    Ok(files)
+  }
+
+  ///
+  /// Calls the given visitor with every file below the given directory, in the same (path) order
+  /// as `expand_directory`, but without collecting the files. Directories are loaded one at a time
+  /// as the walk descends, so peak memory is bounded by the depth of the tree rather than by its
+  /// size. Symlinks are not followed.
+  ///
+  pub async fn visit_directory(
+    &self,
+    digest: DirectoryDigest,
+    mut visitor: impl FnMut(&FileEntry) + Send,
+  ) -> Result<(), StoreError> {
+    if let Some(tree) = &digest.tree {
+      // The DigestTrie is already loaded.
+      tree.walk(SymlinkBehavior::Aware, &mut |path, entry| {
+        if let directory::Entry::File(f) = entry {
+          visitor(&FileEntry {
+            path: path.to_owned(),
+            digest: f.digest(),
+            is_executable: f.is_executable(),
+          })
+        }
+      });
+      return Ok(());
+    }
+    self
+      .visit_directory_helper(digest.as_digest(), PathBuf::new(), &mut visitor)
+      .await
+  }
+
+  fn visit_directory_helper<'a>(
+    &'a self,
+    digest: Digest,
+    path_so_far: PathBuf,
+    visitor: &'a mut (dyn FnMut(&FileEntry) + Send),
+  ) -> BoxFuture<'a, Result<(), StoreError>> {
+    async move {
+      let directory = self.load_directory(digest).await?;
+      // Files and directories are each sorted by name: merge them to visit in path order.
+      let mut files = directory.files.iter().peekable();
+      let mut directories = directory.directories.iter().peekable();
+      loop {
+        let next_is_file = match (files.peek(), directories.peek()) {
+          (Some(file_node), Some(dir_node)) => file_node.name < dir_node.name,
+          (Some(_), None) => true,
+          (None, Some(_)) => false,
+          (None, None) => break,
+        };
+        if next_is_file {
+          let file_node = files.next().unwrap();
+          visitor(&FileEntry {
+            path: path_so_far.join(&file_node.name),
+            digest: require_digest(file_node.digest.as_ref())?,
+            is_executable: file_node.is_executable,
+          });
+        } else {
+          let dir_node = directories.next().unwrap();
+          self
+            .visit_directory_helper(
+              require_digest(dir_node.digest.as_ref())?,
+              path_so_far.join(&dir_node.name),
+              visitor,
+            )
+            .await?;
+        }
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  ///
+  /// Returns a Stream of the same entries as `entries_for_directory`, in the same (path) order,
+  /// but which loads Directories one at a time as the stream is consumed, so that a consumer can
+  /// begin processing the first entries before the rest of the tree has been loaded.
+  ///
+  pub fn load_directory_stream(
+    &self,
+    digest: DirectoryDigest,
+  ) -> BoxStream<'static, Result<DigestEntry, StoreError>> {
+    enum Pending {
+      Directory(PathBuf, Digest),
+      Entry(DigestEntry),
+    }
+
+    let store = self.clone();
+    async_stream::try_stream! {
+      if digest.tree.is_some() {
+        // The DigestTrie is already loaded.
+        for entry in store.entries_for_directory(digest).await? {
+          yield entry;
+        }
+        return;
+      }
+
+      // A stack of pending work, with the next entry in path order on top.
+      let mut pending = vec![Pending::Directory(PathBuf::new(), digest.as_digest())];
+      while let Some(next) = pending.pop() {
+        let (path_so_far, digest) = match next {
+          Pending::Entry(entry) => {
+            yield entry;
+            continue;
+          }
+          Pending::Directory(path_so_far, digest) => (path_so_far, digest),
+        };
+
+        let directory = store.load_directory(digest).await?;
+        let mut children = Vec::with_capacity(
+          directory.files.len() + directory.directories.len() + directory.symlinks.len(),
+        );
+        for file_node in &directory.files {
+          children.push((
+            &file_node.name,
+            Pending::Entry(DigestEntry::File(FileEntry {
+              path: path_so_far.join(&file_node.name),
+              digest: require_digest(file_node.digest.as_ref())?,
+              is_executable: file_node.is_executable,
+            })),
+          ));
+        }
+        for symlink_node in &directory.symlinks {
+          children.push((
+            &symlink_node.name,
+            Pending::Entry(DigestEntry::Symlink(SymlinkEntry {
+              path: path_so_far.join(&symlink_node.name),
+              target: PathBuf::from(&symlink_node.target),
+            })),
+          ));
+        }
+        for dir_node in &directory.directories {
+          let path = path_so_far.join(&dir_node.name);
+          let digest = require_digest(dir_node.digest.as_ref())?;
+          // Only report a directory if it is a leaf node, as `entries_for_directory` does.
+          let child = if digest == EMPTY_DIRECTORY_DIGEST.as_digest() {
+            Pending::Entry(DigestEntry::EmptyDirectory(path))
+          } else {
+            Pending::Directory(path, digest)
+          };
+          children.push((&dir_node.name, child));
+        }
+        children.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        pending.extend(children.into_iter().rev().map(|(_, child)| child));
+      }
+    }
+    .boxed()
+  }
+
   ///
   /// Returns indirect references to files in a Digest sorted by their path.
   ///
@@ -1649,9 +2262,130 @@ impl Store {
     res.boxed()
   }
 
+  ///
+  /// Copies every file and directory in the local store into the local store of `other`, skipping
+  /// any which `other` already contains. Remote stores are not consulted.
+  ///
+  /// Missing blobs are written in batches of up to `COPY_BATCH_SIZE_BYTES`, so that each batch is
+  /// stored with a single transaction per shard.
+  ///
+  pub async fn copy_all_to(&self, other: &Store) -> Result<CopyStats, String> {
+    let mut stats = CopyStats::default();
+    for entry_type in [EntryType::File, EntryType::Directory] {
+      let digests = self.local.all_digests(entry_type).await?;
+      let digest_count = digests.len();
+      let mut missing = other
+        .local
+        .get_missing_digests(entry_type, digests.into_iter().collect())
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+      stats.skipped_count += digest_count - missing.len();
+      missing.sort_by_key(|digest| digest.hash);
+
+      let mut batch = Vec::new();
+      let mut batch_bytes = 0;
+      for digest in missing {
+        let bytes = self
+          .local
+          .load_bytes_with(entry_type, digest, Bytes::copy_from_slice)
+          .await?
+          .ok_or_else(|| {
+            format!("{entry_type:?} {digest:?} was removed from the local store while copying it")
+          })?;
+        batch_bytes += bytes.len();
+        batch.push((digest.hash, bytes));
+        if batch_bytes >= COPY_BATCH_SIZE_BYTES {
+          Self::copy_batch_to(other, entry_type, std::mem::take(&mut batch), &mut stats).await?;
+          batch_bytes = 0;
+        }
+      }
+      if !batch.is_empty() {
+        Self::copy_batch_to(other, entry_type, batch, &mut stats).await?;
+      }
+    }
+    Ok(stats)
+  }
+
+  async fn copy_batch_to(
+    other: &Store,
+    entry_type: EntryType,
+    batch: Vec<(Fingerprint, Bytes)>,
+    stats: &mut CopyStats,
+  ) -> Result<(), String> {
+    stats.copied_count += batch.len();
+    stats.copied_bytes += batch
+      .iter()
+      .map(|(_, bytes)| bytes.len() as u64)
+      .sum::<u64>();
+    other
+      .local
+      .store_bytes_batch(entry_type, batch, false)
+      .await
+  }
+
   pub async fn all_local_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
     self.local.all_digests(entry_type).await
   }
+
+  ///
+  /// Returns the Digests of all files in the local store.
+  ///
+  /// The result is a point-in-time snapshot of the store (which may be concurrently modified), and
+  /// may be large. Contents are not loaded: sizes are read from the stored entries in place.
+  ///
+  pub async fn all_file_digests(&self) -> Result<Vec<Digest>, String> {
+    self.all_local_digests(EntryType::File).await
+  }
+
+  ///
+  /// Returns the Digests of all directories in the local store.
+  ///
+  /// See `Self::all_file_digests`.
+  ///
+  pub async fn all_directory_digests(&self) -> Result<Vec<Digest>, String> {
+    self.all_local_digests(EntryType::Directory).await
+  }
+
+  ///
+  /// Clears LMDB reader slots which were left behind by threads or processes that died while
+  /// holding a read transaction, returning the number of slots which were reclaimed. This is an
+  /// operational tool to recover from `MDB_READERS_FULL`.
+  ///
+  pub fn reader_check(&self) -> Result<usize, String> {
+    self.local.reader_check()
+  }
+
+  ///
+  /// The maximum number of concurrent LMDB read transactions supported by the local store.
+  ///
+  pub fn max_readers(&self) -> Result<usize, String> {
+    self.local.max_readers()
+  }
+
+  ///
+  /// Returns usage statistics for the local store. This is purely observational.
+  ///
+  pub async fn stats(&self) -> Result<StoreStats, String> {
+    self.local.stats().await
+  }
+}
+
+///
+/// Links the given file from the store to the destination, without copying its bytes.
+///
+async fn link_file(target: &Path, destination: &Path) -> std::io::Result<()> {
+  // On macOS, copy uses a copy-on-write syscall (fclonefileat) which creates a disconnected
+  // clone. It is more defensive than a hardlink, but has the same requirement that the source
+  // and destination filesystem are the same.
+  //
+  // It also has the benefit of playing nicely with Docker for macOS file virtualization: see
+  // #18162.
+  #[cfg(target_os = "macos")]
+  copy(target, destination).await?;
+  #[cfg(not(target_os = "macos"))]
+  hard_link(target, destination).await?;
+  Ok(())
 }
 
 #[async_trait]
@@ -14,7 +14,11 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use futures::future::{self, join_all, try_join, try_join_all};
 use hashing::{
-  async_copy_and_hash, async_verified_copy, AgedFingerprint, Digest, Fingerprint, EMPTY_DIGEST,
+  async_copy_and_hash_with_algorithm, async_verified_copy_with_algorithm, AgedFingerprint, Digest,
+  Fingerprint, HashAlgorithm, EMPTY_DIGEST,
+};
+use lmdb::{
+  Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, Transaction, WriteFlags,
 };
 use parking_lot::Mutex;
 use sharded_lmdb::ShardedLmdb;
@@ -58,6 +62,7 @@ trait UnderlyingByteStore {
     initial_lease: bool,
     src_is_immutable: bool,
     expected_digest: Digest,
+    hash_algorithm: HashAlgorithm,
     src: PathBuf,
   ) -> Result<(), String>;
 
@@ -115,13 +120,15 @@ impl UnderlyingByteStore for ShardedLmdb {
     initial_lease: bool,
     src_is_immutable: bool,
     expected_digest: Digest,
+    hash_algorithm: HashAlgorithm,
     src: PathBuf,
   ) -> Result<(), String> {
     self
-      .store(
+      .store_with_algorithm(
         initial_lease,
         src_is_immutable,
         expected_digest,
+        hash_algorithm,
         move || std::fs::File::open(&src),
       )
       .await
@@ -183,15 +190,22 @@ impl ShardedFSDB {
   async fn verified_copier<R>(
     mut file: tokio::fs::File,
     expected_digest: Digest,
+    hash_algorithm: HashAlgorithm,
     src_is_immutable: bool,
     mut reader: R,
   ) -> Result<tokio::fs::File, VerifiedCopyError>
   where
     R: AsyncRead + Unpin,
   {
-    let matches = async_verified_copy(expected_digest, src_is_immutable, &mut reader, &mut file)
-      .await
-      .map_err(|e| VerifiedCopyError::CopyFailure(format!("Failed to copy bytes: {e}")))?;
+    let matches = async_verified_copy_with_algorithm(
+      expected_digest,
+      src_is_immutable,
+      &mut reader,
+      &mut file,
+      hash_algorithm,
+    )
+    .await
+    .map_err(|e| VerifiedCopyError::CopyFailure(format!("Failed to copy bytes: {e}")))?;
     if matches {
       Ok(file)
     } else {
@@ -346,6 +360,7 @@ impl UnderlyingByteStore for ShardedFSDB {
     _initial_lease: bool,
     src_is_immutable: bool,
     expected_digest: Digest,
+    hash_algorithm: HashAlgorithm,
     src: PathBuf,
   ) -> Result<(), String> {
     let mut attempts = 0;
@@ -359,7 +374,13 @@ impl UnderlyingByteStore for ShardedFSDB {
       // resulting content after the syscall (read only).
       let copy_result = self
         .write_using(expected_digest.hash, |file| {
-          Self::verified_copier(file, expected_digest, src_is_immutable, reader)
+          Self::verified_copier(
+            file,
+            expected_digest,
+            hash_algorithm,
+            src_is_immutable,
+            reader,
+          )
         })
         .await;
       let should_retry = match copy_result {
@@ -464,6 +485,172 @@ impl UnderlyingByteStore for ShardedFSDB {
   }
 }
 
+/// The (sparse) size of the memory map of the access log, which bounds the number of entries in it.
+const ACCESS_LOG_MAX_SIZE_BYTES: usize = 256 * 1024 * 1024;
+
+/// The number of accesses which are buffered in memory before being written to the access log.
+const ACCESS_LOG_BATCH_SIZE: usize = 1024;
+
+///
+/// A best-effort record of when each blob in the local store was last loaded, used to evict the
+/// least recently used blobs in `ByteStore::prune_to_size`.
+///
+/// Accesses are buffered in memory and written in batches, so accesses which have not yet been
+/// flushed when the process exits are lost. Failures to write are logged rather than propagated.
+///
+#[derive(Debug)]
+struct AccessLog {
+  env: Environment,
+  // Maps a Fingerprint to the time it was last accessed, in microseconds since the epoch.
+  db: Database,
+  executor: Executor,
+  pending: Mutex<HashMap<Fingerprint, u64>>,
+}
+
+impl AccessLog {
+  fn new(root: &Path, executor: Executor) -> Result<AccessLog, String> {
+    std::fs::create_dir_all(root)
+      .map_err(|e| format!("Failed to create {}: {e}", root.display()))?;
+    // See `ShardedLmdb::make_env` for a description of these flags.
+    let env = Environment::new()
+      .set_flags(EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS)
+      .set_max_dbs(1)
+      .set_map_size(ACCESS_LOG_MAX_SIZE_BYTES)
+      .open(root)
+      .map_err(|e| format!("Error making env for access log at {root:?}: {e}"))?;
+    let db = env
+      .create_db(Some("access"), DatabaseFlags::empty())
+      .map_err(|e| format!("Error creating access log database at {root:?}: {e}"))?;
+    Ok(AccessLog {
+      env,
+      db,
+      executor,
+      pending: Mutex::default(),
+    })
+  }
+
+  fn now_micros() -> u64 {
+    SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map(|d| d.as_micros() as u64)
+      .unwrap_or(0)
+  }
+
+  ///
+  /// Records an access to the given Fingerprint. If the batch is full, the buffered accesses are
+  /// flushed in the background, so that loads never wait for the access log to be written.
+  ///
+  fn record(self: &Arc<Self>, fingerprint: Fingerprint) {
+    let batch_full = {
+      let mut pending = self.pending.lock();
+      pending.insert(fingerprint, Self::now_micros());
+      pending.len() >= ACCESS_LOG_BATCH_SIZE
+    };
+    if batch_full {
+      let access_log = self.clone();
+      let _join = self.executor.native_spawn(async move {
+        if let Err(e) = access_log.flush().await {
+          log::warn!("Failed to record blob accesses: {e}");
+        }
+      });
+    }
+  }
+
+  async fn flush(self: &Arc<Self>) -> Result<(), String> {
+    let pending = std::mem::take(&mut *self.pending.lock());
+    if pending.is_empty() {
+      return Ok(());
+    }
+    let access_log = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut txn = access_log
+            .env
+            .begin_rw_txn()
+            .map_err(|e| format!("Error beginning access log transaction: {e}"))?;
+          for (fingerprint, accessed_micros) in pending {
+            txn
+              .put(
+                access_log.db,
+                &fingerprint,
+                &accessed_micros.to_le_bytes(),
+                WriteFlags::empty(),
+              )
+              .map_err(|e| format!("Error writing access log entry: {e}"))?;
+          }
+          txn
+            .commit()
+            .map_err(|e| format!("Error committing access log transaction: {e}"))
+        },
+        |e| Err(format!("`flush` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Returns the last access time of every Fingerprint which has been recorded, including any
+  /// buffered accesses.
+  ///
+  async fn last_accesses(self: &Arc<Self>) -> Result<HashMap<Fingerprint, u64>, String> {
+    self.flush().await?;
+    let access_log = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let txn = access_log
+            .env
+            .begin_ro_txn()
+            .map_err(|e| format!("Error beginning access log transaction: {e}"))?;
+          let mut cursor = txn
+            .open_ro_cursor(access_log.db)
+            .map_err(|e| format!("Failed to open access log cursor: {e}"))?;
+          let mut last_accesses = HashMap::new();
+          for entry in cursor.iter() {
+            let (key, value) =
+              entry.map_err(|e| format!("Failed to advance access log cursor: {e}"))?;
+            let mut micros = [0_u8; 8];
+            micros.copy_from_slice(value);
+            last_accesses.insert(
+              Fingerprint::from_bytes_unsafe(key),
+              u64::from_le_bytes(micros),
+            );
+          }
+          Ok(last_accesses)
+        },
+        |e| Err(format!("`last_accesses` task failed: {e}")),
+      )
+      .await
+  }
+
+  async fn remove(self: &Arc<Self>, fingerprints: Vec<Fingerprint>) -> Result<(), String> {
+    let access_log = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut txn = access_log
+            .env
+            .begin_rw_txn()
+            .map_err(|e| format!("Error beginning access log transaction: {e}"))?;
+          for fingerprint in fingerprints {
+            match txn.del(access_log.db, &fingerprint, None) {
+              Ok(()) | Err(lmdb::Error::NotFound) => (),
+              Err(e) => return Err(format!("Error removing access log entry: {e}")),
+            }
+          }
+          txn
+            .commit()
+            .map_err(|e| format!("Error committing access log transaction: {e}"))
+        },
+        |e| Err(format!("`remove` task failed: {e}")),
+      )
+      .await
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct ByteStore {
   inner: Arc<InnerStore>,
@@ -476,9 +663,18 @@ struct InnerStore {
   //  2. It's nice to know whether we should be able to parse something as a proto.
   file_lmdb: Result<Arc<ShardedLmdb>, String>,
   directory_lmdb: Result<Arc<ShardedLmdb>, String>,
+  // If enabled (or if it was enabled for a previous use of this store), an unsharded database for
+  // Directories which are smaller than the small_directory_size_limit. Directories may be in
+  // either database, regardless of their size.
+  small_directory_lmdb: Option<Result<Arc<ShardedLmdb>, String>>,
+  small_directory_size_limit: usize,
   file_fsdb: ShardedFSDB,
   executor: task_executor::Executor,
   filesystem_device: u64,
+  read_only: bool,
+  hash_algorithm: HashAlgorithm,
+  // If enabled, the time that each blob was last loaded.
+  access_log: Option<Arc<AccessLog>>,
 }
 
 impl ByteStore {
@@ -493,14 +689,44 @@ impl ByteStore {
     executor: task_executor::Executor,
     path: P,
     options: super::LocalOptions,
+  ) -> Result<ByteStore, String> {
+    Self::new_with_mode(executor, path, options, false)
+  }
+
+  ///
+  /// Open an existing store without write access: all methods which would write to the store
+  /// (including leasing and shrinking) will fail.
+  ///
+  pub fn new_read_only<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    options: super::LocalOptions,
+  ) -> Result<ByteStore, String> {
+    Self::new_with_mode(executor, path, options, true)
+  }
+
+  fn new_with_mode<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    options: super::LocalOptions,
+    read_only: bool,
   ) -> Result<ByteStore, String> {
     let root = path.as_ref();
     let lmdb_files_root = root.join("files");
     let lmdb_directories_root = root.join("directories");
+    let lmdb_small_directories_root = root.join("small_directories");
     let fsdb_files_root = root.join("immutable").join("files");
+    let access_log_root = root.join("access");
 
-    std::fs::create_dir_all(root)
-      .map_err(|e| format!("Failed to create {}: {e}", root.display()))?;
+    if !read_only {
+      std::fs::create_dir_all(root)
+        .map_err(|e| format!("Failed to create {}: {e}", root.display()))?;
+    }
+    let new_lmdb = if read_only {
+      ShardedLmdb::new_read_only
+    } else {
+      ShardedLmdb::new
+    };
 
     let filesystem_device = root
       .metadata()
@@ -512,9 +738,37 @@ impl ByteStore {
       })?
       .dev();
 
+    // NB: Once it exists, the small directory database is always consulted, since it may contain
+    // Directories even if it is no longer enabled.
+    let small_directory_lmdb = if lmdb_small_directories_root.exists()
+      || (options.small_directory_size_limit.is_some() && !read_only)
+    {
+      Some(
+        new_lmdb(
+          lmdb_small_directories_root,
+          options.directories_max_size_bytes,
+          executor.clone(),
+          options.lease_time,
+          1,
+        )
+        .map(Arc::new),
+      )
+    } else {
+      None
+    };
+
+    let access_log = if options.track_access && !read_only {
+      Some(Arc::new(AccessLog::new(
+        &access_log_root,
+        executor.clone(),
+      )?))
+    } else {
+      None
+    };
+
     Ok(ByteStore {
       inner: Arc::new(InnerStore {
-        file_lmdb: ShardedLmdb::new(
+        file_lmdb: new_lmdb(
           lmdb_files_root,
           options.files_max_size_bytes,
           executor.clone(),
@@ -522,7 +776,7 @@ impl ByteStore {
           options.shard_count,
         )
         .map(Arc::new),
-        directory_lmdb: ShardedLmdb::new(
+        directory_lmdb: new_lmdb(
           lmdb_directories_root,
           options.directories_max_size_bytes,
           executor.clone(),
@@ -530,6 +784,8 @@ impl ByteStore {
           options.shard_count,
         )
         .map(Arc::new),
+        small_directory_lmdb,
+        small_directory_size_limit: options.small_directory_size_limit.unwrap_or(0),
         file_fsdb: ShardedFSDB {
           executor: executor.clone(),
           root: fsdb_files_root,
@@ -538,14 +794,29 @@ impl ByteStore {
         },
         executor,
         filesystem_device,
+        read_only,
+        hash_algorithm: options.hash_algorithm,
+        access_log,
       }),
     })
   }
 
+  fn check_writable(&self) -> Result<(), String> {
+    if self.inner.read_only {
+      Err("The local store was opened read-only, and cannot be written to.".to_owned())
+    } else {
+      Ok(())
+    }
+  }
+
   pub fn executor(&self) -> &task_executor::Executor {
     &self.inner.executor
   }
 
+  pub fn hash_algorithm(&self) -> HashAlgorithm {
+    self.inner.hash_algorithm
+  }
+
   pub fn filesystem_device(&self) -> u64 {
     self.inner.filesystem_device
   }
@@ -558,8 +829,7 @@ impl ByteStore {
     }
 
     // In parallel, check for the given fingerprint in all databases.
-    let directory_lmdb = self.inner.directory_lmdb.clone()?;
-    let is_lmdb_dir = directory_lmdb.exists(fingerprint);
+    let is_lmdb_dir = self.directory_exists(fingerprint);
     let file_lmdb = self.inner.file_lmdb.clone()?;
     let is_lmdb_file = file_lmdb.exists(fingerprint);
     let is_fsdb_file = self.inner.file_fsdb.exists(fingerprint);
@@ -578,16 +848,21 @@ impl ByteStore {
     &self,
     digests: impl Iterator<Item = (Digest, EntryType)>,
   ) -> Result<(), String> {
+    self.check_writable()?;
     // NB: Lease extension happens periodically in the background, so this code needn't be parallel.
     for (digest, entry_type) in digests {
       if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
         self.inner.file_fsdb.lease(digest.hash).await?;
       } else {
         let dbs = match entry_type {
-          EntryType::File => self.inner.file_lmdb.clone(),
-          EntryType::Directory => self.inner.directory_lmdb.clone(),
+          EntryType::File => self.inner.file_lmdb.clone()?,
+          EntryType::Directory => {
+            self
+              .directory_lmdb_containing(digest.hash, digest.size_bytes)
+              .await?
+          }
         };
-        dbs?
+        dbs
           .lease(digest.hash)
           .await
           .map_err(|err| format!("Error leasing digest {digest:?}: {err}"))?;
@@ -609,47 +884,13 @@ impl ByteStore {
     target_bytes: usize,
     shrink_behavior: ShrinkBehavior,
   ) -> Result<usize, String> {
-    let mut used_bytes: usize = 0;
-    let mut fingerprints_by_expired_ago = BinaryHeap::new();
-
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .file_lmdb
-        .clone()?
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::File)
-        }),
-    );
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .directory_lmdb
-        .clone()?
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::Directory)
-        }),
-    );
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .file_fsdb
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::File)
-        }),
-    );
+    self.check_writable()?;
+    let aged_fingerprints = self.aged_fingerprints().await?;
+    let mut used_bytes: usize = aged_fingerprints
+      .iter()
+      .map(|(fingerprint, _)| fingerprint.size_bytes)
+      .sum();
+    let mut fingerprints_by_expired_ago = BinaryHeap::from(aged_fingerprints);
 
     while used_bytes > target_bytes {
       let (aged_fingerprint, entry_type) = fingerprints_by_expired_ago
@@ -673,14 +914,135 @@ impl ByteStore {
 
     if shrink_behavior == ShrinkBehavior::Compact {
       self.inner.file_lmdb.clone()?.compact()?;
+      if let Some(small_directory_lmdb) = &self.inner.small_directory_lmdb {
+        small_directory_lmdb.clone()?.compact()?;
+      }
     }
 
     Ok(used_bytes)
   }
 
+  ///
+  /// Removes the least recently loaded blobs until the stored blobs are no bigger than max_bytes
+  /// (excluding lmdb overhead), and returns statistics about what was removed. Blobs which have
+  /// never been loaded are removed first, and leased blobs are never removed.
+  ///
+  /// Fails unless the store was created with `LocalOptions::track_access`.
+  ///
+  pub async fn prune_to_size(&self, max_bytes: u64) -> Result<super::GcStats, String> {
+    self.check_writable()?;
+    let access_log = self.inner.access_log.as_ref().ok_or_else(|| {
+      "Pruning by last access requires that the local store tracks accesses.".to_owned()
+    })?;
+    let last_accesses = access_log.last_accesses().await?;
+
+    let mut aged_fingerprints = self.aged_fingerprints().await?;
+    let mut stats = super::GcStats {
+      removed_count: 0,
+      removed_bytes: 0,
+      remaining_bytes: aged_fingerprints
+        .iter()
+        .map(|(fingerprint, _)| fingerprint.size_bytes as u64)
+        .sum(),
+    };
+    // Order by last access, falling back to the lease expiration for blobs which have never been
+    // loaded.
+    aged_fingerprints.sort_by_key(|(aged_fingerprint, _)| {
+      (
+        last_accesses
+          .get(&aged_fingerprint.fingerprint)
+          .copied()
+          .unwrap_or(0),
+        std::cmp::Reverse(aged_fingerprint.expired_seconds_ago),
+      )
+    });
+
+    let mut removed = Vec::new();
+    for (aged_fingerprint, entry_type) in aged_fingerprints {
+      if stats.remaining_bytes <= max_bytes {
+        break;
+      }
+      if aged_fingerprint.expired_seconds_ago == 0 {
+        continue;
+      }
+      self
+        .remove(
+          entry_type,
+          Digest {
+            hash: aged_fingerprint.fingerprint,
+            size_bytes: aged_fingerprint.size_bytes,
+          },
+        )
+        .await?;
+      removed.push(aged_fingerprint.fingerprint);
+      stats.removed_count += 1;
+      stats.removed_bytes += aged_fingerprint.size_bytes as u64;
+      stats.remaining_bytes -= aged_fingerprint.size_bytes as u64;
+    }
+    access_log.remove(removed).await?;
+
+    Ok(stats)
+  }
+
+  ///
+  /// Returns all of the fingerprints in the store (other than the empty digest), with their ages
+  /// and entry types.
+  ///
+  async fn aged_fingerprints(&self) -> Result<Vec<(AgedFingerprint, EntryType)>, String> {
+    let mut aged_fingerprints = Vec::new();
+    aged_fingerprints.extend(
+      self
+        .inner
+        .file_lmdb
+        .clone()?
+        .aged_fingerprints()
+        .await?
+        .into_iter()
+        .map(|fingerprint| (fingerprint, EntryType::File)),
+    );
+    aged_fingerprints.extend(
+      self
+        .inner
+        .directory_lmdb
+        .clone()?
+        .aged_fingerprints()
+        .await?
+        .into_iter()
+        .map(|fingerprint| (fingerprint, EntryType::Directory)),
+    );
+    if let Some(small_directory_lmdb) = &self.inner.small_directory_lmdb {
+      aged_fingerprints.extend(
+        small_directory_lmdb
+          .clone()?
+          .aged_fingerprints()
+          .await?
+          .into_iter()
+          .map(|fingerprint| (fingerprint, EntryType::Directory)),
+      );
+    }
+    aged_fingerprints.extend(
+      self
+        .inner
+        .file_fsdb
+        .aged_fingerprints()
+        .await?
+        .into_iter()
+        .map(|fingerprint| (fingerprint, EntryType::File)),
+    );
+    Ok(aged_fingerprints)
+  }
+
   pub async fn remove(&self, entry_type: EntryType, digest: Digest) -> Result<bool, String> {
+    self.check_writable()?;
     match entry_type {
-      EntryType::Directory => self.inner.directory_lmdb.clone()?.remove(digest.hash).await,
+      EntryType::Directory => {
+        for dbs in self.directory_lmdbs(digest.size_bytes)? {
+          if dbs.remove(digest.hash).await? {
+            return Ok(true);
+          }
+        }
+        Ok(false)
+      }
       EntryType::File if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) => {
         self.inner.file_fsdb.remove(digest.hash).await
       }
@@ -717,11 +1079,15 @@ impl ByteStore {
     items: Vec<(Fingerprint, Bytes)>,
     initial_lease: bool,
   ) -> Result<(), String> {
+    self.check_writable()?;
     let mut fsdb_items = vec![];
     let mut lmdb_items = vec![];
+    let mut small_directory_lmdb_items = vec![];
     for (fingerprint, bytes) in items {
       if ByteStore::should_use_fsdb(entry_type, bytes.len()) {
         fsdb_items.push((fingerprint, bytes));
+      } else if self.should_use_small_directory_lmdb(entry_type, bytes.len()) {
+        small_directory_lmdb_items.push((fingerprint, bytes));
       } else {
         lmdb_items.push((fingerprint, bytes));
       }
@@ -739,6 +1105,15 @@ impl ByteStore {
       lmdb_dbs?.store_bytes_batch(lmdb_items, initial_lease),
     )
     .await?;
+    if let (false, Some(small_directory_lmdb)) = (
+      small_directory_lmdb_items.is_empty(),
+      &self.inner.small_directory_lmdb,
+    ) {
+      small_directory_lmdb
+        .clone()?
+        .store_bytes_batch(small_directory_lmdb_items, initial_lease)
+        .await?;
+    }
 
     Ok(())
   }
@@ -754,28 +1129,40 @@ impl ByteStore {
     src_is_immutable: bool,
     src: PathBuf,
   ) -> Result<Digest, String> {
+    self.check_writable()?;
     let mut file = tokio::fs::File::open(src.clone())
       .await
       .map_err(|e| format!("Failed to open {src:?}: {e}"))?;
-    let digest = async_copy_and_hash(&mut file, &mut tokio::io::sink())
-      .await
-      .map_err(|e| format!("Failed to hash {src:?}: {e}"))?;
+    let hash_algorithm = self.inner.hash_algorithm;
+    let digest =
+      async_copy_and_hash_with_algorithm(&mut file, &mut tokio::io::sink(), hash_algorithm)
+        .await
+        .map_err(|e| format!("Failed to hash {src:?}: {e}"))?;
 
     if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
       self
         .inner
         .file_fsdb
-        .store(initial_lease, src_is_immutable, digest, src)
+        .store(initial_lease, src_is_immutable, digest, hash_algorithm, src)
         .await?;
     } else {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_lmdb.clone()?,
-        EntryType::File => self.inner.file_lmdb.clone()?,
+      let dbs = match (entry_type, &self.inner.small_directory_lmdb) {
+        (EntryType::Directory, Some(small_directory_lmdb))
+          if self.should_use_small_directory_lmdb(entry_type, digest.size_bytes) =>
+        {
+          small_directory_lmdb.clone()?
+        }
+        (EntryType::Directory, _) => self.inner.directory_lmdb.clone()?,
+        (EntryType::File, _) => self.inner.file_lmdb.clone()?,
       };
       let _ = dbs
-        .store(initial_lease, src_is_immutable, digest, move || {
-          std::fs::File::open(&src)
-        })
+        .store_with_algorithm(
+          initial_lease,
+          src_is_immutable,
+          digest,
+          hash_algorithm,
+          move || std::fs::File::open(&src),
+        )
         .await;
     }
 
@@ -819,6 +1206,16 @@ impl ByteStore {
     .await?;
 
     existing.extend(existing_lmdb_digests);
+    if let (EntryType::Directory, Some(small_directory_lmdb)) =
+      (entry_type, &self.inner.small_directory_lmdb)
+    {
+      existing.extend(
+        small_directory_lmdb
+          .clone()?
+          .exists_batch(lmdb_digests.iter().map(|digest| digest.hash).collect())
+          .await?,
+      );
+    }
 
     Ok(
       digests
@@ -878,14 +1275,36 @@ impl ByteStore {
         .file_fsdb
         .load_bytes_with(digest.hash, len_checked_f)
         .await?
+    } else if entry_type == EntryType::Directory {
+      // The Directory is usually in the database that its size routes it to, but may be in the
+      // other if the size limit has changed since it was stored: only look there on a miss.
+      let len_checked_f = Arc::new(Mutex::new(len_checked_f));
+      let mut result = None;
+      for dbs in self.directory_lmdbs(digest.size_bytes)? {
+        let len_checked_f = len_checked_f.clone();
+        result = dbs
+          .load_bytes_with(digest.hash, move |bytes: &[u8]| {
+            (&mut *len_checked_f.lock())(bytes)
+          })
+          .await?;
+        if result.is_some() {
+          break;
+        }
+      }
+      result
     } else {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_lmdb.clone(),
-        EntryType::File => self.inner.file_lmdb.clone(),
-      }?;
-      dbs.load_bytes_with(digest.hash, len_checked_f).await?
+      self
+        .inner
+        .file_lmdb
+        .clone()?
+        .load_bytes_with(digest.hash, len_checked_f)
+        .await?
     };
 
+    if let (Some(access_log), Some(_)) = (&self.inner.access_log, &result) {
+      access_log.record(digest.hash);
+    }
+
     if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
       workunit_store_handle.store.record_observation(
         ObservationMetric::LocalStoreReadBlobSize,
@@ -900,6 +1319,108 @@ impl ByteStore {
     Ok(result)
   }
 
+  ///
+  /// Loads the bytes of many files at once, reading all of the files which are stored in LMDB
+  /// under a single read transaction per shard. Returns the digests which were found with their
+  /// bytes, and the digests which were missing, each in the order they were requested.
+  ///
+  pub async fn load_file_bytes_batch(
+    &self,
+    digests: Vec<Digest>,
+  ) -> Result<(Vec<(Digest, Bytes)>, Vec<Digest>), String> {
+    let (fsdb_digests, lmdb_digests): (Vec<_>, Vec<_>) = digests
+      .iter()
+      .filter(|digest| **digest != EMPTY_DIGEST)
+      .partition(|digest| ByteStore::should_use_fsdb(EntryType::File, digest.size_bytes));
+
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let (fsdb_loaded, mut loaded) = try_join(
+      try_join_all(fsdb_digests.into_iter().map(|digest| async move {
+        let bytes = self
+          .load_bytes_with(EntryType::File, *digest, Bytes::copy_from_slice)
+          .await?;
+        Ok::<_, String>((digest.hash, bytes))
+      })),
+      file_lmdb.load_bytes_batch(lmdb_digests.iter().map(|digest| digest.hash).collect()),
+    )
+    .await?;
+    loaded.extend(
+      fsdb_loaded
+        .into_iter()
+        .filter_map(|(fingerprint, bytes)| bytes.map(|bytes| (fingerprint, bytes))),
+    );
+
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for digest in digests {
+      if digest == EMPTY_DIGEST {
+        found.push((digest, Bytes::new()));
+        continue;
+      }
+      match loaded.get(&digest.hash) {
+        Some(bytes) if bytes.len() == digest.size_bytes => {
+          found.push((digest, bytes.clone()));
+        }
+        Some(bytes) => {
+          return Err(format!(
+            "Got hash collision reading from store - digest {:?} was requested, but retrieved \
+                bytes with that fingerprint had length {}.",
+            digest,
+            bytes.len(),
+          ));
+        }
+        None => missing.push(digest),
+      }
+    }
+
+    if let Some(access_log) = &self.inner.access_log {
+      for (digest, _) in &found {
+        access_log.record(digest.hash);
+      }
+    }
+
+    Ok((found, missing))
+  }
+
+  ///
+  /// Returns usage statistics for the LMDB databases. Files which are large enough to be stored
+  /// outside of LMDB are not included.
+  ///
+  pub async fn stats(&self) -> Result<super::StoreStats, String> {
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let directory_lmdb = self.inner.directory_lmdb.clone()?;
+    let (files, mut directories) = try_join(file_lmdb.stats(), directory_lmdb.stats()).await?;
+    if let Some(small_directory_lmdb) = &self.inner.small_directory_lmdb {
+      let small_directories = small_directory_lmdb.clone()?.stats().await?;
+      directories.map_size_bytes += small_directories.map_size_bytes;
+      directories.used_bytes += small_directories.used_bytes;
+      directories.entries += small_directories.entries;
+    }
+    Ok(super::StoreStats { files, directories })
+  }
+
+  ///
+  /// Reclaims stale LMDB reader slots: see `ShardedLmdb::reader_check`.
+  ///
+  pub fn reader_check(&self) -> Result<usize, String> {
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let directory_lmdb = self.inner.directory_lmdb.clone()?;
+    let mut reclaimed = file_lmdb.reader_check()? + directory_lmdb.reader_check()?;
+    if let Some(small_directory_lmdb) = &self.inner.small_directory_lmdb {
+      reclaimed += small_directory_lmdb.clone()?.reader_check()?;
+    }
+    Ok(reclaimed)
+  }
+
+  pub fn max_readers(&self) -> Result<usize, String> {
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let directory_lmdb = self.inner.directory_lmdb.clone()?;
+    Ok(std::cmp::max(
+      file_lmdb.max_readers()?,
+      directory_lmdb.max_readers()?,
+    ))
+  }
+
   pub async fn all_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
     let lmdb = match entry_type {
       EntryType::File => self.inner.file_lmdb.clone(),
@@ -907,7 +1428,16 @@ impl ByteStore {
     }?;
     let mut digests = vec![];
     digests.extend(lmdb.all_digests().await?);
-    digests.extend(self.inner.file_fsdb.all_digests().await?);
+    match (entry_type, &self.inner.small_directory_lmdb) {
+      (EntryType::File, _) => {
+        // Only files are large enough to be stored in the FSDB: see `Self::should_use_fsdb`.
+        digests.extend(self.inner.file_fsdb.all_digests().await?);
+      }
+      (EntryType::Directory, Some(small_directory_lmdb)) => {
+        digests.extend(small_directory_lmdb.clone()?.all_digests().await?);
+      }
+      (EntryType::Directory, None) => (),
+    }
     Ok(digests)
   }
 
@@ -915,6 +1445,70 @@ impl ByteStore {
     entry_type == EntryType::File && len >= LARGE_FILE_SIZE_LIMIT
   }
 
+  fn should_use_small_directory_lmdb(&self, entry_type: EntryType, len: usize) -> bool {
+    entry_type == EntryType::Directory
+      && self.inner.small_directory_lmdb.is_some()
+      && len < self.inner.small_directory_size_limit
+  }
+
+  async fn directory_exists(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    if self
+      .inner
+      .directory_lmdb
+      .clone()?
+      .exists(fingerprint)
+      .await?
+    {
+      return Ok(true);
+    }
+    match &self.inner.small_directory_lmdb {
+      Some(small_directory_lmdb) => small_directory_lmdb.clone()?.exists(fingerprint).await,
+      None => Ok(false),
+    }
+  }
+
+  ///
+  /// Returns the directory databases in the order in which a Directory of the given size should be
+  /// looked for: the one its size routes it to first, and then the other, since the size limit may
+  /// have been different when it was stored.
+  ///
+  fn directory_lmdbs(&self, size_bytes: usize) -> Result<Vec<Arc<ShardedLmdb>>, String> {
+    let directory_lmdb = self.inner.directory_lmdb.clone()?;
+    match &self.inner.small_directory_lmdb {
+      None => Ok(vec![directory_lmdb]),
+      Some(small_directory_lmdb) => {
+        let small_directory_lmdb = small_directory_lmdb.clone()?;
+        if self.should_use_small_directory_lmdb(EntryType::Directory, size_bytes) {
+          Ok(vec![small_directory_lmdb, directory_lmdb])
+        } else {
+          Ok(vec![directory_lmdb, small_directory_lmdb])
+        }
+      }
+    }
+  }
+
+  ///
+  /// Returns the database which contains the Directory with the given Fingerprint, trying the one
+  /// its size routes it to first. If neither does, returns that database, which will then fail to
+  /// find it. Only probes when a small directory database is in use.
+  ///
+  async fn directory_lmdb_containing(
+    &self,
+    fingerprint: Fingerprint,
+    size_bytes: usize,
+  ) -> Result<Arc<ShardedLmdb>, String> {
+    let mut dbs = self.directory_lmdbs(size_bytes)?.into_iter();
+    let expected = dbs
+      .next()
+      .expect("There is always a main directory database.");
+    if let Some(other) = dbs.next() {
+      if !expected.exists(fingerprint).await? && other.exists(fingerprint).await? {
+        return Ok(other);
+      }
+    }
+    Ok(expected)
+  }
+
   pub(crate) fn get_file_fsdb(&self) -> ShardedFSDB {
     self.inner.file_fsdb.clone()
   }
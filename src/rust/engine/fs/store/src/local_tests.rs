@@ -548,6 +548,63 @@ async fn garbage_collect_and_compact() {
   );
 }
 
+#[tokio::test]
+async fn garbage_collect_and_compact_small_directories() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      small_directory_size_limit: Some(1024),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+
+  let mut digests = Vec::new();
+  for i in 0..4096_u32 {
+    let mut bytes = BytesMut::with_capacity(512);
+    for _ in 0..128 {
+      bytes.put_u32(i);
+    }
+    let digest = Digest::of_bytes(&bytes);
+    store
+      .store_bytes(EntryType::Directory, digest.hash, bytes.freeze(), false)
+      .await
+      .expect("Error storing");
+    digests.push(digest);
+  }
+
+  let small_directories = dir.path().join("small_directories");
+  let size = get_directory_size(&small_directories);
+  assert!(
+    size >= 2 * 1024 * 1024,
+    "Expect size to be at least 2MB but was {size}"
+  );
+
+  store
+    .shrink(512 * 1024, ShrinkBehavior::Compact)
+    .await
+    .expect("Error shrinking");
+
+  let size = get_directory_size(&small_directories);
+  assert!(
+    size < 2 * 1024 * 1024,
+    "Expect size to be less than 2MB but was {size}"
+  );
+  let mut remaining = 0;
+  for digest in digests {
+    if load_directory_proto_bytes(&store, digest)
+      .await
+      .unwrap()
+      .is_some()
+    {
+      remaining += 1;
+    }
+  }
+  assert!(remaining > 0 && remaining < 4096);
+}
+
 async fn write_1mb(store: &ByteStore, byte: u8) -> Digest {
   let mut bytes = BytesMut::with_capacity(1024 * 1024);
   for _ in 0..1024 * 1024 {
@@ -1,25 +1,34 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::hash;
+use std::io::{self, Write};
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 
+use bytes::Bytes;
 use deepsize::DeepSizeOf;
 use futures::future;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt, TryStreamExt};
 
 use fs::{
-  DigestTrie, Dir, DirectoryDigest, Entry, File, GitignoreStyleExcludes, GlobMatching, PathStat,
-  PosixFS, PreparedPathGlobs, SymlinkBehavior, EMPTY_DIGEST_TREE,
+  DigestTrie, Dir, DirectoryDigest, Entry, File, GitignoreStyleExcludes, GlobMatching, Link,
+  PathGlobs, PathStat, PosixFS, PreparedPathGlobs, RelativePath, SymlinkBehavior, TypedPath,
+  EMPTY_DIGEST_TREE,
 };
-use hashing::{Digest, EMPTY_DIGEST};
+use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+use indexmap::IndexMap;
+use parking_lot::Mutex;
 
 use crate::{Store, StoreError};
 
+/// The first line of a manifest produced by `Snapshot::to_manifest`.
+const MANIFEST_HEADER: &str = "# snapshot manifest v1";
+
 /// The listing of a DirectoryDigest.
 ///
 /// Similar to DirectoryDigest, the presence of the DigestTrie does _not_ guarantee that
@@ -30,6 +39,34 @@ pub struct Snapshot {
   pub tree: DigestTrie,
 }
 
+///
+/// Statistics about the duplication of file content within a Snapshot. Because content is stored
+/// by Digest, each duplicate file costs nothing to store.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DedupStats {
+  /// The number of files which had the same Digest as some other (earlier) file.
+  pub duplicate_files: usize,
+  /// The total size of the duplicate files: i.e., the bytes saved by storing content by Digest.
+  pub duplicate_bytes: usize,
+}
+
+impl DedupStats {
+  fn from_digests<'a>(digests: impl Iterator<Item = &'a Digest>) -> Self {
+    let mut counts: HashMap<Digest, usize> = HashMap::new();
+    for digest in digests {
+      *counts.entry(*digest).or_default() += 1;
+    }
+    counts
+      .into_iter()
+      .fold(DedupStats::default(), |mut stats, (digest, count)| {
+        stats.duplicate_files += count - 1;
+        stats.duplicate_bytes += (count - 1) * digest.size_bytes;
+        stats
+      })
+  }
+}
+
 impl Eq for Snapshot {}
 
 impl PartialEq for Snapshot {
@@ -87,30 +124,100 @@ impl Snapshot {
     file_digester: S,
     path_stats: Vec<PathStat>,
   ) -> Result<Snapshot, String> {
-    let (paths, files): (Vec<_>, Vec<_>) = path_stats
-      .iter()
-      .filter_map(|ps| match ps {
-        PathStat::File { path, stat } => Some((path.clone(), stat.clone())),
-        _ => None,
-      })
-      .unzip();
-    let file_digests = future::try_join_all(
-      files
-        .into_iter()
-        .map(|file| file_digester.store_by_digest(file))
-        .collect::<Vec<_>>(),
-    )
-    .await
-    .map_err(|e| format!("Failed to digest inputs: {e:?}"))?;
+    Self::from_path_stats_with_concurrency(file_digester, path_stats, num_cpus::get()).await
+  }
 
-    let file_digests_map = paths
-      .into_iter()
-      .zip(file_digests)
-      .collect::<HashMap<_, _>>();
+  ///
+  /// As `from_path_stats`, but digests at most `concurrency` files at once (rather than one per
+  /// CPU). Too much parallelism can thrash the disk, while too little underutilizes it.
+  ///
+  pub async fn from_path_stats_with_concurrency<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    file_digester: S,
+    path_stats: Vec<PathStat>,
+    concurrency: usize,
+  ) -> Result<Snapshot, String> {
+    let file_digests_map = Self::digest_files(file_digester, &path_stats, concurrency).await?;
+    Self::from_digested_path_stats(&path_stats, &file_digests_map)
+  }
+
+  ///
+  /// As `from_path_stats`, but additionally reports how many of the files have content which is
+  /// identical to another file in the Snapshot: see `DedupStats`.
+  ///
+  pub async fn from_path_stats_with_dedup_stats<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    file_digester: S,
+    path_stats: Vec<PathStat>,
+  ) -> Result<(Snapshot, DedupStats), String> {
+    let file_digests_map = Self::digest_files(file_digester, &path_stats, num_cpus::get()).await?;
+    let snapshot = Self::from_digested_path_stats(&path_stats, &file_digests_map)?;
+    Ok((
+      snapshot,
+      DedupStats::from_digests(file_digests_map.values()),
+    ))
+  }
 
+  ///
+  /// As `from_path_stats`, but additionally returns the Digest of each file in the Snapshot, keyed
+  /// by path and in path order, as computed while digesting the files. This saves re-walking the
+  /// Snapshot to find the Digest of an individual file.
+  ///
+  pub async fn from_path_stats_with_file_digests<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    file_digester: S,
+    path_stats: Vec<PathStat>,
+  ) -> Result<(Snapshot, IndexMap<PathBuf, Digest>), String> {
+    let file_digests_map = Self::digest_files(file_digester, &path_stats, num_cpus::get()).await?;
+    let snapshot = Self::from_digested_path_stats(&path_stats, &file_digests_map)?;
+    let mut file_digests = file_digests_map.into_iter().collect::<Vec<_>>();
+    file_digests.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+    Ok((snapshot, file_digests.into_iter().collect()))
+  }
+
+  fn from_digested_path_stats(
+    path_stats: &[PathStat],
+    file_digests_map: &HashMap<PathBuf, Digest>,
+  ) -> Result<Snapshot, String> {
     let tree = DigestTrie::from_unique_paths(
       path_stats.iter().map(|p| p.into()).collect(),
+      file_digests_map,
+    )?;
+    Ok(Self {
+      digest: tree.compute_root_digest(),
+      tree,
+    })
+  }
+
+  ///
+  /// Create a new Snapshot by applying changes to this one: the `changed` PathStats are added (or
+  /// replace existing entries), and the `removed` paths are dropped.
+  ///
+  /// Only the changed files are digested, and subtrees of this Snapshot which contain no changes
+  /// are reused as-is. The result is identical to a Snapshot created from scratch for the
+  /// resulting tree.
+  ///
+  pub async fn update<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    &self,
+    file_digester: S,
+    changed: Vec<PathStat>,
+    removed: Vec<PathBuf>,
+  ) -> Result<Snapshot, String> {
+    let file_digests_map = Self::digest_files(file_digester, &changed, num_cpus::get()).await?;
+
+    let tree = self.tree.update(
+      changed.iter().map(|p| p.into()).collect(),
       &file_digests_map,
+      removed.iter().map(PathBuf::as_path).collect(),
     )?;
     Ok(Self {
       digest: tree.compute_root_digest(),
@@ -118,6 +225,221 @@ impl Snapshot {
     })
   }
 
+  ///
+  /// Returns true if this Snapshot has the same paths, file contents and symlink targets as the
+  /// given Snapshot, regardless of whether their files are executable. Unlike comparing digests,
+  /// this allows for comparing outputs captured on platforms which disagree about executable bits.
+  ///
+  /// File content is compared by digest, so no file content is loaded.
+  ///
+  pub fn content_equal_ignoring_exec(&self, other: &Snapshot) -> bool {
+    if self.digest == other.digest {
+      return true;
+    }
+
+    #[derive(PartialEq)]
+    enum Content {
+      Directory,
+      File(Digest),
+      Symlink(PathBuf),
+    }
+
+    let contents = |snapshot: &Snapshot| {
+      let mut contents = Vec::new();
+      snapshot
+        .tree
+        .walk(SymlinkBehavior::Aware, &mut |path, entry| {
+          let content = match entry {
+            Entry::Directory(_) => Content::Directory,
+            Entry::File(f) => Content::File(f.digest()),
+            Entry::Symlink(s) => Content::Symlink(s.target().to_owned()),
+          };
+          contents.push((path.to_owned(), content));
+        });
+      contents
+    };
+    contents(self) == contents(other)
+  }
+
+  ///
+  /// Create a new Snapshot containing only the files and symlinks of this one which match the
+  /// given PathGlobs, without touching the filesystem: see `PathGlobs::matches`.
+  ///
+  /// Directories which no longer contain any matched paths are dropped, and the Directories of
+  /// the resulting tree are recorded in the given Store.
+  ///
+  pub async fn filter(&self, globs: &PathGlobs, store: &Store) -> Result<Snapshot, String> {
+    let mut candidates = Vec::new();
+    let mut file_digests = HashMap::new();
+    let mut entries = HashMap::new();
+    self
+      .tree
+      .walk(SymlinkBehavior::Aware, &mut |path, entry| match entry {
+        Entry::File(f) => {
+          candidates.push(path.to_owned());
+          file_digests.insert(path.to_owned(), f.digest());
+          entries.insert(path.to_owned(), entry.clone());
+        }
+        Entry::Symlink(_) => {
+          candidates.push(path.to_owned());
+          entries.insert(path.to_owned(), entry.clone());
+        }
+        Entry::Directory(_) => (),
+      });
+
+    let matched = globs
+      .matches(&candidates)
+      .map_err(|e| format!("Failed to filter {self:?}: {e}"))?;
+    let typed_paths = matched
+      .iter()
+      .map(|path| match &entries[path] {
+        Entry::File(f) => TypedPath::File {
+          path,
+          is_executable: f.is_executable(),
+        },
+        Entry::Symlink(s) => TypedPath::Link {
+          path,
+          target: s.target(),
+        },
+        Entry::Directory(_) => unreachable!("Only files and symlinks are candidates."),
+      })
+      .collect();
+    let tree = DigestTrie::from_unique_paths(typed_paths, &file_digests)?;
+    let digest = store.record_digest_trie(tree.clone(), true).await?;
+    Ok(Self {
+      digest: digest.as_digest(),
+      tree,
+    })
+  }
+
+  ///
+  /// Create a new Snapshot with the contents of this one nested below the given directory path,
+  /// which must be relative and must not escape the root. The Directories of the resulting tree
+  /// are recorded in the given Store.
+  ///
+  pub async fn prefix(&self, prefix: PathBuf, store: &Store) -> Result<Snapshot, String> {
+    let prefix = RelativePath::new(prefix)?;
+    let tree = self.tree.clone().add_prefix(&prefix)?;
+    let digest = store.record_digest_trie(tree.clone(), true).await?;
+    Ok(Self {
+      digest: digest.as_digest(),
+      tree,
+    })
+  }
+
+  ///
+  /// Create a new Snapshot of the subtree of this one which is rooted at the given directory path.
+  /// Fails if the path does not exist in this Snapshot, or is not a directory. The Directories of
+  /// the subtree are taken from this Snapshot's tree rather than loaded, and are (re-)recorded in
+  /// the given Store.
+  ///
+  pub async fn strip_prefix(&self, prefix: &Path, store: &Store) -> Result<Snapshot, String> {
+    let prefix = RelativePath::new(prefix)?;
+    if prefix.as_os_str().is_empty() {
+      return Ok(self.clone());
+    }
+    let (digest, tree) = match self.tree.entry(&prefix)? {
+      Some(Entry::Directory(d)) => (d.digest(), d.tree().clone()),
+      Some(_) => {
+        return Err(format!(
+          "Cannot strip prefix {prefix:?} from {self:?}: it is not a directory."
+        ))
+      }
+      None => {
+        return Err(format!(
+          "Cannot strip prefix {prefix:?} from {self:?}: it does not exist."
+        ))
+      }
+    };
+    store.record_digest_trie(tree.clone(), true).await?;
+    Ok(Self { digest, tree })
+  }
+
+  ///
+  /// Writes the contents of this Snapshot to the given writer as a tar archive. Entries are written
+  /// in the order of the tree (each directory before its contents, and siblings sorted by name),
+  /// and other than their modes (0o755 for directories and executable files, and 0o644 for other
+  /// files), their metadata is zeroed, so the archive is reproducible.
+  ///
+  /// Large files are streamed from the Store, while small files are loaded one at a time.
+  ///
+  pub async fn to_tar(&self, store: &Store, writer: impl Write) -> Result<(), String> {
+    let mut entries = Vec::new();
+    self.tree.walk(SymlinkBehavior::Aware, &mut |path, entry| {
+      // Skip the root.
+      if !path.as_os_str().is_empty() {
+        entries.push((path.to_owned(), entry.clone()));
+      }
+    });
+
+    let mut builder = tar::Builder::new(writer);
+    for (path, entry) in entries {
+      let mut header = tar::Header::new_gnu();
+      let appended = match entry {
+        Entry::Directory(_) => {
+          header.set_entry_type(tar::EntryType::Directory);
+          header.set_mode(0o755);
+          header.set_size(0);
+          builder.append_data(&mut header, &path, io::empty())
+        }
+        Entry::File(f) => {
+          header.set_mode(if f.is_executable() { 0o755 } else { 0o644 });
+          header.set_size(f.digest().size_bytes as u64);
+          match store.local.load_from_fs(f.digest()).await? {
+            Some(path_in_store) => {
+              let content = std::fs::File::open(&path_in_store)
+                .map_err(|e| format!("Failed to open {path_in_store:?}: {e}"))?;
+              builder.append_data(&mut header, &path, content)
+            }
+            None => {
+              let content = store
+                .load_file_bytes_with(f.digest(), Bytes::copy_from_slice)
+                .await
+                .map_err(|e| e.to_string())?;
+              builder.append_data(&mut header, &path, &content[..])
+            }
+          }
+        }
+        Entry::Symlink(s) => {
+          header.set_entry_type(tar::EntryType::Symlink);
+          header.set_mode(0o777);
+          header.set_size(0);
+          builder.append_link(&mut header, &path, s.target())
+        }
+      };
+      appended.map_err(|e| format!("Failed to write {path:?} to tar: {e}"))?;
+    }
+    builder
+      .finish()
+      .map_err(|e| format!("Failed to finish writing tar: {e}"))
+  }
+
+  async fn digest_files<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    file_digester: S,
+    path_stats: &[PathStat],
+    concurrency: usize,
+  ) -> Result<HashMap<PathBuf, Digest>, String> {
+    let (paths, files): (Vec<_>, Vec<_>) = path_stats
+      .iter()
+      .filter_map(|ps| match ps {
+        PathStat::File { path, stat, .. } => Some((path.clone(), stat.clone())),
+        _ => None,
+      })
+      .unzip();
+    // NB: `buffered` preserves the order of the inputs, so the digests can be zipped with paths.
+    let file_digests = futures::stream::iter(files)
+      .map(|file| file_digester.store_by_digest(file))
+      .buffered(concurrency.max(1))
+      .try_collect::<Vec<_>>()
+      .await
+      .map_err(|e| format!("Failed to digest inputs: {e:?}"))?;
+
+    Ok(paths.into_iter().zip(file_digests).collect())
+  }
+
   pub async fn from_digest(store: Store, digest: DirectoryDigest) -> Result<Snapshot, StoreError> {
     Ok(Self {
       digest: digest.as_digest(),
@@ -125,6 +447,27 @@ impl Snapshot {
     })
   }
 
+  ///
+  /// Expand the given PathGlobs, and capture a Snapshot of the matched paths. Globs which do not
+  /// match are handled according to the strict match behavior of the PathGlobs: when that is
+  /// `Error`, the capture fails.
+  ///
+  pub async fn from_path_globs(
+    posix_fs: Arc<PosixFS>,
+    store: Store,
+    path_globs: PreparedPathGlobs,
+  ) -> Result<Snapshot, String> {
+    let path_stats = posix_fs
+      .expand_globs(path_globs, posix_fs.symlink_behavior(), None)
+      .await
+      .map_err(|err| format!("Error expanding globs: {err}"))?;
+    Snapshot::from_path_stats(
+      OneOffStoreFileByDigest::new(store, posix_fs, false),
+      path_stats,
+    )
+    .await
+  }
+
   ///
   /// Capture a Snapshot of a presumed-immutable piece of the filesystem.
   ///
@@ -144,6 +487,32 @@ impl Snapshot {
     root_path: P,
     path_globs: PreparedPathGlobs,
     digest_hint: Option<DirectoryDigest>,
+  ) -> Result<Snapshot, String> {
+    Self::capture_snapshot_from_arbitrary_root_with_symlink_behavior(
+      store,
+      executor,
+      root_path,
+      path_globs,
+      digest_hint,
+      SymlinkBehavior::Oblivious,
+    )
+    .await
+  }
+
+  ///
+  /// As `capture_snapshot_from_arbitrary_root`, but with the given SymlinkBehavior. When Aware,
+  /// symlinks are captured as symlink entries (with their targets) rather than being resolved, so
+  /// that materializing the Snapshot recreates them.
+  ///
+  pub async fn capture_snapshot_from_arbitrary_root_with_symlink_behavior<
+    P: AsRef<Path> + Send + 'static,
+  >(
+    store: Store,
+    executor: task_executor::Executor,
+    root_path: P,
+    path_globs: PreparedPathGlobs,
+    digest_hint: Option<DirectoryDigest>,
+    symlink_behavior: SymlinkBehavior,
   ) -> Result<Snapshot, String> {
     // Attempt to use the digest hint to load a Snapshot without expanding the globs; otherwise,
     // expand the globs to capture a Snapshot.
@@ -162,11 +531,11 @@ impl Snapshot {
         root_path,
         GitignoreStyleExcludes::create(vec![])?,
         executor,
-        SymlinkBehavior::Oblivious,
+        symlink_behavior,
       )?);
 
       let path_stats = posix_fs
-        .expand_globs(path_globs, SymlinkBehavior::Oblivious, None)
+        .expand_globs(path_globs, symlink_behavior, None)
         .await
         .map_err(|err| format!("Error expanding globs: {err}"))?;
       Snapshot::from_path_stats(
@@ -177,6 +546,113 @@ impl Snapshot {
     }
   }
 
+  ///
+  /// Renders this Snapshot as a portable, versioned textual manifest, with one tab-separated line
+  /// per entry, sorted by path:
+  ///
+  ///   dir   <path>
+  ///   file  <fingerprint>  <size>  <x|->  <path>
+  ///   link  <target>  <path>
+  ///
+  /// The manifest does not include file contents: see `Self::from_manifest`.
+  ///
+  pub fn to_manifest(&self) -> Result<String, String> {
+    let mut lines = vec![MANIFEST_HEADER.to_owned()];
+    let mut invalid_paths = vec![];
+    let valid = |path: &Path| path.to_str().filter(|p| !p.contains(['\t', '\n']));
+    self.tree.walk(SymlinkBehavior::Aware, &mut |path, entry| {
+      let Some(path_str) = valid(path) else {
+        invalid_paths.push(path.to_owned());
+        return;
+      };
+      match entry {
+        Entry::Directory(d) if d.name().is_empty() => {
+          // The root directory is implied by the manifest, and is not emitted.
+        }
+        Entry::Directory(_) => lines.push(format!("dir\t{path_str}")),
+        Entry::File(f) => lines.push(format!(
+          "file\t{}\t{}\t{}\t{path_str}",
+          f.digest().hash.to_hex(),
+          f.digest().size_bytes,
+          if f.is_executable() { "x" } else { "-" },
+        )),
+        Entry::Symlink(s) => match valid(s.target()) {
+          Some(target) => lines.push(format!("link\t{target}\t{path_str}")),
+          None => invalid_paths.push(path.to_owned()),
+        },
+      }
+    });
+
+    if !invalid_paths.is_empty() {
+      return Err(format!(
+        "Cannot create a manifest for paths which are not valid UTF-8 or which contain tabs or \
+         newlines: {invalid_paths:?}"
+      ));
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+  }
+
+  ///
+  /// Reconstructs a Snapshot from a manifest produced by `Self::to_manifest`.
+  ///
+  /// NB: Only the structure of the Snapshot is reconstructed: the contents of its files must
+  /// already be present in (or otherwise be added to) the Store for it to be usable.
+  ///
+  pub fn from_manifest(manifest: &str) -> Result<Snapshot, String> {
+    let mut lines = manifest.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+      return Err(format!(
+        "Expected a manifest beginning with `{MANIFEST_HEADER}`."
+      ));
+    }
+
+    let mut path_stats = vec![];
+    let mut file_digests = HashMap::new();
+    for line in lines {
+      let invalid = || format!("Invalid manifest line: {line:?}");
+      let fields = line.split('\t').collect::<Vec<_>>();
+      match fields.as_slice() {
+        ["dir", path] => {
+          path_stats.push(PathStat::dir(PathBuf::from(path), Dir(PathBuf::from(path))));
+        }
+        ["file", fingerprint, size, executable, path] => {
+          let fingerprint = Fingerprint::from_hex_string(fingerprint).map_err(|_| invalid())?;
+          let size_bytes = size.parse::<usize>().map_err(|_| invalid())?;
+          let is_executable = match *executable {
+            "x" => true,
+            "-" => false,
+            _ => return Err(invalid()),
+          };
+          file_digests.insert(PathBuf::from(path), Digest::new(fingerprint, size_bytes));
+          path_stats.push(PathStat::file(
+            PathBuf::from(path),
+            File {
+              path: PathBuf::from(path),
+              is_executable,
+              xattrs: BTreeMap::new(),
+            },
+          ));
+        }
+        ["link", target, path] => path_stats.push(PathStat::link(
+          PathBuf::from(path),
+          Link {
+            path: PathBuf::from(path),
+            target: PathBuf::from(target),
+          },
+        )),
+        _ => return Err(invalid()),
+      }
+    }
+
+    let tree =
+      DigestTrie::from_unique_paths(path_stats.iter().map(|p| p.into()).collect(), &file_digests)?;
+    Ok(Self {
+      digest: tree.compute_root_digest(),
+      tree,
+    })
+  }
+
   /// Creates a snapshot containing empty Files for testing purposes.
   pub fn create_for_testing(files: Vec<String>, dirs: Vec<String>) -> Result<Self, String> {
     // NB: All files receive the EMPTY_DIGEST.
@@ -192,6 +668,7 @@ impl Snapshot {
           File {
             path: PathBuf::from(s),
             is_executable: false,
+            xattrs: BTreeMap::new(),
           },
         )
       })
@@ -241,15 +718,52 @@ pub trait StoreFileByDigest<Error> {
   fn store_by_digest(&self, file: File) -> future::BoxFuture<'static, Result<Digest, Error>>;
 }
 
+///
+/// A cache of the Digests of files, keyed by the absolute path, modification time and size of
+/// each file. An entry is only valid for as long as the file's modification time and size are
+/// unchanged.
+///
+pub trait DigestCache: Send + Sync {
+  fn get(&self, path: &Path, mtime: SystemTime, size: u64) -> Option<Digest>;
+
+  fn put(&self, path: PathBuf, mtime: SystemTime, size: u64, digest: Digest);
+}
+
+///
+/// A DigestCache which holds the most recent Digest of each path in memory.
+///
+#[derive(Default)]
+pub struct InMemoryDigestCache {
+  entries: Mutex<HashMap<PathBuf, (SystemTime, u64, Digest)>>,
+}
+
+impl DigestCache for InMemoryDigestCache {
+  fn get(&self, path: &Path, mtime: SystemTime, size: u64) -> Option<Digest> {
+    match self.entries.lock().get(path) {
+      Some(&(cached_mtime, cached_size, digest))
+        if cached_mtime == mtime && cached_size == size =>
+      {
+        Some(digest)
+      }
+      _ => None,
+    }
+  }
+
+  fn put(&self, path: PathBuf, mtime: SystemTime, size: u64, digest: Digest) {
+    self.entries.lock().insert(path, (mtime, size, digest));
+  }
+}
+
 ///
 /// A StoreFileByDigest which reads immutable files with a PosixFS and writes to a Store, with no
-/// caching.
+/// caching unless a DigestCache is configured.
 ///
 #[derive(Clone)]
 pub struct OneOffStoreFileByDigest {
   store: Store,
   posix_fs: Arc<PosixFS>,
   immutable: bool,
+  digest_cache: Option<Arc<dyn DigestCache>>,
 }
 
 impl OneOffStoreFileByDigest {
@@ -258,8 +772,22 @@ impl OneOffStoreFileByDigest {
       store,
       posix_fs,
       immutable,
+      digest_cache: None,
     }
   }
+
+  ///
+  /// Consult the given DigestCache before digesting each file, and skip reading any file whose
+  /// modification time and size match a cached entry. The bytes of a file are only stored when it
+  /// is read, so cached entries are assumed to remain present in the Store.
+  ///
+  pub fn with_digest_cache(
+    mut self,
+    digest_cache: Arc<dyn DigestCache>,
+  ) -> OneOffStoreFileByDigest {
+    self.digest_cache = Some(digest_cache);
+    self
+  }
 }
 
 impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
@@ -267,9 +795,27 @@ impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
     let store = self.store.clone();
     let posix_fs = self.posix_fs.clone();
     let immutable = self.immutable;
+    let digest_cache = self.digest_cache.clone();
     let res = async move {
       let path = posix_fs.file_path(&file);
-      store.store_file(true, immutable, path).await
+      let Some(digest_cache) = digest_cache else {
+        return store.store_file(true, immutable, path).await;
+      };
+
+      // NB: The metadata is captured before the file is read, so that if the file changes while
+      // it is being read, the cached entry will not match the file's new metadata.
+      let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| format!("Failed to stat {}: {e}", path.display()))?;
+      let mtime = metadata
+        .modified()
+        .map_err(|e| format!("Failed to get modification time of {}: {e}", path.display()))?;
+      if let Some(digest) = digest_cache.get(&path, mtime, metadata.len()) {
+        return Ok(digest);
+      }
+      let digest = store.store_file(true, immutable, path.clone()).await?;
+      digest_cache.put(path, mtime, metadata.len(), digest);
+      Ok(digest)
     };
     res.boxed()
   }
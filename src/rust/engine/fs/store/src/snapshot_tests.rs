@@ -1,18 +1,23 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
 
 use testutil::data::TestDirectory;
 use testutil::make_file;
 
-use crate::{OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotOps, Store, StoreError};
+use crate::{
+  DedupStats, InMemoryDigestCache, OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotOps,
+  Store, StoreError, StoreFileByDigest,
+};
 use fs::{
   Dir, DirectoryDigest, File, GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching,
-  PathGlobs, PathStat, PosixFS, StrictGlobMatching, SymlinkBehavior,
+  PathGlobs, PathStat, Permissions, PosixFS, StrictGlobMatching, SymlinkBehavior,
 };
 
 pub const STR: &str = "European Burmese";
@@ -93,6 +98,223 @@ async fn snapshot_recursive_directories() {
   assert_eq!(snapshot.directories(), vec![PathBuf::from("cats")]);
 }
 
+#[tokio::test]
+async fn snapshot_digest_concurrency() {
+  let (_, dir, posix_fs, digester) = setup();
+
+  for i in 0..20 {
+    let subdir = PathBuf::from(format!("dir{}", i % 3));
+    std::fs::create_dir_all(dir.path().join(&subdir)).unwrap();
+    make_file(
+      &dir.path().join(subdir.join(format!("file{i}"))),
+      format!("{STR}{i}").as_bytes(),
+      0o600,
+    );
+  }
+  let path_stats = expand_all_sorted(posix_fs).await;
+
+  let serial = Snapshot::from_path_stats_with_concurrency(digester.clone(), path_stats.clone(), 1)
+    .await
+    .unwrap();
+  let parallel = Snapshot::from_path_stats_with_concurrency(digester, path_stats, 8)
+    .await
+    .unwrap();
+  assert_eq!(serial.digest, parallel.digest);
+  assert_eq!(serial.files().len(), 20);
+}
+
+#[tokio::test]
+async fn snapshot_dedup_stats() {
+  let (_, dir, posix_fs, digester) = setup();
+
+  make_file(&dir.path().join("original"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("copy"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("unique"), STR2.as_bytes(), 0o600);
+  let path_stats = expand_all_sorted(posix_fs).await;
+
+  let (snapshot, dedup_stats) =
+    Snapshot::from_path_stats_with_dedup_stats(digester.clone(), path_stats.clone())
+      .await
+      .unwrap();
+  assert_eq!(
+    snapshot,
+    Snapshot::from_path_stats(digester, path_stats)
+      .await
+      .unwrap()
+  );
+  assert_eq!(
+    dedup_stats,
+    DedupStats {
+      duplicate_files: 1,
+      duplicate_bytes: STR.len(),
+    }
+  );
+}
+
+#[tokio::test]
+async fn snapshot_with_file_digests() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  std::fs::create_dir(dir.path().join("cats")).unwrap();
+  let files = [("roland", STR), ("cats/felix", STR2), ("cats/tom", "meow")];
+  for (path, content) in files {
+    make_file(&dir.path().join(path), content.as_bytes(), 0o600);
+  }
+
+  let path_stats = expand_all_sorted(posix_fs).await;
+  let (snapshot, file_digests) =
+    Snapshot::from_path_stats_with_file_digests(digester.clone(), path_stats.clone())
+      .await
+      .unwrap();
+  assert_eq!(
+    snapshot,
+    Snapshot::from_path_stats(digester, path_stats)
+      .await
+      .unwrap()
+  );
+
+  // The digests are in path order, and match those of the individually stored files.
+  assert_eq!(
+    file_digests.keys().cloned().collect::<Vec<_>>(),
+    vec![
+      PathBuf::from("cats/felix"),
+      PathBuf::from("cats/tom"),
+      PathBuf::from("roland")
+    ]
+  );
+  for (path, content) in files {
+    let digest = store
+      .store_file_bytes(content.as_bytes().to_vec().into(), false)
+      .await
+      .unwrap();
+    assert_eq!(file_digests[&PathBuf::from(path)], digest);
+  }
+}
+
+#[tokio::test]
+async fn snapshot_from_path_globs() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  std::fs::create_dir(dir.path().join("sub")).unwrap();
+  make_file(&dir.path().join("a.rs"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("sub/b.rs"), STR2.as_bytes(), 0o600);
+  make_file(&dir.path().join("c.txt"), STR.as_bytes(), 0o600);
+  let globs = |glob: &str| {
+    PathGlobs::new(
+      vec![glob.to_owned()],
+      StrictGlobMatching::Error("".to_owned()),
+      GlobExpansionConjunction::AllMatch,
+    )
+    .parse()
+    .unwrap()
+  };
+
+  let snapshot = Snapshot::from_path_globs(posix_fs.clone(), store.clone(), globs("**/*.rs"))
+    .await
+    .unwrap();
+  assert_eq!(
+    snapshot.files(),
+    vec![PathBuf::from("a.rs"), PathBuf::from("sub/b.rs")]
+  );
+
+  // Identical to expanding and digesting separately.
+  let path_stats = posix_fs
+    .expand_globs(globs("**/*.rs"), SymlinkBehavior::Aware, None)
+    .await
+    .unwrap();
+  assert_eq!(
+    snapshot.digest,
+    Snapshot::from_path_stats(digester, path_stats)
+      .await
+      .unwrap()
+      .digest
+  );
+
+  // Strict matching is honored.
+  assert!(
+    Snapshot::from_path_globs(posix_fs.clone(), store.clone(), globs("*.java"))
+      .await
+      .is_err()
+  );
+
+  // The symlink behavior of the PosixFS is honored.
+  std::os::unix::fs::symlink("a.rs", dir.path().join("link.rs")).unwrap();
+  let snapshot = Snapshot::from_path_globs(posix_fs, store.clone(), globs("*.rs"))
+    .await
+    .unwrap();
+  assert_eq!(snapshot.tree.symlinks(), vec![PathBuf::from("link.rs")]);
+  let oblivious_fs = Arc::new(
+    PosixFS::new_with_symlink_behavior(
+      dir.path(),
+      GitignoreStyleExcludes::create(vec![]).unwrap(),
+      task_executor::Executor::new(),
+      SymlinkBehavior::Oblivious,
+    )
+    .unwrap(),
+  );
+  let snapshot = Snapshot::from_path_globs(oblivious_fs, store, globs("*.rs"))
+    .await
+    .unwrap();
+  assert!(snapshot.tree.symlinks().is_empty());
+  assert_eq!(
+    snapshot.files(),
+    vec![PathBuf::from("a.rs"), PathBuf::from("link.rs")]
+  );
+}
+
+#[tokio::test]
+async fn snapshot_preserving_symlinks_roundtrip() {
+  let (store, dir, _, _) = setup();
+
+  std::fs::create_dir(dir.path().join("sub")).unwrap();
+  make_file(&dir.path().join("sub/target"), STR.as_bytes(), 0o600);
+  std::os::unix::fs::symlink("sub/target", dir.path().join("link")).unwrap();
+  let globs = PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+
+  let capture = |symlink_behavior| {
+    Snapshot::capture_snapshot_from_arbitrary_root_with_symlink_behavior(
+      store.clone(),
+      task_executor::Executor::new(),
+      dir.path().to_owned(),
+      globs.clone(),
+      None,
+      symlink_behavior,
+    )
+  };
+  let snapshot = capture(SymlinkBehavior::Aware).await.unwrap();
+  assert_ne!(
+    snapshot,
+    capture(SymlinkBehavior::Oblivious).await.unwrap(),
+    "By default, the symlink should be resolved."
+  );
+
+  let materialize_dir = tempfile::TempDir::new().unwrap();
+  store
+    .materialize_directory(
+      materialize_dir.path().to_owned(),
+      snapshot.clone().into(),
+      false,
+      &BTreeSet::new(),
+      Permissions::Writable,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    std::fs::read_link(materialize_dir.path().join("link")).unwrap(),
+    PathBuf::from("sub/target")
+  );
+  assert_eq!(
+    std::fs::read(materialize_dir.path().join("link")).unwrap(),
+    STR.as_bytes()
+  );
+}
+
 #[tokio::test]
 async fn snapshot_from_digest() {
   let (store, dir, posix_fs, digester) = setup();
@@ -174,6 +396,417 @@ async fn snapshot_recursive_directories_including_empty() {
   );
 }
 
+#[tokio::test]
+async fn snapshot_update_matches_full_snapshot() {
+  let (_, dir, posix_fs, digester) = setup();
+
+  let cats = PathBuf::from("cats");
+  let dogs = PathBuf::from("dogs");
+  let birds = PathBuf::from("birds");
+  std::fs::create_dir_all(dir.path().join(&cats)).unwrap();
+  std::fs::create_dir_all(dir.path().join(&dogs)).unwrap();
+  make_file(&dir.path().join(cats.join("roland")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join(dogs.join("fido")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("treats"), STR.as_bytes(), 0o600);
+
+  let previous =
+    Snapshot::from_path_stats(digester.clone(), expand_all_sorted(posix_fs.clone()).await)
+      .await
+      .unwrap();
+
+  // Modify one file, add a new directory containing a file, and remove another file.
+  make_file(
+    &dir.path().join(cats.join("roland")),
+    STR2.as_bytes(),
+    0o600,
+  );
+  std::fs::create_dir_all(dir.path().join(&birds)).unwrap();
+  make_file(
+    &dir.path().join(birds.join("tweety")),
+    STR.as_bytes(),
+    0o600,
+  );
+  std::fs::remove_file(dir.path().join(dogs.join("fido"))).unwrap();
+
+  let changed = vec![
+    PathStat::file(
+      cats.join("roland"),
+      File {
+        path: cats.join("roland"),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      },
+    ),
+    PathStat::dir(birds.clone(), Dir(birds.clone())),
+    PathStat::file(
+      birds.join("tweety"),
+      File {
+        path: birds.join("tweety"),
+        is_executable: false,
+        xattrs: BTreeMap::new(),
+      },
+    ),
+  ];
+  let updated = previous
+    .update(digester.clone(), changed, vec![dogs.join("fido")])
+    .await
+    .unwrap();
+
+  let expected = Snapshot::from_path_stats(digester, expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+  assert_eq!(updated, expected);
+  assert_eq!(
+    updated.files(),
+    vec![
+      PathBuf::from("birds/tweety"),
+      PathBuf::from("cats/roland"),
+      PathBuf::from("treats")
+    ]
+  );
+  assert_eq!(
+    updated.directories(),
+    vec![
+      PathBuf::from("birds"),
+      PathBuf::from("cats"),
+      PathBuf::from("dogs")
+    ]
+  );
+}
+
+#[tokio::test]
+async fn snapshot_update_remove_directory() {
+  let (_, dir, posix_fs, digester) = setup();
+
+  let cats = PathBuf::from("cats");
+  std::fs::create_dir_all(dir.path().join(&cats)).unwrap();
+  make_file(&dir.path().join(cats.join("roland")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("treats"), STR.as_bytes(), 0o600);
+
+  let previous =
+    Snapshot::from_path_stats(digester.clone(), expand_all_sorted(posix_fs.clone()).await)
+      .await
+      .unwrap();
+
+  std::fs::remove_dir_all(dir.path().join(&cats)).unwrap();
+  let updated = previous
+    .update(digester.clone(), vec![], vec![cats])
+    .await
+    .unwrap();
+
+  let expected = Snapshot::from_path_stats(digester, expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+  assert_eq!(updated, expected);
+  assert_eq!(updated.files(), vec![PathBuf::from("treats")]);
+}
+
+#[tokio::test]
+async fn snapshot_manifest_roundtrip() {
+  let (_, dir, posix_fs, digester) = setup();
+
+  let cats = PathBuf::from("cats");
+  let empty = PathBuf::from("empty");
+  std::fs::create_dir_all(dir.path().join(&cats)).unwrap();
+  std::fs::create_dir_all(dir.path().join(&empty)).unwrap();
+  make_file(&dir.path().join(cats.join("roland")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join(cats.join("tabby")), STR2.as_bytes(), 0o700);
+  make_file(&dir.path().join("treats"), STR.as_bytes(), 0o600);
+
+  let snapshot = Snapshot::from_path_stats(digester, expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+
+  let manifest = snapshot.to_manifest().unwrap();
+  let roland = Digest::of_bytes(STR.as_bytes());
+  let tabby = Digest::of_bytes(STR2.as_bytes());
+  assert_eq!(
+    manifest,
+    format!(
+      "# snapshot manifest v1\n\
+       dir\tcats\n\
+       file\t{}\t{}\t-\tcats/roland\n\
+       file\t{}\t{}\tx\tcats/tabby\n\
+       dir\tempty\n\
+       file\t{}\t{}\t-\ttreats\n",
+      roland.hash.to_hex(),
+      roland.size_bytes,
+      tabby.hash.to_hex(),
+      tabby.size_bytes,
+      roland.hash.to_hex(),
+      roland.size_bytes,
+    )
+  );
+
+  let roundtripped = Snapshot::from_manifest(&manifest).unwrap();
+  assert_eq!(roundtripped.digest, snapshot.digest);
+  assert_eq!(roundtripped.files(), snapshot.files());
+  assert_eq!(roundtripped.directories(), snapshot.directories());
+
+  assert!(Snapshot::from_manifest("dir\tcats\n").is_err());
+}
+
+#[tokio::test]
+async fn snapshot_filter() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  let src = PathBuf::from("src");
+  std::fs::create_dir_all(dir.path().join(&src)).unwrap();
+  make_file(&dir.path().join(src.join("a.rs")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join(src.join("b.py")), STR2.as_bytes(), 0o600);
+  make_file(&dir.path().join("c.py"), STR2.as_bytes(), 0o600);
+
+  let snapshot = Snapshot::from_path_stats(digester.clone(), expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+
+  let filtered = snapshot
+    .filter(
+      &PathGlobs::new(
+        vec!["**/*.rs".to_owned()],
+        StrictGlobMatching::Ignore,
+        GlobExpansionConjunction::AllMatch,
+      ),
+      &store,
+    )
+    .await
+    .unwrap();
+  assert_eq!(filtered.files(), vec![src.join("a.rs")]);
+  assert_eq!(filtered.directories(), vec![src.clone()]);
+
+  // The result is identical to a Snapshot of just the matched file.
+  let expected = Snapshot::from_path_stats(
+    digester,
+    vec![
+      PathStat::dir(src.clone(), Dir(src.clone())),
+      PathStat::file(
+        src.join("a.rs"),
+        File {
+          path: src.join("a.rs"),
+          is_executable: false,
+          xattrs: BTreeMap::new(),
+        },
+      ),
+    ],
+  )
+  .await
+  .unwrap();
+  assert_eq!(filtered, expected);
+  assert_eq!(
+    Snapshot::from_digest(
+      store,
+      DirectoryDigest::from_persisted_digest(filtered.digest)
+    )
+    .await
+    .unwrap(),
+    filtered
+  );
+}
+
+#[tokio::test]
+async fn snapshot_prefix() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  make_file(&dir.path().join("a.txt"), STR.as_bytes(), 0o600);
+  let snapshot = Snapshot::from_path_stats(digester, expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+
+  let prefixed = snapshot
+    .prefix(PathBuf::from("sub/dir"), &store)
+    .await
+    .unwrap();
+  assert_eq!(prefixed.files(), vec![PathBuf::from("sub/dir/a.txt")]);
+  assert_eq!(
+    prefixed.directories(),
+    vec![PathBuf::from("sub"), PathBuf::from("sub/dir")]
+  );
+  // The new Directories were recorded.
+  assert_eq!(
+    Snapshot::from_digest(
+      store.clone(),
+      DirectoryDigest::from_persisted_digest(prefixed.digest)
+    )
+    .await
+    .unwrap(),
+    prefixed
+  );
+
+  // Absolute and escaping prefixes are not allowed.
+  let err = snapshot
+    .prefix(PathBuf::from("/sub"), &store)
+    .await
+    .unwrap_err();
+  assert!(err.contains("Absolute paths are not allowed"), "{err}");
+  let err = snapshot
+    .prefix(PathBuf::from("sub/../.."), &store)
+    .await
+    .unwrap_err();
+  assert!(err.contains("escape the root"), "{err}");
+}
+
+#[tokio::test]
+async fn snapshot_strip_prefix() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  let sub_dir = PathBuf::from("sub/dir");
+  std::fs::create_dir_all(dir.path().join(&sub_dir)).unwrap();
+  make_file(
+    &dir.path().join(sub_dir.join("a.txt")),
+    STR.as_bytes(),
+    0o600,
+  );
+  make_file(&dir.path().join("b.txt"), STR2.as_bytes(), 0o600);
+  let snapshot = Snapshot::from_path_stats(digester.clone(), expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+
+  let stripped = snapshot.strip_prefix(&sub_dir, &store).await.unwrap();
+  assert_eq!(stripped.files(), vec![PathBuf::from("a.txt")]);
+  assert!(stripped.directories().is_empty());
+  // It is the inverse of prefixing.
+  assert_eq!(
+    stripped
+      .prefix(sub_dir.clone(), &store)
+      .await
+      .unwrap()
+      .files(),
+    vec![sub_dir.join("a.txt")]
+  );
+  assert_eq!(
+    Snapshot::from_digest(
+      store.clone(),
+      DirectoryDigest::from_persisted_digest(stripped.digest)
+    )
+    .await
+    .unwrap(),
+    stripped
+  );
+
+  // Missing paths and files cannot be stripped.
+  let err = snapshot
+    .strip_prefix(Path::new("sub/missing"), &store)
+    .await
+    .unwrap_err();
+  assert!(err.contains("it does not exist"), "{err}");
+  let err = snapshot
+    .strip_prefix(Path::new("b.txt"), &store)
+    .await
+    .unwrap_err();
+  assert!(err.contains("it is not a directory"), "{err}");
+}
+
+#[tokio::test]
+async fn snapshot_to_tar() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  std::fs::create_dir_all(dir.path().join("src/empty")).unwrap();
+  make_file(&dir.path().join("src/a.txt"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("src/run.sh"), STR2.as_bytes(), 0o700);
+  std::os::unix::fs::symlink("src/a.txt", dir.path().join("link")).unwrap();
+  let snapshot = Snapshot::from_path_stats(digester, expand_all_sorted(posix_fs).await)
+    .await
+    .unwrap();
+
+  let mut archive = Vec::new();
+  snapshot.to_tar(&store, &mut archive).await.unwrap();
+  let ingested = store
+    .ingest_tar(&archive[..], SymlinkBehavior::Aware)
+    .await
+    .unwrap();
+  assert_eq!(ingested.digest, snapshot.digest);
+
+  // The archive is reproducible.
+  let mut archive2 = Vec::new();
+  snapshot.to_tar(&store, &mut archive2).await.unwrap();
+  assert_eq!(archive, archive2);
+}
+
+#[tokio::test]
+async fn snapshot_content_equal_ignoring_exec() {
+  let (_, dir, _, digester) = setup();
+
+  let src = PathBuf::from("src");
+  std::fs::create_dir_all(dir.path().join(&src)).unwrap();
+  make_file(&dir.path().join(src.join("roland")), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("other"), STR2.as_bytes(), 0o600);
+
+  let snapshot = |path: &str, is_executable: bool| {
+    let digester = digester.clone();
+    let src = src.clone();
+    let path = PathBuf::from(path);
+    async move {
+      Snapshot::from_path_stats(
+        digester,
+        vec![
+          PathStat::dir(src.clone(), Dir(src)),
+          PathStat::file(
+            path.clone(),
+            File {
+              path,
+              is_executable,
+              xattrs: BTreeMap::new(),
+            },
+          ),
+        ],
+      )
+      .await
+      .unwrap()
+    }
+  };
+
+  let executable = snapshot("src/roland", true).await;
+  let not_executable = snapshot("src/roland", false).await;
+  assert_ne!(executable, not_executable);
+  assert!(executable.content_equal_ignoring_exec(&not_executable));
+  assert!(not_executable.content_equal_ignoring_exec(&executable));
+  assert!(executable.content_equal_ignoring_exec(&executable));
+
+  // Differences in paths or content are not ignored.
+  assert!(!executable.content_equal_ignoring_exec(&snapshot("other", true).await));
+  assert!(!executable.content_equal_ignoring_exec(&Snapshot::empty()));
+}
+
+#[tokio::test]
+async fn digest_cache() {
+  let (store, dir, posix_fs, _) = setup();
+  let digester = OneOffStoreFileByDigest::new(store, posix_fs, false)
+    .with_digest_cache(Arc::new(InMemoryDigestCache::default()));
+
+  let path = dir.path().join("roland");
+  let file = File {
+    path: PathBuf::from("roland"),
+    is_executable: false,
+    xattrs: BTreeMap::new(),
+  };
+  let original = "European Burmese";
+  let modified = "European burmese";
+  make_file(&path, original.as_bytes(), 0o600);
+  let mtime = path.metadata().unwrap().modified().unwrap();
+  let set_mtime =
+    |mtime| fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(mtime)).unwrap();
+
+  assert_eq!(
+    digester.store_by_digest(file.clone()).await.unwrap(),
+    Digest::of_bytes(original.as_bytes())
+  );
+
+  // If the content changes without changing the mtime or size, the cached digest is used.
+  std::fs::write(&path, modified).unwrap();
+  set_mtime(mtime);
+  assert_eq!(
+    digester.store_by_digest(file.clone()).await.unwrap(),
+    Digest::of_bytes(original.as_bytes())
+  );
+
+  // But once the mtime changes, the file is digested again.
+  set_mtime(mtime + Duration::from_secs(10));
+  assert_eq!(
+    digester.store_by_digest(file).await.unwrap(),
+    Digest::of_bytes(modified.as_bytes())
+  );
+}
+
 #[tokio::test]
 async fn merge_directories_two_files() {
   let (store, _, _, _) = setup();
@@ -540,6 +1173,7 @@ fn make_file_stat(root: &Path, relpath: &Path, contents: &[u8], is_executable: b
     File {
       path: relpath.to_owned(),
       is_executable,
+      xattrs: BTreeMap::new(),
     },
   )
 }
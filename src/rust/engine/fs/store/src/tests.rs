@@ -14,15 +14,17 @@ use fs::{
   DigestEntry, DirectoryDigest, FileEntry, Link, PathStat, Permissions, RelativePath,
   EMPTY_DIRECTORY_DIGEST,
 };
+use futures::TryStreamExt;
 use grpc_util::prost::MessageExt;
 use grpc_util::tls;
-use hashing::{Digest, Fingerprint};
+use hashing::{Digest, Fingerprint, HashAlgorithm};
 use mock::StubCAS;
 use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use workunit_store::WorkunitStore;
 
 use crate::{
-  EntryType, FileContent, Snapshot, Store, StoreError, StoreFileByDigest, UploadSummary, MEGABYTES,
+  CopyStats, EntryType, FileContent, LocalOptions, MaterializeOptions, Snapshot, Store, StoreError,
+  StoreFileByDigest, UploadSummary, MEGABYTES,
 };
 
 pub(crate) const STORE_BATCH_API_SIZE_LIMIT: usize = 4 * 1024 * 1024;
@@ -137,6 +139,412 @@ async fn load_file_prefers_local() {
   assert_eq!(0, cas.read_request_count());
 }
 
+#[tokio::test]
+async fn open_readonly() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  let testdir = TestDirectory::containing_roland();
+
+  let store = new_local_store(dir.path());
+  store
+    .store_file_bytes(testdata.bytes(), false)
+    .await
+    .expect("Error storing file bytes");
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error storing directory");
+  std::mem::drop(store);
+
+  let store = Store::open_readonly(task_executor::Executor::new(), dir.path())
+    .expect("Error opening store read-only");
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(testdata.bytes())
+  );
+  assert_eq!(
+    store.load_directory(testdir.digest()).await,
+    Ok(testdir.directory())
+  );
+
+  let err = store
+    .store_file_bytes(TestData::catnip().bytes(), false)
+    .await
+    .expect_err("Expected a write to a read-only store to fail.");
+  assert!(err.contains("read-only"), "{err}");
+  assert_eq!(
+    load_file_bytes(&store, TestData::catnip().digest()).await,
+    Err(StoreError::MissingDigest(
+      "Was not present in the local store".to_owned(),
+      TestData::catnip().digest()
+    ))
+  );
+}
+
+#[tokio::test]
+async fn stats() {
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+
+  let before = store.stats().await.unwrap();
+  assert_eq!(0, before.files.entries);
+  assert!(before.files.map_size_bytes > 0);
+
+  for testdata in [TestData::roland(), TestData::catnip(), TestData::robin()] {
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .await
+      .expect("Error storing file bytes");
+  }
+
+  let after = store.stats().await.unwrap();
+  assert_eq!(3, after.files.entries);
+  assert_eq!(before.directories, after.directories);
+  assert!(after.to_string().starts_with("files: 3 entries"));
+}
+
+#[tokio::test]
+async fn reader_check() {
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+
+  store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .expect("Error storing file bytes");
+
+  // No reader slots are leaked by completed transactions, so there is nothing to reclaim.
+  assert_eq!(0, store.reader_check().unwrap());
+  assert!(store.max_readers().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn all_digests() {
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+
+  let mut expected_files = HashSet::new();
+  for testdata in [TestData::roland(), TestData::catnip()] {
+    expected_files.insert(
+      store
+        .store_file_bytes(testdata.bytes(), false)
+        .await
+        .expect("Error storing file bytes"),
+    );
+  }
+  let testdir = TestDirectory::containing_roland();
+  let directory_digest = store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error storing directory");
+
+  assert_eq!(
+    store
+      .all_file_digests()
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+    expected_files
+  );
+  assert_eq!(
+    store.all_directory_digests().await.unwrap(),
+    vec![directory_digest]
+  );
+}
+
+#[tokio::test]
+async fn prune_to_size_evicts_least_recently_used() {
+  let dir = TempDir::new().unwrap();
+  let src_dir = TempDir::new().unwrap();
+  let store = Store::local_only_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    src_dir.path(),
+    LocalOptions {
+      track_access: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+
+  let mut digests = vec![];
+  for content in ["aaaa", "bbbb", "cccc", "dddd"] {
+    digests.push(
+      store
+        .store_file_bytes(Bytes::from(content), false)
+        .await
+        .expect("Error storing file bytes"),
+    );
+  }
+  let (a, c) = (digests[0], digests[2]);
+
+  // Touch `c` and then `a`: `b` and `d` are never touched.
+  for digest in [c, a] {
+    store
+      .load_file_bytes_with(digest, |bytes| bytes.len())
+      .await
+      .unwrap();
+  }
+
+  // The untouched blobs are evicted first.
+  let stats = store.prune_to_size(8).await.unwrap();
+  assert_eq!(stats.removed_count, 2);
+  assert_eq!(stats.removed_bytes, 8);
+  assert_eq!(stats.remaining_bytes, 8);
+  assert_eq!(
+    store
+      .all_file_digests()
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+    vec![a, c].into_iter().collect::<HashSet<_>>()
+  );
+
+  // Then the least recently touched.
+  let stats = store.prune_to_size(4).await.unwrap();
+  assert_eq!(stats.removed_count, 1);
+  assert_eq!(store.all_file_digests().await.unwrap(), vec![a]);
+}
+
+#[tokio::test]
+async fn prune_to_size_requires_access_tracking() {
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+  let err = store.prune_to_size(0).await.unwrap_err();
+  assert!(err.contains("tracks accesses"), "{err}");
+}
+
+#[tokio::test]
+async fn copy_all_to() {
+  let src_dir = TempDir::new().unwrap();
+  let src = new_local_store(src_dir.path());
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland();
+  src
+    .store_file_bytes(roland.bytes(), false)
+    .await
+    .expect("Error storing file bytes");
+  src
+    .store_file_bytes(catnip.bytes(), false)
+    .await
+    .expect("Error storing file bytes");
+  src
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error storing directory");
+
+  let dst_dir = TempDir::new().unwrap();
+  let dst = new_local_store(dst_dir.path());
+  let stats = src.copy_all_to(&dst).await.expect("Error copying store");
+  assert_eq!(
+    stats,
+    CopyStats {
+      copied_count: 3,
+      copied_bytes: (roland.len() + catnip.len() + testdir.digest().size_bytes) as u64,
+      skipped_count: 0,
+    }
+  );
+
+  for entry_type in [EntryType::File, EntryType::Directory] {
+    let mut expected = src.all_local_digests(entry_type).await.unwrap();
+    let mut actual = dst.all_local_digests(entry_type).await.unwrap();
+    expected.sort_by_key(|digest| digest.hash);
+    actual.sort_by_key(|digest| digest.hash);
+    assert_eq!(expected, actual);
+  }
+  assert_eq!(
+    dst
+      .load_file_bytes_with(roland.digest(), Bytes::copy_from_slice)
+      .await
+      .unwrap(),
+    roland.bytes()
+  );
+
+  let stats = src.copy_all_to(&dst).await.expect("Error copying store");
+  assert_eq!(
+    stats,
+    CopyStats {
+      copied_count: 0,
+      copied_bytes: 0,
+      skipped_count: 3,
+    }
+  );
+}
+
+#[tokio::test]
+async fn small_directories() {
+  let dir = TempDir::new().unwrap();
+  let src_dir = TempDir::new().unwrap();
+  let open_store = |small_directory_size_limit| {
+    Store::local_only_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      src_dir.path(),
+      LocalOptions {
+        small_directory_size_limit,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap()
+  };
+  let tiny_directory = |i: usize| remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: format!("file{i}"),
+      digest: Some((&TestData::roland().digest()).into()),
+      ..Default::default()
+    }],
+    ..Default::default()
+  };
+  let large_directory = remexec::Directory {
+    files: (0..100)
+      .map(|i| remexec::FileNode {
+        name: format!("file{i}"),
+        digest: Some((&TestData::roland().digest()).into()),
+        ..Default::default()
+      })
+      .collect(),
+    ..Default::default()
+  };
+
+  // A Directory stored before small directories were enabled.
+  let existing_digest = open_store(None)
+    .record_directory(&tiny_directory(0), false)
+    .await
+    .unwrap();
+
+  let store = open_store(Some(1024));
+  let mut digests = vec![existing_digest];
+  for i in 1..200 {
+    digests.push(
+      store
+        .record_directory(&tiny_directory(i), false)
+        .await
+        .unwrap(),
+    );
+  }
+  let large_digest = store
+    .record_directory(&large_directory, false)
+    .await
+    .unwrap();
+  assert!(large_digest.size_bytes >= 1024);
+  assert!(dir.path().join("small_directories").exists());
+
+  // All Directories load back correctly, in whichever database they were stored in.
+  for (i, digest) in digests.iter().enumerate() {
+    assert_eq!(
+      store.load_directory(*digest).await.unwrap(),
+      tiny_directory(i)
+    );
+  }
+  assert_eq!(
+    store.load_directory(large_digest).await.unwrap(),
+    large_directory
+  );
+  let mut expected = digests.clone();
+  expected.push(large_digest);
+  assert_eq!(
+    store
+      .all_directory_digests()
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+    expected.iter().cloned().collect::<HashSet<_>>()
+  );
+
+  // And they are still found once small directories are disabled again.
+  std::mem::drop(store);
+  let store = open_store(None);
+  for (i, digest) in digests.iter().enumerate() {
+    assert_eq!(
+      store.load_directory(*digest).await.unwrap(),
+      tiny_directory(i)
+    );
+  }
+}
+
+#[tokio::test]
+async fn digest_file_without_storing() {
+  let dir = TempDir::new().unwrap();
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+
+  let testdata = TestData::roland();
+  std::fs::write(dir.path().join("roland"), testdata.bytes()).unwrap();
+  let posix_fs = fs::PosixFS::new(
+    dir.path(),
+    fs::GitignoreStyleExcludes::empty(),
+    task_executor::Executor::new(),
+  )
+  .unwrap();
+  let digest = posix_fs
+    .digest_file(&fs::File {
+      path: PathBuf::from("roland"),
+      is_executable: false,
+      xattrs: BTreeMap::new(),
+    })
+    .await
+    .unwrap();
+
+  assert_eq!(digest, testdata.digest());
+  assert_eq!(store.all_file_digests().await.unwrap(), vec![]);
+  assert_eq!(
+    digest,
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .await
+      .unwrap()
+  );
+}
+
+#[tokio::test]
+async fn hash_algorithms() {
+  let src_dir = TempDir::new().unwrap();
+  let src = src_dir.path().join("roland");
+  let testdata = TestData::roland();
+  std::fs::write(&src, testdata.bytes()).unwrap();
+
+  let mut digests = vec![];
+  for hash_algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Sha512_256] {
+    let dir = TempDir::new().unwrap();
+    let store = Store::local_only_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      src_dir.path(),
+      LocalOptions {
+        hash_algorithm,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(store.hash_algorithm(), hash_algorithm);
+
+    let expected = Digest::of_bytes_with_algorithm(&testdata.bytes(), hash_algorithm);
+    let streamed = store.store_file(false, false, src.clone()).await.unwrap();
+    let buffered = store
+      .store_file_bytes(testdata.bytes(), false)
+      .await
+      .unwrap();
+    assert_eq!(streamed, expected);
+    assert_eq!(buffered, expected);
+    assert_eq!(
+      store
+        .load_file_bytes_with(expected, Bytes::copy_from_slice)
+        .await
+        .unwrap(),
+      testdata.bytes()
+    );
+    digests.push(expected);
+  }
+
+  assert_eq!(digests[0], testdata.digest());
+  assert_ne!(digests[0].hash, digests[1].hash);
+  assert_eq!(digests[0].size_bytes, digests[1].size_bytes);
+}
+
 #[tokio::test]
 async fn load_directory_prefers_local() {
   let dir = TempDir::new().unwrap();
@@ -1085,12 +1493,7 @@ async fn materialize_missing_file() {
   let store_dir = TempDir::new().unwrap();
   let store = new_local_store(store_dir.path());
   store
-    .materialize_file(
-      file.clone(),
-      TestData::roland().digest(),
-      Permissions::ReadOnly,
-      false,
-    )
+    .materialize_file_contents(file.clone(), TestData::roland().digest(), 0o444)
     .await
     .expect_err("Want unknown digest error");
 }
@@ -1109,18 +1512,69 @@ async fn materialize_file() {
     .await
     .expect("Error saving bytes");
   store
-    .materialize_file(
-      file.clone(),
-      testdata.digest(),
-      Permissions::ReadOnly,
-      false,
-    )
+    .materialize_file_contents(file.clone(), testdata.digest(), 0o444)
     .await
     .expect("Error materializing file");
   assert_eq!(file_contents(&file), testdata.bytes());
   assert!(!is_executable(&file));
 }
 
+#[tokio::test]
+async fn materialize_single_file() {
+  let materialize_dir = TempDir::new().unwrap();
+  let file = materialize_dir.path().join("file");
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .store_file_bytes(roland.bytes(), false)
+    .await
+    .expect("Error saving bytes");
+  let dir_entries = || {
+    let mut entries = std::fs::read_dir(materialize_dir.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    entries.sort();
+    entries
+  };
+
+  store
+    .materialize_file(&file, roland.digest(), true)
+    .await
+    .expect("Error materializing file");
+  assert_eq!(file_contents(&file), roland.bytes());
+  assert!(is_executable(&file));
+  store
+    .materialize_file(&file, roland.digest(), false)
+    .await
+    .expect("Error materializing file");
+  assert!(!is_executable(&file));
+
+  // A failure to load the content leaves the existing file untouched, and nothing else behind.
+  store
+    .materialize_file(&file, catnip.digest(), false)
+    .await
+    .expect_err("Want unknown digest error");
+  assert_eq!(file_contents(&file), roland.bytes());
+  assert_eq!(dir_entries(), vec!["file".to_owned()]);
+
+  // As does a failure to move the (completely written) content into place.
+  let occupied = materialize_dir.path().join("occupied");
+  std::fs::create_dir_all(occupied.join("child")).unwrap();
+  store
+    .materialize_file(&occupied, roland.digest(), false)
+    .await
+    .expect_err("Want rename error");
+  assert!(occupied.join("child").is_dir());
+  assert_eq!(
+    dir_entries(),
+    vec!["file".to_owned(), "occupied".to_owned()]
+  );
+}
+
 #[tokio::test]
 async fn materialize_missing_directory() {
   let materialize_dir = TempDir::new().unwrap();
@@ -1226,6 +1680,49 @@ async fn materialize_directory_readonly_executable() {
   materialize_directory(Permissions::Writable, true).await
 }
 
+#[tokio::test]
+async fn materialize_directory_with_file_mode() {
+  let materialize_dir = TempDir::new().unwrap();
+
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::with_maybe_executable_files(true);
+  let recursive_testdir = TestDirectory::recursive_with(testdir.clone());
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for directory in [&recursive_testdir, &testdir] {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .expect("Error saving Directory");
+  }
+  store
+    .store_file_bytes(catnip.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  store
+    .materialize_directory_with_options(
+      materialize_dir.path().to_owned(),
+      recursive_testdir.directory_digest(),
+      false,
+      &BTreeSet::new(),
+      Permissions::Writable,
+      &MaterializeOptions::default().with_file_mode(|_| 0o600),
+    )
+    .await
+    .expect("Error materializing");
+
+  let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+  for path in ["treats.ext", "cats/feed.ext", "cats/food.ext"] {
+    assert_eq!(mode(&materialize_dir.path().join(path)), 0o600, "{path}");
+  }
+  assert_eq!(
+    file_contents(&materialize_dir.path().join("cats/feed.ext")),
+    catnip.bytes()
+  );
+}
+
 #[tokio::test]
 async fn contents_for_directory_empty() {
   let store_dir = TempDir::new().unwrap();
@@ -1324,6 +1821,194 @@ fn assert_same_filecontents(left: Vec<FileContent>, right: Vec<FileContent>) {
   );
 }
 
+#[tokio::test]
+async fn expand_directory() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland();
+  let recursive_testdir = TestDirectory::recursive();
+
+  // Only the directories are stored: the file contents are not needed.
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .record_directory(&recursive_testdir.directory(), false)
+    .await
+    .expect("Error saving recursive Directory");
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error saving Directory");
+
+  let files = store
+    .expand_directory(recursive_testdir.directory_digest())
+    .await
+    .expect("Expanding directory");
+
+  assert_eq!(
+    files,
+    vec![
+      FileEntry {
+        path: PathBuf::from("cats").join("roland.ext"),
+        digest: roland.digest(),
+        is_executable: false,
+      },
+      FileEntry {
+        path: PathBuf::from("treats.ext"),
+        digest: catnip.digest(),
+        is_executable: false,
+      },
+    ]
+  );
+}
+
+#[tokio::test]
+async fn visit_directory() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  // Directory structure:
+  //
+  // /cats/pets/cats/roland.ext
+  // /treats.ext
+  let testdir = TestDirectory::recursive_with(TestDirectory::double_nested());
+
+  // Only the directories are stored: the file contents are not needed.
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for directory in [
+    TestDirectory::containing_roland(),
+    TestDirectory::nested(),
+    TestDirectory::double_nested(),
+    testdir.clone(),
+  ] {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .expect("Error saving Directory");
+  }
+
+  let expected = vec![
+    FileEntry {
+      path: PathBuf::from("cats/pets/cats/roland.ext"),
+      digest: roland.digest(),
+      is_executable: false,
+    },
+    FileEntry {
+      path: PathBuf::from("treats.ext"),
+      digest: catnip.digest(),
+      is_executable: false,
+    },
+  ];
+
+  let mut visited = Vec::new();
+  store
+    .visit_directory(testdir.directory_digest(), |file| {
+      visited.push(file.clone())
+    })
+    .await
+    .expect("Visiting directory");
+  assert_eq!(visited, expected);
+
+  // The order matches that of an already-loaded tree.
+  let loaded = store.load_directory_digest(testdir.digest()).await.unwrap();
+  let mut visited = Vec::new();
+  store
+    .visit_directory(loaded, |file| visited.push(file.clone()))
+    .await
+    .unwrap();
+  assert_eq!(visited, expected);
+}
+
+#[tokio::test]
+async fn verify_directory() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland();
+  let recursive_testdir = TestDirectory::recursive();
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for directory in [&testdir, &recursive_testdir] {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .expect("Error saving Directory");
+  }
+  for file in [&roland, &catnip] {
+    store
+      .store_file_bytes(file.bytes(), false)
+      .await
+      .expect("Error saving file bytes");
+  }
+  assert_eq!(
+    store.verify_directory(recursive_testdir.digest()).await,
+    Ok(vec![])
+  );
+
+  // Deleting a referenced file is detected.
+  assert!(store
+    .local
+    .remove(EntryType::File, roland.digest())
+    .await
+    .unwrap());
+  assert_eq!(
+    store.verify_directory(recursive_testdir.digest()).await,
+    Ok(vec![roland.digest()])
+  );
+
+  // As is deleting a referenced directory, in which case its contents cannot be checked.
+  assert!(store
+    .local
+    .remove(EntryType::Directory, testdir.digest())
+    .await
+    .unwrap());
+  assert_eq!(
+    store.verify_directory(recursive_testdir.digest()).await,
+    Ok(vec![testdir.digest()])
+  );
+  assert_eq!(
+    store.verify_directory(testdir.digest()).await,
+    Ok(vec![testdir.digest()])
+  );
+}
+
+#[tokio::test]
+async fn load_file_bytes_many() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let robin = TestData::robin();
+  let big_file = big_file_bytes();
+  let big_file_digest = Digest::new(big_file_fingerprint(), big_file.len());
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for bytes in [roland.bytes(), catnip.bytes(), big_file.clone()] {
+    store
+      .store_file_bytes(bytes, false)
+      .await
+      .expect("Error saving file bytes");
+  }
+
+  let (found, missing) = store
+    .load_file_bytes_many(vec![
+      roland.digest(),
+      robin.digest(),
+      big_file_digest,
+      catnip.digest(),
+    ])
+    .await
+    .unwrap();
+  assert_eq!(
+    found,
+    vec![
+      (roland.digest(), roland.bytes()),
+      (big_file_digest, big_file),
+      (catnip.digest(), catnip.bytes()),
+    ]
+  );
+  assert_eq!(missing, vec![robin.digest()]);
+}
+
 #[tokio::test]
 async fn entries_for_directory() {
   let roland = TestData::roland();
@@ -1379,6 +2064,163 @@ async fn entries_for_directory() {
   assert_same_digest_entries(empty_digest_entries, vec![]);
 }
 
+#[tokio::test]
+async fn load_directory_stream() {
+  // cats/animals/birds/falcons
+  // cats/animals/cats/roland.ext
+  // treats.ext
+  let testdir = TestDirectory::recursive_with(TestDirectory::double_nested_dir_and_file());
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for directory in [
+    &testdir,
+    &TestDirectory::double_nested_dir_and_file(),
+    &TestDirectory::nested_dir_and_file(),
+    &TestDirectory::containing_falcons_dir(),
+    &TestDirectory::containing_roland(),
+  ] {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .expect("Error saving Directory");
+  }
+
+  let expected = vec![
+    DigestEntry::EmptyDirectory(PathBuf::from("cats/animals/birds/falcons")),
+    DigestEntry::File(FileEntry {
+      path: PathBuf::from("cats/animals/cats/roland.ext"),
+      digest: TestData::roland().digest(),
+      is_executable: false,
+    }),
+    DigestEntry::File(FileEntry {
+      path: PathBuf::from("treats.ext"),
+      digest: TestData::catnip().digest(),
+      is_executable: false,
+    }),
+  ];
+  let streamed = store
+    .load_directory_stream(testdir.directory_digest())
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+  assert_eq!(streamed, expected);
+
+  // As when the tree has already been loaded.
+  let loaded = store
+    .load_digest_trie(testdir.directory_digest())
+    .await
+    .unwrap();
+  let streamed = store
+    .load_directory_stream(loaded.into())
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+  assert_eq!(streamed, expected);
+
+  // A missing directory fails the stream.
+  assert!(store
+    .load_directory_stream(TestDirectory::containing_roland_and_treats().directory_digest())
+    .try_collect::<Vec<_>>()
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn ingest_tar() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let robin = TestData::robin();
+
+  let mut builder = tar::Builder::new(Vec::new());
+  let mut header = tar::Header::new_gnu();
+  header.set_entry_type(tar::EntryType::Directory);
+  header.set_mode(0o755);
+  header.set_size(0);
+  builder.append_data(&mut header, "dir/", &[][..]).unwrap();
+  for (path, data, mode) in [
+    ("a.txt", &roland, 0o644),
+    ("dir/b.txt", &catnip, 0o644),
+    ("dir/run.sh", &robin, 0o755),
+  ] {
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(mode);
+    header.set_size(data.len() as u64);
+    builder
+      .append_data(&mut header, path, &data.bytes()[..])
+      .unwrap();
+  }
+  let mut header = tar::Header::new_gnu();
+  header.set_entry_type(tar::EntryType::Symlink);
+  header.set_mode(0o777);
+  header.set_size(0);
+  builder
+    .append_link(&mut header, "link", "dir/b.txt")
+    .unwrap();
+  let archive = builder.into_inner().unwrap();
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+
+  let snapshot = store
+    .ingest_tar(&archive[..], fs::SymlinkBehavior::Aware)
+    .await
+    .unwrap();
+  assert_eq!(
+    snapshot.tree.files(fs::SymlinkBehavior::Aware),
+    vec![
+      PathBuf::from("a.txt"),
+      PathBuf::from("dir/b.txt"),
+      PathBuf::from("dir/run.sh"),
+    ]
+  );
+  assert_eq!(snapshot.tree.symlinks(), vec![PathBuf::from("link")]);
+  let is_executable =
+    |snapshot: &Snapshot, path: &str| match snapshot.tree.entry(Path::new(path)).unwrap() {
+      Some(fs::Entry::File(f)) => f.is_executable(),
+      entry => panic!("Expected a file at {path}, got {entry:?}"),
+    };
+  assert!(!is_executable(&snapshot, "a.txt"));
+  assert!(!is_executable(&snapshot, "dir/b.txt"));
+  assert!(is_executable(&snapshot, "dir/run.sh"));
+
+  // The content of the files, and the tree, were stored.
+  for data in [&roland, &catnip, &robin] {
+    assert_eq!(
+      store
+        .load_file_bytes_with(data.digest(), Bytes::copy_from_slice)
+        .await
+        .unwrap(),
+      data.bytes()
+    );
+  }
+  assert_eq!(
+    Snapshot::from_digest(
+      store.clone(),
+      DirectoryDigest::from_persisted_digest(snapshot.digest)
+    )
+    .await
+    .unwrap(),
+    snapshot
+  );
+
+  // When symlinks are not preserved, they are resolved within the archive.
+  let snapshot = store
+    .ingest_tar(&archive[..], fs::SymlinkBehavior::Oblivious)
+    .await
+    .unwrap();
+  assert_eq!(
+    snapshot.tree.files(fs::SymlinkBehavior::Aware),
+    vec![
+      PathBuf::from("a.txt"),
+      PathBuf::from("dir/b.txt"),
+      PathBuf::from("dir/run.sh"),
+      PathBuf::from("link"),
+    ]
+  );
+  assert!(snapshot.tree.symlinks().is_empty());
+}
+
 fn assert_same_digest_entries(left: Vec<DigestEntry>, right: Vec<DigestEntry>) {
   assert_eq!(
     left.len(),
@@ -1725,3 +2567,63 @@ async fn big_file_immutable_link() {
   assert_is_linked(&output_file, false);
   assert_is_linked(&nested_output_file, false);
 }
+
+// NB: On macOS, files are cloned rather than hardlinked.
+#[cfg(not(target_os = "macos"))]
+#[tokio::test]
+async fn big_file_hardlinked_from_store() {
+  use std::os::unix::fs::MetadataExt;
+
+  let store_dir = TempDir::new().unwrap();
+  let materialize_dir = TempDir::new().unwrap();
+  let file_bytes = extra_big_file_bytes();
+  let file_digest = extra_big_file_digest();
+
+  let directory = remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: "file".to_owned(),
+      digest: Some(file_digest.into()),
+      is_executable: true,
+      ..remexec::FileNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+  let store = new_local_store(store_dir.path());
+  let directory_digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+  store
+    .store_file_bytes(file_bytes.clone(), false)
+    .await
+    .expect("Error saving bytes");
+
+  store
+    .materialize_directory(
+      materialize_dir.path().to_owned(),
+      DirectoryDigest::from_persisted_digest(directory_digest),
+      false,
+      &BTreeSet::new(),
+      Permissions::ReadOnly,
+    )
+    .await
+    .expect("Error materializing");
+
+  // Both temporary directories are on the same device, so the file should share an inode with
+  // the copy in the store.
+  let materialized = materialize_dir.path().join("file");
+  let stored = store
+    .local
+    .load_from_fs(file_digest)
+    .await
+    .unwrap()
+    .expect("Large file should be stored on the filesystem");
+  assert_eq!(file_contents(&materialized), file_bytes);
+  assert!(is_executable(&materialized));
+  let materialized_metadata = materialized.metadata().unwrap();
+  assert!(materialized_metadata.nlink() >= 2);
+  assert_eq!(
+    materialized_metadata.ino(),
+    stored.metadata().unwrap().ino()
+  );
+}
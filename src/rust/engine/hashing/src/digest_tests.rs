@@ -3,6 +3,7 @@
 use self::serde_test::{assert_tokens, Token};
 use super::Digest;
 use super::Fingerprint;
+use super::EMPTY_DIGEST;
 use serde_test;
 
 #[test]
@@ -29,3 +30,35 @@ fn serialize_and_deserialize() {
     ],
   );
 }
+
+#[test]
+fn ordering() {
+  let fingerprint = |hex: &str| Fingerprint::from_hex_string(hex).unwrap();
+  let low = fingerprint("0000000000000000000000000000000000000000000000000000000000000001");
+  let high = fingerprint("0100000000000000000000000000000000000000000000000000000000000000");
+
+  let mut digests = vec![
+    Digest::new(high, 1),
+    EMPTY_DIGEST,
+    Digest::new(low, 10),
+    Digest::new(high, 0),
+    Digest::new(low, 2),
+  ];
+  digests.sort();
+  assert_eq!(
+    digests,
+    vec![
+      Digest::new(low, 2),
+      Digest::new(low, 10),
+      Digest::new(high, 0),
+      Digest::new(high, 1),
+      EMPTY_DIGEST,
+    ]
+  );
+
+  assert_eq!(
+    Digest::new(low, 2).cmp(&Digest::new(low, 2)),
+    std::cmp::Ordering::Equal
+  );
+  assert!(Digest::new(low, 1000) < Digest::new(high, 0));
+}
@@ -55,3 +55,28 @@ async fn async_hashes() {
     .unwrap();
   assert_eq!("meep".as_bytes().to_vec(), contents);
 }
+
+#[test]
+fn hash_algorithms() {
+  let sha256 = super::Digest::of_bytes_with_algorithm(b"meep", super::HashAlgorithm::Sha256);
+  let sha512_256 =
+    super::Digest::of_bytes_with_algorithm(b"meep", super::HashAlgorithm::Sha512_256);
+
+  assert_eq!(sha256, super::Digest::of_bytes(b"meep"));
+  assert_eq!(
+    sha256.hash.to_hex(),
+    "23e92dfba8fb0c93cfba31ad2962b4e35a47054296d1d375d7f7e13e0185de7a"
+  );
+  assert_eq!(
+    sha512_256.hash.to_hex(),
+    "becde2eaff72a5455112c5c020fde6903aaa8a8051a52d41fab45c729886e1a7"
+  );
+  assert_ne!(sha256.hash, sha512_256.hash);
+  assert_eq!(sha512_256.size_bytes, 4);
+
+  // Each algorithm is stable, including when hashing incrementally.
+  let mut hasher = super::Hasher::new_with_algorithm(super::HashAlgorithm::Sha512_256);
+  hasher.update(b"me");
+  hasher.update(b"ep");
+  assert_eq!(hasher.finish(), sha512_256);
+}
@@ -38,7 +38,7 @@ use generic_array::GenericArray;
 use serde::de::{MapAccess, Visitor};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde::{Deserialize, Deserializer};
-use sha2::{Digest as Sha256Digest, Sha256};
+use sha2::{Digest as Sha256Digest, Sha256, Sha512_256};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub const EMPTY_FINGERPRINT: Fingerprint = Fingerprint([
@@ -52,6 +52,21 @@ pub const EMPTY_DIGEST: Digest = Digest {
 
 pub const FINGERPRINT_SIZE: usize = 32;
 
+///
+/// The hash function used to compute a Fingerprint.
+///
+/// Every algorithm produces a `FINGERPRINT_SIZE` byte Fingerprint, so Digests (and the store keys
+/// derived from them) have the same shape regardless of the algorithm. But Digests computed with
+/// different algorithms are not comparable, and constants like `EMPTY_DIGEST` are only valid for
+/// the default algorithm (Sha256).
+///
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum HashAlgorithm {
+  #[default]
+  Sha256,
+  Sha512_256,
+}
+
 #[derive(Clone, Copy, DeepSizeOf, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub struct Fingerprint(pub [u8; FINGERPRINT_SIZE]);
 
@@ -190,7 +205,10 @@ pub struct AgedFingerprint {
 /// It is equivalent to a Bazel Remote Execution Digest, but without the overhead (and awkward API)
 /// of needing to create an entire protobuf to pass around the two fields.
 ///
-#[derive(Clone, Copy, Debug, DeepSizeOf, Eq, Hash, PartialEq)]
+/// Digests are ordered by fingerprint (byte-lexicographically), and then by size, which gives a
+/// deterministic order for lists of Digests.
+///
+#[derive(Clone, Copy, Debug, DeepSizeOf, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub struct Digest {
   pub hash: Fingerprint,
   pub size_bytes: usize,
@@ -270,37 +288,57 @@ impl Digest {
   }
 
   pub fn of_bytes(bytes: &[u8]) -> Self {
-    let mut hasher = Sha256::default();
-    hasher.update(bytes);
+    Self::of_bytes_with_algorithm(bytes, HashAlgorithm::Sha256)
+  }
 
-    Digest::new(Fingerprint::from_bytes(hasher.finalize()), bytes.len())
+  pub fn of_bytes_with_algorithm(bytes: &[u8], algorithm: HashAlgorithm) -> Self {
+    let mut hasher = Hasher::new_with_algorithm(algorithm);
+    hasher.update(bytes);
+    hasher.finish()
   }
 }
 
-/// A thin wrapper around a Sha256 hasher to preserve the length as well.
+enum AlgorithmHasher {
+  Sha256(Sha256),
+  Sha512_256(Sha512_256),
+}
+
+/// A thin wrapper around a hasher to preserve the length as well.
 pub struct Hasher {
-  hasher: Sha256,
+  hasher: AlgorithmHasher,
   byte_count: usize,
 }
 
 impl Hasher {
   pub fn new() -> Self {
+    Self::new_with_algorithm(HashAlgorithm::Sha256)
+  }
+
+  pub fn new_with_algorithm(algorithm: HashAlgorithm) -> Self {
+    let hasher = match algorithm {
+      HashAlgorithm::Sha256 => AlgorithmHasher::Sha256(Sha256::default()),
+      HashAlgorithm::Sha512_256 => AlgorithmHasher::Sha512_256(Sha512_256::default()),
+    };
     Self {
-      hasher: Sha256::default(),
+      hasher,
       byte_count: 0,
     }
   }
 
   pub fn update(&mut self, buf: &[u8]) {
-    self.hasher.update(buf);
+    match &mut self.hasher {
+      AlgorithmHasher::Sha256(hasher) => hasher.update(buf),
+      AlgorithmHasher::Sha512_256(hasher) => hasher.update(buf),
+    }
     self.byte_count += buf.len();
   }
 
   pub fn finish(self) -> Digest {
-    Digest::new(
-      Fingerprint::from_bytes(self.hasher.finalize()),
-      self.byte_count,
-    )
+    let fingerprint = match self.hasher {
+      AlgorithmHasher::Sha256(hasher) => Fingerprint::from_bytes(hasher.finalize()),
+      AlgorithmHasher::Sha512_256(hasher) => Fingerprint::from_bytes(hasher.finalize()),
+    };
+    Digest::new(fingerprint, self.byte_count)
   }
 }
 
@@ -314,8 +352,12 @@ pub struct WriterHasher<T> {
 
 impl<T> WriterHasher<T> {
   pub fn new(inner: T) -> WriterHasher<T> {
+    Self::new_with_algorithm(inner, HashAlgorithm::Sha256)
+  }
+
+  pub fn new_with_algorithm(inner: T, algorithm: HashAlgorithm) -> WriterHasher<T> {
     WriterHasher {
-      hasher: Hasher::new(),
+      hasher: Hasher::new_with_algorithm(algorithm),
       inner: inner,
     }
   }
@@ -379,7 +421,22 @@ where
   R: io::Read,
   W: io::Write,
 {
-  let mut hasher = WriterHasher::new(writer);
+  sync_copy_and_hash_with_algorithm(reader, writer, HashAlgorithm::Sha256)
+}
+
+///
+/// As `sync_copy_and_hash`, but using the given HashAlgorithm.
+///
+pub fn sync_copy_and_hash_with_algorithm<R: ?Sized, W: ?Sized>(
+  reader: &mut R,
+  writer: &mut W,
+  algorithm: HashAlgorithm,
+) -> io::Result<Digest>
+where
+  R: io::Read,
+  W: io::Write,
+{
+  let mut hasher = WriterHasher::new_with_algorithm(writer, algorithm);
   let _ = io::copy(reader, &mut hasher)?;
   Ok(hasher.finish().0)
 }
@@ -393,6 +450,29 @@ pub fn sync_verified_copy<R: ?Sized, W: ?Sized>(
   reader: &mut R,
   writer: &mut W,
 ) -> io::Result<bool>
+where
+  R: io::Read,
+  W: io::Write,
+{
+  sync_verified_copy_with_algorithm(
+    expected_digest,
+    data_is_immutable,
+    reader,
+    writer,
+    HashAlgorithm::Sha256,
+  )
+}
+
+///
+/// As `sync_verified_copy`, but for an expected_digest computed with the given HashAlgorithm.
+///
+pub fn sync_verified_copy_with_algorithm<R: ?Sized, W: ?Sized>(
+  expected_digest: Digest,
+  data_is_immutable: bool,
+  reader: &mut R,
+  writer: &mut W,
+  algorithm: HashAlgorithm,
+) -> io::Result<bool>
 where
   R: io::Read,
   W: io::Write,
@@ -402,7 +482,7 @@ where
     let copied = io::copy(reader, writer)?;
     Ok(copied as usize == expected_digest.size_bytes)
   } else {
-    Ok(expected_digest == sync_copy_and_hash(reader, writer)?)
+    Ok(expected_digest == sync_copy_and_hash_with_algorithm(reader, writer, algorithm)?)
   }
 }
 
@@ -415,7 +495,22 @@ where
   R: AsyncRead + Unpin + ?Sized,
   W: AsyncWrite + Unpin + ?Sized,
 {
-  let mut hasher = WriterHasher::new(writer);
+  async_copy_and_hash_with_algorithm(reader, writer, HashAlgorithm::Sha256).await
+}
+
+///
+/// As `async_copy_and_hash`, but using the given HashAlgorithm.
+///
+pub async fn async_copy_and_hash_with_algorithm<R, W>(
+  reader: &mut R,
+  writer: &mut W,
+  algorithm: HashAlgorithm,
+) -> tokio::io::Result<Digest>
+where
+  R: AsyncRead + Unpin + ?Sized,
+  W: AsyncWrite + Unpin + ?Sized,
+{
+  let mut hasher = WriterHasher::new_with_algorithm(writer, algorithm);
   let _ = tokio::io::copy(reader, &mut hasher).await?;
   Ok(hasher.finish().0)
 }
@@ -429,6 +524,30 @@ pub async fn async_verified_copy<R, W>(
   reader: &mut R,
   writer: &mut W,
 ) -> tokio::io::Result<bool>
+where
+  R: AsyncRead + Unpin + ?Sized,
+  W: AsyncWrite + Unpin + ?Sized,
+{
+  async_verified_copy_with_algorithm(
+    expected_digest,
+    data_is_immutable,
+    reader,
+    writer,
+    HashAlgorithm::Sha256,
+  )
+  .await
+}
+
+///
+/// As `async_verified_copy`, but for an expected_digest computed with the given HashAlgorithm.
+///
+pub async fn async_verified_copy_with_algorithm<R, W>(
+  expected_digest: Digest,
+  data_is_immutable: bool,
+  reader: &mut R,
+  writer: &mut W,
+  algorithm: HashAlgorithm,
+) -> tokio::io::Result<bool>
 where
   R: AsyncRead + Unpin + ?Sized,
   W: AsyncWrite + Unpin + ?Sized,
@@ -438,7 +557,7 @@ where
     let copied = tokio::io::copy(reader, writer).await?;
     Ok(copied as usize == expected_digest.size_bytes)
   } else {
-    Ok(expected_digest == async_copy_and_hash(reader, writer).await?)
+    Ok(expected_digest == async_copy_and_hash_with_algorithm(reader, writer, algorithm).await?)
   }
 }
 
@@ -1462,6 +1462,7 @@ pub fn extract_output_files(
         File {
           path: output_file_path_buf,
           is_executable: output_file.is_executable,
+          xattrs: BTreeMap::new(),
         },
       ))
     })
@@ -89,14 +89,12 @@ impl CommandRunner {
     let output_paths = output_dir_paths
       .into_iter()
       .flat_map(|p| {
-        let mut dir_glob = {
-          let mut dir = PathBuf::from(p).into_os_string();
-          if dir.is_empty() {
-            dir.push(".")
-          }
-          dir
-        };
-        let dir = dir_glob.clone();
+        let dir = PathBuf::from(p).into_os_string();
+        if dir.is_empty() {
+          // The root directory cannot itself be matched: only its contents.
+          return vec!["**".into()];
+        }
+        let mut dir_glob = dir.clone();
         dir_glob.push("/**");
         vec![dir, dir_glob]
       })
@@ -29,17 +29,22 @@ use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
 use std::io::{self, Read};
+use std::os::raw::c_int;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{self, Duration};
 
 use bytes::{BufMut, Bytes};
-use hashing::{sync_verified_copy, AgedFingerprint, Digest, Fingerprint, FINGERPRINT_SIZE};
+use hashing::{
+  sync_verified_copy_with_algorithm, AgedFingerprint, Digest, Fingerprint, HashAlgorithm,
+  FINGERPRINT_SIZE,
+};
 use lmdb::{
   self, Cursor, Database, DatabaseFlags, Environment, EnvironmentCopyFlags, EnvironmentFlags,
   RwTransaction, Transaction, WriteFlags,
 };
 use log::trace;
+use parking_lot::RwLock;
 use tempfile::TempDir;
 
 ///
@@ -54,6 +59,9 @@ pub const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(2 * 60 * 60);
 
 const VERSIONED_FINGERPRINT_SIZE: usize = FINGERPRINT_SIZE + 1;
 
+/// The factor by which the map of each shard may grow beyond its configured size when it fills up.
+const MAX_MAP_SIZE_GROWTH_FACTOR: usize = 16;
+
 /// VersionedFingerprint is a byte buffer one longer than the number of bytes stored in a
 /// Fingerprint. It is just the byte pattern of a Fingerprint with the version number concatenated
 /// onto the end of it.
@@ -104,6 +112,19 @@ impl AsRef<[u8]> for VersionedFingerprint {
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 struct EnvironmentId(u8);
 
+///
+/// Usage statistics for a ShardedLmdb, summed across all of its shards.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShardedLmdbStats {
+  /// The total size of the memory maps, which bounds the size the databases can grow to.
+  pub map_size_bytes: usize,
+  /// The number of bytes of the memory maps which are in use.
+  pub used_bytes: usize,
+  /// The number of entries in the content databases.
+  pub entries: usize,
+}
+
 // Each LMDB directory can have at most one concurrent writer.
 // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
 // fingerprint being stored, so that we can write to them in parallel.
@@ -113,12 +134,16 @@ struct EnvironmentId(u8);
 pub struct ShardedLmdb {
   // First Database is content, second is leases.
   lmdbs: HashMap<EnvironmentId, (EnvironmentId, PathBuf, Arc<Environment>, Database, Database)>,
+  // Growing the map of an Environment requires that no transactions are active in the process:
+  // transactions hold this lock for reading, and growing the map holds it for writing.
+  resize_lock: Arc<RwLock<()>>,
   root_path: PathBuf,
   max_size_per_shard: usize,
   executor: task_executor::Executor,
   lease_time: Duration,
   shard_count: u8,
   shard_fingerprint_mask: u8,
+  read_only: bool,
 }
 
 impl ShardedLmdb {
@@ -141,6 +166,38 @@ impl ShardedLmdb {
     executor: task_executor::Executor,
     lease_time: Duration,
     shard_count: u8,
+  ) -> Result<ShardedLmdb, String> {
+    Self::new_with_mode(
+      root_path,
+      max_size,
+      executor,
+      lease_time,
+      shard_count,
+      false,
+    )
+  }
+
+  ///
+  /// Open existing databases without write access: write transactions will fail, and neither the
+  /// shard directories nor the databases will be created if they do not already exist.
+  ///
+  pub fn new_read_only(
+    root_path: PathBuf,
+    max_size: usize,
+    executor: task_executor::Executor,
+    lease_time: Duration,
+    shard_count: u8,
+  ) -> Result<ShardedLmdb, String> {
+    Self::new_with_mode(root_path, max_size, executor, lease_time, shard_count, true)
+  }
+
+  fn new_with_mode(
+    root_path: PathBuf,
+    max_size: usize,
+    executor: task_executor::Executor,
+    lease_time: Duration,
+    shard_count: u8,
+    read_only: bool,
   ) -> Result<ShardedLmdb, String> {
     if shard_count.count_ones() != 1 {
       return Err(format!(
@@ -167,14 +224,19 @@ impl ShardedLmdb {
     let mut lmdbs = HashMap::new();
 
     for (env, dir, environment_id) in
-      ShardedLmdb::envs(&root_path, max_size_per_shard, shard_count)?
+      ShardedLmdb::envs(&root_path, max_size_per_shard, shard_count, read_only)?
     {
-      let content_database = env
-        .create_db(Some("content-versioned"), DatabaseFlags::empty())
+      let open_db = |name| {
+        if read_only {
+          env.open_db(Some(name))
+        } else {
+          env.create_db(Some(name), DatabaseFlags::empty())
+        }
+      };
+      let content_database = open_db("content-versioned")
         .map_err(|e| format!("Error creating/opening content database at {dir:?}: {e}"))?;
 
-      let lease_database = env
-        .create_db(Some("leases-versioned"), DatabaseFlags::empty())
+      let lease_database = open_db("leases-versioned")
         .map_err(|e| format!("Error creating/opening content database at {dir:?}: {e}"))?;
 
       lmdbs.insert(
@@ -191,12 +253,14 @@ impl ShardedLmdb {
 
     Ok(ShardedLmdb {
       lmdbs,
+      resize_lock: Arc::default(),
       root_path,
       max_size_per_shard,
       executor,
       lease_time,
       shard_count,
       shard_fingerprint_mask,
+      read_only,
     })
   }
 
@@ -214,17 +278,20 @@ impl ShardedLmdb {
     root_path: &Path,
     max_size_per_shard: usize,
     shard_count: u8,
+    read_only: bool,
   ) -> Result<Vec<(Environment, PathBuf, EnvironmentId)>, String> {
     let shard_shift = Self::shard_shift(shard_count);
 
     let mut envs = Vec::with_capacity(shard_count as usize);
     for b in 0..shard_count {
       let dir = root_path.join(format!("{b:x}"));
-      std::fs::create_dir_all(&dir)
-        .map_err(|err| format!("Error making directory for store at {dir:?}: {err:?}"))?;
+      if !read_only {
+        std::fs::create_dir_all(&dir)
+          .map_err(|err| format!("Error making directory for store at {dir:?}: {err:?}"))?;
+      }
       let fingerprint_prefix = b.rotate_left(shard_shift as u32);
       envs.push((
-        ShardedLmdb::make_env(&dir, max_size_per_shard)?,
+        ShardedLmdb::make_env(&dir, max_size_per_shard, read_only)?,
         dir,
         EnvironmentId(fingerprint_prefix),
       ));
@@ -232,7 +299,15 @@ impl ShardedLmdb {
     Ok(envs)
   }
 
-  fn make_env(dir: &Path, max_size_per_shard: usize) -> Result<Environment, String> {
+  fn make_env(
+    dir: &Path,
+    max_size_per_shard: usize,
+    read_only: bool,
+  ) -> Result<Environment, String> {
+    let mut flags = EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS;
+    if read_only {
+      flags |= EnvironmentFlags::READ_ONLY;
+    }
     Environment::new()
       // NO_SYNC
       // =======
@@ -264,7 +339,7 @@ impl ShardedLmdb {
       // The only down-side is that you need to make sure that any individual OS thread must
       // not try to perform multiple write transactions concurrently. Fortunately, this
       // property holds for us.
-      .set_flags(EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS)
+      .set_flags(flags)
       // 2 DBs; one for file contents, one for leases.
       .set_max_dbs(2)
       .set_map_size(max_size_per_shard)
@@ -301,6 +376,7 @@ impl ShardedLmdb {
         move || {
           let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
           let (env, db, lease_database) = store.get(&fingerprint);
+          let _resize_guard = store.resize_lock.read();
           let del_res = env.begin_rw_txn().and_then(|mut txn| {
             txn.del(db, &effective_key, None)?;
             txn
@@ -367,6 +443,7 @@ impl ShardedLmdb {
           // Open and commit a Transaction per Environment. Since we never have more than one
           // Transaction open at a time, we don't have to worry about ordering.
           for (_, (env, db, batch)) in items_by_env {
+            let _resize_guard = store.resize_lock.read();
             env
               .begin_ro_txn()
               .and_then(|txn| {
@@ -400,6 +477,42 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Returns usage statistics for the databases.
+  ///
+  pub async fn stats(&self) -> Result<ShardedLmdbStats, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut stats = ShardedLmdbStats::default();
+          for (env, database, _) in &store.all_lmdbs() {
+            let _resize_guard = store.resize_lock.read();
+            let info = env
+              .info()
+              .map_err(|err| format!("Failed to get lmdb environment info: {err}"))?;
+            let env_stat = env
+              .stat()
+              .map_err(|err| format!("Failed to get lmdb environment stats: {err}"))?;
+            stats.map_size_bytes += info.map_size();
+            stats.used_bytes += (info.last_pgno() + 1) * env_stat.page_size() as usize;
+
+            let txn = env
+              .begin_ro_txn()
+              .map_err(|err| format!("Error beginning transaction to collect stats: {err}"))?;
+            let mut cursor = txn
+              .open_ro_cursor(*database)
+              .map_err(|err| format!("Failed to open lmdb read cursor: {err}"))?;
+            stats.entries += cursor.iter().count();
+          }
+          Ok(stats)
+        },
+        |e| Err(format!("`stats` task failed: {e}")),
+      )
+      .await
+  }
+
   ///
   /// Returns all fingerprints and their ages.
   ///
@@ -411,6 +524,7 @@ impl ShardedLmdb {
         move || {
           let mut fingerprints = Vec::new();
           for (env, database, lease_database) in &store.all_lmdbs() {
+            let _resize_guard = store.resize_lock.read();
             let txn = env
               .begin_ro_txn()
               .map_err(|err| format!("Error beginning transaction to garbage collect: {err}"))?;
@@ -510,26 +624,30 @@ impl ShardedLmdb {
           // Open and commit a Transaction per Environment. Since we never have more than one
           // Transaction open at a time, we don't have to worry about ordering.
           for (_, (env, db, lease_database, batch)) in items_by_env {
-            env
-              .begin_rw_txn()
-              .and_then(|mut txn| {
-                for (effective_key, bytes) in &batch {
-                  let put_res = txn.put(db, &effective_key, &bytes, WriteFlags::NO_OVERWRITE);
-                  match put_res {
-                    Ok(()) => (),
-                    Err(lmdb::Error::KeyExist) => continue,
-                    Err(err) => return Err(err),
-                  }
-                  if initial_lease {
-                    store.lease_inner(
-                      lease_database,
-                      effective_key,
-                      store.lease_until_secs_since_epoch(),
-                      &mut txn,
-                    )?;
-                  }
-                }
-                txn.commit()
+            store
+              .write_with_growth(&env, || {
+                env
+                  .begin_rw_txn()
+                  .and_then(|mut txn| {
+                    for (effective_key, bytes) in &batch {
+                      let put_res = txn.put(db, &effective_key, &bytes, WriteFlags::NO_OVERWRITE);
+                      match put_res {
+                        Ok(()) => (),
+                        Err(lmdb::Error::KeyExist) => continue,
+                        Err(err) => return Err(err),
+                      }
+                      if initial_lease {
+                        store.lease_inner(
+                          lease_database,
+                          effective_key,
+                          store.lease_until_secs_since_epoch(),
+                          &mut txn,
+                        )?;
+                      }
+                    }
+                    txn.commit()
+                  })
+                  .map_err(StoreError::Lmdb)
               })
               .map_err(|e| {
                 format!(
@@ -564,6 +682,32 @@ impl ShardedLmdb {
     expected_digest: Digest,
     data_provider: F,
   ) -> Result<(), String>
+  where
+    R: Read + Debug,
+    F: Fn() -> Result<R, io::Error> + Send + 'static,
+  {
+    self
+      .store_with_algorithm(
+        initial_lease,
+        data_is_immutable,
+        expected_digest,
+        HashAlgorithm::Sha256,
+        data_provider,
+      )
+      .await
+  }
+
+  ///
+  /// As `Self::store`, but for an expected_digest which was computed with the given HashAlgorithm.
+  ///
+  pub async fn store_with_algorithm<F, R>(
+    &self,
+    initial_lease: bool,
+    data_is_immutable: bool,
+    expected_digest: Digest,
+    hash_algorithm: HashAlgorithm,
+    data_provider: F,
+  ) -> Result<(), String>
   where
     R: Read + Debug,
     F: Fn() -> Result<R, io::Error> + Send + 'static,
@@ -578,43 +722,50 @@ impl ShardedLmdb {
             let effective_key =
               VersionedFingerprint::new(expected_digest.hash, ShardedLmdb::SCHEMA_VERSION);
             let (env, db, lease_database) = store.get(&expected_digest.hash);
-            let put_res: Result<(), StoreError> = env
-              .begin_rw_txn()
-              .map_err(StoreError::Lmdb)
-              .and_then(|mut txn| {
-                // Second pass: copy into the reserved memory.
-                let mut writer = txn
-                  .reserve(
-                    db,
-                    &effective_key,
-                    expected_digest.size_bytes,
-                    WriteFlags::NO_OVERWRITE,
-                  )?
-                  .writer();
-                let mut read = data_provider().map_err(|e| format!("Failed to read: {e}"))?;
-                let should_retry =
-                  !sync_verified_copy(expected_digest, data_is_immutable, &mut read, &mut writer)
-                    .map_err(|e| {
+            let put_res: Result<(), StoreError> = store.write_with_growth(&env, || {
+              env
+                .begin_rw_txn()
+                .map_err(StoreError::Lmdb)
+                .and_then(|mut txn| {
+                  // Second pass: copy into the reserved memory.
+                  let mut writer = txn
+                    .reserve(
+                      db,
+                      &effective_key,
+                      expected_digest.size_bytes,
+                      WriteFlags::NO_OVERWRITE,
+                    )?
+                    .writer();
+                  let mut read = data_provider().map_err(|e| format!("Failed to read: {e}"))?;
+                  let should_retry = !sync_verified_copy_with_algorithm(
+                    expected_digest,
+                    data_is_immutable,
+                    &mut read,
+                    &mut writer,
+                    hash_algorithm,
+                  )
+                  .map_err(|e| {
                     format!("Failed to copy from {read:?} or store in {env:?}: {e:?}")
                   })?;
 
-                if should_retry {
-                  let msg = format!("Input {read:?} changed while reading.");
-                  log::debug!("{}", msg);
-                  return Err(StoreError::Retry(msg));
-                }
+                  if should_retry {
+                    let msg = format!("Input {read:?} changed while reading.");
+                    log::debug!("{}", msg);
+                    return Err(StoreError::Retry(msg));
+                  }
 
-                if initial_lease {
-                  store.lease_inner(
-                    lease_database,
-                    &effective_key,
-                    store.lease_until_secs_since_epoch(),
-                    &mut txn,
-                  )?;
-                }
-                txn.commit()?;
-                Ok(())
-              });
+                  if initial_lease {
+                    store.lease_inner(
+                      lease_database,
+                      &effective_key,
+                      store.lease_until_secs_since_epoch(),
+                      &mut txn,
+                    )?;
+                  }
+                  txn.commit()?;
+                  Ok(())
+                })
+            });
 
             match put_res {
               Ok(()) => return Ok(()),
@@ -650,16 +801,20 @@ impl ShardedLmdb {
         move || {
           let until_secs_since_epoch: u64 = store.lease_until_secs_since_epoch();
           let (env, _, lease_database) = store.get(&fingerprint);
-          env
-            .begin_rw_txn()
-            .and_then(|mut txn| {
-              store.lease_inner(
-                lease_database,
-                &VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION),
-                until_secs_since_epoch,
-                &mut txn,
-              )?;
-              txn.commit()
+          store
+            .write_with_growth(&env, || {
+              env
+                .begin_rw_txn()
+                .and_then(|mut txn| {
+                  store.lease_inner(
+                    lease_database,
+                    &VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION),
+                    until_secs_since_epoch,
+                    &mut txn,
+                  )?;
+                  txn.commit()
+                })
+                .map_err(StoreError::Lmdb)
             })
             .map_err(|e| format!("Error leasing {fingerprint:?}: {e}"))
         },
@@ -668,6 +823,56 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Runs the given write transaction against the given Environment. If the map of the
+  /// Environment is full, it is grown and the transaction is retried once.
+  ///
+  fn write_with_growth<T>(
+    &self,
+    env: &Environment,
+    mut txn: impl FnMut() -> Result<T, StoreError>,
+  ) -> Result<T, StoreError> {
+    let (map_size, res) = {
+      let _resize_guard = self.resize_lock.read();
+      (env.info()?.map_size(), txn())
+    };
+    match res {
+      Err(StoreError::Lmdb(lmdb::Error::MapFull)) => {
+        self.grow_map(env, map_size)?;
+        let _resize_guard = self.resize_lock.read();
+        txn()
+      }
+      res => res,
+    }
+  }
+
+  ///
+  /// Doubles the map size of the given Environment (up to a limit), unless another writer has
+  /// already grown it beyond the given full size.
+  ///
+  fn grow_map(&self, env: &Environment, full_map_size: usize) -> Result<(), StoreError> {
+    let _resize_guard = self.resize_lock.write();
+    let map_size = env.info()?.map_size();
+    if map_size > full_map_size {
+      return Ok(());
+    }
+    let max_map_size = self.max_size_per_shard * MAX_MAP_SIZE_GROWTH_FACTOR;
+    if map_size >= max_map_size {
+      return Err(StoreError::Io(format!(
+        "The store at {:?} is full: its map size of {map_size} bytes may not grow beyond \
+         {max_map_size} bytes.",
+        self.root_path
+      )));
+    }
+    let new_map_size = std::cmp::min(map_size * 2, max_map_size);
+    log::debug!(
+      "Growing the map of the store at {:?} to {new_map_size} bytes.",
+      self.root_path
+    );
+    env.set_map_size(new_map_size)?;
+    Ok(())
+  }
+
   fn lease_inner(
     &self,
     database: Database,
@@ -705,6 +910,7 @@ impl ShardedLmdb {
       .spawn_blocking(
         move || {
           let (env, db, _) = store.get(&fingerprint);
+          let _resize_guard = store.resize_lock.read();
           let ro_txn = env
             .begin_ro_txn()
             .map_err(|err| format!("Failed to begin read transaction: {err}"))?;
@@ -723,11 +929,121 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Loads the bytes stored for each of the given fingerprints, using a single read Transaction
+  /// per Environment. Fingerprints which are not present are omitted from the result.
+  ///
+  pub async fn load_bytes_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashMap<Fingerprint, Bytes>, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          // Group the items by the Environment that they will be loaded from.
+          let mut items_by_env = HashMap::new();
+          let mut loaded = HashMap::new();
+
+          for fingerprint in &fingerprints {
+            let effective_key =
+              VersionedFingerprint::new(*fingerprint, ShardedLmdb::SCHEMA_VERSION);
+            let (env_id, _, env, db, _) = store.get_raw(&fingerprint.0);
+
+            let (_, _, batch) = items_by_env
+              .entry(*env_id)
+              .or_insert_with(|| (env.clone(), *db, vec![]));
+            batch.push(effective_key);
+          }
+
+          for (_, (env, db, batch)) in items_by_env {
+            let _resize_guard = store.resize_lock.read();
+            env
+              .begin_ro_txn()
+              .and_then(|txn| {
+                for effective_key in &batch {
+                  match txn.get(db, &effective_key) {
+                    Ok(bytes) => {
+                      loaded.insert(
+                        effective_key.get_fingerprint(),
+                        Bytes::copy_from_slice(bytes),
+                      );
+                    }
+                    Err(lmdb::Error::NotFound) => (),
+                    Err(err) => return Err(err),
+                  };
+                }
+                txn.commit()
+              })
+              .map_err(|e| {
+                format!(
+                  "Error loading fingerprints {:?}: {}",
+                  batch
+                    .iter()
+                    .map(|key| key.get_fingerprint())
+                    .collect::<Vec<_>>(),
+                  e
+                )
+              })?;
+          }
+          Ok(loaded)
+        },
+        |e| Err(format!("`load_bytes_batch` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Clears any reader slots which are held by processes or threads that exited without releasing
+  /// them, and returns the number of slots which were reclaimed across all shards. This allows for
+  /// recovery from `MDB_READERS_FULL` errors.
+  ///
+  pub fn reader_check(&self) -> Result<usize, String> {
+    let mut reclaimed = 0;
+    for (env, _, _) in self.all_lmdbs() {
+      let mut dead: c_int = 0;
+      // The environment is held open by the `Arc` for the duration of the call.
+      let rc = unsafe { lmdb_sys::mdb_reader_check(env.env(), &mut dead) };
+      if rc != 0 {
+        return Err(format!(
+          "Failed to check lmdb readers: {}",
+          lmdb::Error::from_err_code(rc)
+        ));
+      }
+      reclaimed += dead as usize;
+    }
+    Ok(reclaimed)
+  }
+
+  ///
+  /// The maximum number of concurrent read transactions supported by each shard.
+  ///
+  pub fn max_readers(&self) -> Result<usize, String> {
+    let mut max_readers = 0;
+    for (env, _, _) in self.all_lmdbs() {
+      let info = env
+        .info()
+        .map_err(|err| format!("Failed to get lmdb environment info: {err}"))?;
+      max_readers = max_readers.max(info.max_readers() as usize);
+    }
+    Ok(max_readers)
+  }
+
   #[allow(clippy::useless_conversion)] // False positive: https://github.com/rust-lang/rust-clippy/issues/3913
   pub fn compact(&self) -> Result<(), String> {
-    for (env, old_dir, _) in
-      ShardedLmdb::envs(&self.root_path, self.max_size_per_shard, self.shard_count)?
-    {
+    if self.read_only {
+      return Err(format!(
+        "Cannot compact the read-only store at {:?}.",
+        self.root_path
+      ));
+    }
+    for (env, old_dir, _) in ShardedLmdb::envs(
+      &self.root_path,
+      self.max_size_per_shard,
+      self.shard_count,
+      false,
+    )? {
       let new_dir = TempDir::new_in(old_dir.parent().unwrap()).expect("TODO");
       env
         .copy(new_dir.path(), EnvironmentCopyFlags::COMPACT)
@@ -763,6 +1079,15 @@ enum StoreError {
   Retry(String),
 }
 
+impl fmt::Display for StoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Lmdb(err) => write!(f, "{err}"),
+      Self::Io(msg) | Self::Retry(msg) => write!(f, "{msg}"),
+    }
+  }
+}
+
 impl From<lmdb::Error> for StoreError {
   fn from(err: lmdb::Error) -> Self {
     Self::Lmdb(err)
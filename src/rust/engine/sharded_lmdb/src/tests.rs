@@ -95,6 +95,41 @@ async fn store_failure() {
   assert!(result.is_err());
 }
 
+#[tokio::test]
+async fn store_grows_full_map() {
+  let tempdir = TempDir::new().unwrap();
+  let s = ShardedLmdb::new(
+    tempdir.path().to_owned(),
+    64 * 1024,
+    Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let initial_map_size = s.stats().await.unwrap().map_size_bytes;
+
+  // Together, the blobs are larger than the initial map.
+  let blobs = (0..6_u8)
+    .map(|i| Bytes::from(vec![i; 20 * 1024]))
+    .collect::<Vec<_>>();
+  for blob in &blobs {
+    s.store_bytes(Digest::of_bytes(blob).hash, blob.clone(), false)
+      .await
+      .unwrap();
+  }
+
+  assert!(s.stats().await.unwrap().map_size_bytes > initial_map_size);
+  for blob in blobs {
+    let loaded = s
+      .load_bytes_with(Digest::of_bytes(&blob).hash, |b| {
+        Ok(Bytes::copy_from_slice(b))
+      })
+      .await
+      .unwrap();
+    assert_eq!(Some(blob), loaded);
+  }
+}
+
 fn bytes(content: u8) -> Bytes {
   Bytes::from(vec![content; 100])
 }
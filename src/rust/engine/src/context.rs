@@ -137,6 +137,9 @@ impl From<&LocalStoreOptions> for store::LocalOptions {
       directories_max_size_bytes: lso.directories_max_size_bytes,
       lease_time: lso.lease_time,
       shard_count: lso.shard_count,
+      hash_algorithm: hashing::HashAlgorithm::default(),
+      track_access: false,
+      small_directory_size_limit: None,
     }
   }
 }
@@ -603,7 +606,7 @@ impl Core {
       http_client,
       local_cache,
       vfs: PosixFS::new(&build_root, ignorer, executor)
-        .map_err(|e| format!("Could not initialize Vfs: {e:?}"))?,
+        .map_err(|e| format!("Could not initialize Vfs: {e}"))?,
       build_root,
       watcher,
       local_parallelism: exec_strategy_opts.local_parallelism,
@@ -706,7 +706,7 @@ impl Paths {
   }
 
   async fn run_node(self, context: Context) -> NodeResult<Arc<Vec<PathStat>>> {
-    let path_globs = self.path_globs.parse().map_err(throw)?;
+    let path_globs = self.path_globs.parse().map_err(|e| throw(e.to_string()))?;
     let path_stats = Self::create(context, path_globs).await?;
     Ok(Arc::new(path_stats))
   }
@@ -938,7 +938,7 @@ impl Snapshot {
   }
 
   async fn run_node(self, context: Context) -> NodeResult<store::Snapshot> {
-    let path_globs = self.path_globs.parse().map_err(throw)?;
+    let path_globs = self.path_globs.parse().map_err(|e| throw(e.to_string()))?;
 
     // We rely on Context::expand_globs to track dependencies for scandirs,
     // and `context.get(DigestFile)` to track dependencies for file digests.